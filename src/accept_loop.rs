@@ -0,0 +1,208 @@
+//! A `Listener`'s accept loop, as a `Stream`
+//!
+//! Manager main loops used to wrap `Listener::accept` in their own timeout,
+//! retry, and shutdown handling. `Listener::run` does that once: it yields an
+//! `AcceptResult` for every client and failure, waits out `AcceptPolicy::retry_delay`
+//! after a failure, and ends when the `ShutdownToken` trips or failures pile up.
+//! The listener is dropped when the stream ends, which closes its idle pipe
+//! instances. Accepted connections are unaffected.
+
+use futures_core::Stream;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use crate::{listener::Admission, Listener, QuotaKey, Scoped, Server, ShutdownToken};
+
+/// How `Listener::run` waits and retries
+#[derive(Clone, Debug)]
+pub struct AcceptPolicy {
+    idle_timeout: Option<Duration>,
+    retry_delay: Duration,
+    max_failures: usize,
+}
+
+impl Default for AcceptPolicy {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            retry_delay: Duration::from_secs(1),
+            max_failures: 5,
+        }
+    }
+}
+
+impl AcceptPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Yields `AcceptResult::Idle` whenever no client connects for `timeout`
+    ///
+    /// e.g. to exit a manager that's only needed while clients are around.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How long to wait after `AcceptResult::Failed` before accepting again
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Ends the stream after this many failures in a row. Values below 1 are treated as 1.
+    pub fn max_failures(mut self, max: usize) -> Self {
+        self.max_failures = max.max(1);
+        self
+    }
+}
+
+/// What happened on one turn of `Listener::run`
+pub enum AcceptResult<M, W> {
+    /// A client connected and passed the listener's checks, with its scopes enforced
+    Accepted(Box<Server<M, W>>),
+    /// A client went over a `ServerOptions::quota` and was disconnected
+    Rejected { pid: u32, quota: QuotaKey },
+    /// A client's identity couldn't be read for the quotas, so it was disconnected
+    Unidentified { pid: u32 },
+    /// No client connected within `AcceptPolicy::idle_timeout`
+    Idle,
+    /// Creating or connecting a pipe instance failed, and the broken instance
+    /// was replaced. The next turn waits `AcceptPolicy::retry_delay` first.
+    Failed(anyhow::Error),
+}
+
+/// The state handed from one turn of the loop to the next
+struct Turn<M, W> {
+    /// `None` once the loop is over
+    result: Option<AcceptResult<M, W>>,
+    listener: Listener,
+    failures: usize,
+}
+
+type TurnFuture<M, W> = Pin<Box<dyn Future<Output = Turn<M, W>> + Send>>;
+
+/// The stream from `Listener::run`
+///
+/// Ends when the shutdown token trips, or after yielding the
+/// `AcceptPolicy::max_failures`th failure in a row.
+pub struct AcceptLoop<M, W> {
+    /// `None` once the loop is over
+    next: Option<TurnFuture<M, W>>,
+    policy: AcceptPolicy,
+    shutdown: ShutdownToken,
+}
+
+impl<M, W> AcceptLoop<M, W>
+where
+    M: Serialize + Send + 'static,
+    W: DeserializeOwned + Scoped + Send + 'static,
+{
+    pub(crate) fn new(listener: Listener, policy: AcceptPolicy, shutdown: ShutdownToken) -> Self {
+        let next = Some(Self::turn(listener, 0, policy.clone(), shutdown.clone()));
+        Self {
+            next,
+            policy,
+            shutdown,
+        }
+    }
+
+    fn turn(
+        mut listener: Listener,
+        failures: usize,
+        policy: AcceptPolicy,
+        shutdown: ShutdownToken,
+    ) -> TurnFuture<M, W> {
+        Box::pin(async move {
+            let stop = |listener| Turn {
+                result: None,
+                listener,
+                failures,
+            };
+            if failures > 0 {
+                tokio::select! {
+                    () = shutdown.triggered() => return stop(listener),
+                    () = tokio::time::sleep(policy.retry_delay) => {}
+                }
+            }
+            let admitted = {
+                let admit = listener.admit_next::<M, W>();
+                let admit = async {
+                    match policy.idle_timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, admit).await.ok(),
+                        None => Some(admit.await),
+                    }
+                };
+                tokio::select! {
+                    biased;
+                    () = shutdown.triggered() => None,
+                    admitted = admit => Some(admitted),
+                }
+            };
+            let Some(admitted) = admitted else {
+                return stop(listener);
+            };
+            let (result, failures) = match admitted {
+                None => (AcceptResult::Idle, 0),
+                Some(Ok(Admission::Admitted(mut server, identity))) => {
+                    listener.enforce_scopes(&mut server, identity.as_ref());
+                    (AcceptResult::Accepted(server), 0)
+                }
+                Some(Ok(Admission::Rejected { pid, quota })) => {
+                    (AcceptResult::Rejected { pid, quota }, 0)
+                }
+                Some(Ok(Admission::Unidentified { pid })) => {
+                    (AcceptResult::Unidentified { pid }, 0)
+                }
+                Some(Err(error)) => {
+                    tracing::warn!(?error, failures, "Listener couldn't accept a client");
+                    (AcceptResult::Failed(error), failures + 1)
+                }
+            };
+            Turn {
+                result: Some(result),
+                listener,
+                failures,
+            }
+        })
+    }
+}
+
+impl<M, W> Stream for AcceptLoop<M, W>
+where
+    M: Serialize + Send + 'static,
+    W: DeserializeOwned + Scoped + Send + 'static,
+{
+    type Item = AcceptResult<M, W>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(next) = &mut self.next else {
+            return Poll::Ready(None);
+        };
+        let Turn {
+            result,
+            listener,
+            failures,
+        } = std::task::ready!(next.as_mut().poll(cx));
+        self.next = match &result {
+            // Dropping the listener here closes its pipe instances
+            None => None,
+            Some(_) if failures >= self.policy.max_failures => {
+                tracing::error!(failures, "Listener keeps failing, giving up");
+                None
+            }
+            Some(_) => Some(Self::turn(
+                listener,
+                failures,
+                self.policy.clone(),
+                self.shutdown.clone(),
+            )),
+        };
+        Poll::Ready(result)
+    }
+}
@@ -0,0 +1,35 @@
+//! Which internal frames a peer may send once the handshake is over
+//!
+//! Before then, each side only takes the handshake's own frames, see
+//! `handshake` and `Client::new_with_config`. After it, a frame that only
+//! belongs to the handshake, like a second `Cookie`, `Hello`, `Proof` or `Accepted`, or a
+//! `Fingerprint` or `ClockOffset` that would overwrite what the handshake
+//! settled, is a `DisconnectReason::ProtocolViolation` and ends the
+//! connection. User payloads are separate variants, so they can't pose as
+//! internal frames.
+
+use crate::{ConnectionInfo, ManagerMsgInternal, WorkerMsgInternal};
+
+/// Whether the manager may act on `msg` from a worker that finished the handshake
+pub(crate) fn worker_frame_allowed<T>(msg: &WorkerMsgInternal<T>, info: &ConnectionInfo) -> bool {
+    match msg {
+        // `split_metadata` already took off the only `Meta` allowed
+        WorkerMsgInternal::Cookie(_)
+        | WorkerMsgInternal::Hello { .. }
+        | WorkerMsgInternal::Meta(..) => false,
+        // Each is sent once, right after `Accepted`
+        WorkerMsgInternal::Fingerprint(_) => info.peer_fingerprint.is_none(),
+        WorkerMsgInternal::ClockOffset(_) => info.clock_offset.is_none(),
+        _ => true,
+    }
+}
+
+/// Whether the worker may act on `msg` from the manager once the handshake is over
+pub(crate) fn manager_frame_allowed<T>(msg: &ManagerMsgInternal<T>) -> bool {
+    !matches!(
+        msg,
+        ManagerMsgInternal::Accepted { .. }
+            | ManagerMsgInternal::Proof(_)
+            | ManagerMsgInternal::Meta(..)
+    )
+}
@@ -0,0 +1,120 @@
+//! Reusing frame body buffers between reads
+//!
+//! Every reader task takes its body buffers from one process-wide pool, and
+//! `PooledBuf` puts them back once the message is decoded, so steady-state
+//! messaging doesn't allocate a buffer per frame. Writers keep their own buffer,
+//! see `FrameWriter`.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// Buffers kept for reuse, across all connections
+const MAX_POOLED: usize = 32;
+
+/// Bigger buffers are freed instead of pooled, so one huge frame doesn't pin its memory
+pub(crate) const MAX_POOLED_CAPACITY: usize = 64 * 1024;
+
+/// New buffers get at least this much room, so small frames of different sizes can share them
+const MIN_CAPACITY: usize = 4 * 1024;
+
+static POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+static HITS: AtomicU64 = AtomicU64::new(0);
+static MISSES: AtomicU64 = AtomicU64::new(0);
+static DISCARDED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters for the frame buffer pool, see `buffer_pool_stats`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BufferPoolStats {
+    /// Reads that reused a pooled buffer
+    pub hits: u64,
+    /// Reads that allocated, because the pool was empty or the frame was too big to pool
+    pub misses: u64,
+    /// Buffers freed instead of pooled, because the pool was full or they were too big
+    pub discarded: u64,
+    /// Buffers in the pool right now
+    pub pooled: usize,
+}
+
+/// Returns the frame buffer pool's counters since the process started
+///
+/// If `misses` keeps climbing with steady traffic, most frames are bigger than
+/// the pool keeps.
+pub fn buffer_pool_stats() -> BufferPoolStats {
+    BufferPoolStats {
+        hits: HITS.load(Ordering::Relaxed),
+        misses: MISSES.load(Ordering::Relaxed),
+        discarded: DISCARDED.load(Ordering::Relaxed),
+        pooled: lock().len(),
+    }
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Vec<Vec<u8>>> {
+    // Every update is one push or pop, so a poisoned lock is still consistent
+    POOL.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Returns a zeroed buffer of `len` bytes, from the pool if it has one
+pub(crate) fn take(len: usize) -> Vec<u8> {
+    let pooled = if len <= MAX_POOLED_CAPACITY {
+        lock().pop()
+    } else {
+        None
+    };
+    let mut buf = match pooled {
+        Some(buf) => {
+            HITS.fetch_add(1, Ordering::Relaxed);
+            buf
+        }
+        None => {
+            MISSES.fetch_add(1, Ordering::Relaxed);
+            Vec::with_capacity(len.max(MIN_CAPACITY))
+        }
+    };
+    buf.clear();
+    buf.resize(len, 0);
+    buf
+}
+
+/// Gives a buffer back for a later `take`
+pub(crate) fn put(buf: Vec<u8>) {
+    if buf.capacity() > MAX_POOLED_CAPACITY {
+        DISCARDED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let mut pool = lock();
+    if pool.len() >= MAX_POOLED {
+        DISCARDED.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    pool.push(buf);
+}
+
+/// A frame body that goes back to the pool when dropped
+#[derive(Debug)]
+pub(crate) struct PooledBuf(Vec<u8>);
+
+impl PooledBuf {
+    pub(crate) fn new(buf: Vec<u8>) -> Self {
+        Self(buf)
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        put(std::mem::take(&mut self.0));
+    }
+}
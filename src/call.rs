@@ -0,0 +1,206 @@
+//! Requests that expect a response, matched up by correlation ID
+//!
+//! `Server::call` sends a `Request` frame and returns a `ResponseFuture`. The
+//! worker answers with `Client::respond`, and `Server::next` routes the
+//! `Response` frame to the waiting future instead of returning it.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+use tracing::Span;
+
+use crate::{
+    sync::{Arc, Mutex, MutexGuard},
+    DisconnectReason, Error,
+};
+
+/// A call that hasn't been answered yet
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PendingCall {
+    pub id: u64,
+    /// The name of the request's enum variant, e.g. `"Connect"`
+    pub method: String,
+    /// Time since the request was written
+    pub age: Duration,
+}
+
+struct Entry<W> {
+    method: String,
+    started: Instant,
+    tx: oneshot::Sender<Result<W, Error>>,
+}
+
+struct Inner<W> {
+    next_id: u64,
+    entries: HashMap<u64, Entry<W>>,
+}
+
+/// The calls in flight on one connection
+///
+/// Cloning shares the same set, so a `Manager` can list them while a task owns the `Server`.
+pub(crate) struct PendingCalls<W> {
+    inner: Arc<Mutex<Inner<W>>>,
+}
+
+impl<W> Clone for PendingCalls<W> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<W> Default for PendingCalls<W> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                next_id: 0,
+                entries: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl<W> PendingCalls<W> {
+    fn lock(&self) -> MutexGuard<'_, Inner<W>> {
+        // Every update is a single map operation, so a poisoned lock is still consistent
+        self.inner
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Allocates an ID for a new call
+    pub(crate) fn register(&self, method: String) -> (u64, ResponseFuture<W>) {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.lock();
+        let id = inner.next_id;
+        inner.next_id = id.wrapping_add(1);
+        inner.entries.insert(
+            id,
+            Entry {
+                method,
+                started: Instant::now(),
+                tx,
+            },
+        );
+        let future = ResponseFuture {
+            id,
+            rx,
+            calls: self.clone(),
+            span: Span::none(),
+        };
+        (id, future)
+    }
+
+    /// Hands a response to the call waiting for it
+    ///
+    /// Returns the call's method and how long it took, unless it wasn't pending.
+    pub(crate) fn complete(&self, id: u64, msg: W) -> Option<(String, Duration)> {
+        let Some(entry) = self.lock().entries.remove(&id) else {
+            tracing::warn!(
+                ?id,
+                "Got a response for a call that isn't pending, maybe it was cancelled"
+            );
+            return None;
+        };
+        let elapsed = entry.started.elapsed();
+        tracing::trace!(?id, method = entry.method, ?elapsed, "Call completed");
+        // The caller may have stopped waiting, that's fine
+        entry.tx.send(Ok(msg)).ok();
+        Some((entry.method, elapsed))
+    }
+
+    /// Fails the call waiting for `id`, e.g. because the worker's handler timed out
+    ///
+    /// Returns the same as `complete`.
+    pub(crate) fn fail(&self, id: u64, error: Error) -> Option<(String, Duration)> {
+        let Some(entry) = self.lock().entries.remove(&id) else {
+            tracing::warn!(?id, ?error, "Got an error for a call that isn't pending");
+            return None;
+        };
+        let elapsed = entry.started.elapsed();
+        tracing::debug!(?id, method = entry.method, ?error, "Call failed");
+        entry.tx.send(Err(error)).ok();
+        Some((entry.method, elapsed))
+    }
+
+    pub(crate) fn remove(&self, id: u64) {
+        self.lock().entries.remove(&id);
+    }
+
+    /// Fails every pending call, since no responses can arrive anymore
+    pub(crate) fn fail_all(&self, reason: &DisconnectReason) {
+        let entries: Vec<_> = self.lock().entries.drain().collect();
+        for (_, entry) in entries {
+            entry.tx.send(Err(Error::Disconnected(reason.clone()))).ok();
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lock().entries.is_empty()
+    }
+
+    /// Lists pending calls, oldest first
+    pub(crate) fn list(&self) -> Vec<PendingCall> {
+        let mut calls: Vec<_> = self
+            .lock()
+            .entries
+            .iter()
+            .map(|(id, entry)| PendingCall {
+                id: *id,
+                method: entry.method.clone(),
+                age: entry.started.elapsed(),
+            })
+            .collect();
+        calls.sort_by_key(|call| std::cmp::Reverse(call.age));
+        calls
+    }
+}
+
+/// Resolves to the worker's response to a `Server::call`
+///
+/// The response is only routed while `Server::next` is being polled. Dropping
+/// this cancels the call, and a late response is logged and discarded.
+pub struct ResponseFuture<W> {
+    id: u64,
+    rx: oneshot::Receiver<Result<W, Error>>,
+    calls: PendingCalls<W>,
+    /// Kept open until the response arrives or the call is cancelled, see `otel`
+    span: Span,
+}
+
+impl<W> ResponseFuture<W> {
+    pub(crate) fn with_span(mut self, span: Span) -> Self {
+        self.span = span;
+        self
+    }
+
+    /// The correlation ID the worker must pass to `Client::respond`
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl<W> Future for ResponseFuture<W> {
+    type Output = Result<W, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let _entered = self.span.clone().entered();
+        Pin::new(&mut self.rx).poll(cx).map(|result| {
+            // The sender is only dropped without sending if the `Server` dropped
+            result.unwrap_or(Err(Error::Disconnected(DisconnectReason::GracefulClose)))
+        })
+    }
+}
+
+impl<W> Drop for ResponseFuture<W> {
+    fn drop(&mut self) {
+        self.calls.remove(self.id);
+    }
+}
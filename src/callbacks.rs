@@ -0,0 +1,64 @@
+//! Unsolicited messages from the worker, as a `Stream`
+//!
+//! Responses to `Server::call` never show up here, they go to their
+//! `ResponseFuture`. So a callback that arrives while a call is in flight
+//! can't be mistaken for its response, the way it can with `send` and `next`.
+
+use futures_core::Stream;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use crate::{Error, Server};
+
+type NextFuture<'a, M, W> =
+    Pin<Box<dyn Future<Output = (Result<W, Error>, &'a mut Server<M, W>)> + Send + 'a>>;
+
+/// The stream from `Server::callbacks`
+///
+/// Ends after yielding the `Error::Disconnected` that ends the connection.
+/// Responses and other control frames are routed while it's being polled.
+pub struct Callbacks<'a, M, W> {
+    /// `None` once the connection is gone
+    next: Option<NextFuture<'a, M, W>>,
+}
+
+impl<'a, M, W> Callbacks<'a, M, W>
+where
+    M: Serialize + Send + 'a,
+    W: DeserializeOwned + Send + 'a,
+{
+    pub(crate) fn new(server: &'a mut Server<M, W>) -> Self {
+        Self {
+            next: Some(Self::next_future(server)),
+        }
+    }
+
+    /// Moves the borrow into the future, so it can be handed back with the result
+    fn next_future(server: &'a mut Server<M, W>) -> NextFuture<'a, M, W> {
+        Box::pin(async move { (server.next().await, server) })
+    }
+}
+
+impl<'a, M, W> Stream for Callbacks<'a, M, W>
+where
+    M: Serialize + Send + 'a,
+    W: DeserializeOwned + Send + 'a,
+{
+    type Item = Result<W, Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let Some(next) = &mut self.next else {
+            return Poll::Ready(None);
+        };
+        let (item, server) = std::task::ready!(next.as_mut().poll(cx));
+        self.next = match &item {
+            Err(Error::Disconnected(_)) => None,
+            _ => Some(Self::next_future(server)),
+        };
+        Poll::Ready(Some(item))
+    }
+}
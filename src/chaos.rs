@@ -0,0 +1,124 @@
+//! Fault injection for tests
+//!
+//! `ChaosLayer` sits between a `FrameWriter` and the real transport and
+//! re-parses the frames passing through, so it can delay, drop, truncate, or
+//! duplicate whole frames. Faults are picked by a seeded PRNG, so a failing
+//! run can be replayed with the same seed.
+
+use std::time::Duration;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    task::JoinHandle,
+};
+
+use crate::multi_process_tests::XorShift;
+
+/// Odds of each fault, per thousand frames
+///
+/// The odds are checked in field order, so they should add up to at most 1000.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ChaosConfig {
+    pub(crate) seed: u64,
+    pub(crate) drop: u64,
+    pub(crate) duplicate: u64,
+    /// Writes the first half of the frame and then closes the transport
+    pub(crate) truncate: u64,
+    pub(crate) delay: u64,
+    pub(crate) delay_by: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum FaultKind {
+    Drop,
+    Duplicate,
+    Truncate,
+    Delay,
+}
+
+/// A fault that was injected, by the sequence number of the frame it hit
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Fault {
+    pub(crate) seq: u64,
+    pub(crate) kind: FaultKind,
+}
+
+pub(crate) struct ChaosLayer;
+
+impl ChaosLayer {
+    /// Returns a stream to hand to `FrameWriter` in place of `inner`
+    ///
+    /// The task relays frames to `inner` until the returned stream is closed
+    /// or a frame is truncated, and then returns every fault it injected.
+    pub(crate) fn wrap<W: AsyncWrite + Send + Unpin + 'static>(
+        inner: W,
+        config: ChaosConfig,
+    ) -> (DuplexStream, JoinHandle<std::io::Result<Vec<Fault>>>) {
+        let (writer, reader) = tokio::io::duplex(64 * 1024);
+        let task = tokio::spawn(relay(reader, inner, config));
+        (writer, task)
+    }
+}
+
+async fn relay<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    mut rx: R,
+    mut tx: W,
+    config: ChaosConfig,
+) -> std::io::Result<Vec<Fault>> {
+    let mut rng = XorShift::new(config.seed);
+    let mut faults = vec![];
+    loop {
+        let mut header = [0u8; 12];
+        match rx.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(error) => return Err(error),
+        }
+        let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let mut seq_buf = [0u8; 8];
+        seq_buf.copy_from_slice(&header[4..]);
+        let seq = u64::from_le_bytes(seq_buf);
+        let mut frame = header.to_vec();
+        frame.resize(
+            12 + usize::try_from(len).expect("u32 should fit in usize"),
+            0,
+        );
+        rx.read_exact(&mut frame[12..]).await?;
+
+        let roll = rng.below(1000);
+        let kind = [
+            (config.drop, FaultKind::Drop),
+            (config.duplicate, FaultKind::Duplicate),
+            (config.truncate, FaultKind::Truncate),
+            (config.delay, FaultKind::Delay),
+        ]
+        .into_iter()
+        .scan(0, |threshold, (odds, kind)| {
+            *threshold += odds;
+            Some((*threshold, kind))
+        })
+        .find(|(threshold, _)| roll < *threshold)
+        .map(|(_, kind)| kind);
+        if let Some(kind) = kind {
+            tracing::debug!(?seq, ?kind, "Injecting fault");
+            faults.push(Fault { seq, kind });
+        }
+        match kind {
+            None => tx.write_all(&frame).await?,
+            Some(FaultKind::Drop) => {}
+            Some(FaultKind::Duplicate) => {
+                tx.write_all(&frame).await?;
+                tx.write_all(&frame).await?;
+            }
+            Some(FaultKind::Truncate) => {
+                tx.write_all(&frame[..frame.len() / 2]).await?;
+                break;
+            }
+            Some(FaultKind::Delay) => {
+                tokio::time::sleep(config.delay_by).await;
+                tx.write_all(&frame).await?;
+            }
+        }
+    }
+    tx.shutdown().await?;
+    Ok(faults)
+}
@@ -0,0 +1,94 @@
+//! Helpers for short-lived command-line tools that talk to a running manager
+//!
+//! e.g. a `subzonectl status` that finds the manager in the `Registry`, sends
+//! one request, prints the reply, and exits with a code scripts can check.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{process::ExitCode, time::Duration};
+
+use crate::{Client, ManagerMsgInternal, Registry};
+
+/// No manager is registered under the name, or it has exited. Same as `EX_UNAVAILABLE`
+pub const EXIT_NOT_RUNNING: u8 = 69;
+/// Anything else went wrong. Same as `EX_SOFTWARE`
+pub const EXIT_FAILED: u8 = 70;
+/// The manager didn't reply in time. Same as `EX_TEMPFAIL`
+pub const EXIT_TIMEOUT: u8 = 75;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CliError {
+    #[error("the manager isn't running")]
+    NotRunning,
+    #[error("the manager didn't reply within {0:?}")]
+    Timeout(Duration),
+    #[error(transparent)]
+    Failed(#[from] anyhow::Error),
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> ExitCode {
+        match self {
+            Self::NotRunning => ExitCode::from(EXIT_NOT_RUNNING),
+            Self::Timeout(_) => ExitCode::from(EXIT_TIMEOUT),
+            Self::Failed(_) => ExitCode::from(EXIT_FAILED),
+        }
+    }
+}
+
+/// Connects to the manager registered as `name`, sends `request`, and waits for one reply
+///
+/// `timeout` covers connecting, sending, and waiting.
+pub async fn request<M: DeserializeOwned, W: Serialize>(
+    registry: &Registry,
+    name: &str,
+    request: W,
+    timeout: Duration,
+) -> Result<M, CliError> {
+    let endpoint = registry.lookup(name)?.ok_or(CliError::NotRunning)?;
+    let exchange = async {
+        let mut client = Client::<M, W>::from_endpoint(&endpoint)?;
+        client.send(request).await?;
+        let reply = loop {
+            match client.next().await? {
+                ManagerMsgInternal::User(reply) => break reply,
+                ManagerMsgInternal::Shutdown => anyhow::bail!("the manager shut down"),
+                _ => {}
+            }
+        };
+        client.close().await?;
+        Ok(reply)
+    };
+    tokio::time::timeout(timeout, exchange)
+        .await
+        .map_err(|_| CliError::Timeout(timeout))?
+        .map_err(CliError::Failed)
+}
+
+/// Runs `request` on a fresh runtime and turns the result into an exit code
+///
+/// Errors are printed to stderr. Meant to be returned straight from `main`.
+pub fn run<M: DeserializeOwned, W: Serialize>(
+    registry: &Registry,
+    name: &str,
+    msg: W,
+    timeout: Duration,
+    on_reply: impl FnOnce(M) -> ExitCode,
+) -> ExitCode {
+    let rt = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(rt) => rt,
+        Err(error) => {
+            eprintln!("couldn't start Tokio runtime: {error}");
+            return ExitCode::from(EXIT_FAILED);
+        }
+    };
+    match rt.block_on(request(registry, name, msg, timeout)) {
+        Ok(reply) => on_reply(reply),
+        Err(error) => {
+            eprintln!("{error:#}");
+            error.exit_code()
+        }
+    }
+}
@@ -1,24 +1,110 @@
 use anyhow::Result;
 use serde::{de::DeserializeOwned, Serialize};
-use std::marker::PhantomData;
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    marker::PhantomData,
+    path::PathBuf,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
 use tokio::{
-    io::AsyncWriteExt,
+    io::{BufReader, WriteHalf},
     net::windows::named_pipe::{self, NamedPipeClient},
-    sync::mpsc,
+    sync::{mpsc, oneshot, watch, Mutex},
+    task::JoinHandle,
+    time::{timeout, timeout_at, Instant},
+};
+use tracing::{Instrument as _, Span};
+
+use crate::{
+    clock::{self, Transit},
+    disconnect::PeerProcess,
+    etw,
+    event_log::EventLog,
+    extension::Extensions,
+    fingerprint,
+    frame::{
+        reader_task, recv_frame, try_recv_frame, FrameLimits, FrameReader, FrameWriter, ReadLimits,
+    },
+    handshake, log_filter,
+    log_forward::LogForwarder,
+    method_stats, os,
+    os_shutdown::{self, OsShutdown},
+    otel,
+    redact::MessageLog,
+    repro::{self, ReproCapture},
+    stats, sys,
+    watchdog::{self, PollTracker},
+    worker_args, ClockOffset, Config, ConfigAck, ConnectionEvent, ConnectionEventKind,
+    ConnectionInfo, DisconnectReason, Endpoint, Error, HealthStatus, Histogram, ManagerMsgInternal,
+    Metadata, PipeId, Redact, RemoteError, ReproSide, ShutdownToken, WorkerMsgInternal,
+    PROTOCOL_VERSION,
 };
 
-use crate::{read_deserialize, write_serialize, Error, ManagerMsgInternal, WorkerMsgInternal};
+/// If the manager stops reading for this long, `send` gives up and closes the connection
+pub const DEFAULT_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long `close` waits for queued frames to reach the manager
+pub const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
 
 /// A client that's connected to a server
 ///
 /// Manual testing shows that if the corresponding Server's process crashes, Windows will
 /// be nice and return errors for anything trying to read from the Client
 pub struct Client<M, W> {
-    pipe_writer: tokio::io::WriteHalf<NamedPipeClient>,
+    info: ConnectionInfo,
+    /// Shared with the log forwarding task
+    pipe_writer: Arc<Mutex<FrameWriter<WriteHalf<NamedPipeClient>>>>,
     /// Needed to make `next` cancel-safe
-    read_rx: mpsc::Receiver<Vec<u8>>,
+    read_rx: mpsc::Receiver<Result<Vec<u8>, Error>>,
     /// Needed to make `next` cancel-safe
-    reader_task: tokio::task::JoinHandle<Result<()>>,
+    reader_task: JoinHandle<()>,
+    /// Writes forwarded `tracing` events, if `forward_logs` was called
+    log_task: Option<LogTask>,
+    /// For waiting until the manager has read everything, see `close_within`
+    flush_handle: Arc<sys::PipeHandle>,
+    /// Why the connection ended, once `next` has seen it
+    disconnect: Option<DisconnectReason>,
+    /// Sent by the manager during the handshake, see `SubprocessBuilder::resume_state`
+    resume_state: Option<Vec<u8>>,
+    extensions: Extensions,
+    log_filter: Option<log_filter::LogFilterHandle>,
+    /// How long `send` waits for the manager to accept a frame
+    send_timeout: Option<Duration>,
+    /// How long `close` waits for queued frames to reach the manager
+    close_timeout: Duration,
+    /// Set when a write timed out, since the frame may be half-written
+    write_stalled: bool,
+    /// Requests returned by `next` that haven't been answered with `respond`
+    unanswered: Unanswered,
+    poll_tracker: PollTracker,
+    /// Warns if `next` stops being polled, see `set_watchdog`
+    watchdog_task: Option<JoinHandle<()>>,
+    /// Writes `WorkerStats` while the manager wants them
+    stats_task: Option<JoinHandle<()>>,
+    /// Sent in answer to health probes, see `set_health`
+    health: HealthStatus,
+    /// Set by `handle_os_shutdown`
+    os_shutdown: Option<watch::Receiver<Option<OsShutdown>>>,
+    /// Headers of the last message from `next`
+    metadata: Option<Metadata>,
+    /// Tripped by `next`, see `shutdown_token`
+    shutdown: ShutdownToken,
+    /// Shared with the reader task, see `transit_latency`
+    transit: Transit,
+    /// Shared with the reader task, see `apply_config`
+    read_limits: ReadLimits,
+    /// See `log_messages`
+    message_log: Option<MessageLog>,
+    /// See `recent_events`
+    history: EventLog,
+    /// See `capture_repros`
+    repro: Option<ReproCapture>,
+    /// See `server_verified`
+    server_verified: bool,
+    /// Replies queued by `handle_frame`, sent before the next frame is read
+    deferred: VecDeque<Deferred>,
     _manager_msg: PhantomData<M>,
     _worker_msg: PhantomData<W>,
 }
@@ -26,13 +112,149 @@ pub struct Client<M, W> {
 impl<M: DeserializeOwned, W: Serialize> Client<M, W> {
     /// Creates a `Client` and echoes the security cookie back to the `Server`
     ///
-    /// Doesn't block, fails instantly if the server isn't up.
-    pub async fn new(server_id: &str) -> Result<Self> {
-        let mut client = Client::new_unsecured(server_id)?;
+    /// Fails instantly if the server isn't up. Otherwise, waits for the server
+    /// to accept the cookie. The server must first prove it knows the cookie,
+    /// or this fails with `Error::ServerUnverified` without sending it.
+    pub async fn new(pipe_id: &PipeId) -> Result<Self> {
+        Self::new_with_config(pipe_id, &Config::default()).await
+    }
+
+    /// `new_with_config`, and calls `retry` whenever the pipe refuses us
+    ///
+    /// `retry` gets the `Error::PermissionDenied` and returns whether to try again,
+    /// e.g. after it asked the user to approve an elevation prompt, or asked the
+    /// manager to loosen its ACL. It runs on the calling task, so hand long waits
+    /// to `spawn_blocking`. Nothing is read from stdin until the pipe opens, so the
+    /// cookie is still there for the retry.
+    pub async fn new_with_elevation_hook(
+        pipe_id: &PipeId,
+        config: &Config,
+        mut retry: impl FnMut(&Error) -> bool,
+    ) -> Result<Self> {
+        loop {
+            match Self::new_with_config(pipe_id, config).await {
+                Err(error) => match error.downcast_ref::<Error>() {
+                    Some(denied @ Error::PermissionDenied { .. }) if retry(denied) => {
+                        tracing::info!("Retrying the connection after permission was denied");
+                    }
+                    _ => return Err(error),
+                },
+                client => return client,
+            }
+        }
+    }
+
+    /// `new`, with timeouts and limits from `config`
+    pub async fn new_with_config(pipe_id: &PipeId, config: &Config) -> Result<Self> {
+        let mut client = Client::connect(pipe_id, config)?;
+        // Not under `handshake_timeout`, which can't cancel a blocking read. The
+        // manager writes the cookie once it accepts us, and kills us if we stall.
         let mut cookie = String::new();
-        std::io::stdin().read_line(&mut cookie)?;
-        let cookie = WorkerMsgInternal::Cookie(cookie.trim().to_string());
-        client.send_internal(&cookie).await?;
+        std::io::stdin()
+            .read_line(&mut cookie)
+            .map_err(|error| handshake_failed(client.info.peer_pid, error.into()))?;
+        client
+            .handshake_within(cookie.trim().to_string(), config)
+            .await?;
+        // The manager's job may not be able to kill us, see `os::wine`
+        if os::wine::detected() {
+            os::wine::watch_manager(client.info.peer_pid);
+        }
+        Ok(client)
+    }
+
+    /// `handshake`, or `Error::HandshakeTimeout` after `Config::handshake_timeout`
+    pub(crate) async fn handshake_within(&mut self, cookie: String, config: &Config) -> Result<()> {
+        let peer = self.info.peer_pid;
+        match timeout(config.handshake_timeout, self.handshake(cookie)).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(error)) => Err(handshake_failed(peer, error)),
+            Err(_) => Err(Error::HandshakeTimeout { peer }.into()),
+        }
+    }
+
+    /// Proves the manager knows `cookie`, then sends it and reads `Accepted`
+    pub(crate) async fn handshake(&mut self, cookie: String) -> Result<()> {
+        // Make the manager prove it knows the cookie before we give it away
+        let nonce = uuid::Uuid::new_v4().simple().to_string();
+        self.send_internal(&WorkerMsgInternal::Hello {
+            nonce: nonce.clone(),
+        })
+        .await?;
+        let buf = recv_frame(&mut self.read_rx, &mut self.disconnect).await?;
+        // Anything but a well-formed `Proof` could be a squatter probing us
+        let Ok(ManagerMsgInternal::<M>::Proof(proof)) = serde_json::from_slice(&buf) else {
+            return Err(Error::ServerUnverified.into());
+        };
+        if !handshake::verify_server_proof(&cookie, &nonce, &proof) {
+            return Err(Error::ServerUnverified.into());
+        }
+        self.server_verified = true;
+        let cookie = WorkerMsgInternal::Cookie(cookie);
+        let cookie_sent_us = clock::now_us();
+        self.send_internal(&cookie).await?;
+        let buf = recv_frame(&mut self.read_rx, &mut self.disconnect).await?;
+        let accepted_us = clock::now_us();
+        let buf = std::str::from_utf8(&buf)?;
+        let ManagerMsgInternal::<M>::Accepted {
+            resume_state,
+            fingerprint,
+            clock,
+        } = serde_json::from_str(buf)?
+        else {
+            return Err(Error::Disconnected(DisconnectReason::ProtocolViolation).into());
+        };
+        self.resume_state = resume_state;
+        // Older managers don't send one, and wouldn't understand ours
+        if let Some(fingerprint) = fingerprint {
+            fingerprint::warn_on_mismatch(&self.info.fingerprint, &fingerprint, self.info.peer_pid);
+            self.info.peer_fingerprint = Some(fingerprint);
+            let ours = WorkerMsgInternal::Fingerprint(self.info.fingerprint.clone());
+            self.send_internal(&ours).await?;
+        }
+        // Likewise, older managers can't read stamped frames
+        let offset = clock.and_then(|sample| {
+            ClockOffset::estimate(cookie_sent_us, sample, accepted_us).plausible()
+        });
+        if let Some(offset) = offset {
+            tracing::debug!(?offset, "Estimated the clock offset");
+            self.info.clock_offset = Some(offset);
+            self.transit.set_offset(offset);
+            let theirs = WorkerMsgInternal::ClockOffset(offset.reversed());
+            self.send_internal(&theirs).await?;
+            self.pipe_writer.lock().await.set_timestamps(true);
+        }
+        Ok(())
+    }
+
+    /// `new_with_config`, for a worker spawned with `SecretDelivery::Stdin` or
+    /// `SecretDelivery::Env`
+    ///
+    /// The pipe ID comes from the environment if it's there, otherwise from the
+    /// first line of stdin.
+    pub async fn new_inherited(config: &Config) -> Result<Self> {
+        let pipe_id = match worker_args::take_env_pipe_id()? {
+            Some(pipe_id) => pipe_id,
+            None => worker_args::read_stdin_pipe_id()?,
+        };
+        Self::new_with_config(&pipe_id, config).await
+    }
+
+    /// Connects to a manager found with `Registry::lookup`
+    ///
+    /// There's no cookie for processes the manager didn't spawn, so the manager
+    /// must be accepting them with a `Listener`.
+    pub fn from_endpoint(endpoint: &Endpoint) -> Result<Self> {
+        if endpoint.protocol_version != PROTOCOL_VERSION {
+            anyhow::bail!(
+                "manager speaks protocol version {}, but we speak {PROTOCOL_VERSION}",
+                endpoint.protocol_version
+            );
+        }
+        let client = Self::new_unsecured(&endpoint.pipe_id)?;
+        if client.info.peer_pid != endpoint.pid {
+            anyhow::bail!("pipe is owned by a different process than the registered manager");
+        }
         Ok(client)
     }
 
@@ -40,31 +262,163 @@ impl<M: DeserializeOwned, W: Serialize> Client<M, W> {
     ///
     /// Doesn't block, will fail instantly if the server isn't ready
     #[tracing::instrument(skip_all)]
-    pub(crate) fn new_unsecured(server_id: &str) -> Result<Self> {
-        let pipe = named_pipe::ClientOptions::new().open(server_id)?;
-        let (mut pipe_reader, pipe_writer) = tokio::io::split(pipe);
-        let (read_tx, read_rx) = mpsc::channel(1);
-        let reader_task = tokio::spawn(async move {
-            loop {
-                let msg = read_deserialize(&mut pipe_reader).await?;
-                read_tx.send(msg).await?;
+    pub(crate) fn new_unsecured(pipe_id: &PipeId) -> Result<Self> {
+        Self::connect(pipe_id, &Config::default())
+    }
+
+    pub(crate) fn connect(pipe_id: &PipeId, config: &Config) -> Result<Self> {
+        let pipe = match named_pipe::ClientOptions::new()
+            .security_qos_flags(sys::security_qos_flags(config.pipe_impersonation))
+            .open(pipe_id.as_str())
+        {
+            Ok(pipe) => pipe,
+            Err(error) if error.kind() == std::io::ErrorKind::PermissionDenied => {
+                return Err(permission_denied(pipe_id).into());
             }
+            Err(error) => return Err(error.into()),
+        };
+        let server_pid = sys::named_pipe_server_pid(&pipe)?;
+        etw::connected(etw::Side::Worker, server_pid);
+        let history = EventLog::default();
+        history.push(ConnectionEventKind::Connected {
+            peer_pid: server_pid,
         });
+        let flush_handle = Arc::new(sys::PipeHandle::duplicate(&pipe)?);
+        let pipe_info = pipe.info()?;
+        let (pipe_reader, pipe_writer) = tokio::io::split(pipe);
+        let (read_tx, read_rx) = mpsc::channel(1);
+        let transit = Transit::default();
+        let pipe_reader = FrameReader::with_max_len(
+            BufReader::with_capacity(config.read_buffer_size, pipe_reader),
+            config.max_frame_len,
+        )
+        .max_message_len(config.max_message_len)
+        .transit(transit.clone());
+        let read_limits = pipe_reader.limits();
+        let reader_task = tokio::spawn(reader_task(
+            pipe_reader,
+            read_tx,
+            PeerProcess::open(server_pid),
+        ));
 
         Ok(Self {
-            pipe_writer,
+            info: ConnectionInfo::new(server_pid, pipe_info),
+            pipe_writer: Arc::new(Mutex::new(
+                FrameWriter::with_max_len(pipe_writer, config.max_frame_len)
+                    .max_message_len(config.max_message_len),
+            )),
             read_rx,
             reader_task,
+            log_task: None,
+            flush_handle,
+            disconnect: None,
+            resume_state: None,
+            extensions: Default::default(),
+            log_filter: None,
+            send_timeout: config.send_timeout,
+            close_timeout: config.close_timeout,
+            write_stalled: false,
+            unanswered: Default::default(),
+            poll_tracker: Default::default(),
+            watchdog_task: None,
+            stats_task: None,
+            health: Default::default(),
+            os_shutdown: None,
+            shutdown: ShutdownToken::new(),
+            transit,
+            read_limits,
+            metadata: None,
+            message_log: None,
+            history,
+            repro: None,
+            server_verified: false,
+            deferred: VecDeque::new(),
             _manager_msg: Default::default(),
             _worker_msg: Default::default(),
         })
     }
 
-    pub async fn close(mut self) -> Result<()> {
-        self.pipe_writer.shutdown().await?;
+    /// Closes the connection once everything queued has reached the manager
+    ///
+    /// Waits up to `Config::close_timeout`, see `close_within`.
+    pub async fn close(self) -> Result<()> {
+        let close_timeout = self.close_timeout;
+        let discarded = self.close_within(close_timeout).await?;
+        if discarded > 0 {
+            tracing::warn!(?discarded, "Discarded queued frames while closing");
+        }
+        Ok(())
+    }
+
+    /// Flushes queued frames for up to `deadline`, then closes
+    ///
+    /// `send` has written its frame by the time it returns, so the only queued
+    /// frames are forwarded logs. After writing those, this waits for the manager
+    /// to read everything on the pipe, so nothing is lost if the worker exits
+    /// right after closing. Returns how many queued frames were discarded
+    /// because the deadline expired, counting those that were written but that
+    /// the manager didn't read in time.
+    pub async fn close_within(mut self, deadline: Duration) -> Result<usize> {
+        let deadline = Instant::now() + deadline;
+        let mut drained = LogDrain::default();
+        if let Some(stats_task) = self.stats_task.take() {
+            stats_task.abort();
+        }
+        if let Some(log_task) = self.log_task.take() {
+            log_task.stop.send(deadline).ok();
+            drained = log_task.task.await.unwrap_or_default();
+        }
+        let mut discarded = drained.discarded;
+        let flush_handle = Arc::clone(&self.flush_handle);
+        let thread = Arc::new(sys::FlushThread::default());
+        let mut flush = tokio::task::spawn_blocking({
+            let thread = Arc::clone(&thread);
+            move || flush_handle.flush(&thread)
+        });
+        match timeout_at(deadline, &mut flush).await {
+            Ok(Ok(Ok(()))) => {}
+            // e.g. the manager already disconnected, so there's nobody to flush to
+            Ok(Ok(Err(error))) => tracing::debug!(?error, "Couldn't flush pipe"),
+            Ok(Err(error)) => tracing::error!(?error, "Flush task panicked"),
+            Err(_) => {
+                tracing::warn!("Manager didn't read the last frames before the deadline");
+                // They may still be sitting in the pipe
+                discarded += drained.written;
+                cancel_flush(&thread, flush).await;
+            }
+        }
+        self.pipe_writer.lock().await.shutdown().await?;
         self.reader_task.abort();
         tracing::debug!("Client closing gracefully");
-        Ok(())
+        Ok(discarded)
+    }
+
+    /// Whether the manager proved it knows the cookie, see `handshake::server_proof`
+    ///
+    /// Always true for clients from `new` and its variants, which fail closed
+    /// with `Error::ServerUnverified`. False for `from_endpoint`, since there's
+    /// no cookie to prove.
+    pub fn server_verified(&self) -> bool {
+        self.server_verified
+    }
+
+    /// Returns diagnostic details about this connection
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// State the manager asked us to resume from, if any
+    ///
+    /// See `SubprocessBuilder::resume_state`
+    pub fn resume_state(&self) -> Option<&[u8]> {
+        self.resume_state.as_deref()
+    }
+
+    /// Sets how long `send` may block before the write is considered stalled
+    ///
+    /// `None` waits forever, e.g. if the manager is paused in a debugger.
+    pub fn set_send_timeout(&mut self, send_timeout: Option<Duration>) {
+        self.send_timeout = send_timeout;
     }
 
     /// Receives a message from the server
@@ -72,18 +426,688 @@ impl<M: DeserializeOwned, W: Serialize> Client<M, W> {
     /// # Cancel safety
     ///
     /// This method is cancel-safe, internally it calls `tokio::sync::mpsc::Receiver::recv`
+    ///
+    /// Extension frames are routed to their registered channels while this is
+    /// being polled, and are never returned here.
     pub async fn next(&mut self) -> Result<ManagerMsgInternal<M>, Error> {
-        let buf = self.read_rx.recv().await.ok_or_else(|| Error::Eof)?;
-        let buf = std::str::from_utf8(&buf)?;
-        let msg = serde_json::from_str(buf)?;
-        Ok(msg)
+        let msg = self.next_frame().await;
+        self.watch_shutdown(msg)
+    }
+
+    /// Trips the shutdown token if `msg` ends the connection
+    fn watch_shutdown(
+        &self,
+        msg: Result<ManagerMsgInternal<M>, Error>,
+    ) -> Result<ManagerMsgInternal<M>, Error> {
+        if matches!(
+            msg,
+            Ok(ManagerMsgInternal::Shutdown) | Err(Error::Disconnected(_))
+        ) {
+            self.shutdown.trigger();
+        }
+        msg
+    }
+
+    /// A token that trips when `next` returns `Shutdown`, or the manager disconnects
+    ///
+    /// Clone it into handlers before handing the client to `serve`, so they can
+    /// stop early while `serve` waits for them to finish.
+    pub fn shutdown_token(&self) -> ShutdownToken {
+        self.shutdown.clone()
+    }
+
+    async fn next_frame(&mut self) -> Result<ManagerMsgInternal<M>, Error> {
+        // A clone, so the guard doesn't borrow `self` while config acks are sent
+        let poll_tracker = self.poll_tracker.clone();
+        let _poll = poll_tracker.enter();
+        loop {
+            self.run_deferred().await?;
+            let was_connected = self.disconnect.is_none();
+            let buf = tokio::select! {
+                buf = recv_frame(&mut self.read_rx, &mut self.disconnect) => buf,
+                event = os_shutdown::wait(&mut self.os_shutdown) => {
+                    tracing::info!(?event, "Shutting down for the OS");
+                    return Ok(ManagerMsgInternal::Shutdown);
+                }
+            };
+            let buf = match buf {
+                Ok(buf) => buf,
+                Err(error) => return Err(self.recv_failed(was_connected, error)),
+            };
+            if let Some(msg) = self.handle_frame(&buf) {
+                // e.g. a `SetConfig` is applied and acked before it's returned
+                self.run_deferred().await?;
+                return msg;
+            }
+        }
+    }
+
+    /// Records a disconnect that `recv_frame` just saw
+    fn recv_failed(&mut self, was_connected: bool, error: Error) -> Error {
+        if let (true, Some(reason)) = (was_connected, &self.disconnect) {
+            self.history.disconnected(self.info.peer_pid, reason);
+        }
+        error
+    }
+
+    /// Handles one frame from the reader task, returning what `next` should
+    ///
+    /// `None` for frames handled internally, like pings and extension frames.
+    /// Never waits, so anything that has to write goes in `deferred`.
+    fn handle_frame(&mut self, buf: &[u8]) -> Option<Result<ManagerMsgInternal<M>, Error>> {
+        let (buf, metadata, msg) = match repro::decode_manager_frame::<M>(buf) {
+            Ok(decoded) => decoded,
+            Err(error) => {
+                if let Some(repro) = &mut self.repro {
+                    let events = self.history.list();
+                    repro.write(ReproSide::Worker, &self.info, &error, buf, events);
+                }
+                if matches!(
+                    error,
+                    Error::Disconnected(DisconnectReason::ProtocolViolation)
+                ) {
+                    tracing::warn!("Manager broke the protocol, disconnecting");
+                    self.history
+                        .disconnected(self.info.peer_pid, &DisconnectReason::ProtocolViolation);
+                    self.disconnect = Some(DisconnectReason::ProtocolViolation);
+                    self.reader_task.abort();
+                    self.deferred.push_back(Deferred::CloseWriter);
+                }
+                return Some(Err(error));
+            }
+        };
+        self.metadata = metadata;
+        if let Some(log) = &self.message_log {
+            if matches!(
+                msg,
+                ManagerMsgInternal::User(_) | ManagerMsgInternal::Request { .. }
+            ) {
+                log.received(buf);
+            }
+        }
+        let method = match msg {
+            ManagerMsgInternal::User(_) | ManagerMsgInternal::Request { .. } => {
+                method_stats::received_manager_method(buf)
+            }
+            _ => None,
+        };
+        self.history.received(msg.kind(), method);
+        match msg {
+            ManagerMsgInternal::Extension(id, payload) => self.extensions.dispatch(id, payload),
+            ManagerMsgInternal::SetLogFilter(directives) => {
+                log_filter::apply_from_peer(self.log_filter.as_ref(), &directives)
+            }
+            ManagerMsgInternal::ReportStats { interval_ms } => {
+                self.report_stats(interval_ms.map(Duration::from_millis))
+            }
+            ManagerMsgInternal::Ping { id } => self.deferred.push_back(Deferred::Health { id }),
+            ManagerMsgInternal::Request {
+                id,
+                msg,
+                traceparent,
+            } => {
+                let span = tracing::info_span!(
+                    "ipc.handle",
+                    otel.kind = "server",
+                    rpc.system = otel::RPC_SYSTEM,
+                    rpc.id = id,
+                );
+                if let Some(traceparent) = &traceparent {
+                    otel::set_parent(&span, traceparent);
+                }
+                lock_unanswered(&self.unanswered).insert(id, span);
+                return Some(Ok(ManagerMsgInternal::Request {
+                    id,
+                    msg,
+                    traceparent,
+                }));
+            }
+            ManagerMsgInternal::SetConfig { id, config } => {
+                self.deferred.push_back(Deferred::Config {
+                    id,
+                    config: config.clone(),
+                });
+                return Some(Ok(ManagerMsgInternal::SetConfig { id, config }));
+            }
+            msg => return Some(Ok(msg)),
+        }
+        None
+    }
+
+    /// Sends the replies `handle_frame` queued, oldest first
+    ///
+    /// Each is taken off the queue before it's sent, so a cancelled call never
+    /// sends one twice.
+    async fn run_deferred(&mut self) -> Result<(), Error> {
+        while let Some(deferred) = self.deferred.pop_front() {
+            match deferred {
+                Deferred::Health { id } => {
+                    let status = self.health.clone();
+                    self.send_internal(&WorkerMsgInternal::Health { id, status })
+                        .await?;
+                }
+                Deferred::Config { id, config } => {
+                    let ack = self.apply_config(&config).await;
+                    self.send_internal(&WorkerMsgInternal::ConfigAck { id, ack })
+                        .await?;
+                }
+                Deferred::CloseWriter => self.pipe_writer.lock().await.mark_closed(),
+            }
+        }
+        Ok(())
+    }
+
+    fn report_stats(&mut self, interval: Option<Duration>) {
+        if let Some(old_task) = self.stats_task.take() {
+            old_task.abort();
+        }
+        // `tokio::time::interval` panics on zero
+        let Some(interval) = interval.filter(|interval| !interval.is_zero()) else {
+            tracing::debug!("Stopped reporting stats");
+            return;
+        };
+        tracing::debug!(?interval, "Reporting stats");
+        self.stats_task = Some(tokio::spawn(stats::report(
+            interval,
+            Arc::clone(&self.pipe_writer),
+            Arc::clone(&self.unanswered),
+        )));
+    }
+
+    /// Applies the parts of a pushed `Config` that belong to this connection, or none of them
+    async fn apply_config(&mut self, config: &Config) -> ConfigAck {
+        if config.max_frame_len == 0 {
+            return ConfigAck::Rejected("max_frame_len must be non-zero".to_string());
+        }
+        if let Some(directives) = &config.log_filter {
+            let Some(handle) = &self.log_filter else {
+                return ConfigAck::Rejected("no LogFilterHandle is set".to_string());
+            };
+            if let Err(error) = handle.set(directives) {
+                return ConfigAck::Rejected(format!("{error:#}"));
+            }
+        }
+        self.send_timeout = config.send_timeout;
+        self.close_timeout = config.close_timeout;
+        // The manager writes within both the old and new limits until it reads our ack,
+        // and reads within either, see `Server::push_config`
+        let limits = FrameLimits::of(config);
+        self.pipe_writer.lock().await.set_limits(limits);
+        self.read_limits.set(limits);
+        tracing::info!(?config, "Config changed by manager");
+        ConfigAck::Applied
+    }
+
+    /// Makes `next` return `Shutdown` when the OS asks this process to stop
+    ///
+    /// Covers Ctrl+C, closing the console, logoff, and system shutdown, see
+    /// `OsShutdown`. Handle `Shutdown` as usual, by calling `close` and
+    /// returning from `main`, so the worker exits 0 and the manager doesn't
+    /// count it as a crash. Installs process-wide handlers on the first call.
+    pub fn handle_os_shutdown(&mut self) -> Result<()> {
+        self.os_shutdown = Some(os_shutdown::subscribe()?);
+        Ok(())
+    }
+
+    /// Sets what we tell `Server::health` probes
+    ///
+    /// Starts as `HealthStatus::Healthy`, so call this with `Ready` once the
+    /// worker can take work. Probes are only answered while `next` is being polled.
+    pub fn set_health(&mut self, status: HealthStatus) {
+        self.health = status;
+    }
+
+    /// Warns if `next` isn't polled for `threshold` while requests are unanswered
+    ///
+    /// A handler that blocks on one request stops the worker from reading the rest.
+    /// The warning lists the stuck request IDs. `None` turns it off.
+    pub fn set_watchdog(&mut self, threshold: Option<Duration>) {
+        if let Some(task) = self.watchdog_task.take() {
+            task.abort();
+        }
+        let Some(threshold) = threshold else {
+            return;
+        };
+        let unanswered = Arc::clone(&self.unanswered);
+        self.watchdog_task = Some(watchdog::spawn(
+            "worker",
+            threshold,
+            self.poll_tracker.clone(),
+            move || lock_unanswered(&unanswered).keys().copied().collect(),
+        ));
+    }
+
+    /// Returns a channel that receives the manager's extension frames with this ID
+    ///
+    /// Frames only arrive while `next` is being polled.
+    pub fn register_extension(&mut self, id: u16) -> Result<mpsc::Receiver<Vec<u8>>, Error> {
+        self.extensions.register(id)
+    }
+
+    /// Lets the manager change our log filter, see `Server::set_worker_log_filter`
+    pub fn set_log_filter_handle(&mut self, handle: log_filter::LogFilterHandle) {
+        self.log_filter = Some(handle);
+    }
+
+    /// Asks the manager to replace its `tracing` filter
+    ///
+    /// `directives` uses `RUST_LOG` syntax. The manager ignores this unless it
+    /// called `Server::set_log_filter_handle`.
+    pub async fn set_manager_log_filter(&mut self, directives: &str) -> Result<(), Error> {
+        self.send_internal(&WorkerMsgInternal::SetLogFilter(directives.to_string()))
+            .await
+    }
+
+    /// Starts writing the worker's forwarded `tracing` events to the manager
+    ///
+    /// The records share the pipe with user messages, but they're written from a
+    /// background task so they never delay `send`. The manager re-emits them
+    /// through its own subscriber.
+    pub fn forward_logs(&mut self, forwarder: LogForwarder) {
+        if let Some(old_task) = self.log_task.take() {
+            old_task.task.abort();
+        }
+        let (stop, stop_rx) = oneshot::channel();
+        let task = tokio::spawn(forward_logs(
+            forwarder,
+            Arc::clone(&self.pipe_writer),
+            stop_rx,
+        ));
+        self.log_task = Some(LogTask { task, stop });
     }
 
+    /// Asks the manager to send us messages published on `topic`
+    ///
+    /// See `Server::publish` and `Manager::publish`
+    pub async fn subscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.send_internal(&WorkerMsgInternal::Subscribe(topic.to_string()))
+            .await
+    }
+
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<(), Error> {
+        self.send_internal(&WorkerMsgInternal::Unsubscribe(topic.to_string()))
+            .await
+    }
+
+    /// Answers a `ManagerMsgInternal::Request` from `next`
+    pub async fn respond(&mut self, id: u64, msg: W) -> Result<(), Error> {
+        let span = lock_unanswered(&self.unanswered)
+            .remove(&id)
+            .unwrap_or_else(Span::none);
+        self.log_sent(&msg);
+        self.send_internal(&WorkerMsgInternal::Response { id, msg })
+            .instrument(span)
+            .await
+    }
+
+    /// Answers the request with `id` with an error, which fails the call with `Error::Remote`
+    pub async fn respond_error(&mut self, id: u64, error: RemoteError) -> Result<(), Error> {
+        let span = lock_unanswered(&self.unanswered)
+            .remove(&id)
+            .unwrap_or_else(Span::none);
+        self.send_internal(&WorkerMsgInternal::Failed { id, error })
+            .instrument(span)
+            .await
+    }
+
+    /// Tells the manager that the handler for request `id` timed out, see `ServeOptions::timeout`
+    pub(crate) async fn respond_timed_out(
+        &mut self,
+        id: u64,
+        method: String,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        let span = lock_unanswered(&self.unanswered)
+            .remove(&id)
+            .unwrap_or_else(Span::none);
+        let timeout_ms = u64::try_from(timeout.as_millis()).unwrap_or(u64::MAX);
+        self.send_internal(&WorkerMsgInternal::HandlerTimeout {
+            id,
+            method,
+            timeout_ms,
+        })
+        .instrument(span)
+        .await
+    }
+
+    /// The `ipc.handle` span of a request from `next`, until it's answered
+    ///
+    /// Instrument the handler with it so its events land in the call's trace, see `otel`.
+    pub fn request_span(&self, id: u64) -> Option<Span> {
+        lock_unanswered(&self.unanswered).get(&id).cloned()
+    }
+
+    /// Traces every user message sent and received, with secrets masked
+    ///
+    /// Off by default. Events are at `trace` level, see `Redact` for what's masked.
+    pub fn log_messages(&mut self)
+    where
+        M: Redact,
+        W: Redact,
+    {
+        self.message_log = Some(MessageLog::new::<W, M>());
+    }
+
+    fn log_sent(&self, msg: &W) {
+        if let Some(log) = &self.message_log {
+            log.sent(msg);
+        }
+    }
+
+    /// The last `EVENT_LOG_LEN` things that happened on this connection, oldest first
+    ///
+    /// See `Server::recent_events`. The method of a sent message isn't recorded,
+    /// since that would mean serializing it twice.
+    pub fn recent_events(&self) -> Vec<ConnectionEvent> {
+        self.history.list()
+    }
+
+    /// Writes a `ReproBundle` into `dir` for each frame that fails to decode or breaks the protocol
+    ///
+    /// See `Server::capture_repros`.
+    pub fn capture_repros(&mut self, dir: impl Into<PathBuf>)
+    where
+        M: Redact,
+    {
+        self.repro = Some(ReproCapture::new(dir.into(), M::SECRET_FIELDS));
+    }
+
+    /// Sends an app-defined control frame to the manager
+    pub async fn send_extension(&mut self, id: u16, payload: Vec<u8>) -> Result<(), Error> {
+        self.send_internal(&WorkerMsgInternal::Extension(id, payload))
+            .await
+    }
+
+    /// Sends a message to the server
+    ///
+    /// If the write doesn't finish within the send timeout, the connection is shut
+    /// down and this and all later calls return `Error::WriteStalled`.
     pub async fn send(&mut self, msg: W) -> Result<(), Error> {
+        self.log_sent(&msg);
         self.send_internal(&WorkerMsgInternal::User(msg)).await
     }
 
-    async fn send_internal(&mut self, msg: &WorkerMsgInternal<W>) -> Result<(), Error> {
-        write_serialize(&mut self.pipe_writer, msg).await
+    /// Like `send`, with headers the manager reads with `Server::metadata`
+    pub async fn send_with(&mut self, msg: W, metadata: &Metadata) -> Result<(), Error> {
+        self.log_sent(&msg);
+        self.send_internal(&WorkerMsgInternal::User(msg).with_metadata(metadata))
+            .await
     }
+
+    /// Sends several messages to the server with one write
+    ///
+    /// For bulk updates, so the manager's reader wakes up once instead of once per
+    /// message. The send timeout covers the whole batch.
+    pub async fn send_all(&mut self, msgs: impl IntoIterator<Item = W>) -> Result<(), Error> {
+        let msgs: Vec<_> = msgs
+            .into_iter()
+            .inspect(|msg| self.log_sent(msg))
+            .map(WorkerMsgInternal::User)
+            .collect();
+        if msgs.is_empty() {
+            return Ok(());
+        }
+        let count = msgs.len();
+        let pipe_writer = Arc::clone(&self.pipe_writer);
+        self.guard_write(async move {
+            pipe_writer.lock().await.write_batch(&msgs).await?;
+            Ok(())
+        })
+        .await?;
+        for _ in 0..count {
+            self.history.sent("User", None);
+        }
+        Ok(())
+    }
+
+    /// Waits for a message, then moves it and up to `max - 1` more that have
+    /// already arrived into `buf`
+    ///
+    /// Returns how many were added, which is 0 only if `max` is 0. Like `next`,
+    /// this is cancel-safe, and on error the messages already added stay in `buf`.
+    /// Pings and `SetConfig`s among them are answered before it returns, or by
+    /// the next call if it's cancelled first.
+    pub async fn recv_many(
+        &mut self,
+        buf: &mut Vec<ManagerMsgInternal<M>>,
+        max: usize,
+    ) -> Result<usize, Error> {
+        if max == 0 {
+            return Ok(0);
+        }
+        buf.push(self.next().await?);
+        let mut count = 1;
+        // Only frames that are already buffered, so nothing is dropped half-handled.
+        // Replies are sent after the loop.
+        let mut drained = Ok(());
+        while count < max {
+            let was_connected = self.disconnect.is_none();
+            let Some(frame) = try_recv_frame(&mut self.read_rx, &mut self.disconnect) else {
+                break;
+            };
+            let msg = match frame {
+                Ok(frame) => self.handle_frame(&frame),
+                Err(error) => Some(Err(self.recv_failed(was_connected, error))),
+            };
+            match msg.map(|msg| self.watch_shutdown(msg)) {
+                Some(Ok(msg)) => {
+                    buf.push(msg);
+                    count += 1;
+                }
+                Some(Err(error)) => {
+                    drained = Err(error);
+                    break;
+                }
+                None => {}
+            }
+        }
+        self.run_deferred().await?;
+        drained.map(|()| count)
+    }
+
+    /// How long frames from the manager took to arrive, in seconds
+    ///
+    /// See `Server::transit_latency`. `None` for managers too old to send a
+    /// `ClockSample`, and connections made with `new_unsecured`.
+    pub fn transit_latency(&self) -> Option<Histogram> {
+        self.transit.histogram()
+    }
+
+    /// Headers of the message `next` returned last, if the manager sent any
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    pub(crate) async fn send_internal(&mut self, msg: &WorkerMsgInternal<W>) -> Result<(), Error> {
+        let pipe_writer = Arc::clone(&self.pipe_writer);
+        self.guard_write(async move { pipe_writer.lock().await.write(msg).await })
+            .await?;
+        let frame = match msg {
+            WorkerMsgInternal::Meta(_, msg) => msg,
+            msg => msg,
+        };
+        if matches!(
+            frame,
+            WorkerMsgInternal::User(_) | WorkerMsgInternal::Response { .. }
+        ) {
+            self.history.sent(frame.kind(), None);
+        }
+        Ok(())
+    }
+
+    /// Runs `write` under the send timeout, and fails fast once a write has stalled
+    async fn guard_write(
+        &mut self,
+        write: impl Future<Output = Result<(), Error>>,
+    ) -> Result<(), Error> {
+        if self.write_stalled {
+            return Err(Error::WriteStalled);
+        }
+        if self.disconnect.is_some() {
+            // `next` saw the manager go away, so the write half is dead too
+            return Err(Error::Closed);
+        }
+        let Some(send_timeout) = self.send_timeout else {
+            return write.await;
+        };
+        match timeout(send_timeout, write).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::error!(?send_timeout, "Write to named pipe stalled, disconnecting");
+                self.write_stalled = true;
+                // Stop reading too, so `next` reports the disconnect instead of
+                // delivering requests we can no longer answer
+                self.reader_task.abort();
+                // The dropped write may have left half a frame in the pipe, so
+                // nothing else may write after it
+                if let Some(log_task) = self.log_task.take() {
+                    log_task.task.abort();
+                }
+                if let Some(stats_task) = self.stats_task.take() {
+                    stats_task.abort();
+                }
+                self.disconnect = Some(DisconnectReason::TransportError(
+                    std::io::ErrorKind::TimedOut.into(),
+                ));
+                Err(Error::WriteStalled)
+            }
+        }
+    }
+}
+
+impl<M, W> Drop for Client<M, W> {
+    fn drop(&mut self) {
+        // The tasks hold halves of the pipe, so it won't close until they stop
+        self.reader_task.abort();
+        if let Some(log_task) = self.log_task.take() {
+            log_task.task.abort();
+        }
+        if let Some(watchdog_task) = self.watchdog_task.take() {
+            watchdog_task.abort();
+        }
+        if let Some(stats_task) = self.stats_task.take() {
+            stats_task.abort();
+        }
+    }
+}
+
+/// Stops a flush that's still blocked after `close_within`'s deadline
+///
+/// The flush holds the pipe open, and would hold its thread for as long as the
+/// manager doesn't read.
+async fn cancel_flush(thread: &sys::FlushThread, mut flush: JoinHandle<Result<()>>) {
+    // The flush may not have blocked yet the first time, so keep at it briefly
+    for _ in 0..100 {
+        thread.cancel();
+        if timeout(Duration::from_millis(10), &mut flush).await.is_ok() {
+            return;
+        }
+    }
+    tracing::error!("Couldn't cancel the pipe flush, the pipe may stay open");
+}
+
+/// Work `Client::handle_frame` can't do without waiting, see `Client::run_deferred`
+enum Deferred {
+    /// Answers a `Ping`
+    Health { id: u64 },
+    /// Applies and acks a `SetConfig`
+    Config { id: u64, config: Config },
+    /// Stops the log and stats tasks writing after a protocol violation
+    CloseWriter,
+}
+
+/// What the log task did with the records queued when the `Client` closed
+#[derive(Default)]
+struct LogDrain {
+    written: usize,
+    discarded: usize,
+}
+
+/// The task started by `forward_logs`
+struct LogTask {
+    task: JoinHandle<LogDrain>,
+    /// Tells the task to write what's queued until this deadline, then stop
+    stop: oneshot::Sender<Instant>,
+}
+
+async fn forward_logs(
+    mut forwarder: LogForwarder,
+    pipe_writer: Arc<Mutex<FrameWriter<WriteHalf<NamedPipeClient>>>>,
+    mut stop: oneshot::Receiver<Instant>,
+) -> LogDrain {
+    let deadline = loop {
+        let record = tokio::select! {
+            record = forwarder.rx.recv() => record,
+            deadline = &mut stop => match deadline {
+                Ok(deadline) => break deadline,
+                // The `Client` dropped
+                Err(_) => return LogDrain::default(),
+            },
+        };
+        let Some(record) = record else {
+            return LogDrain::default();
+        };
+        let msg = WorkerMsgInternal::<()>::Log(record);
+        if let Err(error) = pipe_writer.lock().await.write(&msg).await {
+            tracing::debug!(?error, "Stopped forwarding logs");
+            return LogDrain::default();
+        }
+    };
+
+    // Flush what's already queued, but nothing new
+    forwarder.rx.close();
+    let mut written = 0;
+    let flush = async {
+        while let Some(record) = forwarder.rx.recv().await {
+            let msg = WorkerMsgInternal::<()>::Log(record);
+            if pipe_writer.lock().await.write(&msg).await.is_err() {
+                break;
+            }
+            written += 1;
+        }
+    };
+    let timed_out = timeout_at(deadline, flush).await.is_err();
+    LogDrain {
+        written,
+        // The record that was being written when time ran out may be half-written
+        discarded: forwarder.rx.len() + usize::from(timed_out),
+    }
+}
+
+/// Each unanswered request's `ipc.handle` span, by request ID
+pub(crate) type Unanswered = Arc<StdMutex<BTreeMap<u64, Span>>>;
+
+/// Types a failed handshake so `WorkerExit::from_error` picks `HandshakeFailed`
+fn handshake_failed(peer: u32, error: anyhow::Error) -> anyhow::Error {
+    match error.downcast_ref::<Error>() {
+        Some(Error::ServerUnverified) => error,
+        _ => Error::HandshakeRejected {
+            peer,
+            reason: format!("{error:#}"),
+        }
+        .into(),
+    }
+}
+
+/// Gathers what a user needs to fix a refused pipe, see `Error::PermissionDenied`
+fn permission_denied(pipe_id: &PipeId) -> Error {
+    let peer_user = sys::pipe_owner(pipe_id.as_str())
+        .inspect_err(|error| tracing::debug!(?error, "Couldn't read the pipe's owner"))
+        .ok();
+    let elevated = sys::is_elevated().unwrap_or(false);
+    tracing::warn!(?peer_user, elevated, "Manager's pipe denied access");
+    Error::PermissionDenied {
+        path: pipe_id.clone(),
+        peer_user,
+        elevated,
+    }
+}
+
+pub(crate) fn lock_unanswered(
+    unanswered: &StdMutex<BTreeMap<u64, Span>>,
+) -> std::sync::MutexGuard<'_, BTreeMap<u64, Span>> {
+    // Every update is a single insert or remove, so a poisoned lock is still consistent
+    unanswered
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
 }
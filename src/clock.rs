@@ -0,0 +1,144 @@
+//! Send timestamps on frames, for telling a slow pipe from a slow handler
+//!
+//! Each process stamps frames with the performance counter, in microseconds,
+//! which is monotonic and shared by every process on the machine, so wall
+//! clock changes can't skew it. The secure handshake still estimates how far
+//! apart the two clocks are, the way NTP does, in case the counter can't be
+//! read and a process falls back to its own clock: the worker notes when it sent the cookie and when `Accepted` arrived,
+//! and `Accepted` says when the manager got the cookie and when it answered.
+//! The worker sends the estimate back in `WorkerMsgInternal::ClockOffset`, and
+//! from then on both sides stamp their frames and record each frame's transit
+//! time. Connections from a `Listener` don't handshake, so they don't stamp.
+//!
+//! The peer's numbers are untrusted, so the math saturates, and an offset
+//! beyond `MAX_CLOCK_OFFSET_US` turns stamping off instead of skewing every sample.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex, MutexGuard, OnceLock},
+    time::Instant,
+};
+
+use crate::{sys, Histogram};
+
+/// The largest clock offset either side accepts, one day
+///
+/// Both clocks normally count from boot, so anything near this is a confused
+/// or hostile peer.
+pub(crate) const MAX_CLOCK_OFFSET_US: u64 = 24 * 60 * 60 * 1_000_000;
+
+/// Upper bounds for transit times, in seconds
+const TRANSIT_BOUNDS: &[f64] = &[
+    0.00005, 0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.1, 0.5,
+];
+
+/// Microseconds on the machine's monotonic clock
+pub(crate) fn now_us() -> u64 {
+    static EPOCH: OnceLock<Instant> = OnceLock::new();
+    sys::performance_counter_us().unwrap_or_else(|| {
+        let elapsed = EPOCH.get_or_init(Instant::now).elapsed();
+        u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX)
+    })
+}
+
+/// A peer's timestamp as a signed number, saturating at `i64::MAX`
+fn signed_us(us: u64) -> i64 {
+    i64::try_from(us).unwrap_or(i64::MAX)
+}
+
+/// The manager's half of the clock exchange, sent in `ManagerMsgInternal::Accepted`
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ClockSample {
+    /// When the cookie arrived, on the manager's clock
+    pub received_us: u64,
+    /// When `Accepted` was written, on the manager's clock
+    pub sent_us: u64,
+}
+
+/// How far the peer's clock is from ours, as estimated during the handshake
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ClockOffset {
+    /// Add this to a peer timestamp to get the same instant on our clock
+    pub offset_us: i64,
+    /// The handshake's round trip, minus the time the manager held the cookie.
+    /// The estimate is off by at most half of it.
+    pub round_trip_us: u64,
+}
+
+impl ClockOffset {
+    /// The worker's estimate, from when it sent the cookie and got `Accepted`
+    pub(crate) fn estimate(cookie_sent_us: u64, sample: ClockSample, accepted_us: u64) -> Self {
+        let to_manager = signed_us(sample.received_us).saturating_sub(signed_us(cookie_sent_us));
+        let from_manager = signed_us(sample.sent_us).saturating_sub(signed_us(accepted_us));
+        let held = sample.sent_us.saturating_sub(sample.received_us);
+        Self {
+            // Ours minus the manager's, so the negation of the usual NTP offset
+            offset_us: (to_manager / 2)
+                .saturating_add(from_manager / 2)
+                .saturating_neg(),
+            round_trip_us: accepted_us
+                .saturating_sub(cookie_sent_us)
+                .saturating_sub(held),
+        }
+    }
+
+    /// The same estimate from the peer's side
+    pub(crate) fn reversed(self) -> Self {
+        Self {
+            offset_us: self.offset_us.saturating_neg(),
+            round_trip_us: self.round_trip_us,
+        }
+    }
+
+    /// `None` if the clocks seem more than `MAX_CLOCK_OFFSET_US` apart
+    pub(crate) fn plausible(self) -> Option<Self> {
+        if self.offset_us.unsigned_abs() > MAX_CLOCK_OFFSET_US {
+            tracing::warn!(offset = ?self, "Clock offset is implausible, not stamping frames");
+            return None;
+        }
+        Some(self)
+    }
+}
+
+/// Transit times of the stamped frames one connection has read
+///
+/// Cloning shares it, so the reader task records while the `Server` or `Client` reads it.
+#[derive(Clone, Default)]
+pub(crate) struct Transit(Arc<Mutex<Option<(ClockOffset, Histogram)>>>);
+
+impl Transit {
+    fn lock(&self) -> MutexGuard<'_, Option<(ClockOffset, Histogram)>> {
+        // Every update is one observation or one assignment, so a poisoned lock is still usable
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Starts recording, once the handshake has estimated the offset
+    pub(crate) fn set_offset(&self, offset: ClockOffset) {
+        let mut transit = self.lock();
+        match &mut *transit {
+            Some((old, _)) => *old = offset,
+            None => *transit = Some((offset, Histogram::with_bounds(TRANSIT_BOUNDS))),
+        }
+    }
+
+    /// Records a frame the peer stamped at `sent_us` on its clock
+    pub(crate) fn record(&self, sent_us: u64) {
+        let now = now_us();
+        let mut transit = self.lock();
+        let Some((offset, histogram)) = &mut *transit else {
+            // A peer only stamps after the handshake, so this is a confused peer
+            return;
+        };
+        let sent = signed_us(sent_us).saturating_add(offset.offset_us);
+        // The offset is only an estimate, so a fast frame can seem to arrive before it was sent
+        let elapsed_us = signed_us(now).saturating_sub(sent).max(0);
+        histogram.observe(elapsed_us as f64 / 1_000_000.0);
+    }
+
+    /// `None` until the handshake has estimated the offset
+    pub(crate) fn histogram(&self) -> Option<Histogram> {
+        self.lock().as_ref().map(|(_, histogram)| histogram.clone())
+    }
+}
@@ -0,0 +1,150 @@
+//! Timeouts and limits in one place, so products can ship them as a config file
+//!
+//! Every field has a default, so a file only needs the ones it changes.
+//! Durations are whole milliseconds, e.g.
+//!
+//! ```json
+//! { "handshake_timeout_ms": 10000, "max_frame_len": 1048576 }
+//! ```
+//!
+//! A running worker can be retuned with `Server::push_config`, without a restart.
+
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::{
+    frame::{MAX_FRAME_LEN, MAX_MESSAGE_LEN},
+    DEFAULT_CLOSE_TIMEOUT, DEFAULT_SEND_TIMEOUT,
+};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// How long `SubprocessBuilder::spawn` waits for the worker to open the pipe
+    #[serde(rename = "accept_timeout_ms", with = "millis")]
+    pub accept_timeout: Duration,
+    /// How long both sides wait for the cookie exchange once the pipe is open
+    #[serde(rename = "handshake_timeout_ms", with = "millis")]
+    pub handshake_timeout: Duration,
+    /// See `Client::set_send_timeout`. `null` waits forever.
+    #[serde(rename = "send_timeout_ms", with = "optional_millis")]
+    pub send_timeout: Option<Duration>,
+    /// How long `Client::close` waits for queued frames to reach the manager
+    #[serde(rename = "close_timeout_ms", with = "millis")]
+    pub close_timeout: Duration,
+    /// How long to wait for a worker to exit before killing it. Pass it to
+    /// `Manager::remove`, `Manager::close`, or `SubcommandChild::wait_then_kill`.
+    #[serde(rename = "kill_grace_period_ms", with = "millis")]
+    pub kill_grace_period: Duration,
+    /// The largest frame body either side writes or reads, in bytes.
+    /// Both sides should use the same value. `Server::push_config` changes it on
+    /// both sides at once.
+    pub max_frame_len: usize,
+    /// The largest message either side sends or reassembles, in bytes. Messages
+    /// over `max_frame_len` are split into fragments, and the reader refuses to
+    /// buffer more than this of unfinished messages. Set it to `max_frame_len`
+    /// to refuse big messages instead.
+    pub max_message_len: usize,
+    /// Events from all workers that `Manager::next` hasn't returned yet.
+    /// When it's full, workers stop being read until there's room.
+    pub event_queue_len: usize,
+    /// `tracing` directives for the worker, in `RUST_LOG` syntax.
+    /// Only applied by `Server::push_config`, `None` keeps the current filter.
+    pub log_filter: Option<String>,
+    /// What Windows should reserve for each direction of the pipe, in bytes.
+    /// Only used where the pipe is created, i.e. by `SubprocessBuilder` and
+    /// `Listener`. Windows may round it, see `ConnectionInfo::in_buffer_size`.
+    pub pipe_buffer_size: u32,
+    /// How much each side reads from the pipe at once, in bytes. Small frames
+    /// are served from this buffer, so a burst of them costs one read instead
+    /// of three per frame. Only applies to new connections.
+    pub read_buffer_size: usize,
+    /// Makes `SubprocessBuilder::spawn` fail unless the worker reports the
+    /// manager's `Fingerprint::arch`, e.g. for apps that pass structs with
+    /// pointer-sized fields in extension frames. Off by default, since JSON
+    /// messages work across architectures.
+    pub require_same_arch: bool,
+    /// How far the pipe server may act as the worker, set when the worker opens the pipe.
+    /// The default lets the manager check who connected but not act as them, so a
+    /// process squatting on the pipe name can't borrow the worker's token.
+    pub pipe_impersonation: ImpersonationLevel,
+}
+
+/// The security quality of service a `Client` asks for, see `Config::pipe_impersonation`
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImpersonationLevel {
+    /// The server can't tell who connected
+    Anonymous,
+    /// The server can check who connected, but not act as them
+    #[default]
+    Identification,
+    /// The server can act as the worker on this machine
+    Impersonation,
+    /// The server can act as the worker on other machines too
+    Delegation,
+}
+
+/// The worker's answer to `Server::push_config`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ConfigAck {
+    Applied,
+    /// Nothing was changed
+    Rejected(String),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            accept_timeout: Duration::from_secs(10),
+            handshake_timeout: Duration::from_secs(5),
+            send_timeout: Some(DEFAULT_SEND_TIMEOUT),
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            kill_grace_period: Duration::from_secs(5),
+            max_frame_len: MAX_FRAME_LEN,
+            max_message_len: MAX_MESSAGE_LEN,
+            // Events from all workers that haven't been read yet
+            event_queue_len: 64,
+            log_filter: None,
+            // Tokio's default, raise it if `throughput` shows writes stalling
+            pipe_buffer_size: 64 * 1024,
+            read_buffer_size: 64 * 1024,
+            require_same_arch: false,
+            pipe_impersonation: ImpersonationLevel::Identification,
+        }
+    }
+}
+
+mod millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub(super) fn serialize<S: Serializer>(value: &Duration, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(u64::try_from(value.as_millis()).unwrap_or(u64::MAX))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Duration, D::Error> {
+        Ok(Duration::from_millis(u64::deserialize(d)?))
+    }
+}
+
+mod optional_millis {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub(super) fn serialize<S: Serializer>(
+        value: &Option<Duration>,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => super::millis::serialize(value, s),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<u64>::deserialize(d)?.map(Duration::from_millis))
+    }
+}
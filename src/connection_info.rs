@@ -0,0 +1,108 @@
+//! Diagnostic details about an established connection, for support bundles
+
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+use crate::{ClockOffset, Fingerprint};
+
+/// Parameters of a connected `Server` or `Client`
+///
+/// Serializable so it can be dropped straight into a diagnostic bundle.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ConnectionInfo {
+    pub transport: Transport,
+    /// How Windows delimits writes on the pipe. Framing works the same either way.
+    pub pipe_mode: PipeMode,
+    pub codec: Codec,
+    /// The protocol version this side speaks. There is no negotiation yet,
+    /// so both sides must be built from the same version of subzone.
+    pub protocol_version: u32,
+    pub compression: Compression,
+    /// Process ID of the other end of the pipe, as reported by Windows
+    pub peer_pid: u32,
+    /// When the pipe connection was established
+    pub connected_at: SystemTime,
+    /// Versions of this process, see `Fingerprint`
+    pub fingerprint: Fingerprint,
+    /// Versions the peer reported during the handshake, if it's new enough to
+    /// report them
+    ///
+    /// A `Server` fills this in from the worker's first frame, so it's set by the
+    /// time `Server::next` returns anything. Connections from a `Listener` don't
+    /// handshake, so they never have it.
+    pub peer_fingerprint: Option<Fingerprint>,
+    /// The pipe's input buffer as Windows sized it, see `Config::pipe_buffer_size`
+    pub in_buffer_size: u32,
+    /// The pipe's output buffer as Windows sized it
+    pub out_buffer_size: u32,
+    /// The handshake's estimate of the peer's clock, see `clock`. Like
+    /// `peer_fingerprint`, connections from a `Listener` never have it.
+    #[serde(default)]
+    pub clock_offset: Option<ClockOffset>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Transport {
+    NamedPipe,
+}
+
+/// The type a named pipe was created with
+///
+/// subzone creates byte-mode pipes. In message mode every `write_all` becomes
+/// its own message and reads can stop at message boundaries, but `FrameReader`
+/// uses `read_exact`, so frames come out the same.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum PipeMode {
+    #[default]
+    Byte,
+    Message,
+}
+
+impl From<PipeMode> for tokio::net::windows::named_pipe::PipeMode {
+    fn from(mode: PipeMode) -> Self {
+        match mode {
+            PipeMode::Byte => Self::Byte,
+            PipeMode::Message => Self::Message,
+        }
+    }
+}
+
+impl PipeMode {
+    fn from_tokio(mode: tokio::net::windows::named_pipe::PipeMode) -> Self {
+        match mode {
+            tokio::net::windows::named_pipe::PipeMode::Message => Self::Message,
+            // Tokio's enum is non-exhaustive, but Windows only has two pipe types
+            _ => Self::Byte,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Codec {
+    /// 32-bit little-endian length prefix followed by a JSON body
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum Compression {
+    None,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(peer_pid: u32, pipe_info: tokio::net::windows::named_pipe::PipeInfo) -> Self {
+        Self {
+            transport: Transport::NamedPipe,
+            pipe_mode: PipeMode::from_tokio(pipe_info.mode),
+            codec: Codec::Json,
+            protocol_version: crate::PROTOCOL_VERSION,
+            compression: Compression::None,
+            peer_pid,
+            connected_at: SystemTime::now(),
+            fingerprint: Fingerprint::current(),
+            peer_fingerprint: None,
+            in_buffer_size: pipe_info.in_buffer_size,
+            out_buffer_size: pipe_info.out_buffer_size,
+            clock_offset: None,
+        }
+    }
+}
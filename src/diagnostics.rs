@@ -0,0 +1,117 @@
+//! A JSON-friendly dump of a live `Manager`, for support bundles
+//!
+//! `Manager::diagnostic_snapshot` copies everything out under short locks, so it's
+//! safe to call from a support command while the workers are busy.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+use crate::{
+    ConnectionEvent, ConnectionInfo, Fingerprint, Histogram, MethodStats, PendingCall, WorkerStats,
+};
+
+/// How many errors each worker keeps for `WorkerSnapshot::recent_errors`
+const RECENT_ERRORS_LEN: usize = 16;
+
+/// How many removed workers the manager keeps for `DiagnosticSnapshot::removed`
+pub(crate) const REMOVED_WORKERS_LEN: usize = 32;
+
+/// Everything a `Manager` knows about its workers at one point in time
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DiagnosticSnapshot {
+    pub taken_at: SystemTime,
+    pub manager_pid: u32,
+    pub fingerprint: Fingerprint,
+    /// Sorted by role
+    pub workers: Vec<WorkerSnapshot>,
+    /// Workers taken out with `Manager::remove`, oldest first
+    ///
+    /// A worker that was replaced under the same role shows up here once per
+    /// earlier process, so this doubles as its restart history.
+    pub removed: Vec<RemovedWorker>,
+}
+
+/// One worker the manager still has
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct WorkerSnapshot {
+    pub role: String,
+    pub pid: u32,
+    /// False for connections added with `Manager::add_server`
+    pub spawned: bool,
+    /// As it was when the worker was added, so `peer_fingerprint` may be missing
+    pub connection: ConnectionInfo,
+    pub connected: bool,
+    /// User messages written to the worker
+    pub sent: u64,
+    /// User messages read from the worker
+    pub received: u64,
+    pub send_queue_depth: usize,
+    pub pending_calls: Vec<PendingCall>,
+    /// The last few errors from the worker's connection, oldest first
+    pub recent_errors: Vec<RecentError>,
+    pub stats: Option<WorkerStats>,
+    pub methods: Vec<MethodStats>,
+    /// See `Server::transit_latency`
+    pub transit: Option<Histogram>,
+    pub subscriptions: BTreeSet<String>,
+    /// See `Server::recent_events`
+    #[serde(default)]
+    pub recent_events: Vec<ConnectionEvent>,
+}
+
+/// A worker that `Manager::remove` took out
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RemovedWorker {
+    pub role: String,
+    pub pid: u32,
+    pub connected_at: SystemTime,
+    pub removed_at: SystemTime,
+    /// How the process ended, formatted from `SubcommandExit`.
+    /// `None` for workers added with `Manager::add_server`.
+    pub exit: Option<String>,
+    pub recent_errors: Vec<RecentError>,
+    /// What the connection did before it was removed, see `Server::recent_events`
+    #[serde(default)]
+    pub recent_events: Vec<ConnectionEvent>,
+}
+
+/// An error a worker's connection returned
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecentError {
+    pub at: SystemTime,
+    pub error: String,
+}
+
+/// The last `RECENT_ERRORS_LEN` errors of one worker
+#[derive(Default)]
+pub(crate) struct RecentErrors(Mutex<VecDeque<RecentError>>);
+
+impl RecentErrors {
+    pub(crate) fn push(&self, error: &crate::Error) {
+        // Every update is one push and maybe one pop, so a poisoned lock is still consistent
+        let mut errors = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if errors.len() == RECENT_ERRORS_LEN {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            at: SystemTime::now(),
+            error: error.to_string(),
+        });
+    }
+
+    pub(crate) fn list(&self) -> Vec<RecentError> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
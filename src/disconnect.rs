@@ -0,0 +1,108 @@
+//! Figuring out why a connection ended
+
+use std::time::Duration;
+
+use crate::sys::{self, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_SYNCHRONIZE};
+
+/// How long to wait for the peer process to finish exiting after its end of the pipe closes
+///
+/// Windows closes a dying process' handles before the process object is signaled,
+/// so the EOF can arrive slightly before the exit code is available.
+const PEER_EXIT_GRACE: Duration = Duration::from_millis(100);
+
+/// Why `next` stopped returning messages
+#[derive(Debug, thiserror::Error)]
+pub enum DisconnectReason {
+    /// The peer closed its end of the pipe and its process is still running
+    #[error("peer closed the connection")]
+    GracefulClose,
+    /// The peer process exited, e.g. it crashed or was killed
+    #[error("peer process exited with code {exit}")]
+    PeerExited { exit: u32 },
+    /// Any IO error on the pipe except EOF
+    #[error("transport error: {0}")]
+    TransportError(std::io::Error),
+    /// The peer sent bytes that can't be a valid frame
+    #[error("protocol violation")]
+    ProtocolViolation,
+    /// One of our own tasks for the connection panicked, see `Error::Internal`
+    #[error("internal task panicked: {0}")]
+    Internal(String),
+    /// A `Listener` dropped the connection to make room for a newer one from the
+    /// same user or exe, see `Eviction::EvictOldest`
+    #[error("evicted by a connection quota")]
+    Evicted,
+}
+
+impl Clone for DisconnectReason {
+    fn clone(&self) -> Self {
+        match self {
+            Self::GracefulClose => Self::GracefulClose,
+            Self::PeerExited { exit } => Self::PeerExited { exit: *exit },
+            // `io::Error` isn't `Clone`, so keep the kind and message
+            Self::TransportError(error) => {
+                Self::TransportError(std::io::Error::new(error.kind(), error.to_string()))
+            }
+            Self::ProtocolViolation => Self::ProtocolViolation,
+            Self::Internal(message) => Self::Internal(message.clone()),
+            Self::Evicted => Self::Evicted,
+        }
+    }
+}
+
+/// A handle to the process on the other end of the pipe
+///
+/// Opened as soon as we connect, so that the process ID can't be recycled
+/// before we check the exit code.
+pub(crate) struct PeerProcess {
+    process: sys::Process,
+}
+
+impl PeerProcess {
+    pub(crate) fn open(pid: u32) -> Option<Self> {
+        match sys::Process::open(pid, PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_SYNCHRONIZE) {
+            Ok(process) => Some(Self { process }),
+            Err(error) => {
+                tracing::debug!(
+                    ?error,
+                    ?pid,
+                    "Couldn't open peer process, disconnect reasons will be less specific"
+                );
+                None
+            }
+        }
+    }
+
+    /// Returns true if the process hasn't exited yet
+    pub(crate) fn is_running(&self) -> bool {
+        !self.process.wait(Duration::ZERO)
+    }
+
+    /// Returns the peer's exit code if it exits within `PEER_EXIT_GRACE`
+    ///
+    /// Blocks the current thread, call it through `spawn_blocking`
+    fn wait_for_exit(&self) -> Option<u32> {
+        if !self.process.wait(PEER_EXIT_GRACE) {
+            return None;
+        }
+        self.process.exit_code().ok()
+    }
+}
+
+/// Turns the error that stopped a reader task into a `DisconnectReason`
+pub(crate) async fn classify(error: crate::Error, peer: Option<PeerProcess>) -> DisconnectReason {
+    match error {
+        crate::Error::Disconnected(DisconnectReason::GracefulClose) => {
+            let Some(peer) = peer else {
+                return DisconnectReason::GracefulClose;
+            };
+            match tokio::task::spawn_blocking(move || peer.wait_for_exit()).await {
+                Ok(Some(exit)) => DisconnectReason::PeerExited { exit },
+                _ => DisconnectReason::GracefulClose,
+            }
+        }
+        crate::Error::Disconnected(reason) => reason,
+        crate::Error::Io(error) => DisconnectReason::TransportError(error),
+        _ => DisconnectReason::ProtocolViolation,
+    }
+}
@@ -0,0 +1,98 @@
+//! An ETW provider for connection and process lifecycle events
+//!
+//! Enterprise admins already collect ETW with standard Windows tooling, so they
+//! don't need our log files to see workers connecting, failing the handshake, or
+//! being killed. The provider is a TraceLogging provider named `Subzone`, so it
+//! can be enabled by name, e.g. `tracelog -start subzone -guid *Subzone`.
+//!
+//! Without the `etw` feature, every event is a no-op.
+
+/// Which end of the pipe wrote an event
+#[derive(Clone, Copy)]
+pub(crate) enum Side {
+    Manager,
+    Worker,
+}
+
+impl Side {
+    #[cfg(feature = "etw")]
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Manager => "manager",
+            Self::Worker => "worker",
+        }
+    }
+}
+
+#[cfg(feature = "etw")]
+tracelogging::define_provider!(PROVIDER, "Subzone");
+
+/// Starts sending events to ETW sessions that enable the `Subzone` provider
+///
+/// Call it once, early in `main`. Events written before this are dropped.
+#[cfg(feature = "etw")]
+pub fn register_etw_provider() {
+    // SAFETY: `PROVIDER` is a static that's never unregistered, and we're an exe,
+    // so it can't be unloaded while registered
+    let result = unsafe { PROVIDER.register() };
+    if result != 0 {
+        tracing::warn!(?result, "Couldn't register ETW provider");
+    }
+}
+
+#[cfg(feature = "etw")]
+pub(crate) fn connected(side: Side, peer_pid: u32) {
+    tracelogging::write_event!(
+        PROVIDER,
+        "Connected",
+        level(Informational),
+        str8("Side", side.as_str()),
+        u32("PeerPid", &peer_pid),
+    );
+}
+
+#[cfg(feature = "etw")]
+pub(crate) fn disconnected(side: Side, peer_pid: u32, reason: &crate::DisconnectReason) {
+    tracelogging::write_event!(
+        PROVIDER,
+        "Disconnected",
+        level(Informational),
+        str8("Side", side.as_str()),
+        u32("PeerPid", &peer_pid),
+        str8("Reason", &reason.to_string()),
+    );
+}
+
+#[cfg(feature = "etw")]
+pub(crate) fn handshake_failed(child_pid: u32, error: &anyhow::Error) {
+    tracelogging::write_event!(
+        PROVIDER,
+        "HandshakeFailed",
+        level(Warning),
+        u32("ChildPid", &child_pid),
+        str8("Error", &format!("{error:#}")),
+    );
+}
+
+#[cfg(feature = "etw")]
+pub(crate) fn killed(pid: u32, reason: &str) {
+    tracelogging::write_event!(
+        PROVIDER,
+        "Killed",
+        level(Warning),
+        u32("Pid", &pid),
+        str8("Reason", reason),
+    );
+}
+
+#[cfg(not(feature = "etw"))]
+pub(crate) fn connected(_side: Side, _peer_pid: u32) {}
+
+#[cfg(not(feature = "etw"))]
+pub(crate) fn disconnected(_side: Side, _peer_pid: u32, _reason: &crate::DisconnectReason) {}
+
+#[cfg(not(feature = "etw"))]
+pub(crate) fn handshake_failed(_child_pid: u32, _error: &anyhow::Error) {}
+
+#[cfg(not(feature = "etw"))]
+pub(crate) fn killed(_pid: u32, _reason: &str) {}
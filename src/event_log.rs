@@ -0,0 +1,149 @@
+//! The last few things that happened on one connection, for post-mortems
+//!
+//! `Server` and `Client` each keep a ring of `EVENT_LOG_LEN` events: the connect,
+//! user messages sent, frames received, and the disconnect. Only frame kinds and
+//! method names are kept, never payloads. The ring is logged at `debug` when the
+//! connection fails, and `Manager::diagnostic_snapshot` includes it, so an error
+//! report carries its own recent history.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use crate::{DisconnectReason, ManagerMsgInternal, WorkerMsgInternal};
+
+/// How many events each connection keeps
+pub const EVENT_LOG_LEN: usize = 64;
+
+/// Something that happened on a connection, see `Server::recent_events`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ConnectionEvent {
+    pub at: SystemTime,
+    pub kind: ConnectionEventKind,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ConnectionEventKind {
+    Connected {
+        peer_pid: u32,
+    },
+    /// A user message, request or response was written
+    ///
+    /// `frame` is the internal frame, e.g. `User` or `Request`. `method` is the
+    /// message's variant, when it's known without serializing the message again.
+    Sent {
+        frame: String,
+        method: Option<String>,
+    },
+    /// A frame was read, other than a forwarded log event
+    Received {
+        frame: String,
+        method: Option<String>,
+    },
+    /// Formatted from `DisconnectReason`
+    Disconnected(String),
+}
+
+/// One connection's ring of events
+///
+/// Cloning shares it, so a `Manager` can read it while a task owns the `Server`.
+#[derive(Clone, Default)]
+pub(crate) struct EventLog(Arc<Mutex<VecDeque<ConnectionEvent>>>);
+
+impl EventLog {
+    fn lock(&self) -> MutexGuard<'_, VecDeque<ConnectionEvent>> {
+        // Every update is one push and maybe one pop, so a poisoned lock is still consistent
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn push(&self, kind: ConnectionEventKind) {
+        let mut events = self.lock();
+        if events.len() == EVENT_LOG_LEN {
+            events.pop_front();
+        }
+        events.push_back(ConnectionEvent {
+            at: SystemTime::now(),
+            kind,
+        });
+    }
+
+    pub(crate) fn sent(&self, frame: &str, method: Option<String>) {
+        self.push(ConnectionEventKind::Sent {
+            frame: frame.to_string(),
+            method,
+        });
+    }
+
+    pub(crate) fn received(&self, frame: &str, method: Option<String>) {
+        self.push(ConnectionEventKind::Received {
+            frame: frame.to_string(),
+            method,
+        });
+    }
+
+    /// Records the disconnect and logs everything leading up to it
+    pub(crate) fn disconnected(&self, peer_pid: u32, reason: &DisconnectReason) {
+        self.push(ConnectionEventKind::Disconnected(reason.to_string()));
+        tracing::debug!(
+            peer_pid,
+            events = ?self.list(),
+            "Connection history up to the disconnect"
+        );
+    }
+
+    /// Oldest first
+    pub(crate) fn list(&self) -> Vec<ConnectionEvent> {
+        self.lock().iter().cloned().collect()
+    }
+}
+
+impl<T> ManagerMsgInternal<T> {
+    /// The variant name, for `ConnectionEventKind`
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::Accepted { .. } => "Accepted",
+            Self::Shutdown => "Shutdown",
+            Self::User(_) => "User",
+            Self::Request { .. } => "Request",
+            Self::Meta(..) => "Meta",
+            Self::Extension(..) => "Extension",
+            Self::SetLogFilter(_) => "SetLogFilter",
+            Self::Power(_) => "Power",
+            Self::NetworkChanged(_) => "NetworkChanged",
+            Self::SetConfig { .. } => "SetConfig",
+            Self::ReportStats { .. } => "ReportStats",
+            Self::Ping { .. } => "Ping",
+            Self::Proof(_) => "Proof",
+        }
+    }
+}
+
+impl<T> WorkerMsgInternal<T> {
+    /// The variant name, for `ConnectionEventKind`
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::Cookie(_) => "Cookie",
+            Self::User(_) => "User",
+            Self::Response { .. } => "Response",
+            Self::Meta(..) => "Meta",
+            Self::Extension(..) => "Extension",
+            Self::SetLogFilter(_) => "SetLogFilter",
+            Self::Log(_) => "Log",
+            Self::Subscribe(_) => "Subscribe",
+            Self::Unsubscribe(_) => "Unsubscribe",
+            Self::ConfigAck { .. } => "ConfigAck",
+            Self::Stats(_) => "Stats",
+            Self::Health { .. } => "Health",
+            Self::Fingerprint(_) => "Fingerprint",
+            Self::ClockOffset(_) => "ClockOffset",
+            Self::HandlerTimeout { .. } => "HandlerTimeout",
+            Self::Failed { .. } => "Failed",
+            Self::Hello { .. } => "Hello",
+        }
+    }
+}
@@ -0,0 +1,54 @@
+//! Out-of-band control messages defined by the embedding application
+//!
+//! Extension frames travel on the same pipe as user messages, but they're routed
+//! to a per-ID channel instead of being returned from `next`. This lets an app
+//! add things like log-level changes without touching its own protocol types.
+
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+
+use crate::Error;
+
+/// How many unread frames each extension channel can hold before new ones are dropped
+const EXTENSION_QUEUE_LEN: usize = 16;
+
+/// Extension IDs registered on one end of a connection
+#[derive(Default)]
+pub(crate) struct Extensions {
+    senders: HashMap<u16, mpsc::Sender<Vec<u8>>>,
+}
+
+impl Extensions {
+    pub(crate) fn register(&mut self, id: u16) -> Result<mpsc::Receiver<Vec<u8>>, Error> {
+        if self
+            .senders
+            .get(&id)
+            .is_some_and(|sender| !sender.is_closed())
+        {
+            return Err(Error::ExtensionAlreadyRegistered(id));
+        }
+        let (tx, rx) = mpsc::channel(EXTENSION_QUEUE_LEN);
+        self.senders.insert(id, tx);
+        Ok(rx)
+    }
+
+    /// Routes an incoming extension frame to its channel
+    ///
+    /// Never blocks, so that a slow extension consumer can't stall user messages.
+    pub(crate) fn dispatch(&mut self, id: u16, payload: Vec<u8>) {
+        let Some(sender) = self.senders.get(&id) else {
+            tracing::warn!(?id, "Dropping frame for unregistered extension");
+            return;
+        };
+        match sender.try_send(payload) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::warn!(?id, "Extension channel is full, dropping frame");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::debug!(?id, "Extension receiver dropped, unregistering");
+                self.senders.remove(&id);
+            }
+        }
+    }
+}
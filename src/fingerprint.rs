@@ -0,0 +1,105 @@
+//! Versions of both peers, exchanged during the handshake
+//!
+//! The manager sends its `Fingerprint` in `Accepted`, and a worker that got one
+//! answers with its own before any other frame. Older peers skip the exchange,
+//! so the peer's fingerprint is optional. A worker binary left over from another
+//! release is otherwise only found after hours of field debugging.
+
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+use crate::sys;
+
+static APP_VERSION: OnceLock<String> = OnceLock::new();
+
+/// Where a process' code came from, see `ConnectionInfo::peer_fingerprint`
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Fingerprint {
+    /// Version of subzone
+    pub crate_version: String,
+    /// Set with `Fingerprint::set_app_version`
+    pub app_version: Option<String>,
+    /// e.g. `22631.2861`, if it could be read
+    pub os_build: Option<String>,
+    /// e.g. `x86_64`
+    pub arch: String,
+    /// The OS's architecture, if it differs from `arch`, e.g. `x86_64` for a
+    /// 32-bit worker under WOW64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub native_arch: Option<String>,
+}
+
+impl Fingerprint {
+    /// The fingerprint of the current process
+    pub fn current() -> Self {
+        Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            app_version: APP_VERSION.get().cloned(),
+            os_build: sys::os_build()
+                .inspect_err(|error| tracing::debug!(?error, "Couldn't read the OS build"))
+                .ok(),
+            arch: std::env::consts::ARCH.to_string(),
+            native_arch: sys::native_arch()
+                .inspect_err(|error| tracing::debug!(?error, "Couldn't read the native arch"))
+                .ok()
+                .filter(|native| *native != std::env::consts::ARCH)
+                .map(str::to_string),
+        }
+    }
+
+    /// 32 or 64, going by `arch`. `None` for an arch subzone doesn't know.
+    ///
+    /// Messages are JSON, so peers of different widths understand each other,
+    /// but a `usize` over `u32::MAX` can't reach a 32-bit peer.
+    pub fn pointer_width(&self) -> Option<u32> {
+        pointer_width(&self.arch)
+    }
+
+    /// Sets the app version this process reports to its peers
+    ///
+    /// Call it before spawning or connecting. Only the first call has an effect.
+    pub fn set_app_version(version: impl Into<String>) {
+        APP_VERSION.set(version.into()).ok();
+    }
+
+    /// Names of the fields that differ between `self` and `other`
+    pub(crate) fn mismatches(&self, other: &Self) -> Vec<&'static str> {
+        let mut mismatches = vec![];
+        if self.crate_version != other.crate_version {
+            mismatches.push("crate_version");
+        }
+        if self.app_version != other.app_version {
+            mismatches.push("app_version");
+        }
+        if self.os_build != other.os_build {
+            mismatches.push("os_build");
+        }
+        if self.arch != other.arch {
+            mismatches.push("arch");
+        }
+        mismatches
+    }
+}
+
+/// See `Fingerprint::pointer_width`
+pub(crate) fn pointer_width(arch: &str) -> Option<u32> {
+    match arch {
+        "x86" | "arm" => Some(32),
+        "x86_64" | "aarch64" => Some(64),
+        _ => None,
+    }
+}
+
+/// Logs a warning if the peer wasn't built and run the same way we were
+pub(crate) fn warn_on_mismatch(ours: &Fingerprint, theirs: &Fingerprint, peer_pid: u32) {
+    let mismatches = ours.mismatches(theirs);
+    if !mismatches.is_empty() {
+        tracing::warn!(
+            ?mismatches,
+            ?ours,
+            ?theirs,
+            peer_pid,
+            "Peer's fingerprint doesn't match ours"
+        );
+    }
+}
@@ -0,0 +1,678 @@
+//! Framing for the pipe
+//!
+//! Each frame is a 32-bit little-endian body length, a 64-bit little-endian
+//! sequence number, and then the JSON body. Each direction counts its own
+//! frames from 0, so a lost or reordered frame shows up as `Error::SequenceGap`.
+//!
+//! Bodies over the maximum, `MAX_FRAME_LEN` unless `Config::max_frame_len`
+//! says otherwise, are refused on both ends, so a corrupt length can't make
+//! the reader allocate gigabytes.
+//!
+//! A message too big for one frame, but within `Config::max_message_len`, is
+//! split into fragments. A fragment has `FRAGMENT_FLAG` set in its sequence
+//! number, and its body is a 32-bit little-endian stream ID, a byte that's 1 on
+//! the last fragment, and then a piece of the message. The reader puts each
+//! stream back together separately, so fragments of different messages may be
+//! interleaved with each other and with whole frames. At most
+//! `MAX_OPEN_STREAMS` may be unfinished at once, and only the last fragment of
+//! a stream may be empty.
+//!
+//! Once the handshake has estimated the peer's clock, frames have `STAMP_FLAG`
+//! set, and their body starts with the 64-bit little-endian time they were
+//! written, see `clock`. The length includes it.
+//!
+//! A privileged manager reads these bytes from a less-privileged worker, so
+//! `FrameReader` must never panic, whatever arrives. Clippy enforces that with
+//! the lints on its `impl` block, and `framing_props` feeds it random and
+//! mangled frames.
+
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+};
+
+use crate::{
+    buffer_pool::{self, PooledBuf},
+    clock::{self, Transit},
+    disconnect, panic_guard, Config, DisconnectReason, Error,
+};
+
+/// The length and sequence number before each body
+const HEADER_LEN: usize = 12;
+
+/// The default for the largest frame body either side will write or read
+pub(crate) const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// The default for the largest message either side will fragment or reassemble
+pub(crate) const MAX_MESSAGE_LEN: usize = 64 * 1024 * 1024;
+
+/// Set in the sequence number of a fragment. Real sequence numbers never get this high.
+const FRAGMENT_FLAG: u64 = 1 << 63;
+
+/// Set in the sequence number of a frame with a send timestamp
+const STAMP_FLAG: u64 = 1 << 62;
+
+/// The send timestamp before a stamped frame's body
+const STAMP_LEN: usize = 8;
+
+/// Strips the flags from a sequence number
+fn seq_number(seq: u64) -> u64 {
+    seq & !(FRAGMENT_FLAG | STAMP_FLAG)
+}
+
+/// The stream ID and last-fragment byte before each piece
+const FRAGMENT_HEADER_LEN: usize = 5;
+
+/// How many fragmented messages may be unfinished at once
+///
+/// `FrameWriter` writes each message's fragments back to back, so it never
+/// has more than one open. This only stops a peer from making the reader
+/// track streams without end.
+pub(crate) const MAX_OPEN_STREAMS: usize = 16;
+
+/// How big frames and messages may be, see `Config::max_frame_len` and `Config::max_message_len`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct FrameLimits {
+    pub(crate) max_len: usize,
+    /// Messages over `max_len` but within this are fragmented
+    pub(crate) max_message_len: usize,
+}
+
+impl FrameLimits {
+    pub(crate) fn of(config: &Config) -> Self {
+        Self {
+            max_len: config.max_frame_len,
+            max_message_len: config.max_message_len,
+        }
+    }
+
+    /// Allows whatever either of them allows
+    pub(crate) fn union(self, other: Self) -> Self {
+        Self {
+            max_len: self.max_len.max(other.max_len),
+            max_message_len: self.max_message_len.max(other.max_message_len),
+        }
+    }
+
+    /// Allows only what both of them allow
+    pub(crate) fn intersection(self, other: Self) -> Self {
+        Self {
+            max_len: self.max_len.min(other.max_len),
+            max_message_len: self.max_message_len.min(other.max_message_len),
+        }
+    }
+}
+
+/// The limits a `FrameReader` checks
+///
+/// Cloning shares them, so they can change while the reader task owns the reader.
+/// Frames already read aren't checked again.
+#[derive(Clone)]
+pub(crate) struct ReadLimits(Arc<Mutex<FrameLimits>>);
+
+impl ReadLimits {
+    pub(crate) fn get(&self) -> FrameLimits {
+        // It's only ever assigned, so a poisoned lock is still usable
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub(crate) fn set(&self, limits: FrameLimits) {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = limits;
+    }
+}
+
+/// Reads frames and checks their sequence numbers
+pub(crate) struct FrameReader<R> {
+    inner: R,
+    next_seq: u64,
+    /// A frame that arrived out of sequence. Returned after the `SequenceGap` error.
+    pending: Option<Vec<u8>>,
+    /// `max_message_len` is the most `partial` may hold, across all streams
+    limits: ReadLimits,
+    /// Messages whose last fragment hasn't arrived yet, by stream ID
+    partial: HashMap<u32, Vec<u8>>,
+    /// The bytes in `partial`, kept so each fragment doesn't have to sum them
+    partial_len: usize,
+    /// Where stamped frames' transit times go
+    transit: Option<Transit>,
+}
+
+#[deny(
+    clippy::arithmetic_side_effects,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::panic,
+    clippy::unwrap_used
+)]
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    #[cfg(test)]
+    pub(crate) fn new(inner: R) -> Self {
+        Self::with_max_len(inner, MAX_FRAME_LEN)
+    }
+
+    pub(crate) fn with_max_len(inner: R, max_len: usize) -> Self {
+        Self {
+            inner,
+            next_seq: 0,
+            pending: None,
+            limits: ReadLimits(Arc::new(Mutex::new(FrameLimits {
+                max_len,
+                max_message_len: max_len,
+            }))),
+            partial: HashMap::new(),
+            partial_len: 0,
+            transit: None,
+        }
+    }
+
+    /// Records the transit time of stamped frames in `transit`
+    pub(crate) fn transit(mut self, transit: Transit) -> Self {
+        self.transit = Some(transit);
+        self
+    }
+
+    /// Reassembles fragmented messages up to this length.
+    /// Without it, the limit is `max_len`, so fragments are refused.
+    pub(crate) fn max_message_len(self, max_message_len: usize) -> Self {
+        let limits = self.limits.get();
+        self.limits.set(FrameLimits {
+            max_message_len,
+            ..limits
+        });
+        self
+    }
+
+    /// A handle to change the limits later, e.g. after the reader moved into its task
+    pub(crate) fn limits(&self) -> ReadLimits {
+        self.limits.clone()
+    }
+
+    /// Reads one frame body, into a buffer from the pool
+    ///
+    /// On a sequence gap, returns `Error::SequenceGap` and then the frame itself
+    /// on the next call, so nothing is silently dropped. Fragments are collected
+    /// until a message is complete, and only the whole message is returned.
+    pub(crate) async fn read(&mut self) -> Result<Vec<u8>, Error> {
+        if let Some(buf) = self.pending.take() {
+            return Ok(buf);
+        }
+        loop {
+            let (seq, buf) = self.read_frame().await?;
+            let expected = self.next_seq;
+            let gap = seq_number(seq) != expected;
+            self.next_seq = seq_number(seq).wrapping_add(1);
+            let buf = if seq & FRAGMENT_FLAG == 0 {
+                buf
+            } else if let Some(buf) = self.reassemble(&buf)? {
+                buf
+            } else if gap {
+                // The fragment is kept, so only report the gap
+                return Err(Error::SequenceGap {
+                    expected,
+                    got: seq_number(seq),
+                });
+            } else {
+                continue;
+            };
+            if gap {
+                self.pending = Some(buf);
+                return Err(Error::SequenceGap {
+                    expected,
+                    got: seq_number(seq),
+                });
+            }
+            return Ok(buf);
+        }
+    }
+
+    /// Adds a fragment to its stream, returning the message if that was the last piece
+    fn reassemble(&mut self, fragment: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        // `FRAGMENT_HEADER_LEN` bytes, then the piece
+        let [s0, s1, s2, s3, last, piece @ ..] = fragment else {
+            return Err(Error::Protocol);
+        };
+        let stream = u32::from_le_bytes([*s0, *s1, *s2, *s3]);
+        let last = match last {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::Protocol),
+        };
+        // An empty piece adds nothing, so it could only be used to open streams
+        if piece.is_empty() && !last {
+            return Err(Error::Protocol);
+        }
+        if !self.partial.contains_key(&stream) && self.partial.len() >= MAX_OPEN_STREAMS {
+            return Err(Error::Protocol);
+        }
+        let buffered = self
+            .partial_len
+            .checked_add(piece.len())
+            .filter(|buffered| *buffered <= self.limits.get().max_message_len)
+            .ok_or(Error::MessageLength)?;
+        tracing::trace!(?stream, len = piece.len(), ?last, "reading fragment");
+        self.partial_len = buffered;
+        let buf = self.partial.entry(stream).or_default();
+        buf.extend_from_slice(piece);
+        if !last {
+            return Ok(None);
+        }
+        let message = self.partial.remove(&stream);
+        if let Some(message) = &message {
+            self.partial_len = self.partial_len.saturating_sub(message.len());
+        }
+        Ok(message)
+    }
+
+    /// Reads one frame's sequence number, flags included, and its body without the stamp
+    async fn read_frame(&mut self) -> Result<(u64, Vec<u8>), Error> {
+        let mut len_buf = [0u8; 4];
+        self.inner.read_exact(&mut len_buf).await?;
+        let len = u32::from_le_bytes(len_buf);
+        let mut seq_buf = [0u8; 8];
+        self.inner.read_exact(&mut seq_buf).await?;
+        let seq = u64::from_le_bytes(seq_buf);
+        tracing::trace!(?len, ?seq, "reading message");
+        let len = usize::try_from(len).map_err(|_| Error::MessageLength)?;
+        if len > self.limits.get().max_len {
+            return Err(Error::MessageLength);
+        }
+        let len = if seq & STAMP_FLAG == 0 {
+            len
+        } else {
+            // Checked first, so a short stamped frame doesn't eat into the next one
+            let len = len.checked_sub(STAMP_LEN).ok_or(Error::Protocol)?;
+            let mut stamp_buf = [0u8; STAMP_LEN];
+            self.inner.read_exact(&mut stamp_buf).await?;
+            if let Some(transit) = &self.transit {
+                transit.record(u64::from_le_bytes(stamp_buf));
+            }
+            len
+        };
+        let mut buf = buffer_pool::take(len);
+        self.inner.read_exact(&mut buf).await?;
+        Ok((seq, buf))
+    }
+}
+
+/// Writes frames, numbering each one
+pub(crate) struct FrameWriter<W> {
+    inner: W,
+    /// The frame being written, header included. Reused so writes don't allocate.
+    buf: Vec<u8>,
+    next_seq: u64,
+    /// Set once the pipe is known to be dead, so later writes fail with `Error::Closed`
+    /// without touching it
+    closed: bool,
+    max_len: usize,
+    /// Messages over `max_len` but within this are sent as fragments
+    max_message_len: usize,
+    next_stream: u32,
+    /// Whether frames start with a send timestamp, see `set_timestamps`
+    timestamps: bool,
+}
+
+/// Windows error codes for writing to a pipe whose other end is gone
+///
+/// `ERROR_BROKEN_PIPE` and `ERROR_NO_DATA` map to `BrokenPipe`, but `ERROR_PIPE_NOT_CONNECTED` doesn't.
+const ERROR_PIPE_NOT_CONNECTED: i32 = 233;
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    #[cfg(test)]
+    pub(crate) fn new(inner: W) -> Self {
+        Self::with_max_len(inner, MAX_FRAME_LEN)
+    }
+
+    pub(crate) fn with_max_len(inner: W, max_len: usize) -> Self {
+        Self {
+            inner,
+            buf: Vec::new(),
+            next_seq: 0,
+            closed: false,
+            max_len,
+            max_message_len: max_len,
+            next_stream: 0,
+            timestamps: false,
+        }
+    }
+
+    /// Fragments messages up to this length.
+    /// Without it, the limit is `max_len`, so nothing is fragmented.
+    pub(crate) fn max_message_len(mut self, max_message_len: usize) -> Self {
+        self.max_message_len = max_message_len;
+        self
+    }
+
+    /// Applies to frames written after this returns
+    pub(crate) fn set_limits(&mut self, limits: FrameLimits) {
+        self.max_len = limits.max_len;
+        self.max_message_len = limits.max_message_len;
+    }
+
+    /// Stamps frames written after this returns, once the peer is known to read stamps
+    pub(crate) fn set_timestamps(&mut self, timestamps: bool) {
+        self.timestamps = timestamps;
+    }
+
+    /// The space `encode` leaves before each body
+    fn header_len(&self) -> usize {
+        if self.timestamps {
+            HEADER_LEN + STAMP_LEN
+        } else {
+            HEADER_LEN
+        }
+    }
+
+    /// Makes every later write fail with `Error::Closed`, e.g. after the reader saw a disconnect
+    pub(crate) fn mark_closed(&mut self) {
+        self.closed = true;
+    }
+
+    /// Serializes and writes one frame
+    ///
+    /// `msg` is serialized before the returned future is first polled, so the
+    /// future doesn't borrow it and `T` doesn't need to be `Sync`.
+    pub(crate) fn write<T: Serialize>(
+        &mut self,
+        msg: &T,
+    ) -> impl Future<Output = Result<(), Error>> + '_ {
+        let written = self.write_counted(msg);
+        async move { written.await.map(|_| ()) }
+    }
+
+    /// Like `write`, but resolves to the length of the frame's body
+    pub(crate) fn write_counted<T: Serialize>(
+        &mut self,
+        msg: &T,
+    ) -> impl Future<Output = Result<usize, Error>> + '_ {
+        self.buf.clear();
+        let encoded = self.encode(msg);
+        async move {
+            encoded?;
+            let body_len = self.buf.len() - self.header_len();
+            self.write_buf(&[0]).await?;
+            Ok(body_len)
+        }
+    }
+
+    /// Serializes several frames and writes them with one write
+    ///
+    /// Resolves to each message's body length. If any message can't be encoded
+    /// or is too long, nothing is written.
+    pub(crate) fn write_batch<T: Serialize>(
+        &mut self,
+        msgs: &[T],
+    ) -> impl Future<Output = Result<Vec<usize>, Error>> + '_ {
+        self.buf.clear();
+        let mut starts = Vec::with_capacity(msgs.len());
+        let encoded = msgs.iter().try_for_each(|msg| {
+            starts.push(self.buf.len());
+            self.encode(msg)
+        });
+        async move {
+            encoded?;
+            let ends = starts.iter().skip(1).copied().chain([self.buf.len()]);
+            let lens = starts
+                .iter()
+                .zip(ends)
+                .map(|(start, end)| end - start - self.header_len())
+                .collect();
+            self.write_buf(&starts).await?;
+            Ok(lens)
+        }
+    }
+
+    /// Appends a frame with a blank header to `buf`
+    fn encode<T: Serialize>(&mut self, msg: &T) -> Result<(), serde_json::Error> {
+        self.buf.resize(self.buf.len() + self.header_len(), 0);
+        // Using JSON because `bincode` couldn't decode `ResourceDescription`
+        serde_json::to_writer(&mut self.buf, msg)
+    }
+
+    /// Fills in the headers of the frames in `buf`, which begin at `starts`, and writes them
+    async fn write_buf(&mut self, starts: &[usize]) -> Result<(), Error> {
+        if self.closed {
+            return Err(Error::Closed);
+        }
+        let header_len = self.header_len();
+        let stamp_len = header_len - HEADER_LEN;
+        // The most of a message that fits in one frame
+        let frame_room = self.max_len.saturating_sub(stamp_len);
+        let max_message_len = if frame_room > FRAGMENT_HEADER_LEN {
+            self.max_message_len.max(frame_room)
+        } else {
+            // No room for a piece of the message
+            frame_room
+        };
+        let mut fragmented = false;
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(self.buf.len());
+            let body_len = end - start - header_len;
+            if body_len > max_message_len || u32::try_from(body_len + stamp_len).is_err() {
+                return Err(Error::MessageLength);
+            }
+            fragmented |= body_len > frame_room;
+        }
+        let stamp = self.timestamps.then(clock::now_us);
+        if fragmented {
+            self.fragment(starts, stamp);
+        } else {
+            let mut seq = self.next_seq;
+            for (i, &start) in starts.iter().enumerate() {
+                let end = starts.get(i + 1).copied().unwrap_or(self.buf.len());
+                let body_len = end - start - header_len;
+                tracing::trace!(len = body_len, ?seq, "writing message");
+                let frame = &mut self.buf[start..];
+                match stamp {
+                    Some(stamp) => {
+                        fill_header(frame, body_len + STAMP_LEN, seq | STAMP_FLAG);
+                        frame[HEADER_LEN..header_len].copy_from_slice(&stamp.to_le_bytes());
+                    }
+                    None => fill_header(frame, body_len, seq),
+                }
+                seq = seq.wrapping_add(1);
+            }
+            // Count the frames even if the write fails, since part of them may be on the wire
+            self.next_seq = seq;
+        }
+        // A half-written frame would desync the reader, so never write again if
+        // this fails, or if the future is dropped partway through, e.g. by a timeout
+        self.closed = true;
+        let result = self.inner.write_all(&self.buf).await;
+        if self.buf.capacity() > buffer_pool::MAX_POOLED_CAPACITY {
+            // Don't pin the memory of one huge frame
+            self.buf = Vec::new();
+        }
+        let Err(error) = result else {
+            self.closed = false;
+            return Ok(());
+        };
+        if error.kind() == std::io::ErrorKind::BrokenPipe
+            || error.raw_os_error() == Some(ERROR_PIPE_NOT_CONNECTED)
+        {
+            return Err(Error::Closed);
+        }
+        Err(error.into())
+    }
+
+    /// Rebuilds `buf` with a header on each frame, splitting the ones over `max_len` into fragments
+    ///
+    /// Fragments of one message are written back to back, but get a stream ID
+    /// anyway, so the reader doesn't depend on that.
+    fn fragment(&mut self, starts: &[usize], stamp: Option<u64>) {
+        let header_len = self.header_len();
+        let frame_room = self.max_len - (header_len - HEADER_LEN);
+        let piece_len = frame_room - FRAGMENT_HEADER_LEN;
+        let mut out = Vec::with_capacity(self.buf.len() * 2);
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(self.buf.len());
+            let body = &self.buf[start + header_len..end];
+            if body.len() <= frame_room {
+                tracing::trace!(len = body.len(), seq = self.next_seq, "writing message");
+                push_frame(&mut out, self.next_seq, stamp, &[body]);
+                self.next_seq = self.next_seq.wrapping_add(1);
+                continue;
+            }
+            let stream = self.next_stream;
+            self.next_stream = stream.wrapping_add(1);
+            tracing::trace!(len = body.len(), ?stream, "writing fragmented message");
+            let mut pieces = body.chunks(piece_len).peekable();
+            while let Some(piece) = pieces.next() {
+                let last = u8::from(pieces.peek().is_none());
+                let mut header = [0u8; FRAGMENT_HEADER_LEN];
+                header[..4].copy_from_slice(&stream.to_le_bytes());
+                header[4] = last;
+                push_frame(
+                    &mut out,
+                    self.next_seq | FRAGMENT_FLAG,
+                    stamp,
+                    &[&header, piece],
+                );
+                self.next_seq = self.next_seq.wrapping_add(1);
+            }
+        }
+        self.buf = out;
+    }
+
+    pub(crate) async fn shutdown(&mut self) -> std::io::Result<()> {
+        self.closed = true;
+        self.inner.shutdown().await
+    }
+
+    #[cfg(test)]
+    pub(crate) fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+/// Writes the length of a frame's body and its sequence number into the start of `frame`
+fn fill_header(frame: &mut [u8], body_len: usize, seq: u64) {
+    // `write_buf` already checked that it fits
+    let len = body_len as u32;
+    frame[..4].copy_from_slice(&len.to_le_bytes());
+    frame[4..HEADER_LEN].copy_from_slice(&seq.to_le_bytes());
+}
+
+/// Appends a frame whose body is `parts`, one after the other, after `stamp` if there is one
+fn push_frame(out: &mut Vec<u8>, seq: u64, stamp: Option<u64>, parts: &[&[u8]]) {
+    let start = out.len();
+    out.resize(start + HEADER_LEN, 0);
+    let seq = match stamp {
+        Some(stamp) => {
+            out.extend_from_slice(&stamp.to_le_bytes());
+            seq | STAMP_FLAG
+        }
+        None => seq,
+    };
+    for part in parts {
+        out.extend_from_slice(part);
+    }
+    let body_len = out.len() - start - HEADER_LEN;
+    fill_header(&mut out[start..], body_len, seq);
+}
+
+/// Forwards frames from `reader` to `read_tx` until the pipe fails, then sends
+/// the reason it stopped as the last item
+///
+/// A panic is sent as `Error::Internal` instead.
+pub(crate) async fn reader_task<R: AsyncRead + Unpin>(
+    reader: FrameReader<R>,
+    read_tx: mpsc::Sender<Result<Vec<u8>, Error>>,
+    peer: Option<disconnect::PeerProcess>,
+) {
+    let panic_tx = read_tx.clone();
+    if let Err(message) = panic_guard::catch_unwind(read_frames(reader, read_tx, peer)).await {
+        tracing::error!(?message, "Reader task panicked");
+        panic_tx.send(Err(Error::Internal(message))).await.ok();
+    }
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut reader: FrameReader<R>,
+    read_tx: mpsc::Sender<Result<Vec<u8>, Error>>,
+    peer: Option<disconnect::PeerProcess>,
+) {
+    let error = loop {
+        let item = match reader.read().await {
+            Ok(msg) => Ok(msg),
+            // The stream is still in sync, so report the gap and keep reading
+            Err(error @ Error::SequenceGap { .. }) => Err(error),
+            Err(error) => break error,
+        };
+        if read_tx.send(item).await.is_err() {
+            // Nobody is listening anymore
+            return;
+        }
+    };
+    let reason = disconnect::classify(error, peer).await;
+    tracing::debug!(?reason, "Reader task stopped");
+    read_tx.send(Err(Error::Disconnected(reason))).await.ok();
+}
+
+/// Receives the next frame from a reader task
+///
+/// Remembers why the connection ended, so every call after a disconnect
+/// returns the same reason.
+pub(crate) async fn recv_frame(
+    read_rx: &mut mpsc::Receiver<Result<Vec<u8>, Error>>,
+    disconnect: &mut Option<DisconnectReason>,
+) -> Result<PooledBuf, Error> {
+    if let Some(reason) = disconnect {
+        return Err(Error::Disconnected(reason.clone()));
+    }
+    received(read_rx.recv().await, disconnect)
+}
+
+/// Like `recv_frame`, but returns `None` instead of waiting
+///
+/// For `recv_many`, so it only takes frames the reader task has already buffered.
+pub(crate) fn try_recv_frame(
+    read_rx: &mut mpsc::Receiver<Result<Vec<u8>, Error>>,
+    disconnect: &mut Option<DisconnectReason>,
+) -> Option<Result<PooledBuf, Error>> {
+    if let Some(reason) = disconnect {
+        return Some(Err(Error::Disconnected(reason.clone())));
+    }
+    let item = match read_rx.try_recv() {
+        Ok(item) => Some(item),
+        Err(mpsc::error::TryRecvError::Empty) => return None,
+        Err(mpsc::error::TryRecvError::Disconnected) => None,
+    };
+    Some(received(item, disconnect))
+}
+
+fn received(
+    item: Option<Result<Vec<u8>, Error>>,
+    disconnect: &mut Option<DisconnectReason>,
+) -> Result<PooledBuf, Error> {
+    match item {
+        Some(Ok(buf)) => Ok(PooledBuf::new(buf)),
+        Some(Err(Error::Disconnected(reason))) => {
+            *disconnect = Some(reason.clone());
+            Err(Error::Disconnected(reason))
+        }
+        // The reader task is gone, so this is the last item
+        Some(Err(Error::Internal(message))) => {
+            *disconnect = Some(DisconnectReason::Internal(message.clone()));
+            Err(Error::Internal(message))
+        }
+        Some(Err(error)) => Err(error),
+        // The reader task was aborted without saying why
+        None => {
+            let reason = DisconnectReason::GracefulClose;
+            *disconnect = Some(reason.clone());
+            Err(Error::Disconnected(reason))
+        }
+    }
+}
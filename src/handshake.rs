@@ -0,0 +1,184 @@
+//! The manager's side of the secure handshake, without any IO
+//!
+//! `SubprocessBuilder::spawn` feeds `HandshakeState` what happens on the pipe
+//! and does what it answers, so every ordering of events can be tested
+//! without processes or pipes. The worker must, in this order:
+//!
+//! 1. Be the process that connects, checked by PID
+//! 2. Optionally, send a `Hello` with a nonce, which the manager answers with
+//!    a `Proof`. Workers since protocol version 3 always do, see `server_proof`.
+//! 3. Echo the cookie that was written to its stdin, after which the manager
+//!    writes `Accepted`
+//! 4. With `Config::require_same_arch`, send a `Fingerprint` with the manager's arch
+//!
+//! Anything else, e.g. a user message before the cookie, ends the handshake.
+
+use serde::de::IgnoredAny;
+
+use crate::{fingerprint, sys, Fingerprint, WorkerMsgInternal};
+
+/// Proves the manager knows the cookie, so the worker knows it reached the real one
+///
+/// HMAC-SHA256 of the worker's nonce, keyed with the cookie, in hex. The worker
+/// checks it before it sends the cookie, so a server squatting on the pipe name
+/// learns neither the cookie nor a proof it could replay.
+pub(crate) fn server_proof(cookie: &str, nonce: &str) -> anyhow::Result<String> {
+    let mac = sys::hmac_sha256(cookie.as_bytes(), nonce.as_bytes())?;
+    Ok(mac.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Whether `proof` is `server_proof(cookie, nonce)`, comparing in constant time
+pub(crate) fn verify_server_proof(cookie: &str, nonce: &str, proof: &str) -> bool {
+    let Ok(expected) = server_proof(cookie, nonce) else {
+        return false;
+    };
+    expected.len() == proof.len()
+        && expected
+            .bytes()
+            .zip(proof.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Something that happened on the pipe
+#[derive(Clone, Copy)]
+pub(crate) enum Input<'a> {
+    /// A client opened the pipe
+    Connected { pid: u32 },
+    /// A frame body from the client
+    Frame(&'a [u8]),
+}
+
+/// What the caller should do next
+#[derive(Debug, PartialEq)]
+pub(crate) enum Output {
+    /// Read the next frame
+    Read,
+    /// Write `Proof` with this, then read the next frame
+    Prove(String),
+    /// Write `Accepted`. Unless `finished`, then read the next frame.
+    Accept { finished: bool },
+    /// The worker's fingerprint passed, and the handshake is over
+    Finished(Fingerprint),
+    /// Drop the connection, for this reason
+    Rejected(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Connect,
+    /// Takes a `Hello` or, from older workers, the `Cookie`
+    Hello,
+    Cookie,
+    Fingerprint,
+    Done,
+    Failed,
+}
+
+pub(crate) struct HandshakeState {
+    child_pid: u32,
+    cookie: String,
+    /// Our `Fingerprint::arch`, if the worker's has to match it
+    required_arch: Option<String>,
+    phase: Phase,
+}
+
+#[deny(
+    clippy::arithmetic_side_effects,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::panic,
+    clippy::unwrap_used
+)]
+impl HandshakeState {
+    pub(crate) fn new(child_pid: u32, cookie: String, required_arch: Option<String>) -> Self {
+        Self {
+            child_pid,
+            cookie,
+            required_arch,
+            phase: Phase::Connect,
+        }
+    }
+
+    /// What to write to the worker's stdin
+    pub(crate) fn cookie(&self) -> &str {
+        &self.cookie
+    }
+
+    /// Takes the next event. After `Rejected`, rejects everything.
+    pub(crate) fn advance(&mut self, input: Input<'_>) -> Output {
+        let (output, phase) = self.step(input);
+        self.phase = match output {
+            Output::Rejected(_) => Phase::Failed,
+            _ => phase,
+        };
+        output
+    }
+
+    /// The output, and the phase to move to unless it's `Rejected`
+    fn step(&self, input: Input<'_>) -> (Output, Phase) {
+        let reject = |reason: &str| (Output::Rejected(reason.to_string()), Phase::Failed);
+        let frame = match (self.phase, input) {
+            (Phase::Failed, _) => return reject("handshake already failed"),
+            (Phase::Done, _) => return reject("handshake already finished"),
+            (Phase::Connect, Input::Connected { pid }) if pid == self.child_pid => {
+                return (Output::Read, Phase::Hello)
+            }
+            (Phase::Connect, Input::Connected { .. }) => {
+                return reject("PID of pipe client doesn't match our child process")
+            }
+            (Phase::Connect, Input::Frame(_)) => {
+                return reject("got a frame before the pipe client connected")
+            }
+            (_, Input::Connected { .. }) => return reject("pipe client connected twice"),
+            (_, Input::Frame(frame)) => frame,
+        };
+        // Only the handshake's own variants matter, so user payloads are skipped unparsed
+        let msg = serde_json::from_slice::<WorkerMsgInternal<IgnoredAny>>(frame).ok();
+        // Only `Hello`, `Cookie` and `Fingerprint` get this far
+        if let (Phase::Hello, Some(WorkerMsgInternal::Hello { nonce })) = (self.phase, &msg) {
+            return match server_proof(&self.cookie, nonce) {
+                Ok(proof) => (Output::Prove(proof), Phase::Cookie),
+                Err(_) => reject("couldn't compute the server proof"),
+            };
+        }
+        if matches!(self.phase, Phase::Hello | Phase::Cookie) {
+            let Some(WorkerMsgInternal::Cookie(echoed)) = msg else {
+                return reject("didn't receive cookie from pipe client");
+            };
+            tracing::trace!(?echoed, "Got cookie back");
+            if echoed != self.cookie {
+                return reject(
+                    "cookie received from pipe client should match the cookie we sent to our child process",
+                );
+            }
+            return if self.required_arch.is_none() {
+                (Output::Accept { finished: true }, Phase::Done)
+            } else {
+                (Output::Accept { finished: false }, Phase::Fingerprint)
+            };
+        }
+        match msg {
+            Some(WorkerMsgInternal::Fingerprint(theirs)) => (self.check_arch(theirs), Phase::Done),
+            Some(WorkerMsgInternal::Cookie(_)) => reject("pipe client sent the cookie twice"),
+            Some(WorkerMsgInternal::Hello { .. }) => reject("pipe client sent a late hello"),
+            _ => reject("worker didn't report its architecture, it may be too old"),
+        }
+    }
+
+    fn check_arch(&self, theirs: Fingerprint) -> Output {
+        let Some(ours) = &self.required_arch else {
+            return Output::Finished(theirs);
+        };
+        if theirs.arch == *ours {
+            return Output::Finished(theirs);
+        }
+        Output::Rejected(format!(
+            "worker is {} ({}-bit) but the manager is {} ({}-bit)",
+            theirs.arch,
+            theirs.pointer_width().unwrap_or_default(),
+            ours,
+            fingerprint::pointer_width(ours).unwrap_or_default(),
+        ))
+    }
+}
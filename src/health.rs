@@ -0,0 +1,64 @@
+//! A standard liveness and readiness probe, separate from app messages
+//!
+//! `Server::health` writes a `Ping`, and `Client::next` answers it with the
+//! status the worker last set with `Client::set_health`. A worker that doesn't
+//! answer isn't live. One that answers `Healthy` is live but not ready for work yet.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::{Error, ResponseFuture};
+
+/// What the worker says about itself, see `Client::set_health`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum HealthStatus {
+    /// Running, but not ready for work yet, e.g. still starting up
+    #[default]
+    Healthy,
+    Ready,
+    /// Running, but it knows something's wrong
+    Unhealthy(String),
+}
+
+/// The result of `Server::health`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Health {
+    /// From writing the `Ping` to routing the answer, including however long the
+    /// worker took to poll `next`
+    pub rtt: Duration,
+    pub status: HealthStatus,
+}
+
+impl Health {
+    pub fn is_ready(&self) -> bool {
+        self.status == HealthStatus::Ready
+    }
+}
+
+/// Resolves to the worker's answer to `Server::health`
+///
+/// Like `ResponseFuture`, the answer is only routed while `Server::next` is
+/// being polled. Wrap it in a timeout, since a hung worker never answers.
+pub struct HealthCheck {
+    pub(crate) sent: Instant,
+    pub(crate) status: ResponseFuture<HealthStatus>,
+}
+
+impl Future for HealthCheck {
+    type Output = Result<Health, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let sent = self.sent;
+        Pin::new(&mut self.status).poll(cx).map(|status| {
+            Ok(Health {
+                rtt: sent.elapsed(),
+                status: status?,
+            })
+        })
+    }
+}
@@ -0,0 +1,117 @@
+//! Finding and checking the worker exe before a privileged manager runs it
+//!
+//! If a less-privileged user can replace the exe, a manager running as SYSTEM
+//! would otherwise run their code for them.
+
+use std::{
+    fs::File,
+    io::Read as _,
+    os::windows::fs::{MetadataExt as _, OpenOptionsExt as _},
+    path::{Path, PathBuf},
+};
+use windows::Win32::Storage::FileSystem::{FILE_ATTRIBUTE_REPARSE_POINT, FILE_SHARE_READ};
+
+use crate::{sys, Error};
+
+/// How `SubprocessBuilder::spawn` checks the worker exe, see `SubprocessBuilder::binary_check`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BinaryCheck {
+    /// The exe has a valid Authenticode signature, made by this signer
+    ///
+    /// Any publisher the machine trusts can sign a valid exe, so the signer has
+    /// to be pinned too.
+    Authenticode(AuthenticodeSigner),
+    /// The exe's SHA-256 hash matches, e.g. for unsigned builds pinned at install time
+    Sha256([u8; 32]),
+}
+
+/// Which certificate `BinaryCheck::Authenticode` accepts the signature of
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AuthenticodeSigner {
+    /// The signing certificate's SHA-1 thumbprint, as Windows shows it
+    Thumbprint([u8; 20]),
+    /// The signing certificate's subject name, usually its common name, e.g. `Firezone, Inc.`
+    ///
+    /// A trusted CA may issue the same name to someone else, so prefer `Thumbprint`
+    /// where the certificate is known ahead of time.
+    Subject(String),
+}
+
+impl AuthenticodeSigner {
+    fn matches(&self, cert: &sys::SigningCert) -> bool {
+        match self {
+            Self::Thumbprint(thumbprint) => *thumbprint == cert.thumbprint,
+            Self::Subject(subject) => *subject == cert.subject,
+        }
+    }
+}
+
+/// The canonical path of the worker exe, `exe` if it's set or else the current exe
+///
+/// When we're elevated, refuses paths that pass through a symlink, junction, or
+/// any other reparse point, since we can't tell if a less-privileged user
+/// controls where it leads.
+pub(crate) fn resolve_exe(exe: Option<&Path>) -> Result<PathBuf, Error> {
+    let exe = match exe {
+        Some(exe) => exe.to_owned(),
+        None => std::env::current_exe()?,
+    };
+    let untrusted = |reason: String| Error::UntrustedBinary {
+        path: exe.clone(),
+        reason,
+    };
+    if !exe.is_absolute() {
+        return Err(untrusted("not an absolute path".to_string()));
+    }
+    let elevated = sys::is_elevated()
+        .map_err(|error| untrusted(format!("couldn't check if we're elevated: {error:#}")))?;
+    if elevated {
+        for ancestor in exe.ancestors() {
+            let metadata = std::fs::symlink_metadata(ancestor)?;
+            if metadata.file_attributes() & FILE_ATTRIBUTE_REPARSE_POINT.0 != 0 {
+                return Err(untrusted(format!(
+                    "{ancestor:?} is a link, and we're elevated"
+                )));
+            }
+        }
+    }
+    Ok(std::fs::canonicalize(&exe)?)
+}
+
+/// Opens and checks the exe at `path`
+///
+/// The returned file denies writes and deletes while it's open, so keep it
+/// until the process is created, or the exe could be swapped after the check.
+pub(crate) fn verify(path: &Path, check: &BinaryCheck) -> Result<File, Error> {
+    let untrusted = |reason: String| Error::UntrustedBinary {
+        path: path.to_owned(),
+        reason,
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ.0)
+        .open(path)
+        .map_err(|error| untrusted(format!("couldn't open it: {error}")))?;
+    match check {
+        BinaryCheck::Authenticode(signer) => {
+            let cert = sys::verify_authenticode(path, &file)
+                .map_err(|error| untrusted(format!("{error:#}")))?;
+            if !signer.matches(&cert) {
+                return Err(untrusted(format!(
+                    "signed by {:?}, not the pinned signer",
+                    cert.subject
+                )));
+            }
+        }
+        BinaryCheck::Sha256(expected) => {
+            let mut contents = vec![];
+            file.read_to_end(&mut contents)
+                .map_err(|error| untrusted(format!("couldn't read it: {error}")))?;
+            let actual = sys::sha256(&contents).map_err(|error| untrusted(format!("{error:#}")))?;
+            if actual != *expected {
+                return Err(untrusted("SHA-256 hash doesn't match".to_string()));
+            }
+        }
+    }
+    Ok(file)
+}
@@ -0,0 +1,25 @@
+//! Containment stats for a `LeakGuard`, for diagnostics bundles
+
+use serde::{Deserialize, Serialize};
+
+/// What a `LeakGuard`'s job object has contained, see `LeakGuard::accounting`
+///
+/// Counts include processes the workers spawned themselves.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct JobAccounting {
+    /// See `LeakGuard::with_name`
+    pub name: Option<String>,
+    /// Every process that was ever in the job
+    pub total_processes: u32,
+    pub active_processes: u32,
+    /// Processes Windows killed for breaking a job limit
+    pub terminated_by_limits: u32,
+    /// Workers subzone killed, e.g. after a failed handshake or a missed grace period
+    pub kills: u64,
+    /// User plus kernel time of every process in the job, including ones that exited
+    pub cpu_time_ms: u64,
+    /// Most committed memory any one process used, in bytes
+    pub peak_process_memory: u64,
+    /// Most committed memory all processes used at once, in bytes
+    pub peak_job_memory: u64,
+}
@@ -2,9 +2,19 @@
 //!
 //! To run the unit tests and multi-process tests, use
 //! ```bash
-//! cargo test && cargo run && echo good
+//! cargo test && cargo run --features harness && echo good
 //! ```
 //!
+//! # Features
+//!
+//! - `subscriber`, on by default: `init_reloadable_subscriber` and
+//!   `log_forward_layer`, which pull in `tracing-subscriber`
+//! - `clap`: `WorkerArgs::parse`
+//! - `harness`: the multi-process test driver, `run_multi_process_tests`
+//!
+//! With `default-features = false` the library depends on neither `clap` nor
+//! `tracing-subscriber`.
+//!
 //! # Security
 //!
 //! The IPC module uses Windows' named pipes primitive.
@@ -30,123 +40,384 @@
 //! - Automatically kill workers even if the manager process crashes
 //! - Bails out if some other process tries to intercept IPC between the two processes
 
-use anyhow::Result;
-use clap::Parser;
 use serde::{Deserialize, Serialize};
-use std::{fmt::Debug, marker::Unpin};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::fmt::Debug;
 
+mod accept_loop;
+mod acl;
+mod buffer_pool;
+mod call;
+mod callbacks;
+#[cfg(test)]
+mod chaos;
+pub mod cli;
 mod client;
+mod clock;
+mod config;
+mod connection_info;
+mod diagnostics;
+mod disconnect;
+mod etw;
+mod event_log;
+mod extension;
+mod fingerprint;
+mod frame;
+mod handshake;
+mod health;
+mod integrity;
+mod job_accounting;
+mod listener;
+mod log_filter;
+mod log_forward;
+mod manager;
+mod metadata;
+mod method_stats;
+mod network;
+mod orphans;
+pub mod os;
+mod os_shutdown;
+mod otel;
+mod panic_guard;
+mod pipe_id;
+mod power;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod quota;
+mod redact;
+mod registry;
+mod remote_error;
+mod repro;
+mod scope;
+mod serve;
 mod server;
-// Always enabled, since the integration tests can't run in `cargo test` yet
+#[cfg(feature = "tower")]
+mod service;
+mod shutdown;
+mod single_instance;
+mod stats;
+mod sync;
+mod sys;
+pub mod testing;
+mod watchdog;
+mod worker_args;
+mod worker_exit;
+// The integration tests can't run in `cargo test` yet, but the unit tests share their messages
+#[cfg(any(test, feature = "harness"))]
 pub(crate) mod multi_process_tests;
 
-pub use client::Client;
-pub use server::{LeakGuard, Server, SubcommandChild, SubcommandExit, Subprocess};
+pub use accept_loop::{AcceptLoop, AcceptPolicy, AcceptResult};
+pub use buffer_pool::{buffer_pool_stats, BufferPoolStats};
+pub use call::{PendingCall, ResponseFuture};
+pub use callbacks::Callbacks;
+pub use client::{Client, DEFAULT_CLOSE_TIMEOUT, DEFAULT_SEND_TIMEOUT};
+pub use clock::{ClockOffset, ClockSample};
+pub use config::{Config, ConfigAck, ImpersonationLevel};
+pub use connection_info::{Codec, Compression, ConnectionInfo, PipeMode, Transport};
+pub use diagnostics::{DiagnosticSnapshot, RecentError, RemovedWorker, WorkerSnapshot};
+pub use disconnect::DisconnectReason;
+#[cfg(feature = "etw")]
+pub use etw::register_etw_provider;
+pub use event_log::{ConnectionEvent, ConnectionEventKind, EVENT_LOG_LEN};
+pub use fingerprint::Fingerprint;
+pub use health::{Health, HealthCheck, HealthStatus};
+pub use integrity::{AuthenticodeSigner, BinaryCheck};
+pub use job_accounting::JobAccounting;
+pub use listener::{Listener, ServerOptions};
+#[cfg(feature = "subscriber")]
+pub use log_filter::init_reloadable_subscriber;
+pub use log_filter::LogFilterHandle;
+#[cfg(feature = "subscriber")]
+pub use log_forward::{log_forward_layer, LogForwardLayer};
+pub use log_forward::{LogForwarder, LogRecord};
+pub use manager::{BroadcastReport, Manager, ManagerEvent};
+pub use metadata::Metadata;
+pub use method_stats::{Histogram, MethodStats};
+pub use network::{NetworkChange, NetworkChangeKind, NetworkWatcher};
+pub use orphans::WorkerPidFile;
+pub use os_shutdown::OsShutdown;
+pub use pipe_id::PipeId;
+pub use power::{PowerEvent, PowerWatcher};
+pub use quota::{ConnectionQuota, Eviction, QuotaKey};
+pub use redact::{Redact, DEFAULT_SECRET_FIELDS, REDACTED};
+pub use registry::{Endpoint, Registration, Registry};
+pub use remote_error::{Envelope, RemoteError};
+pub use repro::{
+    ReproBundle, ReproSide, MAX_REPROS_PER_CONNECTION, MAX_REPRO_FRAME, REPRO_EXTENSION,
+    REPRO_FORMAT, REPRO_INTERVAL,
+};
+pub use scope::{PeerIdentity, Scoped};
+pub use serve::{serve, ServeOptions, HANDLER_PANIC_CODE};
+pub use server::{
+    LeakGuard, LeakGuardOptions, SecretDelivery, Server, SubcommandChild, SubcommandExit,
+    Subprocess, SubprocessBuilder,
+};
+#[cfg(feature = "tower")]
+pub use service::{CallFuture, CallService};
+pub use shutdown::ShutdownToken;
+pub use single_instance::{
+    forward_to_running_instance, single_instance, InstanceGuard, SingleInstance,
+};
+pub use stats::WorkerStats;
+pub use worker_args::WorkerArgs;
+pub use worker_exit::{worker_main, FatalError, WorkerExit};
+
+/// Version of the framing and internal message protocol
+///
+/// Bump this when the wire format changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 3;
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    /// Used to detected graceful named pipe closes
-    #[error("EOF")]
-    Eof,
+    /// The connection is gone, and why
+    #[error("Disconnected: {0}")]
+    Disconnected(DisconnectReason),
     /// Any IO error except EOF
     #[error(transparent)]
     Io(std::io::Error),
     #[error(transparent)]
     Json(#[from] serde_json::Error),
-    #[error("Something went wrong while converting message length to u32 or usize")]
+    /// The frame is bigger than the framing allows, or its length didn't fit in `u32` or `usize`
+    #[error("Message length is too big")]
     MessageLength,
-    #[error("Protocol error, got Cookie or Shutdown at an incorrect time")]
+    /// The peer sent a frame that's malformed, e.g. a bad fragment or timestamp,
+    /// or a control frame at the wrong time
+    #[error("Protocol error, the peer sent a malformed or unexpected frame")]
     Protocol,
     #[error(transparent)]
     Utf8(#[from] std::str::Utf8Error),
+    /// A frame was lost or reordered. The frame that arrived is returned by the next call.
+    #[error("Expected frame {expected} but got frame {got}")]
+    SequenceGap { expected: u64, got: u64 },
+    #[error("Extension ID {0} already has a receiver")]
+    ExtensionAlreadyRegistered(u16),
+    #[error("No worker with role {0:?}")]
+    UnknownRole(String),
+    /// A pipe ID from the command line or a file isn't a named pipe path
+    #[error("Invalid pipe ID: {0}")]
+    InvalidPipeId(&'static str),
+    /// No worker opened the pipe before `Config::accept_timeout`
+    #[error("No client connected within {0:?}")]
+    NoConnectionWithin(std::time::Duration),
+    /// The peer opened the pipe but didn't finish the handshake before
+    /// `Config::handshake_timeout`. On a worker, `peer` is the manager.
+    #[error("Peer {peer} didn't finish the handshake in time")]
+    HandshakeTimeout { peer: u32 },
+    /// A client opened the pipe but isn't our child, or got the handshake wrong.
+    /// On a worker, the manager hung up or answered the cookie with something
+    /// other than `Accepted`.
+    #[error("Rejected handshake with {peer}: {reason}")]
+    HandshakeRejected { peer: u32, reason: String },
+    /// The worker exe failed `SubprocessBuilder::binary_check`, so it wasn't run
+    #[error("Untrusted worker binary {path:?}: {reason}")]
+    UntrustedBinary {
+        path: std::path::PathBuf,
+        reason: String,
+    },
+    /// An argument for the worker looks like a pipe ID, see `SubprocessBuilder::arg`
+    #[error("Worker arguments can't look like a pipe ID")]
+    ReservedArgument,
+    /// The `Manager`'s task for a worker is gone, e.g. it was removed while sending
+    #[error("Worker task stopped")]
+    WorkerTaskStopped,
+    /// The connection is already gone, so nothing was written
+    ///
+    /// Returned by sends after the peer disconnected, after `next` returned
+    /// `Error::Disconnected`, or after an earlier write failed. Reads keep
+    /// returning `Error::Disconnected` with the reason instead.
+    #[error("The connection is closed")]
+    Closed,
+    /// A write didn't finish within the send timeout. The frame may be half-written,
+    /// so the connection is unusable after this.
+    #[error("Write stalled for longer than the send timeout, the connection is closed")]
+    WriteStalled,
+    /// The worker's handler for a call overran its `ServeOptions::timeout`
+    #[error("Worker's {method} handler didn't finish within {timeout:?}")]
+    HandlerTimeout {
+        method: String,
+        timeout: std::time::Duration,
+    },
+    /// The worker answered the call with `Client::respond_error`
+    #[error("Worker returned an error: {0}")]
+    Remote(RemoteError),
+    /// A task inside subzone panicked, with the panic message
+    ///
+    /// The connection is marked failed, so later reads return `Error::Disconnected`
+    /// with `DisconnectReason::Internal`. `close` and drop still work.
+    #[error("Internal task panicked: {0}")]
+    Internal(String),
+    /// Windows wouldn't let the worker open the pipe, instead of a bare os error 5
+    ///
+    /// Usually the manager runs elevated, or as another user, and its pipe's ACL
+    /// doesn't admit this process. `peer_user` is the pipe's owner, if Windows
+    /// let us read it. `elevated` is whether this process is. The pipe ID stays
+    /// out of the message, see `PipeId`. To retry, e.g. after asking for elevation,
+    /// see `Client::new_with_elevation_hook`.
+    #[error(
+        "Access denied to the manager's pipe, owned by {}{}",
+        .peer_user.as_deref().unwrap_or("an unknown user"),
+        if *.elevated { "" } else { ". Try running this process elevated" }
+    )]
+    PermissionDenied {
+        path: PipeId,
+        peer_user: Option<String>,
+        elevated: bool,
+    },
+    /// The pipe server didn't prove it knows the cookie, so it may be squatting on
+    /// the pipe name. The cookie wasn't sent.
+    #[error("The pipe server couldn't prove it's our manager")]
+    ServerUnverified,
+    /// The client sent a message whose method needs a scope it wasn't granted, see
+    /// `Scoped`. The message was dropped, but the connection is still up.
+    #[error("Client isn't allowed to call {method}, which needs the {scope} scope")]
+    ScopeDenied { method: String, scope: String },
 }
 
 #[derive(Deserialize, Serialize)]
 pub enum ManagerMsgInternal<T> {
+    /// The cookie checked out. Handled inside `Client::new`.
+    Accepted {
+        resume_state: Option<Vec<u8>>,
+        /// The manager's versions, see `fingerprint`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fingerprint: Option<Fingerprint>,
+        /// The manager's clock readings, see `clock`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        clock: Option<ClockSample>,
+    },
     Shutdown,
     User(T),
+    /// A message the worker must answer with `Client::respond`, see `Server::call`
+    Request {
+        id: u64,
+        msg: T,
+        /// W3C trace context of the caller's span, see `otel`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        traceparent: Option<String>,
+    },
+    /// Another frame with headers, see `Server::send_with`. Unwrapped inside `Client::next`.
+    Meta(Metadata, Box<ManagerMsgInternal<T>>),
+    /// App-defined control frame, see `Server::register_extension`.
+    /// `Client::next` routes these and never returns them.
+    Extension(u16, Vec<u8>),
+    /// Replace the worker's `tracing` filter. Handled inside `Client::next`.
+    SetLogFilter(String),
+    /// The system is suspending or has resumed, see `Server::send_power_event`
+    Power(PowerEvent),
+    /// A network interface changed, see `Server::send_network_change`
+    NetworkChanged(NetworkChange),
+    /// New timeouts and limits, see `Server::push_config`. `Client::next` applies
+    /// and acks it before returning it.
+    SetConfig {
+        id: u64,
+        config: Config,
+    },
+    /// Start or stop sending `WorkerMsgInternal::Stats`, see `Server::report_stats`.
+    /// Handled inside `Client::next`.
+    ReportStats {
+        interval_ms: Option<u64>,
+    },
+    /// A health probe, see `Server::health`. Answered inside `Client::next`.
+    Ping {
+        id: u64,
+    },
+    /// The answer to `WorkerMsgInternal::Hello`, see `handshake::server_proof`.
+    /// Checked inside `Client::new`.
+    Proof(String),
 }
 
 #[derive(Deserialize, Serialize)]
 pub enum WorkerMsgInternal<T> {
     Cookie(String),
     User(T),
+    /// The answer to a `ManagerMsgInternal::Request`. Routed inside `Server::next`.
+    Response {
+        id: u64,
+        msg: T,
+    },
+    /// Another frame with headers, see `Client::send_with`. Unwrapped inside `Server::next`.
+    Meta(Metadata, Box<WorkerMsgInternal<T>>),
+    /// App-defined control frame, see `Client::register_extension`
+    Extension(u16, Vec<u8>),
+    /// Replace the manager's `tracing` filter. Handled inside `Server::next`.
+    SetLogFilter(String),
+    /// A forwarded `tracing` event, see `Client::forward_logs`
+    Log(LogRecord),
+    /// Start receiving messages published on this topic, see `Server::publish`
+    Subscribe(String),
+    Unsubscribe(String),
+    /// The answer to a `ManagerMsgInternal::SetConfig`. Routed inside `Server::next`.
+    ConfigAck {
+        id: u64,
+        ack: ConfigAck,
+    },
+    /// A periodic resource usage sample. Handled inside `Server::next`.
+    Stats(WorkerStats),
+    /// The answer to a `ManagerMsgInternal::Ping`. Routed inside `Server::next`.
+    Health {
+        id: u64,
+        status: HealthStatus,
+    },
+    /// The worker's versions, sent right after `Accepted` if the manager sent its own.
+    /// Handled inside `Server::next`.
+    Fingerprint(Fingerprint),
+    /// The clock offset from the manager's side, sent after `Accepted` if it had a
+    /// `ClockSample`. Handled inside `Server::next`, which starts stamping frames.
+    ClockOffset(ClockOffset),
+    /// Answers a `ManagerMsgInternal::Request` whose handler overran its
+    /// `ServeOptions::timeout`. Routed inside `Server::next`.
+    HandlerTimeout {
+        id: u64,
+        method: String,
+        timeout_ms: u64,
+    },
+    /// Answers a `ManagerMsgInternal::Request` with an error, see `Client::respond_error`.
+    /// Routed inside `Server::next`.
+    Failed {
+        id: u64,
+        error: RemoteError,
+    },
+    /// Asks the manager to prove it knows the cookie, before the worker sends it.
+    /// Sent by `Client::new`, see `handshake::server_proof`.
+    Hello {
+        nonce: String,
+    },
 }
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
         if e.kind() == std::io::ErrorKind::UnexpectedEof {
-            Self::Eof
+            // The reader task refines this once it knows if the peer exited
+            Self::Disconnected(DisconnectReason::GracefulClose)
         } else {
             Self::Io(e)
         }
     }
 }
 
-#[derive(Parser)]
+#[cfg(feature = "harness")]
+#[derive(clap::Parser)]
 struct Cli {
     #[command(subcommand)]
     cmd: Option<multi_process_tests::Subcommand>,
 }
 
 /// Don't use. This is just for internal tests that are difficult to do with `cargo test`
-pub fn run_multi_process_tests() -> Result<()> {
+#[cfg(feature = "harness")]
+pub fn run_multi_process_tests() -> anyhow::Result<()> {
+    use clap::Parser as _;
+
     let cli = Cli::parse();
     multi_process_tests::run(cli.cmd)
 }
 
-/// Returns a random valid named pipe ID based on a UUIDv4
-///
-/// e.g. "\\.\pipe\dev.firezone.client\9508e87c-1c92-4630-bb20-839325d169bd"
-///
-/// Normally you don't need to call this directly. Tests may need it to inject
-/// a known pipe ID into a process controlled by the test.
-pub(crate) fn random_pipe_id() -> String {
-    named_pipe_path(&uuid::Uuid::new_v4().to_string())
-}
-
-/// Returns a valid named pipe ID
-///
-/// e.g. "\\.\pipe\dev.firezone.client\{path}"
-pub(crate) fn named_pipe_path(path: &str) -> String {
-    format!(r"\\.\pipe\subzone\{path}")
-}
-
-/// Reads a message from an async reader, with a 32-bit little-endian length prefix
-async fn read_deserialize<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Vec<u8>, Error> {
-    let mut len_buf = [0u8; 4];
-    reader.read_exact(&mut len_buf).await?;
-    let len = u32::from_le_bytes(len_buf);
-    tracing::trace!(?len, "reading message");
-    let len = usize::try_from(len).map_err(|_| Error::MessageLength)?;
-    let mut buf = vec![0u8; len];
-    reader.read_exact(&mut buf).await?;
-    Ok(buf)
-}
-
-/// Writes a message to an async writer, with a 32-bit little-endian length prefix
-async fn write_serialize<W: AsyncWrite + Unpin, T: Serialize>(
-    writer: &mut W,
-    msg: &T,
-) -> Result<(), Error> {
-    // Using JSON because `bincode` couldn't decode `ResourceDescription`
-    let buf = serde_json::to_string(msg)?;
-    let len = u32::try_from(buf.len())
-        .map_err(|_| Error::MessageLength)?
-        .to_le_bytes();
-    tracing::trace!(len = buf.len(), "writing message");
-    writer.write_all(&len).await?;
-    writer.write_all(buf.as_bytes()).await?;
-    Ok(())
-}
-
 #[cfg(test)]
 mod tests {
     use super::server::UnconnectedServer;
     use super::*;
     use crate::multi_process_tests::{Callback, ManagerMsg, WorkerMsg};
+    use crate::testing::LatencyBudget;
     use anyhow::Context;
+    use anyhow::Result;
     use std::time::{Duration, Instant};
     use tokio::runtime::Runtime;
 
@@ -225,8 +496,8 @@ mod tests {
                 WorkerMsg::Response(ManagerMsg::Connect)
             );
 
-            let elapsed = start_time.elapsed();
-            assert!(elapsed < Duration::from_millis(20), "{:?}", elapsed);
+            LatencyBudget::new("IPC round trips", Duration::from_millis(20))
+                .check_since(start_time)?;
 
             server.close().await?;
 
@@ -238,10 +509,3975 @@ mod tests {
         Ok(())
     }
 
-    fn sample_resources() -> Vec<String> {
-        vec![
-            "2efe9c25-bd92-49a0-99d7-8b92da014dd5".into(),
-            "613eaf56-6efa-45e5-88aa-ea4ad64d8c18".into(),
-        ]
+    /// Both sides report the parameters they negotiated, and the report serializes
+    #[test]
+    fn connection_info() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, client) = connected_pair().await?;
+            assert_eq!(client.connection_info().peer_pid, std::process::id());
+            let info = server.connection_info();
+            assert_eq!(info.peer_pid, std::process::id());
+            assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+            serde_json::to_string(info)?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// If the manager stops reading, the worker's `send` should give up instead of blocking forever
+    #[test]
+    fn send_timeout() -> Result<()> {
+        tracing_subscriber::fmt::try_init().ok();
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, server_id) = UnconnectedServer::new()?;
+            let mut client: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(&server_id)?;
+            client.set_send_timeout(Some(Duration::from_millis(100)));
+            // Accept the connection and then never call `next`
+            let _server: Server<ManagerMsg, WorkerMsg> = server.accept().await?;
+
+            let big_msg = || WorkerMsg::Callback(Callback::Cookie("a".repeat(1_000_000)));
+            let mut stalled = false;
+            for _ in 0..10 {
+                match client.send(big_msg()).await {
+                    Ok(()) => {}
+                    Err(Error::WriteStalled) => {
+                        stalled = true;
+                        break;
+                    }
+                    Err(error) => return Err(error.into()),
+                }
+            }
+            assert!(stalled, "send should have timed out");
+            assert!(matches!(
+                client.send(big_msg()).await,
+                Err(Error::WriteStalled)
+            ));
+            assert!(client.next().await.is_err());
+
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A manager that never answers times the worker's handshake out, and
+    /// nothing left running holds up the runtime's shutdown
+    #[test]
+    fn client_handshake_timeout() -> Result<()> {
+        let config = Config {
+            handshake_timeout: Duration::from_millis(250),
+            ..Default::default()
+        };
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (_server, mut client) = connected_pair().await?;
+            let error = client
+                .handshake_within("cookie".into(), &config)
+                .await
+                .err()
+                .context("handshake should time out")?;
+            assert!(
+                matches!(error.downcast_ref(), Some(Error::HandshakeTimeout { .. })),
+                "{error:#}"
+            );
+            Ok::<_, anyhow::Error>(())
+        })?;
+        let start = Instant::now();
+        drop(rt);
+        LatencyBudget::new("Runtime drop", Duration::from_millis(500)).check_since(start)?;
+        Ok(())
+    }
+
+    /// After a send times out partway through a frame, the log task can't write
+    /// behind it and desync the manager's reader
+    #[cfg(feature = "subscriber")]
+    #[test]
+    fn no_log_frames_after_write_stalled() -> Result<()> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (layer, forwarder) = log_forward_layer();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            for i in 0..100 {
+                tracing::info!(target: "app", i, "queued");
+            }
+        });
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            client.set_send_timeout(Some(Duration::from_millis(100)));
+            let big_msg = || WorkerMsg::Callback(Callback::Cookie("a".repeat(1_000_000)));
+            loop {
+                match client.send(big_msg()).await {
+                    Ok(()) => {}
+                    Err(Error::WriteStalled) => break,
+                    Err(error) => return Err(error.into()),
+                }
+            }
+            client.forward_logs(forwarder);
+            // Give the log task time to try
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(client);
+
+            // The manager reads whole frames up to the half-written one, then the EOF
+            loop {
+                match tokio::time::timeout(Duration::from_secs(5), server.next()).await? {
+                    Ok(WorkerMsg::Callback(Callback::Cookie(_))) => {}
+                    Err(Error::Disconnected(_)) => break,
+                    other => panic!("unexpected result {other:?}"),
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A worker that closes its end of the pipe without exiting isn't a crash
+    #[test]
+    fn disconnect_reason_graceful() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, server_id) = UnconnectedServer::new()?;
+            let client: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(&server_id)?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = server.accept().await?;
+
+            client.close().await?;
+            for _ in 0..2 {
+                // The reason should be sticky
+                assert!(matches!(
+                    server.next().await,
+                    Err(Error::Disconnected(DisconnectReason::GracefulClose))
+                ));
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Extension frames go to their registered channel, not to `next`
+    #[test]
+    fn extension_frames() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, server_id) = UnconnectedServer::new()?;
+            let mut client: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(&server_id)?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = server.accept().await?;
+
+            let mut server_ext = server.register_extension(7)?;
+            assert!(matches!(
+                server.register_extension(7),
+                Err(Error::ExtensionAlreadyRegistered(7))
+            ));
+            let mut client_ext = client.register_extension(9)?;
+
+            client.send_extension(7, b"verbose".to_vec()).await?;
+            client
+                .send(WorkerMsg::Callback(Callback::TunnelReady))
+                .await?;
+            assert_eq!(
+                server.next().await?,
+                WorkerMsg::Callback(Callback::TunnelReady)
+            );
+            assert_eq!(server_ext.recv().await.unwrap(), b"verbose");
+
+            server.send_extension(9, vec![1, 2, 3]).await?;
+            server.send(ManagerMsg::Connect).await?;
+            let ManagerMsgInternal::User(msg) = client.next().await? else {
+                panic!("expected a user message");
+            };
+            assert_eq!(msg, ManagerMsg::Connect);
+            assert_eq!(client_ext.recv().await.unwrap(), vec![1, 2, 3]);
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Log filter frames are consumed by `next` even if nobody can apply them
+    #[test]
+    fn log_filter_frames() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, server_id) = UnconnectedServer::new()?;
+            let mut client: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(&server_id)?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = server.accept().await?;
+
+            server.set_worker_log_filter("debug").await?;
+            server.send(ManagerMsg::Connect).await?;
+            assert!(matches!(
+                client.next().await?,
+                ManagerMsgInternal::User(ManagerMsg::Connect)
+            ));
+
+            client.set_manager_log_filter("trace").await?;
+            client
+                .send(WorkerMsg::Callback(Callback::TunnelReady))
+                .await?;
+            assert_eq!(
+                server.next().await?,
+                WorkerMsg::Callback(Callback::TunnelReady)
+            );
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn config_push() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+
+            // Acks are only routed while `Server::next` is polled
+            async fn push(
+                server: &mut Server<ManagerMsg, WorkerMsg>,
+                client: &mut Client<ManagerMsg, WorkerMsg>,
+                config: &Config,
+            ) -> Result<ConfigAck> {
+                let ack = server.push_config(config).await?;
+                let ManagerMsgInternal::SetConfig { config: pushed, .. } = client.next().await?
+                else {
+                    anyhow::bail!("expected SetConfig");
+                };
+                assert_eq!(&pushed, config);
+                tokio::select! {
+                    ack = ack => Ok(ack?),
+                    msg = server.next() => anyhow::bail!("unexpected {msg:?}"),
+                }
+            }
+
+            // No `LogFilterHandle`, so nothing is applied
+            let config = Config {
+                max_frame_len: 1024,
+                log_filter: Some("debug".to_string()),
+                ..Default::default()
+            };
+            let ConfigAck::Rejected(_) = push(&mut server, &mut client, &config).await? else {
+                anyhow::bail!("expected the config to be rejected");
+            };
+            let big = WorkerMsg::Callback(Callback::OnUpdateResources(vec!["x".repeat(2048)]));
+            client.send(big).await?;
+            assert!(matches!(server.next().await?, WorkerMsg::Callback(_)));
+
+            let config = Config {
+                max_frame_len: 1024,
+                ..Default::default()
+            };
+            assert_eq!(
+                push(&mut server, &mut client, &config).await?,
+                ConfigAck::Applied
+            );
+            // Big messages are fragmented instead of refused
+            let big = || WorkerMsg::Callback(Callback::OnUpdateResources(vec!["x".repeat(2048)]));
+            client.send(big()).await?;
+            assert_eq!(server.next().await?, big());
+
+            let config = Config {
+                max_frame_len: 1024,
+                max_message_len: 1024,
+                ..Default::default()
+            };
+            assert_eq!(
+                push(&mut server, &mut client, &config).await?,
+                ConfigAck::Applied
+            );
+            assert!(matches!(
+                client.send(big()).await,
+                Err(Error::MessageLength)
+            ));
+            // The manager switched once the worker acked
+            let echo = || ManagerMsg::Echo("x".repeat(2048));
+            assert!(matches!(
+                server.send(echo()).await,
+                Err(Error::MessageLength)
+            ));
+
+            // Raised limits hold both ways, and readers accept what the writers now send
+            let config = Config {
+                max_frame_len: 4096,
+                max_message_len: 4096,
+                ..Default::default()
+            };
+            assert_eq!(
+                push(&mut server, &mut client, &config).await?,
+                ConfigAck::Applied
+            );
+            client.send(big()).await?;
+            assert_eq!(server.next().await?, big());
+            server.send(echo()).await?;
+            assert!(matches!(
+                client.next().await?,
+                ManagerMsgInternal::User(ManagerMsg::Echo(_))
+            ));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn health_probe() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            let (ready_tx, mut ready_rx) = tokio::sync::mpsc::channel(1);
+            let worker = tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        msg = client.next() => if msg.is_err() { break },
+                        Some(()) = ready_rx.recv() => client.set_health(HealthStatus::Ready),
+                    }
+                }
+            });
+
+            // Answers are only routed while `Server::next` is polled
+            async fn probe(server: &mut Server<ManagerMsg, WorkerMsg>) -> Result<Health> {
+                let check = server.health().await?;
+                tokio::select! {
+                    health = check => Ok(health?),
+                    msg = server.next() => anyhow::bail!("unexpected {msg:?}"),
+                }
+            }
+
+            let health = probe(&mut server).await?;
+            assert_eq!(health.status, HealthStatus::Healthy);
+            assert!(!health.is_ready());
+            ready_tx.send(()).await?;
+            // Let the worker apply it
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let health = probe(&mut server).await?;
+            assert!(health.is_ready());
+            assert!(health.rtt < Duration::from_secs(5));
+
+            drop(server);
+            worker.await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn worker_stats() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            assert_eq!(server.worker_stats(), None);
+
+            server.report_stats(Some(Duration::from_millis(20))).await?;
+            // `ReportStats` is handled inside `next`, which never returns it
+            assert!(
+                tokio::time::timeout(Duration::from_millis(100), client.next())
+                    .await
+                    .is_err()
+            );
+            // Same for the `Stats` frames
+            assert!(
+                tokio::time::timeout(Duration::from_millis(200), server.next())
+                    .await
+                    .is_err()
+            );
+            let stats = server.worker_stats().context("no stats arrived")?;
+            assert!(stats.rss_bytes > 0);
+            assert!(stats.handles > 0);
+            assert_eq!(stats.unanswered_requests, 0);
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Logs that are still queued when the worker closes reach the manager
+    #[cfg(feature = "subscriber")]
+    #[test]
+    fn close_flushes_forwarded_logs() -> Result<()> {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        use tracing_subscriber::layer::SubscriberExt;
+
+        /// Counts the records the manager re-emits
+        struct Count(Arc<AtomicUsize>);
+
+        impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for Count {
+            fn on_event(
+                &self,
+                event: &tracing::Event<'_>,
+                _: tracing_subscriber::layer::Context<'_, S>,
+            ) {
+                if event.metadata().target() == "subzone::log_forward" {
+                    self.0.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let (layer, forwarder) = log_forward_layer();
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            for i in 0..100 {
+                tracing::info!(target: "app", i, "queued");
+            }
+        });
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::registry().with(Count(Arc::clone(&count))),
+        );
+        // Current-thread, so the manager's re-emitted events hit the default subscriber
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            client.forward_logs(forwarder);
+            let closing = tokio::spawn(client.close_within(Duration::from_secs(5)));
+            // `next` handles every log frame itself and returns when the worker disconnects
+            let result = server.next().await;
+            assert!(matches!(result, Err(Error::Disconnected(_))), "{result:?}");
+            assert_eq!(closing.await??, 0);
+            Ok::<_, anyhow::Error>(())
+        })?;
+        assert_eq!(count.load(Ordering::Relaxed), 100);
+        Ok(())
+    }
+
+    /// A manager that stops reading can't hold a closed worker's pipe open
+    #[cfg(feature = "subscriber")]
+    #[test]
+    fn close_within_gives_up_on_flush() -> Result<()> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (layer, forwarder) = log_forward_layer();
+        let padding = "x".repeat(1024);
+        tracing::subscriber::with_default(tracing_subscriber::registry().with(layer), || {
+            for i in 0..1000 {
+                tracing::info!(target: "app", i, padding, "queued");
+            }
+        });
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            client.forward_logs(forwarder);
+            // The manager isn't reading, so the records can't all fit in the pipe
+            let discarded = LatencyBudget::new("Client::close_within", Duration::from_millis(500))
+                .time(client.close_within(Duration::from_millis(100)))
+                .await??;
+            assert!(discarded > 0);
+            // Once the manager takes what's in the pipe, it sees the worker is gone
+            loop {
+                match tokio::time::timeout(Duration::from_secs(5), server.next()).await? {
+                    Err(Error::Disconnected(_)) => break,
+                    Err(error) => return Err(error.into()),
+                    Ok(_) => {}
+                }
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[cfg(feature = "subscriber")]
+    #[test]
+    fn log_forward_layer_records_events() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let (layer, mut forwarder) = log_forward_layer();
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let _span = tracing::info_span!("outer").entered();
+            tracing::warn!(target: "app", answer = 42, "hello");
+            // subzone's own events stay local
+            tracing::warn!("not forwarded");
+        });
+
+        let record = forwarder.rx.try_recv().unwrap();
+        assert_eq!(record.level, "WARN");
+        assert_eq!(record.target, "app");
+        assert_eq!(record.spans, vec!["outer".to_string()]);
+        assert_eq!(record.message, "hello");
+        assert_eq!(record.fields.get("answer").map(String::as_str), Some("42"));
+        assert!(forwarder.rx.try_recv().is_err());
+        assert_eq!(forwarder.dropped(), 0);
+    }
+
+    #[test]
+    fn sequence_gap() -> Result<()> {
+        use crate::frame::{FrameReader, FrameWriter};
+        use tokio::io::AsyncWriteExt;
+
+        fn raw_frame(seq: u64, body: &str) -> Vec<u8> {
+            let mut buf = u32::try_from(body.len()).unwrap().to_le_bytes().to_vec();
+            buf.extend_from_slice(&seq.to_le_bytes());
+            buf.extend_from_slice(body.as_bytes());
+            buf
+        }
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (writer, reader) = tokio::io::duplex(1024);
+            let mut writer = FrameWriter::new(writer);
+            let mut reader = FrameReader::new(reader);
+
+            writer.write(&"zero").await?;
+            assert_eq!(reader.read().await?, br#""zero""#);
+
+            // Frame 1 is lost
+            let mut writer = writer.into_inner();
+            writer.write_all(&raw_frame(2, "two")).await?;
+            writer.write_all(&raw_frame(3, "three")).await?;
+            assert!(matches!(
+                reader.read().await,
+                Err(Error::SequenceGap {
+                    expected: 1,
+                    got: 2
+                })
+            ));
+            // The out-of-sequence frame isn't dropped
+            assert_eq!(reader.read().await?, b"two");
+            assert_eq!(reader.read().await?, b"three");
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn chaos_layer() -> Result<()> {
+        use crate::chaos::{ChaosConfig, ChaosLayer, Fault, FaultKind};
+        use crate::frame::{FrameReader, FrameWriter};
+
+        /// Sends `count` frames through a `ChaosLayer` and returns what the reader saw
+        async fn run(
+            config: ChaosConfig,
+            count: u64,
+        ) -> Result<(Vec<Fault>, Vec<Result<u64, Error>>)> {
+            let (tx, rx) = tokio::io::duplex(1024);
+            let (tx, relay) = ChaosLayer::wrap(tx, config);
+            let mut writer = FrameWriter::new(tx);
+            let mut reader = FrameReader::new(rx);
+            let read_all = async move {
+                let mut seen = vec![];
+                loop {
+                    match reader.read().await {
+                        Ok(buf) => seen.push(Ok(serde_json::from_slice(&buf)?)),
+                        Err(Error::Disconnected(_)) => break,
+                        Err(error) => seen.push(Err(error)),
+                    }
+                }
+                Ok::<_, anyhow::Error>(seen)
+            };
+            let write_all = async move {
+                for i in 0..count {
+                    // The relay stops early after a truncation
+                    if writer.write(&i).await.is_err() {
+                        break;
+                    }
+                }
+                writer.shutdown().await.ok();
+            };
+            let (seen, ()) = tokio::join!(read_all, write_all);
+            Ok((relay.await??, seen?))
+        }
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            // The same seed injects the same faults
+            let config = ChaosConfig {
+                seed: 42,
+                drop: 50,
+                duplicate: 50,
+                delay: 100,
+                delay_by: Duration::from_millis(1),
+                ..Default::default()
+            };
+            let (faults, _) = run(config.clone(), 200).await?;
+            assert!(!faults.is_empty());
+            assert_eq!(run(config, 200).await?.0, faults);
+
+            // Every run of dropped frames is reported as exactly one gap
+            let config = ChaosConfig {
+                seed: 7,
+                drop: 100,
+                ..Default::default()
+            };
+            let (faults, seen) = run(config, 200).await?;
+            let dropped: Vec<_> = faults.iter().map(|fault| fault.seq).collect();
+            let gaps = seen.iter().filter(|item| item.is_err()).count();
+            let expected_gaps = dropped
+                .iter()
+                .filter(|seq| **seq + 1 < 200 && !dropped.contains(&(**seq + 1)))
+                .count();
+            assert_eq!(gaps, expected_gaps);
+            let received = seen.iter().filter(|item| item.is_ok()).count();
+            assert_eq!(received + dropped.len(), 200);
+
+            // A duplicate is a gap, and then the frame again
+            let config = ChaosConfig {
+                seed: 1,
+                duplicate: 1000,
+                ..Default::default()
+            };
+            let (_, seen) = run(config, 2).await?;
+            assert!(matches!(
+                seen[..],
+                [
+                    Ok(0),
+                    Err(Error::SequenceGap {
+                        expected: 1,
+                        got: 0
+                    }),
+                    Ok(0),
+                    Ok(1),
+                    ..
+                ]
+            ));
+
+            // A truncated frame disconnects instead of hanging
+            let config = ChaosConfig {
+                seed: 1,
+                truncate: 1000,
+                ..Default::default()
+            };
+            let (faults, seen) =
+                tokio::time::timeout(Duration::from_secs(5), run(config, 10)).await??;
+            assert_eq!(
+                faults,
+                vec![Fault {
+                    seq: 0,
+                    kind: FaultKind::Truncate
+                }]
+            );
+            assert!(seen.is_empty());
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    mod framing_props {
+        use crate::frame::{FrameReader, FrameWriter, MAX_FRAME_LEN};
+        use crate::{clock::Transit, ClockOffset, ClockSample};
+        use crate::{
+            Client, Error, Listener, ManagerMsgInternal, PipeMode, Server, WorkerMsgInternal,
+        };
+        use proptest::prelude::*;
+        use serde_json::Value;
+        use std::{
+            pin::Pin,
+            task::{Context, Poll},
+        };
+        use tokio::io::{AsyncRead, ReadBuf};
+
+        /// Any JSON, which covers every shape a serde-derived message can take
+        fn arb_json() -> impl Strategy<Value = Value> {
+            let leaf = prop_oneof![
+                Just(Value::Null),
+                any::<bool>().prop_map(Value::from),
+                any::<i64>().prop_map(Value::from),
+                any::<u64>().prop_map(Value::from),
+                ".*".prop_map(Value::from),
+            ];
+            leaf.prop_recursive(4, 64, 8, |inner| {
+                prop_oneof![
+                    prop::collection::vec(inner.clone(), 0..8).prop_map(Value::from),
+                    prop::collection::btree_map(".*", inner, 0..8)
+                        .prop_map(|map| Value::Object(map.into_iter().collect())),
+                ]
+            })
+        }
+
+        /// Hands out `data` in reads of the given sizes, cycling through them
+        struct ChunkedReader {
+            data: Vec<u8>,
+            pos: usize,
+            chunks: Vec<usize>,
+            reads: usize,
+        }
+
+        impl AsyncRead for ChunkedReader {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<std::io::Result<()>> {
+                let this = &mut *self;
+                let chunk = this.chunks[this.reads % this.chunks.len()];
+                this.reads += 1;
+                let end = (this.pos + chunk.min(buf.remaining())).min(this.data.len());
+                buf.put_slice(&this.data[this.pos..end]);
+                this.pos = end;
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        fn block_on<F: std::future::Future>(f: F) -> F::Output {
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap()
+                .block_on(f)
+        }
+
+        /// Reads frames until the reader gives up, decoding each body as either side's message
+        ///
+        /// Whatever the bytes are, this must return instead of panicking. With
+        /// `offset`, stamped frames' transit times are recorded too.
+        async fn read_untrusted(data: Vec<u8>, chunks: Vec<usize>, offset: Option<ClockOffset>) {
+            let reader = ChunkedReader {
+                data,
+                pos: 0,
+                chunks,
+                reads: 0,
+            };
+            let transit = Transit::default();
+            if let Some(offset) = offset {
+                transit.set_offset(offset);
+            }
+            let mut reader = FrameReader::with_max_len(reader, 1024)
+                .max_message_len(4096)
+                .transit(transit);
+            // Bounded, since a gap is followed by the frame itself
+            for _ in 0..1024 {
+                match reader.read().await {
+                    Ok(buf) => {
+                        serde_json::from_slice::<ManagerMsgInternal<Value>>(&buf).ok();
+                        serde_json::from_slice::<WorkerMsgInternal<Value>>(&buf).ok();
+                    }
+                    Err(Error::SequenceGap { .. }) => {}
+                    Err(_) => break,
+                }
+            }
+        }
+
+        /// Well-formed headers with any flags and small sequence numbers, around any bodies
+        fn arb_frames() -> impl Strategy<Value = Vec<u8>> {
+            let seq = (0u64..4, 0u64..32).prop_map(|(flags, seq)| flags << 62 | seq);
+            let body = prop::collection::vec(any::<u8>(), 0..64);
+            prop::collection::vec((seq, body), 0..16).prop_map(|frames| {
+                let mut data = Vec::new();
+                for (seq, body) in frames {
+                    data.extend_from_slice(&(body.len() as u32).to_le_bytes());
+                    data.extend_from_slice(&seq.to_le_bytes());
+                    data.extend_from_slice(&body);
+                }
+                data
+            })
+        }
+
+        /// Every truncation and some single-byte corruptions of the golden frames
+        #[test]
+        fn mangled_golden_frames_never_panic() {
+            let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/golden");
+            for entry in std::fs::read_dir(dir).unwrap() {
+                let golden = std::fs::read(entry.unwrap().path()).unwrap();
+                block_on(async move {
+                    for len in 0..golden.len() {
+                        read_untrusted(golden[..len].to_vec(), vec![usize::MAX], None).await;
+                    }
+                    for i in 0..golden.len() {
+                        for flip in [0x01, 0x40, 0x80, 0xff] {
+                            let mut mangled = golden.clone();
+                            mangled[i] ^= flip;
+                            read_untrusted(mangled, vec![usize::MAX], None).await;
+                        }
+                    }
+                });
+            }
+        }
+
+        proptest! {
+            #[test]
+            fn untrusted_bytes_never_panic(
+                data in prop::collection::vec(any::<u8>(), 0..512),
+                chunks in prop::collection::vec(1usize..64, 1..16),
+            ) {
+                block_on(read_untrusted(data, chunks, None));
+            }
+
+            #[test]
+            fn untrusted_frames_never_panic(
+                data in arb_frames(),
+                chunks in prop::collection::vec(1usize..64, 1..16),
+            ) {
+                block_on(read_untrusted(data, chunks, None));
+            }
+
+            /// Stamps and offsets at the ends of their ranges can't overflow the transit math
+            #[test]
+            fn stamped_frames_never_panic(
+                data in arb_frames(),
+                offset_us in prop_oneof![Just(i64::MIN), Just(i64::MAX), any::<i64>()],
+                round_trip_us in any::<u64>(),
+            ) {
+                let offset = ClockOffset { offset_us, round_trip_us };
+                block_on(read_untrusted(data, vec![usize::MAX], Some(offset)));
+            }
+
+            #[test]
+            fn clock_estimates_never_panic(
+                cookie_sent_us in any::<u64>(),
+                received_us in any::<u64>(),
+                sent_us in any::<u64>(),
+                accepted_us in any::<u64>(),
+            ) {
+                let sample = ClockSample { received_us, sent_us };
+                let offset = ClockOffset::estimate(cookie_sent_us, sample, accepted_us);
+                if let Some(offset) = offset.reversed().plausible() {
+                    prop_assert!(offset.offset_us.unsigned_abs() <= crate::clock::MAX_CLOCK_OFFSET_US);
+                }
+            }
+
+            #[test]
+            fn round_trip_with_any_read_split(
+                msgs in prop::collection::vec(arb_json(), 1..8),
+                chunks in prop::collection::vec(1usize..64, 1..16),
+            ) {
+                block_on(async move {
+                    let mut writer = FrameWriter::new(Vec::new());
+                    for (id, msg) in msgs.iter().enumerate() {
+                        let id = u64::try_from(id).unwrap();
+                        writer.write(&ManagerMsgInternal::Request { id, msg, traceparent: None }).await.unwrap();
+                    }
+                    let mut reader = FrameReader::new(ChunkedReader {
+                        data: writer.into_inner(),
+                        pos: 0,
+                        chunks,
+                        reads: 0,
+                    });
+                    for (id, msg) in msgs.iter().enumerate() {
+                        let buf = reader.read().await.unwrap();
+                        let decoded: ManagerMsgInternal<Value> = serde_json::from_slice(&buf).unwrap();
+                        let ManagerMsgInternal::Request { id: got_id, msg: got, .. } = decoded else {
+                            panic!("wrong variant");
+                        };
+                        assert_eq!(got_id, u64::try_from(id).unwrap());
+                        assert_eq!(&got, msg);
+                    }
+                    assert!(matches!(reader.read().await, Err(Error::Disconnected(_))));
+                });
+            }
+
+            #[test]
+            fn oversized_length_is_refused(extra in 1u32..u32::MAX - MAX_FRAME_LEN as u32) {
+                let len = MAX_FRAME_LEN as u32 + extra;
+                let mut data = len.to_le_bytes().to_vec();
+                data.extend_from_slice(&0u64.to_le_bytes());
+                block_on(async move {
+                    let mut reader = FrameReader::new(ChunkedReader {
+                        data,
+                        pos: 0,
+                        chunks: vec![usize::MAX],
+                        reads: 0,
+                    });
+                    assert!(matches!(reader.read().await, Err(Error::MessageLength)));
+                });
+            }
+        }
+
+        proptest! {
+            // Each case opens a real pipe
+            #![proptest_config(ProptestConfig::with_cases(16))]
+            #[test]
+            fn round_trip_over_pipes(
+                msgs in prop::collection::vec(arb_json(), 1..4),
+                pipe_mode in prop_oneof![Just(PipeMode::Byte), Just(PipeMode::Message)],
+            ) {
+                block_on(async move {
+                    let mut listener = Listener::with_pipe_mode(pipe_mode).unwrap();
+                    let mut client: Client<Value, Value> =
+                        Client::new_unsecured(listener.pipe_id()).unwrap();
+                    let mut server: Server<Value, Value> = listener.accept().await.unwrap();
+                    for msg in &msgs {
+                        server.send(msg.clone()).await.unwrap();
+                        let ManagerMsgInternal::User(got) = client.next().await.unwrap() else {
+                            panic!("wrong variant");
+                        };
+                        assert_eq!(&got, msg);
+                        client.send(got).await.unwrap();
+                        assert_eq!(&server.next().await.unwrap(), msg);
+                    }
+                });
+            }
+        }
+
+        #[test]
+        fn oversized_write_is_refused() {
+            block_on(async move {
+                let mut writer = FrameWriter::new(Vec::new());
+                // The JSON quotes push it over the limit
+                let result = writer.write(&"x".repeat(MAX_FRAME_LEN - 1)).await;
+                assert!(matches!(result, Err(Error::MessageLength)));
+                // Nothing was written or counted, so the next frame is still frame 0
+                writer.write(&"x".repeat(MAX_FRAME_LEN - 2)).await.unwrap();
+                let data = writer.into_inner();
+                assert_eq!(data.len(), 12 + MAX_FRAME_LEN);
+                assert_eq!(data[4..12], 0u64.to_le_bytes());
+            });
+        }
+
+        #[test]
+        fn fragmented_round_trip() {
+            block_on(async move {
+                let msgs = ["x".repeat(1000), "short".to_string(), "y".repeat(200)];
+                let mut writer = FrameWriter::with_max_len(Vec::new(), 64).max_message_len(4096);
+                writer.write_batch(&msgs).await.unwrap();
+                writer.write(&"z".repeat(5000)).await.unwrap_err();
+                let data = writer.into_inner();
+
+                let mut reader = FrameReader::with_max_len(&data[..], 64).max_message_len(4096);
+                for msg in &msgs {
+                    let buf = reader.read().await.unwrap();
+                    assert_eq!(&serde_json::from_slice::<String>(&buf).unwrap(), msg);
+                }
+                // Without a higher cap, the reader refuses to reassemble it
+                let mut reader = FrameReader::with_max_len(&data[..], 64);
+                assert!(matches!(reader.read().await, Err(Error::MessageLength)));
+            });
+        }
+
+        /// A frame, or a fragment of stream `stream`, as `FrameWriter` would write it
+        fn frame(seq: u64, fragment: Option<(u32, bool)>, body: &[u8]) -> Vec<u8> {
+            let mut payload = Vec::new();
+            let mut seq = seq;
+            if let Some((stream, last)) = fragment {
+                seq |= 1 << 63;
+                payload.extend_from_slice(&stream.to_le_bytes());
+                payload.push(u8::from(last));
+            }
+            payload.extend_from_slice(body);
+            let mut data = (payload.len() as u32).to_le_bytes().to_vec();
+            data.extend_from_slice(&seq.to_le_bytes());
+            data.extend_from_slice(&payload);
+            data
+        }
+
+        #[test]
+        fn interleaved_fragments() {
+            block_on(async move {
+                let data = [
+                    frame(0, Some((1, false)), b"\"aa"),
+                    frame(1, Some((2, false)), b"\"bb"),
+                    frame(2, None, b"\"whole\""),
+                    frame(3, Some((1, false)), b"aa"),
+                    frame(4, Some((2, true)), b"bb\""),
+                    frame(5, Some((1, true)), b"aa\""),
+                ]
+                .concat();
+                let mut reader = FrameReader::with_max_len(&data[..], 64).max_message_len(64);
+                let mut got = Vec::new();
+                for _ in 0..3 {
+                    let buf = reader.read().await.unwrap();
+                    got.push(serde_json::from_slice::<String>(&buf).unwrap());
+                }
+                assert_eq!(got, ["whole", "bbbb", "aaaaaa"]);
+                assert!(matches!(reader.read().await, Err(Error::Disconnected(_))));
+
+                // The cap covers every unfinished stream together
+                let data = [
+                    frame(0, Some((1, false)), &[b'a'; 40]),
+                    frame(1, Some((2, false)), &[b'b'; 40]),
+                ]
+                .concat();
+                let mut reader = FrameReader::with_max_len(&data[..], 64).max_message_len(64);
+                assert!(matches!(reader.read().await, Err(Error::MessageLength)));
+
+                // Empty pieces can't be used to open streams without limit
+                let data = (0..1000)
+                    .map(|i| frame(i, Some((i as u32, false)), b""))
+                    .collect::<Vec<_>>()
+                    .concat();
+                let mut reader = FrameReader::with_max_len(&data[..], 64).max_message_len(64);
+                assert!(matches!(reader.read().await, Err(Error::Protocol)));
+                // Nor can tiny ones, past `MAX_OPEN_STREAMS`
+                let data = (0..1000)
+                    .map(|i| frame(i, Some((i as u32, false)), b"a"))
+                    .collect::<Vec<_>>()
+                    .concat();
+                let mut reader = FrameReader::with_max_len(&data[..], 64).max_message_len(64);
+                assert!(matches!(reader.read().await, Err(Error::Protocol)));
+                // An empty last piece still finishes its stream, and the
+                // running total drops back so the next message fits
+                let data = [
+                    frame(0, Some((1, false)), &[b'a'; 40]),
+                    frame(1, Some((1, true)), b""),
+                    frame(2, Some((2, false)), &[b'b'; 40]),
+                    frame(3, Some((2, true)), b""),
+                ]
+                .concat();
+                let mut reader = FrameReader::with_max_len(&data[..], 64).max_message_len(64);
+                assert_eq!(reader.read().await.unwrap(), [b'a'; 40]);
+                assert_eq!(reader.read().await.unwrap(), [b'b'; 40]);
+
+                // A gap is reported once the message is complete, and nothing is lost
+                let data = [
+                    frame(0, Some((1, false)), b"\"a"),
+                    frame(2, Some((1, true)), b"a\""),
+                ]
+                .concat();
+                let mut reader = FrameReader::with_max_len(&data[..], 64).max_message_len(64);
+                assert!(matches!(
+                    reader.read().await,
+                    Err(Error::SequenceGap {
+                        expected: 1,
+                        got: 2
+                    })
+                ));
+                assert_eq!(reader.read().await.unwrap(), b"\"aa\"");
+            });
+        }
+    }
+
+    fn golden_fingerprint() -> Fingerprint {
+        Fingerprint {
+            crate_version: "0.1.0".into(),
+            app_version: Some("1.2.3".into()),
+            os_build: Some("22631.2861".into()),
+            arch: "x86_64".into(),
+            native_arch: None,
+        }
+    }
+
+    #[test]
+    fn binary_check() -> Result<()> {
+        assert_eq!(
+            sys::sha256(b"abc")?[..4],
+            [0xba, 0x78, 0x16, 0xbf],
+            "should be the FIPS 180-2 test vector"
+        );
+
+        let exe = std::env::current_exe()?;
+        let hash = sys::sha256(&std::fs::read(&exe)?)?;
+        integrity::verify(&exe, &BinaryCheck::Sha256(hash))?;
+
+        assert!(matches!(
+            integrity::verify(&exe, &BinaryCheck::Sha256([0; 32])),
+            Err(Error::UntrustedBinary { .. })
+        ));
+        // Test builds aren't signed
+        assert!(matches!(
+            integrity::verify(
+                &exe,
+                &BinaryCheck::Authenticode(AuthenticodeSigner::Subject("Firezone, Inc.".into()))
+            ),
+            Err(Error::UntrustedBinary { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn worker_exe() -> Result<()> {
+        assert_eq!(
+            integrity::resolve_exe(None)?,
+            std::fs::canonicalize(std::env::current_exe()?)?
+        );
+        assert!(matches!(
+            integrity::resolve_exe(Some(std::path::Path::new("worker.exe"))),
+            Err(Error::UntrustedBinary { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn worker_args() {
+        let pipe_id = PipeId::random();
+        let args = WorkerArgs::from_args(
+            ["worker.exe", "--verbose", &pipe_id.to_arg()]
+                .map(Into::into)
+                .to_vec(),
+        )
+        .expect("should find the pipe ID");
+        assert_eq!(args.pipe_id(), &pipe_id);
+        assert_eq!(args.app_args(), ["worker.exe", "--verbose"]);
+
+        // With `SecretDelivery::Stdin` or `Env`, the last argument is the worker's own
+        assert!(
+            WorkerArgs::from_args(["worker.exe", "--verbose"].map(Into::into).to_vec()).is_none()
+        );
+        assert!(WorkerArgs::from_args(vec![]).is_none());
+    }
+
+    #[test]
+    fn worker_app_args() -> Result<()> {
+        let pipe_id = PipeId::random();
+        let args = WorkerArgs::from_args(
+            ["worker.exe", "--name", "tunnel", &pipe_id.to_arg()]
+                .map(Into::into)
+                .to_vec(),
+        )
+        .expect("should find the pipe ID");
+        #[cfg(feature = "clap")]
+        {
+            #[derive(clap::Parser)]
+            struct WorkerCli {
+                #[arg(long)]
+                name: String,
+            }
+
+            assert_eq!(args.parse::<WorkerCli>()?.name, "tunnel");
+        }
+        assert_eq!(args.app_args().len(), 3);
+
+        // An app argument can't pose as the pipe ID
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let mut leak_guard = LeakGuard::new()?;
+            let result = SubprocessBuilder::new(&["api-worker"])
+                .args(["--name", &pipe_id.to_arg()])
+                .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard)
+                .await;
+            assert!(matches!(
+                result
+                    .err()
+                    .and_then(|error| error.downcast::<Error>().ok()),
+                Some(Error::ReservedArgument)
+            ));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn leak_guard_accounting() -> Result<()> {
+        let name = format!(r"Local\subzone-test-{}", uuid::Uuid::new_v4());
+        let leak_guard = LeakGuard::with_name(&name)?;
+        assert!(
+            LeakGuard::with_name(&name).is_err(),
+            "shouldn't open a job someone else created"
+        );
+        let accounting = leak_guard.accounting()?;
+        assert_eq!(accounting.name.as_deref(), Some(name.as_str()));
+        assert_eq!(accounting.total_processes, 0);
+        assert_eq!(accounting.kills, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn leak_guard_verify() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            LeakGuard::new()?.verify().await?;
+            LeakGuard::with_options(LeakGuardOptions::new().kill_on_close(false))?
+                .verify()
+                .await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn fingerprint_mismatches() {
+        let ours = golden_fingerprint();
+        assert!(ours.mismatches(&ours.clone()).is_empty());
+        let theirs = Fingerprint {
+            app_version: None,
+            arch: "x86".into(),
+            native_arch: Some("x86_64".into()),
+            ..ours.clone()
+        };
+        assert_eq!(ours.mismatches(&theirs), ["app_version", "arch"]);
+        assert_eq!(ours.pointer_width(), Some(64));
+        assert_eq!(theirs.pointer_width(), Some(32));
+
+        let current = Fingerprint::current();
+        assert_eq!(current.pointer_width(), Some(usize::BITS));
+        assert_ne!(current.native_arch.as_ref(), Some(&current.arch));
+    }
+
+    /// Frames from the current code must match the ones already-deployed workers speak
+    ///
+    /// Never regenerate `testdata/golden` to make this pass. If the wire format has to
+    /// change, bump `PROTOCOL_VERSION` and add new golden files next to the old ones.
+    #[test]
+    fn golden_frames() -> Result<()> {
+        use crate::frame::{FrameReader, FrameWriter};
+
+        async fn check<T: serde::de::DeserializeOwned + Serialize>(
+            name: &str,
+            msg: T,
+        ) -> Result<()> {
+            let path = format!("{}/testdata/golden/{name}.bin", env!("CARGO_MANIFEST_DIR"));
+            let golden = std::fs::read(&path).with_context(|| format!("reading {path}"))?;
+
+            let mut writer = FrameWriter::new(Vec::new());
+            writer.write(&msg).await?;
+            assert_eq!(writer.into_inner(), golden, "encoding of {name} changed");
+
+            let buf = FrameReader::new(golden.as_slice()).read().await?;
+            let decoded: T =
+                serde_json::from_slice(&buf).with_context(|| format!("decoding {name}"))?;
+            assert_eq!(
+                serde_json::to_value(decoded)?,
+                serde_json::to_value(msg)?,
+                "decoding of {name} changed"
+            );
+            Ok(())
+        }
+
+        type M = ManagerMsgInternal<String>;
+        type W = WorkerMsgInternal<String>;
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            check(
+                "manager_accepted",
+                // Without a fingerprint the frame is unchanged
+                M::Accepted {
+                    resume_state: None,
+                    fingerprint: None,
+                    clock: None,
+                },
+            )
+            .await?;
+            check(
+                "manager_accepted_resume",
+                M::Accepted {
+                    resume_state: Some(vec![1, 2, 3]),
+                    fingerprint: None,
+                    clock: None,
+                },
+            )
+            .await?;
+            check(
+                "manager_accepted_fingerprint",
+                M::Accepted {
+                    resume_state: None,
+                    fingerprint: Some(golden_fingerprint()),
+                    clock: None,
+                },
+            )
+            .await?;
+            check(
+                "manager_accepted_clock",
+                M::Accepted {
+                    resume_state: None,
+                    fingerprint: None,
+                    clock: Some(ClockSample {
+                        received_us: 1000,
+                        sent_us: 1250,
+                    }),
+                },
+            )
+            .await?;
+            check("manager_shutdown", M::Shutdown).await?;
+            check(
+                "manager_meta",
+                M::User("hello".into()).with_metadata(
+                    &Metadata::new()
+                        .with(Metadata::TENANT, "acme")
+                        .with(Metadata::SESSION, "42"),
+                ),
+            )
+            .await?;
+            check("manager_user", M::User("hello".into())).await?;
+            check("manager_proof", M::Proof("0123456789abcdef".into())).await?;
+            check(
+                "manager_request",
+                // Without a trace context the frame is unchanged
+                M::Request {
+                    id: 7,
+                    msg: "hello".into(),
+                    traceparent: None,
+                },
+            )
+            .await?;
+            check("manager_extension", M::Extension(3, vec![1, 2])).await?;
+            check("manager_set_log_filter", M::SetLogFilter("debug".into())).await?;
+            check("manager_power", M::Power(PowerEvent::Suspend)).await?;
+            check(
+                "manager_network_changed",
+                M::NetworkChanged(NetworkChange {
+                    interface_index: 5,
+                    kind: NetworkChangeKind::Added,
+                }),
+            )
+            .await?;
+
+            check("worker_cookie", W::Cookie("0123456789abcdef".into())).await?;
+            check("worker_fingerprint", W::Fingerprint(golden_fingerprint())).await?;
+            check(
+                "worker_clock_offset",
+                W::ClockOffset(ClockOffset {
+                    offset_us: -42,
+                    round_trip_us: 300,
+                }),
+            )
+            .await?;
+            check("worker_user", W::User("hello".into())).await?;
+            check(
+                "worker_hello",
+                W::Hello {
+                    nonce: "0123456789abcdef".into(),
+                },
+            )
+            .await?;
+            check(
+                "worker_meta",
+                W::User("hello".into())
+                    .with_metadata(&Metadata::new().with(Metadata::REQUEST_ID, "7")),
+            )
+            .await?;
+            check(
+                "worker_failed",
+                W::Failed {
+                    id: 7,
+                    error: RemoteError::new("no such resource")
+                        .with_code("not_found")
+                        .with_details(&"hello")?,
+                },
+            )
+            .await?;
+            check(
+                "worker_handler_timeout",
+                W::HandlerTimeout {
+                    id: 7,
+                    method: "Connect".into(),
+                    timeout_ms: 5000,
+                },
+            )
+            .await?;
+            check(
+                "worker_response",
+                W::Response {
+                    id: 7,
+                    msg: "hello".into(),
+                },
+            )
+            .await?;
+            check("worker_extension", W::Extension(3, vec![1, 2])).await?;
+            check("worker_set_log_filter", W::SetLogFilter("debug".into())).await?;
+            check("worker_subscribe", W::Subscribe("resources".into())).await?;
+            check("worker_unsubscribe", W::Unsubscribe("resources".into())).await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn manager_routes_by_role() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let mut manager = Manager::<ManagerMsg, WorkerMsg>::with_router(|_| "dns");
+            let mut clients = vec![];
+            for role in ["tunnel", "dns"] {
+                let (server, server_id) = UnconnectedServer::new()?;
+                let client: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(&server_id)?;
+                manager.add_server(role, server.accept().await?)?;
+                clients.push(client);
+            }
+            let mut dns = clients.pop().unwrap();
+            let mut tunnel = clients.pop().unwrap();
+
+            manager.send("tunnel", ManagerMsg::Connect).await?;
+            assert!(matches!(
+                tunnel.next().await?,
+                ManagerMsgInternal::User(ManagerMsg::Connect)
+            ));
+            manager.route(ManagerMsg::Connect).await?;
+            assert!(matches!(
+                dns.next().await?,
+                ManagerMsgInternal::User(ManagerMsg::Connect)
+            ));
+            assert!(matches!(
+                manager.send("gui-bridge", ManagerMsg::Connect).await,
+                Err(Error::UnknownRole(_))
+            ));
+
+            dns.send(WorkerMsg::Callback(Callback::TunnelReady)).await?;
+            let event = manager.next().await;
+            assert_eq!(event.role, "dns");
+            assert_eq!(event.msg?, WorkerMsg::Callback(Callback::TunnelReady));
+
+            tunnel.close().await?;
+            let event = manager.next().await;
+            assert_eq!(event.role, "tunnel");
+            assert!(matches!(event.msg, Err(Error::Disconnected(_))));
+
+            dns.close().await?;
+            manager.close(Duration::from_secs(1)).await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn manager_broadcast() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let mut manager = Manager::<ManagerMsg, WorkerMsg>::new();
+            let mut clients = vec![];
+            for role in ["session-1", "session-2"] {
+                let (server, client) = connected_pair().await?;
+                manager.add_server(role, server)?;
+                clients.push(client);
+            }
+            manager.set_broadcast_filter("session-2", |_| false)?;
+
+            let report = manager.broadcast(ManagerMsg::Connect).await;
+            assert_eq!(report.delivered, vec!["session-1".to_string()]);
+            assert_eq!(report.filtered, vec!["session-2".to_string()]);
+            assert!(report.failed.is_empty());
+            assert!(matches!(
+                clients[0].next().await?,
+                ManagerMsgInternal::User(ManagerMsg::Connect)
+            ));
+
+            for client in clients {
+                client.close().await?;
+            }
+            manager.close(Duration::from_secs(1)).await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn pub_sub_topics() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            client.subscribe("config").await?;
+            client.subscribe("dns").await?;
+            client.unsubscribe("dns").await?;
+            // `next` applies the subscriptions on its way to the user message
+            client
+                .send(WorkerMsg::Callback(Callback::TunnelReady))
+                .await?;
+            server.next().await?;
+            assert_eq!(
+                server.subscriptions().into_iter().collect::<Vec<_>>(),
+                vec!["config".to_string()]
+            );
+
+            assert!(!server.publish("dns", ManagerMsg::Connect).await?);
+            assert!(server.publish("config", ManagerMsg::Connect).await?);
+            assert!(matches!(
+                client.next().await?,
+                ManagerMsgInternal::User(ManagerMsg::Connect)
+            ));
+
+            let mut manager = Manager::<ManagerMsg, WorkerMsg>::new();
+            manager.add_server("tunnel", server)?;
+            let (other_server, other_client) = connected_pair().await?;
+            manager.add_server("gui-bridge", other_server)?;
+            let report = manager.publish("config", ManagerMsg::Connect).await;
+            assert_eq!(report.delivered, vec!["tunnel".to_string()]);
+            assert_eq!(report.filtered, vec!["gui-bridge".to_string()]);
+            assert!(matches!(
+                client.next().await?,
+                ManagerMsgInternal::User(ManagerMsg::Connect)
+            ));
+
+            client.close().await?;
+            other_client.close().await?;
+            manager.close(Duration::from_secs(1)).await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    async fn next_callback<M, W>(callbacks: &mut Callbacks<'_, M, W>) -> Option<Result<W, Error>>
+    where
+        M: Serialize + Send,
+        W: serde::de::DeserializeOwned + Send,
+    {
+        use futures_core::Stream as _;
+        std::future::poll_fn(|cx| std::pin::Pin::new(&mut *callbacks).poll_next(cx)).await
+    }
+
+    /// A callback that arrives before a call's response isn't mistaken for it
+    #[test]
+    fn callbacks_stream() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            let mut response = server.call(ManagerMsg::Connect).await?;
+            let ManagerMsgInternal::Request { id, msg, .. } = client.next().await? else {
+                panic!("expected a request");
+            };
+            client
+                .send(WorkerMsg::Callback(Callback::TunnelReady))
+                .await?;
+            client.respond(id, WorkerMsg::Response(msg)).await?;
+
+            let mut callbacks = server.callbacks();
+            let mut received = vec![];
+            let response = loop {
+                tokio::select! {
+                    response = &mut response => break response?,
+                    callback = next_callback(&mut callbacks) => {
+                        received.push(callback.expect("stream ended early")?);
+                    }
+                }
+            };
+            assert_eq!(received, vec![WorkerMsg::Callback(Callback::TunnelReady)]);
+            assert_eq!(response, WorkerMsg::Response(ManagerMsg::Connect));
+
+            client.close().await?;
+            assert!(matches!(
+                next_callback(&mut callbacks).await,
+                Some(Err(Error::Disconnected(_)))
+            ));
+            assert!(next_callback(&mut callbacks).await.is_none());
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Messages with the same key keep their order, other keys go around them
+    #[test]
+    fn serve_ordered_by_key() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, client) = connected_pair().await?;
+            let options = ServeOptions::new().ordered_by(|msg: &ManagerMsg| match msg {
+                ManagerMsg::Echo(text) => text.split(':').next().unwrap_or_default().to_string(),
+                ManagerMsg::Connect | ManagerMsg::ReadFile(_) => "connect".to_string(),
+            });
+            let worker = tokio::spawn(serve(client, options, |msg: ManagerMsg| async move {
+                if matches!(&msg, ManagerMsg::Echo(text) if text.ends_with("slow")) {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                }
+                WorkerMsg::Response(msg)
+            }));
+
+            for text in ["a:slow", "a:fast", "b:fast"] {
+                server.send(ManagerMsg::Echo(text.to_string())).await?;
+            }
+            let call = server.call(ManagerMsg::Connect).await?;
+            let mut received = vec![];
+            for _ in 0..3 {
+                let WorkerMsg::Response(ManagerMsg::Echo(text)) = server.next().await? else {
+                    panic!("expected an echo");
+                };
+                received.push(text);
+            }
+            assert_eq!(received, ["b:fast", "a:slow", "a:fast"]);
+            assert_eq!(call.await?, WorkerMsg::Response(ManagerMsg::Connect));
+
+            server.close().await?;
+            worker.await??;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Handlers run concurrently up to the limit, and a slow one doesn't block the driver
+    #[test]
+    fn serve_concurrency() -> Result<()> {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, client) = connected_pair().await?;
+            let running = Arc::new(AtomicUsize::new(0));
+            let most_running = Arc::new(AtomicUsize::new(0));
+            let handler = {
+                let running = Arc::clone(&running);
+                let most_running = Arc::clone(&most_running);
+                move |msg: ManagerMsg| {
+                    let running = Arc::clone(&running);
+                    let most_running = Arc::clone(&most_running);
+                    async move {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_running.fetch_max(now, Ordering::SeqCst);
+                        if matches!(&msg, ManagerMsg::Echo(text) if text.ends_with("slow")) {
+                            tokio::time::sleep(Duration::from_millis(200)).await;
+                        }
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        WorkerMsg::Response(msg)
+                    }
+                }
+            };
+            let options = ServeOptions::new().concurrency(2);
+            let worker = tokio::spawn(serve(client, options, handler));
+
+            for text in ["1:slow", "2:slow", "3:slow", "4:fast"] {
+                server.send(ManagerMsg::Echo(text.to_string())).await?;
+            }
+            // Answered by the driver while the handlers are busy
+            let health = server.health().await?;
+            let mut received = vec![];
+            tokio::select! {
+                health = health => assert!(health?.rtt < Duration::from_millis(200)),
+                msg = server.next() => panic!("handler finished before the health probe: {msg:?}"),
+            }
+            for _ in 0..4 {
+                let WorkerMsg::Response(ManagerMsg::Echo(text)) = server.next().await? else {
+                    panic!("expected an echo");
+                };
+                received.push(text);
+            }
+            assert_eq!(received.len(), 4);
+            assert_eq!(most_running.load(Ordering::SeqCst), 2);
+
+            server.close().await?;
+            worker.await??;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A stuck handler fails the call instead of leaving the manager waiting
+    #[test]
+    fn serve_handler_timeout() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, client) = connected_pair().await?;
+            let options = ServeOptions::new().timeout("Connect", Duration::from_millis(50));
+            let worker = tokio::spawn(serve(client, options, |msg: ManagerMsg| async move {
+                if msg == ManagerMsg::Connect {
+                    tokio::time::sleep(Duration::from_secs(60)).await;
+                }
+                WorkerMsg::Response(msg)
+            }));
+
+            let mut connect = server.call(ManagerMsg::Connect).await?;
+            let mut echo = server.call(ManagerMsg::Echo("hi".into())).await?;
+            let result = tokio::select! {
+                result = &mut connect => result,
+                msg = server.next() => panic!("unexpected message {msg:?}"),
+            };
+            let Err(Error::HandlerTimeout { method, timeout }) = result else {
+                panic!("expected a handler timeout, got {result:?}");
+            };
+            assert_eq!(method, "Connect");
+            assert_eq!(timeout, Duration::from_millis(50));
+            let echoed = tokio::select! {
+                result = &mut echo => result?,
+                msg = server.next() => panic!("unexpected message {msg:?}"),
+            };
+            assert_eq!(echoed, WorkerMsg::Response(ManagerMsg::Echo("hi".into())));
+
+            server.close().await?;
+            worker.await??;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A panicking handler fails its call, and the next call still works
+    #[test]
+    fn serve_handler_panic() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, client) = connected_pair().await?;
+            let worker = tokio::spawn(serve(
+                client,
+                ServeOptions::new().concurrency(2),
+                |msg: ManagerMsg| async move {
+                    if msg == ManagerMsg::Connect {
+                        panic!("can't connect");
+                    }
+                    WorkerMsg::Response(msg)
+                },
+            ));
+
+            let mut connect = server.call(ManagerMsg::Connect).await?;
+            let result = tokio::select! {
+                result = &mut connect => result,
+                msg = server.next() => panic!("unexpected message {msg:?}"),
+            };
+            let Err(Error::Remote(error)) = result else {
+                panic!("expected a remote error, got {result:?}");
+            };
+            assert_eq!(error.code.as_deref(), Some(HANDLER_PANIC_CODE));
+            assert_eq!(error.message, "handler panicked: can't connect");
+
+            let mut echo = server.call(ManagerMsg::Echo("hi".into())).await?;
+            let echoed = tokio::select! {
+                result = &mut echo => result?,
+                msg = server.next() => panic!("unexpected message {msg:?}"),
+            };
+            assert_eq!(echoed, WorkerMsg::Response(ManagerMsg::Echo("hi".into())));
+
+            server.close().await?;
+            worker.await??;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A panic in the reader task fails the connection instead of looking like a close
+    #[test]
+    fn reader_task_panic() -> Result<()> {
+        struct PanickingReader;
+
+        impl tokio::io::AsyncRead for PanickingReader {
+            fn poll_read(
+                self: std::pin::Pin<&mut Self>,
+                _: &mut std::task::Context<'_>,
+                _: &mut tokio::io::ReadBuf<'_>,
+            ) -> std::task::Poll<std::io::Result<()>> {
+                panic!("reader blew up")
+            }
+        }
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (read_tx, mut read_rx) = tokio::sync::mpsc::channel(1);
+            tokio::spawn(frame::reader_task(
+                frame::FrameReader::with_max_len(PanickingReader, 1024),
+                read_tx,
+                None,
+            ));
+            let mut disconnect = None;
+            let result = frame::recv_frame(&mut read_rx, &mut disconnect).await;
+            let Err(Error::Internal(message)) = result else {
+                panic!("expected an internal error, got {result:?}");
+            };
+            assert_eq!(message, "reader blew up");
+            let result = frame::recv_frame(&mut read_rx, &mut disconnect).await;
+            assert!(matches!(
+                result,
+                Err(Error::Disconnected(DisconnectReason::Internal(_)))
+            ));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A handler error arrives with its code, context chain, and typed details
+    #[test]
+    fn remote_errors() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            let mut response = server.call(ManagerMsg::Connect).await?;
+            let ManagerMsgInternal::Request { id, .. } = client.next().await? else {
+                panic!("expected a request");
+            };
+            let error = std::fs::read("does-not-exist")
+                .context("couldn't read the config")
+                .unwrap_err();
+            let error = RemoteError::from(&error)
+                .with_code("config")
+                .with_details(&ManagerMsg::Echo("details".into()))?;
+            client.respond_error(id, error).await?;
+
+            let result = tokio::select! {
+                result = &mut response => result,
+                msg = server.next() => panic!("unexpected message {msg:?}"),
+            };
+            let Err(Error::Remote(error)) = result else {
+                panic!("expected a remote error, got {result:?}");
+            };
+            assert_eq!(error.code.as_deref(), Some("config"));
+            assert_eq!(error.message, "couldn't read the config");
+            assert_eq!(error.chain.len(), 1);
+            assert_eq!(
+                error.details::<ManagerMsg>(),
+                Some(ManagerMsg::Echo("details".into()))
+            );
+            assert_eq!(error.details::<u32>(), None);
+
+            let io = std::io::Error::other("disk on fire");
+            let error = RemoteError::from_error(&io);
+            assert_eq!(error.message, "disk on fire");
+            assert!(error.chain.is_empty());
+
+            client.close().await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Headers reach the other side without touching the app's message types
+    #[test]
+    fn metadata() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            let metadata = Metadata::new().with(Metadata::SESSION, "42");
+
+            server.send_with(ManagerMsg::Connect, &metadata).await?;
+            assert!(matches!(
+                client.next().await?,
+                ManagerMsgInternal::User(ManagerMsg::Connect)
+            ));
+            assert_eq!(client.metadata(), Some(&metadata));
+
+            server.send(ManagerMsg::Connect).await?;
+            client.next().await?;
+            assert_eq!(client.metadata(), None);
+
+            let call = server
+                .call_with(
+                    ManagerMsg::Connect,
+                    &Metadata::new().with(Metadata::TENANT, "acme"),
+                )
+                .await?;
+            let ManagerMsgInternal::Request { id, .. } = client.next().await? else {
+                panic!("expected a request");
+            };
+            assert_eq!(
+                client.metadata().and_then(|m| m.get(Metadata::TENANT)),
+                Some("acme")
+            );
+            client
+                .respond(id, WorkerMsg::Response(ManagerMsg::Connect))
+                .await?;
+
+            client
+                .send_with(WorkerMsg::Callback(Callback::TunnelReady), &metadata)
+                .await?;
+            assert_eq!(
+                server.next().await?,
+                WorkerMsg::Callback(Callback::TunnelReady)
+            );
+            assert_eq!(server.metadata(), Some(&metadata));
+            assert_eq!(call.await?, WorkerMsg::Response(ManagerMsg::Connect));
+
+            // Only one layer of headers is allowed
+            let nested = ManagerMsgInternal::Meta(
+                metadata.clone(),
+                Box::new(ManagerMsgInternal::<ManagerMsg>::Shutdown.with_metadata(&metadata)),
+            );
+            assert!(matches!(nested.split_metadata(), Err(Error::Protocol)));
+
+            client.close().await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn calls() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            let response = server.call(ManagerMsg::Connect).await?;
+            let pending = server.pending_calls();
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].id, response.id());
+            assert_eq!(pending[0].method, "Connect");
+
+            let ManagerMsgInternal::Request {
+                id,
+                msg,
+                traceparent,
+            } = client.next().await?
+            else {
+                panic!("expected a request");
+            };
+            // No OpenTelemetry layer is installed, so there's no trace to continue
+            assert_eq!(traceparent, None);
+            assert!(client.request_span(id).is_some());
+            client.respond(id, WorkerMsg::Response(msg)).await?;
+            assert!(client.request_span(id).is_none());
+            // Responses aren't returned by `next`, so give it something that is
+            client
+                .send(WorkerMsg::Callback(Callback::TunnelReady))
+                .await?;
+            assert_eq!(
+                server.next().await?,
+                WorkerMsg::Callback(Callback::TunnelReady)
+            );
+            assert_eq!(response.await?, WorkerMsg::Response(ManagerMsg::Connect));
+            assert!(server.pending_calls().is_empty());
+
+            // The manager's worker task keeps `next` polled, so `call` just works
+            let mut manager = Manager::<ManagerMsg, WorkerMsg>::new();
+            manager.add_server("tunnel", server)?;
+            let worker = tokio::spawn(async move {
+                let ManagerMsgInternal::Request { id, msg, .. } = client.next().await? else {
+                    anyhow::bail!("expected a request");
+                };
+                client.respond(id, WorkerMsg::Response(msg)).await?;
+                Ok(client)
+            });
+            assert_eq!(
+                manager.call("tunnel", ManagerMsg::Connect).await?,
+                WorkerMsg::Response(ManagerMsg::Connect)
+            );
+            assert_eq!(manager.pending_calls("tunnel"), Some(vec![]));
+            assert_eq!(manager.send_queue_depth("tunnel"), Some(0));
+
+            worker.await??.close().await?;
+            manager.close(Duration::from_secs(1)).await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn os_events() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, mut client) = connected_pair().await?;
+            let mut manager = Manager::<ManagerMsg, WorkerMsg>::new();
+            manager.add_server("tunnel", server)?;
+            manager.set_broadcast_filter("tunnel", |_| false)?;
+
+            let report = manager.broadcast_power_event(PowerEvent::Suspend).await;
+            assert_eq!(report.delivered, vec!["tunnel".to_string()]);
+            assert!(matches!(
+                client.next().await?,
+                ManagerMsgInternal::Power(PowerEvent::Suspend)
+            ));
+            let change = NetworkChange {
+                interface_index: 3,
+                kind: NetworkChangeKind::Added,
+            };
+            let report = manager.broadcast_network_change(change).await;
+            assert_eq!(report.delivered, vec!["tunnel".to_string()]);
+            assert!(matches!(
+                client.next().await?,
+                ManagerMsgInternal::NetworkChanged(got) if got == change
+            ));
+
+            client.close().await?;
+            manager.close(Duration::from_secs(1)).await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn single_instance_forwards_args() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let name = uuid::Uuid::new_v4().to_string();
+            let SingleInstance::Primary(mut guard) = single_instance(&name)? else {
+                panic!("nobody else should have this lock");
+            };
+            assert!(matches!(
+                single_instance(&name)?,
+                SingleInstance::AlreadyRunning
+            ));
+
+            for args in [vec!["--open".to_string()], vec![]] {
+                forward_to_running_instance(&name, args.clone()).await?;
+                assert_eq!(guard.next_forwarded().await?, args);
+            }
+            // Oversized args fail on the sender, and an oversized frame on the receiver
+            let huge = vec!["x".repeat(single_instance::MAX_FORWARDED_LEN)];
+            assert!(forward_to_running_instance(&name, huge).await.is_err());
+            {
+                use tokio::io::AsyncWriteExt as _;
+
+                let mut pipe = tokio::net::windows::named_pipe::ClientOptions::new()
+                    .open(PipeId::named(&format!("instance-{name}")).as_str())?;
+                pipe.write_all(&u32::MAX.to_le_bytes()).await?;
+                pipe.write_all(&0u64.to_le_bytes()).await?;
+                assert!(guard.next_forwarded().await.is_err());
+            }
+            // Still locked after accepting connections
+            assert!(matches!(
+                single_instance(&name)?,
+                SingleInstance::AlreadyRunning
+            ));
+
+            drop(guard);
+            assert!(matches!(
+                single_instance(&name)?,
+                SingleInstance::Primary(_)
+            ));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn kill_orphans_skips_other_processes() -> Result<()> {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir)?;
+        let pid_file = WorkerPidFile::new(dir.join("workers.pid"));
+        // A missing file means there's nothing to clean up
+        assert!(pid_file.kill_orphans()?.is_empty());
+
+        // PIDs are multiples of 4, so this can't be a running process
+        assert!(pid_file.record(u32::MAX).is_err());
+        std::fs::write(pid_file.path(), format!("{} 0\n", u32::MAX))?;
+        assert!(pid_file.kill_orphans()?.is_empty());
+        assert_eq!(std::fs::read_to_string(pid_file.path())?, "");
+
+        // We run our own exe, but a manager never kills itself
+        pid_file.record(std::process::id())?;
+        assert!(pid_file.kill_orphans()?.is_empty());
+        // Nor a process that started after the worker with its PID
+        let our_exe = std::env::current_exe()?;
+        assert!(!orphans::kill_if_running(
+            std::process::id(),
+            &our_exe,
+            Some(1)
+        )?);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn registry_discovery() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let registry =
+                Registry::new(std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()));
+            assert!(registry.lookup("subzone-test")?.is_none());
+
+            let mut listener = Listener::new()?;
+            assert!(registry.register("../escape", listener.pipe_id()).is_err());
+            let registration = registry.register("subzone-test", listener.pipe_id())?;
+            let endpoint = registry
+                .lookup("subzone-test")?
+                .context("should find our own endpoint")?;
+            assert_eq!(endpoint.pid, std::process::id());
+
+            let mut client: Client<ManagerMsg, WorkerMsg> = Client::from_endpoint(&endpoint)?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+            client
+                .send(WorkerMsg::Callback(Callback::TunnelReady))
+                .await?;
+            assert_eq!(
+                server.next().await?,
+                WorkerMsg::Callback(Callback::TunnelReady)
+            );
+
+            // A file from a manager that's gone is stale
+            let path = registration.path().to_owned();
+            drop(registration);
+            let stale = Endpoint {
+                pid: u32::MAX,
+                ..endpoint
+            };
+            std::fs::write(&path, serde_json::to_string(&stale)?)?;
+            assert!(registry.lookup("subzone-test")?.is_none());
+            assert!(!path.exists());
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn cli_request() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let registry =
+                Registry::new(std::env::temp_dir().join(uuid::Uuid::new_v4().to_string()));
+            let timeout = Duration::from_secs(5);
+            let result = cli::request::<ManagerMsg, WorkerMsg>(
+                &registry,
+                "subzonectl-test",
+                WorkerMsg::Callback(Callback::TunnelReady),
+                timeout,
+            )
+            .await;
+            assert!(matches!(result, Err(cli::CliError::NotRunning)));
+
+            let mut listener = Listener::new()?;
+            let _registration = registry.register("subzonectl-test", listener.pipe_id())?;
+            let manager = tokio::spawn(async move {
+                let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+                server.next().await?;
+                server.send(ManagerMsg::Connect).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+            let reply: ManagerMsg = cli::request(
+                &registry,
+                "subzonectl-test",
+                WorkerMsg::Callback(Callback::TunnelReady),
+                timeout,
+            )
+            .await?;
+            assert_eq!(reply, ManagerMsg::Connect);
+            manager.await??;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Message mode splits every write into its own message, framing shouldn't notice
+    #[test]
+    fn framing_in_both_pipe_modes() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            for pipe_mode in [PipeMode::Byte, PipeMode::Message] {
+                let mut listener = Listener::with_pipe_mode(pipe_mode)?;
+                let mut client: Client<ManagerMsg, WorkerMsg> =
+                    Client::new_unsecured(listener.pipe_id())?;
+                let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+                assert_eq!(server.connection_info().pipe_mode, pipe_mode);
+                assert_eq!(client.connection_info().pipe_mode, pipe_mode);
+
+                // Bigger than the pipe buffer, so reads can't get a whole message at once
+                let sent = vec![
+                    WorkerMsg::Callback(Callback::TunnelReady),
+                    WorkerMsg::Callback(Callback::Cookie("a".repeat(200_000))),
+                    WorkerMsg::Callback(Callback::TunnelReady),
+                ];
+                let writer = tokio::spawn(async move {
+                    for msg in sent {
+                        client.send(msg).await?;
+                    }
+                    Ok::<_, Error>(client)
+                });
+                assert_eq!(
+                    server.next().await?,
+                    WorkerMsg::Callback(Callback::TunnelReady)
+                );
+                assert!(matches!(
+                    server.next().await?,
+                    WorkerMsg::Callback(Callback::Cookie(cookie)) if cookie.len() == 200_000
+                ));
+                assert_eq!(
+                    server.next().await?,
+                    WorkerMsg::Callback(Callback::TunnelReady)
+                );
+
+                let mut client = writer.await??;
+                server.send(ManagerMsg::Connect).await?;
+                assert!(matches!(
+                    client.next().await?,
+                    ManagerMsgInternal::User(ManagerMsg::Connect)
+                ));
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn sys_process_wrappers() -> Result<()> {
+        use crate::sys::{Process, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_SYNCHRONIZE};
+
+        let us = Process::open(
+            std::process::id(),
+            PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_SYNCHRONIZE,
+        )?;
+        assert!(!us.wait(Duration::ZERO));
+        // `STILL_ACTIVE`
+        assert_eq!(us.exit_code()?, 259);
+        assert!(us
+            .image_name()?
+            .as_os_str()
+            .eq_ignore_ascii_case(std::env::current_exe()?.as_os_str()));
+        // Not opened with `PROCESS_TERMINATE`
+        assert!(us.terminate(1).is_err());
+        Ok(())
+    }
+
+    /// Every wrapper in `sys` closes what it opens
+    #[test]
+    fn sys_handles_dont_leak() -> Result<()> {
+        use crate::sys::{
+            self, JobObject, Process, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_SYNCHRONIZE,
+        };
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            // Warm up the runtime and any lazily opened handles first
+            let (server, client) = connected_pair().await?;
+            drop((server, client));
+            let handles = sys::HandleSnapshot::new()?;
+
+            for _ in 0..100 {
+                let process = Process::open(
+                    std::process::id(),
+                    PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_SYNCHRONIZE,
+                )?;
+                process.wait(Duration::ZERO);
+                process.image_name()?;
+                drop(JobObject::new(
+                    None,
+                    &sys::JobFlags {
+                        kill_on_close: true,
+                        breakaway_ok: false,
+                        silent_breakaway_ok: false,
+                    },
+                )?);
+                let (server, server_id) = UnconnectedServer::new()?;
+                let pipe = tokio::net::windows::named_pipe::ClientOptions::new()
+                    .open(server_id.as_str())?;
+                assert_eq!(sys::named_pipe_server_pid(&pipe)?, std::process::id());
+                assert_eq!(
+                    sys::named_pipe_client_pid(&server.pipe)?,
+                    std::process::id()
+                );
+            }
+
+            // The runtime's threads may open a few handles of their own, but not one per loop
+            handles.check(20)?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Connections, abandoned accepts, and subprocesses give back all their handles
+    #[test]
+    fn no_handle_leaks() -> Result<()> {
+        use crate::sys::HandleSnapshot;
+
+        /// Aborted tasks drop their halves of the pipe on the runtime's threads, a little later
+        async fn settle() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, client) = connected_pair().await?;
+            drop((server, client));
+            let mut leak_guard = LeakGuard::new()?;
+
+            let handles = HandleSnapshot::new()?;
+            for _ in 0..50 {
+                let (mut server, mut client) = connected_pair().await?;
+                server.send(ManagerMsg::Connect).await?;
+                client.next().await?;
+                drop(server);
+                drop(client);
+            }
+            settle().await;
+            handles.check(20)?;
+
+            // Nobody connects, so the accept is cancelled
+            let handles = HandleSnapshot::new()?;
+            for _ in 0..50 {
+                let (server, _server_id) = UnconnectedServer::new()?;
+                let accept = server.accept::<ManagerMsg, WorkerMsg>();
+                assert!(tokio::time::timeout(Duration::from_millis(1), accept)
+                    .await
+                    .is_err());
+            }
+            settle().await;
+            handles.check(20)?;
+
+            // A `Server` dropped while its client is still connected closes its end,
+            // like a `Subprocess` whose handshake is abandoned
+            let handles = HandleSnapshot::new()?;
+            let mut clients = vec![];
+            for _ in 0..50 {
+                let (server, client) = connected_pair().await?;
+                drop(server);
+                clients.push(client);
+            }
+            for client in &mut clients {
+                let result = tokio::time::timeout(Duration::from_secs(5), client.next()).await?;
+                assert!(matches!(result, Err(Error::Disconnected(_))));
+            }
+            drop(clients);
+            settle().await;
+            handles.check(20)?;
+
+            let handles = HandleSnapshot::new()?;
+            for _ in 0..20 {
+                let mut child = tokio::process::Command::new("cmd")
+                    .args(["/C", "exit 0"])
+                    .spawn()?;
+                leak_guard.add_process(&child)?;
+                child.wait().await?;
+            }
+            settle().await;
+            handles.check(20)?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A refused pipe names its owner and doesn't leak the pipe ID
+    #[test]
+    fn permission_denied() -> Result<()> {
+        let path = PipeId::random();
+        let error = Error::PermissionDenied {
+            path: path.clone(),
+            peer_user: Some(r"NT AUTHORITY\SYSTEM".into()),
+            elevated: false,
+        };
+        let message = error.to_string();
+        assert!(message.contains(r"NT AUTHORITY\SYSTEM"), "{message}");
+        assert!(message.contains("elevated"), "{message}");
+        assert!(!message.contains(path.as_str()), "{message}");
+        assert!(!format!("{error:?}").contains(path.as_str()));
+
+        // We own the pipes we create
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let listener = Listener::new()?;
+            let owner = sys::pipe_owner(listener.pipe_id().as_str())?;
+            assert!(!owner.is_empty());
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A listener client can't call methods that need scopes it wasn't granted
+    #[test]
+    fn scopes() -> Result<()> {
+        let ours = PeerIdentity::of_process(std::process::id())?;
+        assert_eq!(ours.pid, std::process::id());
+        assert!(!ours.user.is_empty());
+        assert_eq!(ours.exe.file_name(), std::env::current_exe()?.file_name());
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            for admin in [false, true] {
+                let ours = ours.clone();
+                let options = ServerOptions::new().grant_scopes(move |identity| {
+                    // Read through the pipe, it matches what the process token says
+                    assert_eq!(identity, &ours);
+                    if admin {
+                        vec!["admin".into()]
+                    } else {
+                        Vec::new()
+                    }
+                });
+                let mut listener = Listener::with_options(options)?;
+                let mut client: Client<ManagerMsg, WorkerMsg> =
+                    Client::new_unsecured(listener.pipe_id())?;
+                let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept_scoped().await?;
+                assert_eq!(
+                    server.granted_scopes().map(|s| s.len()),
+                    Some(usize::from(admin))
+                );
+
+                client.send(WorkerMsg::ChildPid(1)).await?;
+                client
+                    .send(WorkerMsg::Callback(Callback::TunnelReady))
+                    .await?;
+                if admin {
+                    assert_eq!(server.next().await?, WorkerMsg::ChildPid(1));
+                } else {
+                    let Err(Error::ScopeDenied { method, scope }) = server.next().await else {
+                        anyhow::bail!("ChildPid needs the admin scope");
+                    };
+                    assert_eq!((method.as_str(), scope.as_str()), ("ChildPid", "admin"));
+                }
+                // Methods that need no scope still work, and the connection is still up
+                assert_eq!(
+                    server.next().await?,
+                    WorkerMsg::Callback(Callback::TunnelReady)
+                );
+            }
+
+            // The check is on the decoded message, so an alias gets no further
+            let options = ServerOptions::new().grant_scopes(|_| Vec::new());
+            let mut listener = Listener::with_options(options)?;
+            let mut client: Client<ManagerMsg, serde_json::Value> =
+                Client::new_unsecured(listener.pipe_id())?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept_scoped().await?;
+            client.send(serde_json::json!({ "Pid": 1 })).await?;
+            assert!(matches!(
+                server.next().await,
+                Err(Error::ScopeDenied { scope, .. }) if scope == "admin"
+            ));
+
+            // Plain `accept` doesn't check anything
+            let mut listener = Listener::new()?;
+            let mut client: Client<ManagerMsg, WorkerMsg> =
+                Client::new_unsecured(listener.pipe_id())?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+            assert!(server.granted_scopes().is_none());
+            client.send(WorkerMsg::ChildPid(1)).await?;
+            assert_eq!(server.next().await?, WorkerMsg::ChildPid(1));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A client over its quota is turned away, or pushes out its oldest connection
+    #[test]
+    fn connection_quotas() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let options = ServerOptions::new().quota(ConnectionQuota::per_user(1));
+            let mut listener = Listener::with_options(options)?;
+            let _first: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(listener.pipe_id())?;
+            let first: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+            let mut second: Client<ManagerMsg, WorkerMsg> =
+                Client::new_unsecured(listener.pipe_id())?;
+            let accept = listener.accept::<ManagerMsg, WorkerMsg>();
+            assert!(tokio::time::timeout(Duration::from_millis(500), accept)
+                .await
+                .is_err());
+            assert!(matches!(second.next().await, Err(Error::Disconnected(_))));
+
+            // Dropping a connection gives its place back
+            drop(first);
+            let _third: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(listener.pipe_id())?;
+            let _third: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+
+            let options = ServerOptions::new()
+                .quota(ConnectionQuota::per_executable(1).eviction(Eviction::EvictOldest));
+            let mut listener = Listener::with_options(options)?;
+            let mut old_client: Client<ManagerMsg, WorkerMsg> =
+                Client::new_unsecured(listener.pipe_id())?;
+            let mut old: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+            let mut new: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(listener.pipe_id())?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+            // The evicted client is cut off without `old` being polled
+            let evicted = tokio::time::timeout(Duration::from_secs(5), old_client.next()).await?;
+            assert!(matches!(evicted, Err(Error::Disconnected(_))));
+            assert!(old_client.send(WorkerMsg::ChildPid(2)).await.is_err());
+            assert!(matches!(
+                old.next().await,
+                Err(Error::Disconnected(DisconnectReason::Evicted))
+            ));
+            new.send(WorkerMsg::ChildPid(1)).await?;
+            assert_eq!(server.next().await?, WorkerMsg::ChildPid(1));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A client whose identity can't be read is turned away instead of sharing a
+    /// quota key, so it can't push out anyone's connection
+    #[test]
+    fn unidentified_client_cant_evict() -> Result<()> {
+        use crate::listener::Admission;
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let options = ServerOptions::new()
+                .quota(ConnectionQuota::per_user(1).eviction(Eviction::EvictOldest));
+            let mut listener = Listener::with_options(options)?;
+            let mut client: Client<ManagerMsg, WorkerMsg> =
+                Client::new_unsecured(listener.pipe_id())?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+
+            let config = Config {
+                pipe_impersonation: ImpersonationLevel::Anonymous,
+                ..Config::default()
+            };
+            let mut anonymous: Client<ManagerMsg, WorkerMsg> =
+                Client::connect(listener.pipe_id(), &config)?;
+            let Admission::Unidentified { pid } =
+                listener.admit_next::<ManagerMsg, WorkerMsg>().await?
+            else {
+                anyhow::bail!("an anonymous client should be turned away");
+            };
+            assert_eq!(pid, std::process::id());
+            assert!(matches!(
+                anonymous.next().await,
+                Err(Error::Disconnected(_))
+            ));
+
+            client.send(WorkerMsg::ChildPid(1)).await?;
+            assert_eq!(server.next().await?, WorkerMsg::ChildPid(1));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// `Listener::run` reports every turn, and cleans up when the token trips
+    #[test]
+    fn accept_loop() -> Result<()> {
+        async fn next_accept(
+            accepts: &mut AcceptLoop<ManagerMsg, WorkerMsg>,
+        ) -> Option<AcceptResult<ManagerMsg, WorkerMsg>> {
+            use futures_core::Stream as _;
+            std::future::poll_fn(|cx| std::pin::Pin::new(&mut *accepts).poll_next(cx)).await
+        }
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let listener = Listener::with_options(
+                ServerOptions::new()
+                    .quota(ConnectionQuota::per_user(1))
+                    .grant_scopes(|_| vec!["admin".into()]),
+            )?;
+            let pipe_id = listener.pipe_id().clone();
+            let shutdown = ShutdownToken::new();
+            let policy = AcceptPolicy::new().idle_timeout(Duration::from_millis(200));
+            let mut accepts = listener.run(policy, shutdown.clone());
+            assert!(matches!(
+                next_accept(&mut accepts).await,
+                Some(AcceptResult::Idle)
+            ));
+
+            let mut client: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(&pipe_id)?;
+            let Some(AcceptResult::Accepted(mut server)) = next_accept(&mut accepts).await else {
+                anyhow::bail!("the first client should be accepted");
+            };
+            assert_eq!(server.granted_scopes().map(|s| s.len()), Some(1));
+            let _over: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(&pipe_id)?;
+            let Some(AcceptResult::Rejected { pid, quota }) = next_accept(&mut accepts).await
+            else {
+                anyhow::bail!("the second client is over the quota");
+            };
+            assert_eq!((pid, quota), (std::process::id(), QuotaKey::User));
+
+            shutdown.trigger();
+            assert!(next_accept(&mut accepts).await.is_none());
+            assert!(next_accept(&mut accepts).await.is_none());
+            // The listener's idle instances are gone, the accepted connection isn't
+            assert!(Client::<ManagerMsg, WorkerMsg>::new_unsecured(&pipe_id).is_err());
+            client.send(WorkerMsg::ChildPid(1)).await?;
+            assert_eq!(server.next().await?, WorkerMsg::ChildPid(1));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A server squatting on the pipe name only gets to identify the worker, unless configured
+    #[test]
+    fn hostile_server_cant_impersonate() -> Result<()> {
+        use tokio::io::AsyncReadExt as _;
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            for level in [
+                ImpersonationLevel::Identification,
+                ImpersonationLevel::Impersonation,
+            ] {
+                let pipe_id = PipeId::random();
+                let mut hostile = tokio::net::windows::named_pipe::ServerOptions::new()
+                    .first_pipe_instance(true)
+                    .create(pipe_id.as_str())?;
+                let config = Config {
+                    pipe_impersonation: level,
+                    ..Config::default()
+                };
+                let mut client: Client<ManagerMsg, WorkerMsg> = Client::connect(&pipe_id, &config)?;
+                hostile.connect().await?;
+                // Windows only lets a server impersonate after it read something
+                client.send(WorkerMsg::ChildPid(1)).await?;
+                hostile.read_exact(&mut [0; 4]).await?;
+                assert_eq!(sys::client_impersonation_level(&hostile)?, level);
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        assert_eq!(
+            Config::default().pipe_impersonation,
+            ImpersonationLevel::Identification
+        );
+        Ok(())
+    }
+
+    /// A squatting server that can't answer `Hello` with the right `Proof` never sees the cookie
+    #[test]
+    fn squatter_never_gets_the_cookie() -> Result<()> {
+        use crate::frame::{FrameReader, FrameWriter};
+
+        let replies = [
+            serde_json::to_value(ManagerMsgInternal::<ManagerMsg>::Proof("wrong".into()))?,
+            serde_json::to_value(ManagerMsgInternal::User(ManagerMsg::Connect))?,
+            serde_json::json!("not a message"),
+        ];
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            for reply in replies {
+                let pipe_id = PipeId::random();
+                let hostile = tokio::net::windows::named_pipe::ServerOptions::new()
+                    .first_pipe_instance(true)
+                    .create(pipe_id.as_str())?;
+                let mut client: Client<ManagerMsg, WorkerMsg> =
+                    Client::connect(&pipe_id, &Config::default())?;
+                hostile.connect().await?;
+                let (reader, writer) = tokio::io::split(hostile);
+                let mut reader = FrameReader::new(reader);
+                let mut writer = FrameWriter::new(writer);
+
+                let handshake = client.handshake("secret".into());
+                let squat = async {
+                    assert!(matches!(
+                        serde_json::from_slice(&reader.read().await?)?,
+                        WorkerMsgInternal::<WorkerMsg>::Hello { .. }
+                    ));
+                    writer.write(&reply).await?;
+                    Ok::<_, anyhow::Error>(())
+                };
+                let (error, squat) = tokio::join!(handshake, squat);
+                squat?;
+                assert!(matches!(
+                    error.unwrap_err().downcast_ref(),
+                    Some(Error::ServerUnverified)
+                ));
+                drop(client);
+                // The pipe closes without another frame, so no `Cookie` was written
+                assert!(reader.read().await.is_err());
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A burst of clients fits in the backlog before anything is accepted
+    #[test]
+    fn listener_backlog() -> Result<()> {
+        use tokio::net::windows::named_pipe::ClientOptions;
+
+        // `ERROR_PIPE_BUSY`
+        const PIPE_BUSY: i32 = 231;
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let listener = Listener::new()?;
+            let _first = ClientOptions::new().open(listener.pipe_id().as_str())?;
+            let error = ClientOptions::new()
+                .open(listener.pipe_id().as_str())
+                .expect_err("the only instance is taken");
+            assert_eq!(error.raw_os_error(), Some(PIPE_BUSY));
+
+            let mut listener = Listener::with_options(ServerOptions::new().backlog(4))?;
+            let mut clients = vec![];
+            for _ in 0..4 {
+                let client: Client<ManagerMsg, WorkerMsg> =
+                    Client::new_unsecured(listener.pipe_id())?;
+                clients.push(client);
+            }
+            // Instances are accepted in any order, but each one reaches exactly one client
+            let mut servers = vec![];
+            for _ in 0..4 {
+                let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+                server.send(ManagerMsg::Connect).await?;
+                servers.push(server);
+            }
+            for client in &mut clients {
+                assert!(matches!(
+                    tokio::time::timeout(Duration::from_secs(5), client.next()).await??,
+                    ManagerMsgInternal::User(ManagerMsg::Connect)
+                ));
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Sending on a dead connection fails with `Closed` instead of an IO error
+    #[test]
+    fn send_after_close() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, client) = connected_pair().await?;
+            client.close().await?;
+            assert!(matches!(server.next().await, Err(Error::Disconnected(_))));
+            assert!(matches!(
+                server.send(ManagerMsg::Connect).await,
+                Err(Error::Closed)
+            ));
+            assert!(matches!(
+                server.call(ManagerMsg::Connect).await,
+                Err(Error::Closed)
+            ));
+            // Reads still say why
+            assert!(matches!(server.next().await, Err(Error::Disconnected(_))));
+
+            let (server, mut client) = connected_pair().await?;
+            drop(server);
+            assert!(matches!(client.next().await, Err(Error::Disconnected(_))));
+            assert!(matches!(
+                client
+                    .send(WorkerMsg::Callback(Callback::TunnelReady))
+                    .await,
+                Err(Error::Closed)
+            ));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Sends racing the other side's close either land or fail with `Closed`, and
+    /// once one fails, every later one does
+    #[test]
+    fn send_during_close() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            for _ in 0..20 {
+                let (mut server, client) = connected_pair().await?;
+                let closer = tokio::spawn(async move {
+                    tokio::task::yield_now().await;
+                    client.close().await
+                });
+                let mut closed = false;
+                for _ in 0..200 {
+                    match server.send(ManagerMsg::Connect).await {
+                        Ok(()) => assert!(!closed, "send succeeded after a send saw Closed"),
+                        Err(Error::Closed) => closed = true,
+                        Err(error) => anyhow::bail!("unexpected error {error:?}"),
+                    }
+                }
+                closer.await??;
+                assert!(matches!(server.next().await, Err(Error::Disconnected(_))));
+                assert!(matches!(
+                    server.send(ManagerMsg::Connect).await,
+                    Err(Error::Closed)
+                ));
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// `drain` waits for the in-flight call before the close handshake
+    #[test]
+    fn drain() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            let response = server.call(ManagerMsg::Connect).await?;
+            let worker = tokio::spawn(async move {
+                let ManagerMsgInternal::Request { id, msg, .. } = client.next().await? else {
+                    anyhow::bail!("expected a request");
+                };
+                client
+                    .send(WorkerMsg::Callback(Callback::TunnelReady))
+                    .await?;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                client.respond(id, WorkerMsg::Response(msg)).await?;
+                let ManagerMsgInternal::Shutdown = client.next().await? else {
+                    anyhow::bail!("expected shutdown");
+                };
+                client.close().await?;
+                Ok(())
+            });
+
+            let received = server.drain(Duration::from_secs(5)).await?;
+            assert_eq!(received, vec![WorkerMsg::Callback(Callback::TunnelReady)]);
+            assert_eq!(response.await?, WorkerMsg::Response(ManagerMsg::Connect));
+            worker.await??;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn config_file() -> Result<()> {
+        let config: Config = serde_json::from_str(r#"{ "handshake_timeout_ms": 250 }"#)?;
+        assert_eq!(config.handshake_timeout, Duration::from_millis(250));
+        assert_eq!(
+            config,
+            Config {
+                handshake_timeout: Duration::from_millis(250),
+                ..Default::default()
+            }
+        );
+
+        let config = Config {
+            send_timeout: None,
+            max_frame_len: 1024,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&config)?;
+        assert_eq!(serde_json::from_str::<Config>(&json)?, config);
+
+        // Typos shouldn't be silently ignored
+        assert!(serde_json::from_str::<Config>(r#"{ "handshake_timeout": 250 }"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn pipe_id_args() -> Result<()> {
+        let pipe_id = PipeId::random();
+        assert_eq!(PipeId::from_arg(&pipe_id.to_arg())?, pipe_id);
+        assert!(!format!("{pipe_id:?}").contains(pipe_id.as_str()));
+
+        for bad in [
+            "subzone",
+            r"\\.\pipe\",
+            "\\\\.\\pipe\\a\nb",
+            &format!(r"\\.\pipe\{}", "a".repeat(256)),
+        ] {
+            assert!(matches!(
+                PipeId::from_arg(bad),
+                Err(Error::InvalidPipeId(_))
+            ));
+        }
+        Ok(())
+    }
+
+    /// An OS shutdown takes the same path as a `Shutdown` from the manager
+    #[test]
+    fn os_shutdown_closes_gracefully() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, server_id) = UnconnectedServer::new()?;
+            let worker_task = tokio::spawn(async move {
+                let mut client: Client<ManagerMsg, WorkerMsg> = Client::new_unsecured(&server_id)?;
+                client.handle_os_shutdown()?;
+                os_shutdown::notify(OsShutdown::Shutdown);
+                let ManagerMsgInternal::Shutdown = client.next().await? else {
+                    anyhow::bail!("expected Shutdown");
+                };
+                client.close().await?;
+                Ok::<_, anyhow::Error>(())
+            });
+            let mut server: Server<ManagerMsg, WorkerMsg> = server.accept().await?;
+            assert!(matches!(
+                server.next().await,
+                Err(Error::Disconnected(DisconnectReason::GracefulClose))
+            ));
+            worker_task.await??;
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn worker_exit_codes() {
+        for exit in [
+            WorkerExit::Clean,
+            WorkerExit::ManagerGone,
+            WorkerExit::HandshakeFailed,
+            WorkerExit::FatalAppError,
+            WorkerExit::Panic,
+        ] {
+            assert_eq!(WorkerExit::from_code(exit.code().into()), Some(exit));
+        }
+        assert_eq!(WorkerExit::from_code(1), None);
+
+        let fatal = anyhow::Error::from(FatalError(anyhow::anyhow!("bad config")));
+        assert_eq!(
+            WorkerExit::from_error(&fatal),
+            Some(WorkerExit::FatalAppError)
+        );
+        let gone = anyhow::Error::from(Error::Disconnected(DisconnectReason::GracefulClose));
+        assert_eq!(WorkerExit::from_error(&gone), Some(WorkerExit::ManagerGone));
+        let rejected = anyhow::Error::from(Error::HandshakeRejected {
+            peer: 1,
+            reason: "bad cookie".to_string(),
+        });
+        assert_eq!(
+            WorkerExit::from_error(&rejected),
+            Some(WorkerExit::HandshakeFailed)
+        );
+        for error in [
+            Error::HandshakeTimeout { peer: 1 },
+            Error::ServerUnverified,
+            Error::PermissionDenied {
+                path: PipeId::random(),
+                peer_user: None,
+                elevated: false,
+            },
+        ] {
+            assert_eq!(
+                WorkerExit::from_error(&error.into()),
+                Some(WorkerExit::HandshakeFailed)
+            );
+        }
+        assert_eq!(WorkerExit::from_error(&anyhow::anyhow!("other")), None);
+    }
+
+    #[test]
+    fn os_handles() -> Result<()> {
+        use crate::os::handles;
+        use std::io::{Read as _, Seek as _, Write as _};
+
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let file = std::fs::File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        assert!(!handles::is_inheritable(&file)?);
+        handles::set_inheritable(&file, true)?;
+        assert!(handles::is_inheritable(&file)?);
+        handles::set_inheritable(&file, false)?;
+        assert!(!handles::is_inheritable(&file)?);
+
+        let remote = handles::duplicate_into(&file, std::process::id())?;
+        let remote: handles::RemoteHandle = serde_json::from_str(&serde_json::to_string(&remote)?)?;
+        // SAFETY: We duplicated it into our own process, and only take it once
+        let mut copy = std::fs::File::from(unsafe { remote.into_owned() });
+        copy.write_all(b"subzone")?;
+        drop(copy);
+
+        let mut file = file;
+        file.rewind()?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        assert_eq!(contents, "subzone");
+        drop(file);
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// `Config::max_frame_len` reaches the `Server` from `ServerOptions`
+    #[test]
+    fn config_max_frame_len() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let config = Config {
+                max_frame_len: 1024,
+                max_message_len: 4096,
+                ..Default::default()
+            };
+            let mut listener = Listener::with_options(ServerOptions::new().config(config))?;
+            let mut client: Client<ManagerMsg, WorkerMsg> =
+                Client::new_unsecured(listener.pipe_id())?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+
+            let result = server.send(ManagerMsg::Echo("x".repeat(8192))).await;
+            assert!(matches!(result, Err(Error::MessageLength)));
+            server.send(ManagerMsg::Echo("x".repeat(2048))).await?;
+            assert!(matches!(
+                tokio::time::timeout(Duration::from_secs(5), client.next()).await??,
+                ManagerMsgInternal::User(ManagerMsg::Echo(echo)) if echo.len() == 2048
+            ));
+            server.send(ManagerMsg::Echo("x".repeat(512))).await?;
+            assert!(matches!(
+                tokio::time::timeout(Duration::from_secs(5), client.next()).await??,
+                ManagerMsgInternal::User(ManagerMsg::Echo(_))
+            ));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Pipe and read buffer sizes from `ServerOptions` reach the pipe and the reader
+    #[test]
+    fn config_buffer_sizes() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let config = Config {
+                pipe_buffer_size: 256 * 1024,
+                // Smaller than a frame header, so frames span reads
+                read_buffer_size: 5,
+                ..Default::default()
+            };
+            let mut listener = Listener::with_options(ServerOptions::new().config(config))?;
+            let mut client: Client<ManagerMsg, WorkerMsg> =
+                Client::new_unsecured(listener.pipe_id())?;
+            let mut server: Server<ManagerMsg, WorkerMsg> = listener.accept().await?;
+            assert!(server.connection_info().in_buffer_size >= 256 * 1024);
+            assert!(server.connection_info().out_buffer_size >= 256 * 1024);
+
+            for _ in 0..10 {
+                client
+                    .send(WorkerMsg::Callback(Callback::TunnelReady))
+                    .await?;
+            }
+            let big = || WorkerMsg::Response(ManagerMsg::Echo("x".repeat(100_000)));
+            client.send(big()).await?;
+            for _ in 0..10 {
+                assert_eq!(
+                    server.next().await?,
+                    WorkerMsg::Callback(Callback::TunnelReady)
+                );
+            }
+            assert_eq!(server.next().await?, big());
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// A worker flooding callbacks that nobody reads doesn't hold up sends to it
+    #[test]
+    fn manager_send_during_flood() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, mut client) = connected_pair().await?;
+            let config = Config {
+                event_queue_len: 1,
+                ..Default::default()
+            };
+            let mut manager = Manager::<ManagerMsg, WorkerMsg>::with_config(&config);
+            manager.add_server("tunnel", server)?;
+            let flood = tokio::spawn(async move {
+                let callback =
+                    || WorkerMsg::Callback(Callback::OnUpdateResources(sample_resources()));
+                while client.send(callback()).await.is_ok() {}
+            });
+            // Let the event queue fill up and the pipe back up
+            tokio::time::sleep(Duration::from_millis(200)).await;
+
+            for _ in 0..10 {
+                LatencyBudget::new("Manager::send during a flood", Duration::from_millis(500))
+                    .time(manager.send("tunnel", ManagerMsg::Connect))
+                    .await??;
+            }
+            assert!(manager.next().await.msg.is_ok());
+            flood.abort();
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn diagnostic_snapshot() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, mut client) = connected_pair().await?;
+            let mut manager = Manager::<ManagerMsg, WorkerMsg>::new();
+            manager.add_server("tunnel", server)?;
+            manager.send("tunnel", ManagerMsg::Connect).await?;
+            client.next().await?;
+
+            let snapshot = manager.diagnostic_snapshot();
+            let [worker] = &snapshot.workers[..] else {
+                panic!("expected one worker");
+            };
+            assert_eq!(worker.role, "tunnel");
+            assert_eq!(worker.pid, std::process::id());
+            assert!(!worker.spawned);
+            assert!(worker.connected);
+            assert_eq!(worker.sent, 1);
+            assert!(worker.recent_errors.is_empty());
+            assert!(snapshot.removed.is_empty());
+            let json = serde_json::to_string(&snapshot)?;
+            assert_eq!(serde_json::from_str::<DiagnosticSnapshot>(&json)?, snapshot);
+
+            // The disconnect is kept after the worker is removed
+            drop(client);
+            assert!(manager.next().await.msg.is_err());
+            assert_eq!(
+                manager.remove("tunnel", Duration::from_secs(1)).await?,
+                None
+            );
+            let snapshot = manager.diagnostic_snapshot();
+            assert!(snapshot.workers.is_empty());
+            let [removed] = &snapshot.removed[..] else {
+                panic!("expected one removed worker");
+            };
+            assert_eq!(removed.role, "tunnel");
+            assert_eq!(removed.exit, None);
+            assert_eq!(removed.recent_errors.len(), 1);
+            assert!(matches!(
+                removed.recent_events.last().map(|event| &event.kind),
+                Some(ConnectionEventKind::Disconnected(_))
+            ));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Both ends keep a payload-free history that ends with the disconnect
+    #[test]
+    fn recent_events() -> Result<()> {
+        let kinds = |events: Vec<ConnectionEvent>| {
+            events
+                .into_iter()
+                .map(|event| event.kind)
+                .collect::<Vec<_>>()
+        };
+        let sent = |frame: &str, method: Option<&str>| ConnectionEventKind::Sent {
+            frame: frame.into(),
+            method: method.map(Into::into),
+        };
+        let received = |frame: &str, method: Option<&str>| ConnectionEventKind::Received {
+            frame: frame.into(),
+            method: method.map(Into::into),
+        };
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            server.send(ManagerMsg::Connect).await?;
+            client.next().await?;
+            client.send(WorkerMsg::ChildPid(1)).await?;
+            server.next().await?;
+            let pid = std::process::id();
+            assert_eq!(
+                kinds(client.recent_events()),
+                [
+                    ConnectionEventKind::Connected { peer_pid: pid },
+                    received("User", Some("Connect")),
+                    sent("User", None),
+                ]
+            );
+
+            drop(client);
+            assert!(server.next().await.is_err());
+            let events = kinds(server.recent_events());
+            assert_eq!(
+                events[..3],
+                [
+                    ConnectionEventKind::Connected { peer_pid: pid },
+                    sent("User", Some("Connect")),
+                    received("User", Some("ChildPid")),
+                ]
+            );
+            assert!(matches!(
+                events[3..],
+                [ConnectionEventKind::Disconnected(_)]
+            ));
+
+            // Only the newest are kept
+            let log = event_log::EventLog::default();
+            for _ in 0..=EVENT_LOG_LEN {
+                log.sent("User", None);
+            }
+            assert_eq!(log.list().len(), EVENT_LOG_LEN);
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Steady-state reads reuse the buffers earlier reads gave back
+    #[test]
+    fn buffer_pool_reuse() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            for _ in 0..10 {
+                server.send(ManagerMsg::Connect).await?;
+                client.next().await?;
+            }
+            // Other tests share the pool, so only check that these reads hit it
+            let before = buffer_pool_stats();
+            for _ in 0..100 {
+                server.send(ManagerMsg::Connect).await?;
+                client.next().await?;
+            }
+            let after = buffer_pool_stats();
+            assert!(after.hits - before.hits >= 50, "{before:?} {after:?}");
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn batch_send_recv_many() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            server
+                .send_all((0..100).map(|i| ManagerMsg::Echo(i.to_string())))
+                .await?;
+            let mut received = Vec::new();
+            while received.len() < 100 {
+                let count = client.recv_many(&mut received, 10).await?;
+                assert!((1..=10).contains(&count));
+            }
+            for (i, msg) in received.into_iter().enumerate() {
+                let ManagerMsgInternal::User(msg) = msg else {
+                    anyhow::bail!("Expected a user message");
+                };
+                assert_eq!(msg, ManagerMsg::Echo(i.to_string()));
+            }
+            let echo = server.method_stats();
+            assert_eq!(echo[0].sent_bytes.count, 100);
+
+            client.send_all((0..100).map(WorkerMsg::ChildPid)).await?;
+            let mut received = Vec::new();
+            while received.len() < 100 {
+                server.recv_many(&mut received, 1000).await?;
+            }
+            assert_eq!(
+                received,
+                (0..100).map(WorkerMsg::ChildPid).collect::<Vec<_>>()
+            );
+            assert_eq!(client.recv_many(&mut Vec::new(), 0).await?, 0);
+            server.send_all([]).await?;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn recv_many_answers_pings_it_drains() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            server.send(ManagerMsg::Echo("1".to_string())).await?;
+            let check = server.health().await?;
+            server.send(ManagerMsg::Echo("2".to_string())).await?;
+            // Let all three frames reach the client's reader task
+            tokio::time::sleep(Duration::from_millis(50)).await;
+
+            let mut received = Vec::new();
+            let first = client.recv_many(&mut received, 10).await?;
+            let rest = client.recv_many(&mut received, 10);
+            let rest = tokio::time::timeout(Duration::from_millis(50), rest).await;
+            assert_eq!(first + rest.map_or(Ok(0), |count| count)?, 2);
+            for (msg, text) in received.into_iter().zip(["1", "2"]) {
+                let ManagerMsgInternal::User(ManagerMsg::Echo(echo)) = msg else {
+                    anyhow::bail!("Expected an echo");
+                };
+                assert_eq!(echo, text);
+            }
+            // The ping was answered, not dropped
+            tokio::select! {
+                health = check => assert_eq!(health?.status, HealthStatus::Healthy),
+                msg = server.next() => anyhow::bail!("unexpected {msg:?}"),
+            }
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn frame_timestamps() -> Result<()> {
+        use crate::{
+            clock::Transit,
+            frame::{FrameReader, FrameWriter},
+        };
+
+        // The manager's clock is 5000 us ahead, and the pipe takes 10 us each way
+        let sample = ClockSample {
+            received_us: 5110,
+            sent_us: 5130,
+        };
+        let offset = ClockOffset::estimate(100, sample, 140);
+        assert_eq!(
+            offset,
+            ClockOffset {
+                offset_us: -5000,
+                round_trip_us: 20,
+            }
+        );
+        assert_eq!(offset.reversed().offset_us, 5000);
+        assert_eq!(offset.plausible(), Some(offset));
+        // A peer's numbers can't overflow the estimate, and a wild one is refused
+        let wild = ClockSample {
+            received_us: u64::MAX,
+            sent_us: u64::MAX,
+        };
+        let offset = ClockOffset::estimate(0, wild, 0);
+        assert_eq!(offset.plausible(), None);
+        assert_eq!(offset.reversed().plausible(), None);
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let mut writer = FrameWriter::with_max_len(Vec::new(), 64).max_message_len(4096);
+            writer.write(&"before").await?;
+            writer.set_timestamps(true);
+            writer.write(&"stamped").await?;
+            // Fragments are stamped too
+            writer.write(&"x".repeat(200)).await?;
+            let data = writer.into_inner();
+
+            let transit = Transit::default();
+            transit.set_offset(ClockOffset {
+                offset_us: 0,
+                round_trip_us: 0,
+            });
+            let mut reader = FrameReader::with_max_len(&data[..], 64)
+                .max_message_len(4096)
+                .transit(transit.clone());
+            assert_eq!(reader.read().await?, b"\"before\"");
+            assert_eq!(reader.read().await?, b"\"stamped\"");
+            assert_eq!(reader.read().await?.len(), 202);
+            let histogram = transit.histogram().context("should have an offset")?;
+            // One for "stamped" and one per fragment of the long one
+            assert_eq!(histogram.count, 5);
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Handshake frames after the handshake drop the connection
+    #[test]
+    fn acl_refuses_handshake_frames() -> Result<()> {
+        assert!(!acl::manager_frame_allowed(
+            &ManagerMsgInternal::<()>::Accepted {
+                resume_state: None,
+                fingerprint: None,
+                clock: None,
+            }
+        ));
+        assert!(acl::manager_frame_allowed(
+            &ManagerMsgInternal::<()>::Shutdown
+        ));
+
+        let violation = |result: Result<WorkerMsg, Error>| {
+            matches!(
+                result,
+                Err(Error::Disconnected(DisconnectReason::ProtocolViolation))
+            )
+        };
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            client
+                .send_internal(&WorkerMsgInternal::Fingerprint(golden_fingerprint()))
+                .await?;
+            client.send(WorkerMsg::ChildPid(1)).await?;
+            assert_eq!(server.next().await?, WorkerMsg::ChildPid(1));
+            // A second one would overwrite what the worker reported first
+            client
+                .send_internal(&WorkerMsgInternal::Fingerprint(Fingerprint::current()))
+                .await?;
+            assert!(violation(server.next().await));
+            assert!(violation(server.next().await));
+            assert!(matches!(
+                server.send(ManagerMsg::Connect).await,
+                Err(Error::Closed)
+            ));
+            assert_eq!(
+                server.connection_info().peer_fingerprint,
+                Some(golden_fingerprint())
+            );
+
+            let (mut server, mut client) = connected_pair().await?;
+            client
+                .send_internal(&WorkerMsgInternal::Cookie("late".into()))
+                .await?;
+            assert!(violation(server.next().await));
+            Ok(())
+        })
+    }
+
+    /// Secrets are masked at any depth, whatever their case
+    #[test]
+    fn redact_masks_secrets() {
+        struct Login;
+        impl Redact for Login {
+            const SECRET_FIELDS: &'static [&'static str] = &["otp"];
+        }
+
+        let mut msg = serde_json::json!({
+            "User": {
+                "user": "alice",
+                "OTP": "123456",
+                "devices": [{ "name": "laptop", "WireGuardPrivateKey": "abc" }],
+                "auth": { "access_token": "xyz", "expires": 60 },
+                "otp_sent": true,
+            }
+        });
+        redact::redact(&mut msg, Login::SECRET_FIELDS);
+        assert_eq!(
+            msg,
+            serde_json::json!({
+                "User": {
+                    "user": "alice",
+                    "OTP": REDACTED,
+                    "devices": [{ "name": "laptop", "WireGuardPrivateKey": REDACTED }],
+                    "auth": { "access_token": REDACTED, "expires": 60 },
+                    "otp_sent": true,
+                }
+            })
+        );
+
+        // For repro bundles, which have to decode again
+        let mut msg = serde_json::json!({ "pin": 1234, "session_token": "xyz", "tokens": [1] });
+        redact::redact_keeping_types(&mut msg, &["pin"]);
+        assert_eq!(
+            msg,
+            serde_json::json!({ "pin": 0, "session_token": REDACTED, "tokens": [] })
+        );
+    }
+
+    /// A frame the ACL refuses leaves a bundle that replays to the same error
+    #[test]
+    fn repro_bundle_replays() -> Result<()> {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir)?;
+        let rt = Runtime::new()?;
+        let path = rt.block_on(async {
+            let (mut server, mut client) = connected_pair().await?;
+            server.capture_repros(&dir);
+            client
+                .send_internal(&WorkerMsgInternal::Cookie("late".into()))
+                .await?;
+            assert!(matches!(
+                server.next().await,
+                Err(Error::Disconnected(DisconnectReason::ProtocolViolation))
+            ));
+            Ok::<_, anyhow::Error>(wait_for_repros(&dir, 1).await?.remove(0))
+        })?;
+        assert_eq!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some(REPRO_EXTENSION)
+        );
+
+        let bundle = ReproBundle::load(&path)?;
+        assert_eq!(bundle.reader, ReproSide::Manager);
+        assert_eq!(bundle.connection.peer_pid, std::process::id());
+        assert!(matches!(
+            bundle.events.first().map(|event| &event.kind),
+            Some(ConnectionEventKind::Connected { .. })
+        ));
+        assert!(matches!(
+            testing::replay_repro::<ManagerMsg, WorkerMsg>(&bundle),
+            Some(Error::Disconnected(DisconnectReason::ProtocolViolation))
+        ));
+
+        // A frame that's fine now replays to nothing
+        let fixed = ReproBundle {
+            frame: serde_json::to_vec(&WorkerMsgInternal::User(WorkerMsg::ChildPid(1)))?,
+            ..bundle
+        };
+        assert!(testing::replay_repro::<ManagerMsg, WorkerMsg>(&fixed).is_none());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Waits for the background writes of `count` repro bundles in `dir`
+    async fn wait_for_repros(
+        dir: &std::path::Path,
+        count: usize,
+    ) -> Result<Vec<std::path::PathBuf>> {
+        for _ in 0..100 {
+            let bundles = std::fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>>>()?;
+            if bundles.len() >= count {
+                return Ok(bundles);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        anyhow::bail!("expected {count} repro bundles")
+    }
+
+    /// A peer sending junk can only write a few small bundles, and junk isn't kept
+    #[test]
+    fn repro_capture_is_bounded() -> Result<()> {
+        use crate::repro::ReproCapture;
+
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir(&dir)?;
+        let rt = Runtime::new()?;
+        let bundles = rt.block_on(async {
+            let (server, _client) = connected_pair().await?;
+            let mut capture = ReproCapture::new(dir.clone(), &["pin"]);
+            let error = Error::Disconnected(DisconnectReason::ProtocolViolation);
+            let junk = [0xff; 3 * MAX_REPRO_FRAME];
+            for _ in 0..MAX_REPROS_PER_CONNECTION * 2 {
+                capture.write(
+                    ReproSide::Manager,
+                    server.connection_info(),
+                    &error,
+                    &junk,
+                    vec![],
+                );
+            }
+            let bundles = wait_for_repros(&dir, 1).await?;
+            // Give any bundles that shouldn't exist time to show up
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            anyhow::ensure!(bundles.len() == 1, "only one bundle per interval");
+
+            let big = serde_json::json!({ "pin": 1234, "text": "x".repeat(2 * MAX_REPRO_FRAME) });
+            let mut capture = ReproCapture::new(dir.clone(), &["pin"]);
+            capture.write(
+                ReproSide::Manager,
+                server.connection_info(),
+                &error,
+                &serde_json::to_vec(&big)?,
+                vec![],
+            );
+            wait_for_repros(&dir, 2).await
+        })?;
+        let mut bundles = bundles
+            .iter()
+            .map(|path| ReproBundle::load(path))
+            .collect::<Result<Vec<_>>>()?;
+        bundles.sort_by_key(|bundle| bundle.frame.is_empty());
+
+        let json = &bundles[0];
+        assert_eq!(json.frame.len(), MAX_REPRO_FRAME);
+        assert!(json.frame_len > 2 * MAX_REPRO_FRAME);
+        assert!(json.frame.starts_with(br#"{"pin":0,"#));
+        assert_eq!(json.frame_sha256, None);
+
+        let junk = &bundles[1];
+        assert!(junk.frame.is_empty());
+        assert_eq!(junk.frame_len, 3 * MAX_REPRO_FRAME);
+        assert_eq!(junk.frame_sha256.as_ref().map(String::len), Some(64));
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+
+    /// Every ordering of handshake events, up to five of them
+    #[test]
+    fn handshake_state() -> Result<()> {
+        use crate::handshake::{HandshakeState, Input, Output};
+
+        const CHILD: u32 = 100;
+        let frame = |msg: WorkerMsgInternal<String>| serde_json::to_vec(&msg).unwrap();
+        let fingerprint = |arch: &str| Fingerprint {
+            arch: arch.into(),
+            native_arch: None,
+            ..golden_fingerprint()
+        };
+        let cookie = frame(WorkerMsgInternal::Cookie("cookie".into()));
+        let wrong_cookie = frame(WorkerMsgInternal::Cookie("guess".into()));
+        let user = frame(WorkerMsgInternal::User("early".into()));
+        let same_arch = frame(WorkerMsgInternal::Fingerprint(fingerprint("x86_64")));
+        let other_arch = frame(WorkerMsgInternal::Fingerprint(fingerprint("x86")));
+        let garbage = b"\xff{".to_vec();
+        let hello = frame(WorkerMsgInternal::Hello {
+            nonce: "nonce".into(),
+        });
+        let inputs = [
+            Input::Connected { pid: CHILD },
+            Input::Connected { pid: CHILD + 1 },
+            Input::Frame(&cookie),
+            Input::Frame(&wrong_cookie),
+            Input::Frame(&user),
+            Input::Frame(&same_arch),
+            Input::Frame(&other_arch),
+            Input::Frame(&garbage),
+            Input::Frame(&hello),
+        ];
+        let new = |same_arch: bool| {
+            HandshakeState::new(
+                CHILD,
+                "cookie".into(),
+                same_arch.then(|| "x86_64".to_string()),
+            )
+        };
+
+        // The only orderings that pass
+        let mut state = new(false);
+        assert_eq!(state.advance(Input::Connected { pid: CHILD }), Output::Read);
+        assert_eq!(
+            state.advance(Input::Frame(&cookie)),
+            Output::Accept { finished: true }
+        );
+        let mut state = new(true);
+        assert_eq!(state.advance(Input::Connected { pid: CHILD }), Output::Read);
+        assert_eq!(
+            state.advance(Input::Frame(&cookie)),
+            Output::Accept { finished: false }
+        );
+        assert_eq!(
+            state.advance(Input::Frame(&same_arch)),
+            Output::Finished(fingerprint("x86_64"))
+        );
+        let mut state = new(false);
+        assert_eq!(state.advance(Input::Connected { pid: CHILD }), Output::Read);
+        assert_eq!(
+            state.advance(Input::Frame(&hello)),
+            Output::Prove(handshake::server_proof("cookie", "nonce")?)
+        );
+        let proof = handshake::server_proof("cookie", "nonce")?;
+        assert!(handshake::verify_server_proof("cookie", "nonce", &proof));
+        assert!(!handshake::verify_server_proof("guess", "nonce", &proof));
+        assert!(!handshake::verify_server_proof("cookie", "other", &proof));
+        assert_eq!(
+            state.advance(Input::Frame(&cookie)),
+            Output::Accept { finished: true }
+        );
+
+        let Output::Rejected(reason) = ({
+            let mut state = new(true);
+            state.advance(Input::Connected { pid: CHILD });
+            state.advance(Input::Frame(&cookie));
+            state.advance(Input::Frame(&other_arch))
+        }) else {
+            anyhow::bail!("a worker of another arch should be rejected");
+        };
+        assert_eq!(
+            reason,
+            "worker is x86 (32-bit) but the manager is x86_64 (64-bit)"
+        );
+
+        for same_arch in [false, true] {
+            // Indexes into `inputs` of every passing run, with or without the hello
+            let passing: &[&[usize]] = if same_arch {
+                &[&[0, 2, 5], &[0, 8, 2, 5]]
+            } else {
+                &[&[0, 2], &[0, 8, 2]]
+            };
+            for len in 1..=5 {
+                for mut n in 0..inputs.len().pow(len) {
+                    let mut state = new(same_arch);
+                    let mut rejected = false;
+                    let mut history = Vec::new();
+                    for step in 0..len as usize {
+                        let index = n % inputs.len();
+                        n /= inputs.len();
+                        history.push(index);
+                        let output = state.advance(inputs[index]);
+                        let on_path =
+                            !rejected && passing.iter().any(|run| run.starts_with(&history));
+                        match output {
+                            Output::Rejected(_) => rejected = true,
+                            _ => assert!(on_path, "{output:?} at step {step}"),
+                        }
+                        if !on_path {
+                            assert!(rejected, "should have been rejected at step {step}");
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shutdown_token() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, mut client) = connected_pair().await?;
+            let token = client.shutdown_token();
+            let waiter = tokio::spawn({
+                let token = token.clone();
+                async move { token.triggered().await }
+            });
+            assert!(!token.is_triggered());
+            let close = tokio::spawn(server.close());
+            assert!(matches!(client.next().await?, ManagerMsgInternal::Shutdown));
+            assert!(token.is_triggered());
+            tokio::time::timeout(Duration::from_secs(5), waiter).await??;
+            drop(client);
+            close.await??;
+
+            let manager = Manager::<ManagerMsg, WorkerMsg>::default();
+            let token = manager.shutdown_token();
+            assert!(!token.is_triggered());
+            manager.close(Duration::from_secs(1)).await?;
+            assert!(token.is_triggered());
+            // Waiting after the fact doesn't hang
+            token.triggered().await;
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    #[test]
+    fn latency_budget() -> Result<()> {
+        let budget = LatencyBudget::new("Connect", Duration::from_millis(10)).margin(2.0);
+        assert_eq!(budget.limit(), Duration::from_millis(20));
+        budget.check(Duration::from_millis(20))?;
+        let error = budget.check(Duration::from_millis(25)).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Connect took 25ms, over its budget of 10ms (x2 margin, limit 20ms)"
+        );
+
+        // One outlier in 100 passes p99 but not p100
+        let mut samples = vec![Duration::from_millis(5); 99];
+        samples.push(Duration::from_secs(1));
+        budget.check_percentile(&samples, 99.0)?;
+        let error = budget.check_percentile(&samples, 100.0).unwrap_err();
+        assert!(error.to_string().contains("1 samples over the limit"));
+        assert!(budget.check_percentile(&[], 50.0).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn method_stats() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (mut server, mut client) = connected_pair().await?;
+            server.send(ManagerMsg::Echo("x".repeat(2000))).await?;
+            server.send(ManagerMsg::Connect).await?;
+            client.next().await?;
+            client.next().await?;
+
+            let call = server.call(ManagerMsg::Connect).await?;
+            let ManagerMsgInternal::Request { id, .. } = client.next().await? else {
+                panic!("expected a request");
+            };
+            client
+                .respond(id, WorkerMsg::Response(ManagerMsg::Connect))
+                .await?;
+            client
+                .send(WorkerMsg::Callback(Callback::TunnelReady))
+                .await?;
+            server.next().await?;
+            call.await?;
+
+            let stats = server.method_stats();
+            let methods: Vec<_> = stats.iter().map(|m| m.method.as_str()).collect();
+            assert_eq!(methods, ["Callback", "Connect", "Echo"]);
+            let [callback, connect, echo] = &stats[..] else {
+                unreachable!()
+            };
+            assert_eq!(callback.received_bytes.count, 1);
+            assert_eq!(callback.call_seconds.count, 0);
+            // One plain send and one call, and the call's response
+            assert_eq!(connect.sent_bytes.count, 2);
+            assert_eq!(connect.received_bytes.count, 1);
+            assert_eq!(connect.call_seconds.count, 1);
+            assert!(echo.sent_bytes.sum > 2000.0);
+            assert_eq!(echo.sent_bytes.buckets[2], (1024.0, 0));
+            assert_eq!(echo.sent_bytes.buckets[3], (4096.0, 1));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// The name taken from a message before sending matches the one read back from its frame
+    #[test]
+    fn method_names() -> Result<()> {
+        #[derive(Serialize)]
+        enum Shapes {
+            Unit,
+            Newtype(u32),
+            Tuple(u32, u32),
+            Struct { field: u32 },
+        }
+
+        let metadata = Metadata::new().with(Metadata::SESSION, "42");
+        for msg in [
+            Shapes::Unit,
+            Shapes::Newtype(1),
+            Shapes::Tuple(1, 2),
+            Shapes::Struct { field: 1 },
+        ] {
+            let method = method_stats::sent_method(&msg);
+            let user = ManagerMsgInternal::User(&msg);
+            for frame in [
+                serde_json::to_string(&user)?,
+                serde_json::to_string(&user.with_metadata(&metadata))?,
+                serde_json::to_string(&ManagerMsgInternal::Request {
+                    id: 1,
+                    msg: &msg,
+                    traceparent: None,
+                })?,
+            ] {
+                assert_eq!(
+                    method_stats::received_manager_method(&frame).as_ref(),
+                    Some(&method),
+                    "{frame}"
+                );
+            }
+        }
+        assert_eq!(method_stats::sent_method(&Shapes::Newtype(1)), "Newtype");
+        assert_eq!(method_stats::sent_method(&"Ping"), "Ping");
+        assert_eq!(method_stats::sent_method(&1), "unknown");
+        assert_eq!(
+            method_stats::received_method("{\"Response\":{}}"),
+            "unknown"
+        );
+        Ok(())
+    }
+
+    #[cfg(feature = "prometheus")]
+    #[test]
+    fn prometheus_export() -> Result<()> {
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, mut client) = connected_pair().await?;
+            let mut manager = Manager::new();
+            manager.add_server("tun\"nel", server)?;
+            manager.send("tun\"nel", ManagerMsg::Connect).await?;
+            client.next().await?;
+
+            let mut out = String::new();
+            manager.write_prometheus(&mut out);
+            assert!(out.contains("# TYPE subzone_messages_sent_total counter\n"));
+            assert!(out.contains("subzone_messages_sent_total{role=\"tun\\\"nel\"} 1\n"));
+            assert!(out.contains("subzone_worker_connected{role=\"tun\\\"nel\"} 1\n"));
+            // No stats were asked for
+            assert!(!out.contains("subzone_worker_rss_bytes{"));
+            assert!(out.contains("# TYPE subzone_method_sent_bytes histogram\n"));
+            assert!(out.contains(
+                "subzone_method_sent_bytes_count{role=\"tun\\\"nel\",method=\"Connect\"} 1\n"
+            ));
+            assert!(!out.contains("subzone_call_duration_seconds_count{"));
+            assert!(out.contains("# TYPE subzone_buffer_pool_hits_total counter\n"));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Calls through `CallService` get their own responses, and callbacks come out the side
+    #[cfg(feature = "tower")]
+    #[test]
+    fn call_service() -> Result<()> {
+        use tower_service::Service as _;
+
+        async fn ready_call(
+            service: &mut CallService<ManagerMsg, WorkerMsg>,
+            msg: ManagerMsg,
+        ) -> Result<WorkerMsg, Error> {
+            std::future::poll_fn(|cx| service.poll_ready(cx)).await?;
+            service.call(msg).await
+        }
+
+        let rt = Runtime::new()?;
+        rt.block_on(async move {
+            let (server, mut client) = connected_pair().await?;
+            let worker = tokio::spawn(async move {
+                client
+                    .send(WorkerMsg::Callback(Callback::TunnelReady))
+                    .await?;
+                for _ in 0..2 {
+                    let ManagerMsgInternal::Request { id, msg, .. } = client.next().await? else {
+                        panic!("expected a request");
+                    };
+                    client.respond(id, WorkerMsg::Response(msg)).await?;
+                }
+                client.close().await?;
+                Ok::<_, anyhow::Error>(())
+            });
+
+            let (mut service, mut callbacks) = CallService::new(server, 4);
+            let mut other = service.clone();
+            let (first, second) = tokio::join!(
+                ready_call(&mut service, ManagerMsg::Connect),
+                ready_call(&mut other, ManagerMsg::Echo("hi".into())),
+            );
+            assert_eq!(first?, WorkerMsg::Response(ManagerMsg::Connect));
+            assert_eq!(second?, WorkerMsg::Response(ManagerMsg::Echo("hi".into())));
+            assert_eq!(
+                callbacks
+                    .recv()
+                    .await
+                    .expect("should have gotten a callback")?,
+                WorkerMsg::Callback(Callback::TunnelReady)
+            );
+            worker.await??;
+
+            // The receiver ends after the disconnect, once the task is gone
+            assert!(matches!(
+                callbacks.recv().await,
+                Some(Err(Error::Disconnected(_)))
+            ));
+            assert!(callbacks.recv().await.is_none());
+            assert!(matches!(
+                ready_call(&mut service, ManagerMsg::Connect).await,
+                Err(Error::Closed)
+            ));
+            Ok::<_, anyhow::Error>(())
+        })?;
+        Ok(())
+    }
+
+    /// Returns a `Server` and `Client` connected to each other inside this process
+    async fn connected_pair(
+    ) -> Result<(Server<ManagerMsg, WorkerMsg>, Client<ManagerMsg, WorkerMsg>)> {
+        let (server, server_id) = UnconnectedServer::new()?;
+        let client = Client::new_unsecured(&server_id)?;
+        Ok((server.accept().await?, client))
+    }
+
+    fn sample_resources() -> Vec<String> {
+        vec![
+            "2efe9c25-bd92-49a0-99d7-8b92da014dd5".into(),
+            "613eaf56-6efa-45e5-88aa-ea4ad64d8c18".into(),
+        ]
+    }
+}
+
+/// Model-checked interleavings of the state shared between tasks, see `sync`
+#[cfg(all(test, subzone_loom))]
+mod loom_tests {
+    use crate::{
+        call::PendingCalls,
+        frame::FrameWriter,
+        sync::{Arc, Mutex},
+        DisconnectReason, Error,
+    };
+    use loom::{future::block_on, thread};
+
+    /// The write half as `Client` and the log task share it
+    fn shared_writer() -> Arc<Mutex<FrameWriter<Vec<u8>>>> {
+        Arc::new(Mutex::new(FrameWriter::new(Vec::new())))
+    }
+
+    /// A send racing close either writes its whole frame first, or fails with
+    /// `Closed` and writes nothing
+    #[test]
+    fn loom_close_races_send() {
+        loom::model(|| {
+            let writer = shared_writer();
+
+            let sender = {
+                let writer = Arc::clone(&writer);
+                thread::spawn(move || block_on(writer.lock().unwrap().write(&"Connect")))
+            };
+            let closer = {
+                let writer = Arc::clone(&writer);
+                thread::spawn(move || writer.lock().unwrap().mark_closed())
+            };
+            let sent = sender.join().unwrap();
+            closer.join().unwrap();
+
+            let Ok(writer) = Arc::try_unwrap(writer) else {
+                panic!("both threads are done with the writer");
+            };
+            let mut writer = writer.into_inner().unwrap();
+            assert!(matches!(
+                block_on(writer.write(&"late")),
+                Err(Error::Closed)
+            ));
+            let wire = writer.into_inner();
+            match sent {
+                Ok(()) => {
+                    let mut alone = FrameWriter::new(Vec::new());
+                    block_on(alone.write(&"Connect")).unwrap();
+                    assert_eq!(wire, alone.into_inner());
+                }
+                Err(Error::Closed) => assert!(wire.is_empty()),
+                other => panic!("unexpected result {other:?}"),
+            }
+        });
+    }
+
+    /// A call racing close, like `Server::call_with` against the reader seeing a
+    /// disconnect, never waits forever and never leaks its entry
+    #[test]
+    fn loom_close_races_call() {
+        loom::model(|| {
+            let writer = shared_writer();
+            let calls = PendingCalls::<u32>::default();
+
+            let caller = {
+                let writer = Arc::clone(&writer);
+                let calls = calls.clone();
+                thread::spawn(move || {
+                    let (_, response) = calls.register("Connect".into());
+                    // On a failed write, `call_with` drops the response, which removes the entry
+                    block_on(writer.lock().unwrap().write(&"Connect"))?;
+                    block_on(response)
+                })
+            };
+            let closer = {
+                let writer = Arc::clone(&writer);
+                let calls = calls.clone();
+                thread::spawn(move || {
+                    writer.lock().unwrap().mark_closed();
+                    calls.fail_all(&DisconnectReason::GracefulClose);
+                })
+            };
+            let result = caller.join().unwrap();
+            closer.join().unwrap();
+
+            assert!(calls.is_empty());
+            match result {
+                Err(Error::Closed | Error::Disconnected(DisconnectReason::GracefulClose)) => {}
+                other => panic!("unexpected result {other:?}"),
+            }
+        });
+    }
+
+    /// A response racing the disconnect resolves the call exactly once
+    #[test]
+    fn loom_complete_races_fail_all() {
+        loom::model(|| {
+            let calls = PendingCalls::<u32>::default();
+            let (id, response) = calls.register("Connect".into());
+
+            let reader = {
+                let calls = calls.clone();
+                thread::spawn(move || calls.complete(id, 42))
+            };
+            let disconnect = {
+                let calls = calls.clone();
+                thread::spawn(move || calls.fail_all(&DisconnectReason::GracefulClose))
+            };
+            reader.join().unwrap();
+            disconnect.join().unwrap();
+
+            assert!(calls.is_empty());
+            match block_on(response) {
+                Ok(42) | Err(Error::Disconnected(DisconnectReason::GracefulClose)) => {}
+                other => panic!("unexpected result {other:?}"),
+            }
+        });
+    }
+
+    /// Cancelling a call while its response arrives doesn't leak the entry
+    #[test]
+    fn loom_cancel_races_complete() {
+        loom::model(|| {
+            let calls = PendingCalls::<u32>::default();
+            let (id, response) = calls.register("Connect".into());
+
+            let reader = {
+                let calls = calls.clone();
+                thread::spawn(move || calls.complete(id, 42))
+            };
+            let caller = thread::spawn(move || drop(response));
+            reader.join().unwrap();
+            caller.join().unwrap();
+
+            assert!(calls.is_empty());
+        });
+    }
+
+    /// A call registered while the connection fails is either failed or still pending,
+    /// and new IDs stay unique
+    #[test]
+    fn loom_register_races_fail_all() {
+        loom::model(|| {
+            let calls = PendingCalls::<u32>::default();
+            let (first_id, _first) = calls.register("Connect".into());
+
+            let caller = {
+                let calls = calls.clone();
+                thread::spawn(move || calls.register("Connect".into()))
+            };
+            let disconnect = {
+                let calls = calls.clone();
+                thread::spawn(move || calls.fail_all(&DisconnectReason::GracefulClose))
+            };
+            let (second_id, second) = caller.join().unwrap();
+            disconnect.join().unwrap();
+
+            assert_ne!(first_id, second_id);
+            let pending = calls.list().len();
+            assert!(pending <= 1);
+            drop(second);
+            assert!(calls.is_empty());
+        });
     }
 }
@@ -0,0 +1,313 @@
+//! Accepting connections from processes we didn't spawn
+//!
+//! `Subprocess` checks that the client is our own child and knows the cookie.
+//! A `Listener` accepts anyone who can open its pipe, e.g. a CLI tool or a GUI
+//! that found us through the `Registry`, so only use it for requests that any
+//! local user may make, or gate the rest with `accept_scoped`, see `scope`.
+//! `ServerOptions::quota` limits how many connections each client may hold.
+//! Manager main loops can drive it with `Listener::run`, see `accept_loop`.
+
+use anyhow::{Context as _, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::VecDeque, future::Future, pin::Pin, task::Poll};
+use tokio::net::windows::named_pipe::{self, NamedPipeServer};
+
+use crate::{
+    accept_loop::{AcceptLoop, AcceptPolicy},
+    quota::{EvictHandle, QuotaTable},
+    scope::ScopeGrant,
+    sys, Config, ConnectionQuota, PeerIdentity, PipeId, PipeMode, QuotaKey, Scoped, Server,
+    ShutdownToken,
+};
+
+/// How a `Listener` creates its pipe instances
+///
+/// `UnconnectedServer` doesn't take these, since it only ever accepts the one
+/// child it spawned.
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    pipe_mode: PipeMode,
+    backlog: usize,
+    config: Config,
+    /// See `grant_scopes`
+    grant: Option<ScopeGrant>,
+    /// See `quota`
+    quotas: Vec<ConnectionQuota>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            pipe_mode: PipeMode::Byte,
+            backlog: 1,
+            config: Config::default(),
+            grant: None,
+            quotas: Vec::new(),
+        }
+    }
+}
+
+impl ServerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// See `PipeMode`
+    pub fn pipe_mode(mut self, pipe_mode: PipeMode) -> Self {
+        self.pipe_mode = pipe_mode;
+        self
+    }
+
+    /// How many pipe instances wait for clients at once
+    ///
+    /// A client that opens the pipe while every instance is taken gets
+    /// `ERROR_PIPE_BUSY`, so raise this if many clients connect at once, e.g.
+    /// at boot. Values below 1 are treated as 1.
+    pub fn backlog(mut self, backlog: usize) -> Self {
+        self.backlog = backlog.max(1);
+        self
+    }
+
+    /// Limits for the accepted connections, e.g. `Config::max_frame_len`
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Decides the scopes of each client accepted with `Listener::accept_scoped`
+    ///
+    /// e.g. `admin` for elevated clients, and `agent` for one running as SYSTEM.
+    /// A client whose identity can't be read gets no scopes, or is turned away
+    /// if there are quotas, e.g. one that opened the pipe with
+    /// `ImpersonationLevel::Anonymous`.
+    pub fn grant_scopes(
+        mut self,
+        grant: impl Fn(&PeerIdentity) -> Vec<String> + Send + Sync + 'static,
+    ) -> Self {
+        self.grant = Some(ScopeGrant::new(grant));
+        self
+    }
+
+    /// Limits concurrent connections per user or exe, see `ConnectionQuota`
+    ///
+    /// May be called more than once, e.g. for a limit per user and a tighter one
+    /// per exe. A connection must fit under all of them.
+    pub fn quota(mut self, quota: ConnectionQuota) -> Self {
+        self.quotas.push(quota);
+        self
+    }
+}
+
+/// A client that connected to a `Listener`, see `admit_next`
+pub(crate) enum Admission<M, W> {
+    Admitted(Box<Server<M, W>>, Option<PeerIdentity>),
+    /// Over a `ServerOptions::quota`, and already disconnected
+    Rejected {
+        pid: u32,
+        quota: QuotaKey,
+    },
+    /// Its identity couldn't be read for the quotas, and it's already disconnected
+    Unidentified {
+        pid: u32,
+    },
+}
+
+/// A named pipe that accepts any number of clients, one `Server` each
+pub struct Listener {
+    pipe_id: PipeId,
+    options: ServerOptions,
+    /// Instances that the next clients will connect to, `options.backlog` of them
+    pool: VecDeque<NamedPipeServer>,
+    /// Connections counted against `options.quotas`
+    held: QuotaTable,
+}
+
+impl Listener {
+    /// Creates a byte-mode listener on a random pipe ID. Requires a Tokio context
+    pub fn new() -> Result<Self> {
+        Self::with_options(ServerOptions::new())
+    }
+
+    /// Creates a listener whose pipe instances use `pipe_mode`, see `PipeMode`
+    pub fn with_pipe_mode(pipe_mode: PipeMode) -> Result<Self> {
+        Self::with_options(ServerOptions::new().pipe_mode(pipe_mode))
+    }
+
+    pub fn with_options(options: ServerOptions) -> Result<Self> {
+        let pipe_id = PipeId::random();
+        let mut pool = VecDeque::with_capacity(options.backlog);
+        pool.push_back(
+            named_pipe::ServerOptions::new()
+                .first_pipe_instance(true)
+                .pipe_mode(options.pipe_mode.into())
+                .in_buffer_size(options.config.pipe_buffer_size)
+                .out_buffer_size(options.config.pipe_buffer_size)
+                .create(pipe_id.as_str())
+                .context("couldn't create listener pipe")?,
+        );
+        let mut this = Self {
+            pipe_id,
+            options,
+            pool,
+            held: QuotaTable::default(),
+        };
+        while this.pool.len() < this.options.backlog {
+            let pipe = this.create_instance()?;
+            this.pool.push_back(pipe);
+        }
+        Ok(this)
+    }
+
+    /// The ID clients connect to, e.g. to publish with `Registry::register`
+    pub fn pipe_id(&self) -> &PipeId {
+        &self.pipe_id
+    }
+
+    /// Waits for the next client
+    ///
+    /// Clients over a `ServerOptions::quota` are disconnected and skipped, as
+    /// are clients whose identity can't be read while there are quotas.
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel-safe. If it's cancelled, the next call waits on the same pipe instances.
+    /// A client that's already connected, while its identity is read for
+    /// `ServerOptions::grant_scopes` or `quota`, is disconnected.
+    pub async fn accept<M: Serialize, W: DeserializeOwned>(&mut self) -> Result<Server<M, W>> {
+        loop {
+            if let Admission::Admitted(server, _) = self.admit_next().await? {
+                return Ok(*server);
+            }
+        }
+    }
+
+    /// Waits for the next client and checks it against the quotas
+    ///
+    /// Also returns the client's identity, if any options needed it.
+    pub(crate) async fn admit_next<M: Serialize, W: DeserializeOwned>(
+        &mut self,
+    ) -> Result<Admission<M, W>> {
+        let pipe = self.accept_any().await?;
+        let client_pid = sys::named_pipe_client_pid(&pipe)?;
+        if self.options.grant.is_none() && self.options.quotas.is_empty() {
+            let server = Server::new(pipe, &self.options.config, client_pid)?;
+            return Ok(Admission::Admitted(Box::new(server), None));
+        }
+        // Impersonating the client needs the pipe's handle, which `Server::new`
+        // moves into its reader and writer when it splits the pipe. It waits on
+        // a thread of its own, so don't hold up one of the runtime's workers.
+        let (identity, pipe) =
+            tokio::task::spawn_blocking(move || (PeerIdentity::of_pipe_client(&pipe), pipe))
+                .await?;
+        let identity = match identity {
+            Ok(identity) => identity,
+            // Unidentified clients would all share one key, and could evict each other
+            Err(error) if !self.options.quotas.is_empty() => {
+                tracing::warn!(?error, "Turning away a client whose identity can't be read");
+                return Ok(Admission::Unidentified { pid: client_pid });
+            }
+            Err(error) => {
+                tracing::warn!(?error, "Couldn't read the client's identity");
+                let server = Server::new(pipe, &self.options.config, client_pid)?;
+                return Ok(Admission::Admitted(Box::new(server), None));
+            }
+        };
+        let evict = EvictHandle::new(&pipe)?;
+        let mut server = Server::new(pipe, &self.options.config, client_pid)?;
+        if self.options.quotas.is_empty() {
+            return Ok(Admission::Admitted(Box::new(server), Some(identity)));
+        }
+        match self.held.admit(&self.options.quotas, &identity, evict) {
+            Ok(slot) => {
+                server.set_quota(slot);
+                Ok(Admission::Admitted(Box::new(server), Some(identity)))
+            }
+            // Dropping the server closes the pipe instance, and the client sees a disconnect
+            Err(quota) => Ok(Admission::Rejected {
+                pid: server.client_pid(),
+                quota,
+            }),
+        }
+    }
+
+    /// Enforces the scopes `ServerOptions::grant_scopes` picks for `identity`
+    pub(crate) fn enforce_scopes<M: Serialize, W: DeserializeOwned + Scoped>(
+        &self,
+        server: &mut Server<M, W>,
+        identity: Option<&PeerIdentity>,
+    ) {
+        let granted = self
+            .options
+            .grant
+            .as_ref()
+            .map(|grant| grant.scopes_for(identity))
+            .unwrap_or_default();
+        server.enforce_scopes(granted);
+    }
+
+    /// Waits for the next client, without checking quotas
+    async fn accept_any(&mut self) -> Result<NamedPipeServer> {
+        // Clients pick any free instance, so wait on all of them
+        let (index, connected) = {
+            let mut connects: Vec<_> = self
+                .pool
+                .iter()
+                .map(|pipe| Box::pin(pipe.connect()))
+                .collect();
+            std::future::poll_fn(|cx| {
+                for (index, connect) in connects.iter_mut().enumerate() {
+                    if let Poll::Ready(result) = Pin::new(connect).poll(cx) {
+                        return Poll::Ready((index, result));
+                    }
+                }
+                Poll::Pending
+            })
+            .await
+        };
+        // Create the replacement first, so clients never see the pipe missing
+        let next = self.create_instance()?;
+        self.pool.push_back(next);
+        let pipe = self
+            .pool
+            .remove(index)
+            .context("connected instance should be in the pool")?;
+        // A failed instance is dropped too, so it can't fail every later call
+        connected.context("couldn't connect listener pipe instance")?;
+        Ok(pipe)
+    }
+
+    /// `accept`, and enforces the client's scopes from `ServerOptions::grant_scopes`
+    ///
+    /// Without `grant_scopes`, every client gets no scopes, so only methods that
+    /// need none are allowed.
+    pub async fn accept_scoped<M: Serialize, W: DeserializeOwned + Scoped>(
+        &mut self,
+    ) -> Result<Server<M, W>> {
+        loop {
+            if let Admission::Admitted(mut server, identity) = self.admit_next().await? {
+                self.enforce_scopes(&mut server, identity.as_ref());
+                return Ok(*server);
+            }
+        }
+    }
+
+    /// Accepts clients until `shutdown` trips, see `AcceptLoop`
+    ///
+    /// Clients get their scopes as with `accept_scoped`.
+    pub fn run<M, W>(self, policy: AcceptPolicy, shutdown: ShutdownToken) -> AcceptLoop<M, W>
+    where
+        M: Serialize + Send + 'static,
+        W: DeserializeOwned + Scoped + Send + 'static,
+    {
+        AcceptLoop::new(self, policy, shutdown)
+    }
+
+    fn create_instance(&self) -> Result<NamedPipeServer> {
+        named_pipe::ServerOptions::new()
+            .pipe_mode(self.options.pipe_mode.into())
+            .in_buffer_size(self.options.config.pipe_buffer_size)
+            .out_buffer_size(self.options.config.pipe_buffer_size)
+            .create(self.pipe_id.as_str())
+            .context("couldn't create next listener pipe instance")
+    }
+}
@@ -0,0 +1,72 @@
+//! Changing the `tracing` filter of a running process over the pipe
+//!
+//! Restarting a worker to make it more verbose usually destroys the bug we're
+//! chasing, so either side can ask the other to swap its filter at runtime.
+
+#[cfg(feature = "subscriber")]
+use anyhow::Context as _;
+use anyhow::Result;
+#[cfg(feature = "subscriber")]
+use tracing_subscriber::{
+    fmt, layer::SubscriberExt, reload, util::SubscriberInitExt, EnvFilter, Registry,
+};
+
+/// Swaps the filter of a subscriber installed by `init_reloadable_subscriber`
+///
+/// Give it to `Client::set_log_filter_handle` or `Server::set_log_filter_handle`
+/// so the peer can change our filter.
+#[derive(Clone)]
+pub struct LogFilterHandle {
+    #[cfg(feature = "subscriber")]
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// Without `init_reloadable_subscriber` there's no way to make one
+    #[cfg(not(feature = "subscriber"))]
+    never: std::convert::Infallible,
+}
+
+/// Installs a global `fmt` subscriber whose filter can be replaced later
+///
+/// `directives` uses the same syntax as `RUST_LOG`, e.g. `"info,subzone=debug"`
+#[cfg(feature = "subscriber")]
+pub fn init_reloadable_subscriber(directives: &str) -> Result<LogFilterHandle> {
+    let filter = EnvFilter::try_new(directives).context("invalid log filter")?;
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(fmt::layer())
+        .try_init()
+        .context("couldn't install the global tracing subscriber")?;
+    Ok(LogFilterHandle { handle })
+}
+
+impl LogFilterHandle {
+    /// Replaces the filter, keeping the old one if `directives` doesn't parse
+    #[cfg(feature = "subscriber")]
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let filter = EnvFilter::try_new(directives).context("invalid log filter")?;
+        self.handle
+            .reload(filter)
+            .context("couldn't reload log filter")?;
+        Ok(())
+    }
+
+    #[cfg(not(feature = "subscriber"))]
+    pub fn set(&self, _directives: &str) -> Result<()> {
+        match self.never {}
+    }
+}
+
+/// Applies a filter the peer asked for, if we have a handle to apply it with
+pub(crate) fn apply_from_peer(handle: Option<&LogFilterHandle>, directives: &str) {
+    let Some(handle) = handle else {
+        tracing::warn!(
+            ?directives,
+            "Peer tried to change our log filter, but no LogFilterHandle is set"
+        );
+        return;
+    };
+    match handle.set(directives) {
+        Ok(()) => tracing::info!(?directives, "Log filter changed by peer"),
+        Err(error) => tracing::error!(?error, ?directives, "Couldn't apply log filter from peer"),
+    }
+}
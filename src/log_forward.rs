@@ -0,0 +1,200 @@
+//! Forwarding the worker's `tracing` events to the manager
+//!
+//! The worker installs `LogForwardLayer` in its subscriber and hands the
+//! matching `LogForwarder` to `Client::forward_logs`. The manager re-emits the
+//! records through its own subscriber, so both processes end up in one log file.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::mpsc;
+use tracing::Level;
+#[cfg(feature = "subscriber")]
+use {
+    std::fmt::Write,
+    tracing::{field::Field, Event, Subscriber},
+    tracing_subscriber::{layer::Context, registry::LookupSpan, Layer},
+};
+
+/// Records waiting to be written to the pipe. Beyond this, new records are dropped.
+#[cfg(feature = "subscriber")]
+const LOG_QUEUE_LEN: usize = 1024;
+
+/// A `tracing` event from the worker, serialized for the pipe
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LogRecord {
+    /// e.g. "INFO"
+    pub level: String,
+    pub target: String,
+    /// Names of the spans the event happened in, outermost first
+    pub spans: Vec<String>,
+    pub message: String,
+    /// All fields except `message`, formatted with `Debug`
+    pub fields: BTreeMap<String, String>,
+}
+
+/// A `tracing_subscriber` layer that queues the worker's events for the manager
+///
+/// Never blocks. If the pipe can't keep up, records are dropped and counted.
+///
+/// Events from subzone itself are not forwarded, since writing a forwarded record
+/// produces more of them.
+#[cfg(feature = "subscriber")]
+pub struct LogForwardLayer {
+    tx: mpsc::Sender<LogRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// The receiving end of a `LogForwardLayer`, see `Client::forward_logs`
+pub struct LogForwarder {
+    pub(crate) rx: mpsc::Receiver<LogRecord>,
+    dropped: Arc<AtomicU64>,
+}
+
+/// Creates a layer for the worker's subscriber and the forwarder that drains it
+#[cfg(feature = "subscriber")]
+pub fn log_forward_layer() -> (LogForwardLayer, LogForwarder) {
+    let (tx, rx) = mpsc::channel(LOG_QUEUE_LEN);
+    let dropped = Arc::new(AtomicU64::new(0));
+    (
+        LogForwardLayer {
+            tx,
+            dropped: Arc::clone(&dropped),
+        },
+        LogForwarder { rx, dropped },
+    )
+}
+
+impl LogForwarder {
+    /// How many records were dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(feature = "subscriber")]
+impl<S> Layer<S> for LogForwardLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let metadata = event.metadata();
+        if metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+            return;
+        }
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let spans = ctx
+            .event_scope(event)
+            .map(|scope| {
+                scope
+                    .from_root()
+                    .map(|span| span.name().to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        let record = LogRecord {
+            level: metadata.level().to_string(),
+            target: metadata.target().to_string(),
+            spans,
+            message: visitor.message,
+            fields: visitor.fields,
+        };
+        if self.tx.try_send(record).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "subscriber")]
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+#[cfg(feature = "subscriber")]
+impl tracing::field::Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields
+                .insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            write!(self.message, "{value:?}").ok();
+        } else {
+            self.fields
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+}
+
+/// Re-emits a worker's record through the manager's subscriber
+pub(crate) fn emit(worker_pid: u32, record: LogRecord) {
+    let LogRecord {
+        level,
+        target,
+        spans,
+        message,
+        fields,
+    } = record;
+    let spans = spans.join(":");
+    // `tracing` needs the level at compile time
+    match level.parse().unwrap_or(Level::INFO) {
+        Level::ERROR => {
+            tracing::error!(
+                worker_pid,
+                worker_target = target,
+                spans,
+                ?fields,
+                "{message}"
+            )
+        }
+        Level::WARN => {
+            tracing::warn!(
+                worker_pid,
+                worker_target = target,
+                spans,
+                ?fields,
+                "{message}"
+            )
+        }
+        Level::INFO => {
+            tracing::info!(
+                worker_pid,
+                worker_target = target,
+                spans,
+                ?fields,
+                "{message}"
+            )
+        }
+        Level::DEBUG => {
+            tracing::debug!(
+                worker_pid,
+                worker_target = target,
+                spans,
+                ?fields,
+                "{message}"
+            )
+        }
+        Level::TRACE => {
+            tracing::trace!(
+                worker_pid,
+                worker_target = target,
+                spans,
+                ?fields,
+                "{message}"
+            )
+        }
+    }
+}
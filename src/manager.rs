@@ -0,0 +1,761 @@
+//! Orchestrating several workers identified by role
+//!
+//! Each worker's `Server` is moved into its own task, so the manager can wait on
+//! one combined event stream instead of a `select!` over every connection.
+
+use anyhow::{bail, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{Instrument as _, Span};
+
+use crate::{
+    call::PendingCalls,
+    clock::Transit,
+    config::{Config, ConfigAck},
+    diagnostics::{RecentErrors, REMOVED_WORKERS_LEN},
+    event_log::EventLog,
+    method_stats::MethodTable,
+    panic_guard,
+    server::{lock_subscriptions, Subscriptions},
+    stats::LatestStats,
+    ConnectionInfo, DiagnosticSnapshot, DisconnectReason, Error, Fingerprint, Health, HealthCheck,
+    Histogram, MethodStats, NetworkChange, PendingCall, PowerEvent, RemovedWorker, ResponseFuture,
+    Server, ShutdownToken, SubcommandChild, SubcommandExit, Subprocess, WorkerSnapshot,
+    WorkerStats,
+};
+
+/// Picks the role of the worker a message should go to
+type Router<M> = Box<dyn Fn(&M) -> &str + Send + Sync>;
+
+/// Decides whether a worker wants a broadcast message
+type BroadcastFilter<M> = Box<dyn Fn(&M) -> bool + Send + Sync>;
+
+/// Owns several workers by role, e.g. `"tunnel"`, `"dns"`, `"gui-bridge"`
+pub struct Manager<M, W> {
+    workers: HashMap<String, WorkerHandle<M, W>>,
+    router: Option<Router<M>>,
+    events_tx: mpsc::Sender<ManagerEvent<W>>,
+    events_rx: mpsc::Receiver<ManagerEvent<W>>,
+    /// The last few workers taken out with `remove`, see `diagnostic_snapshot`
+    removed: VecDeque<RemovedWorker>,
+    /// Tripped by `close` and drop, see `shutdown_token`
+    shutdown: ShutdownToken,
+}
+
+/// Something that happened on one of the workers
+#[derive(Debug)]
+pub struct ManagerEvent<W> {
+    pub role: String,
+    /// A message from the worker, or the error that ended its connection.
+    /// After an `Error::Disconnected`, no more events come from this role.
+    pub msg: Result<W, Error>,
+}
+
+struct WorkerHandle<M, W> {
+    commands: mpsc::Sender<Command<M, W>>,
+    /// Messages queued for the worker that haven't been written to its pipe yet
+    queued: Arc<AtomicUsize>,
+    /// Updated by the worker's task, read for `write_prometheus` and `diagnostic_snapshot`
+    traffic: Arc<Traffic>,
+    task: JoinHandle<()>,
+    client_pid: u32,
+    /// Copied when the worker was added
+    info: ConnectionInfo,
+    /// `None` for connections that weren't spawned by us
+    worker: Option<SubcommandChild>,
+    /// `None` means the worker gets every broadcast
+    filter: Option<BroadcastFilter<M>>,
+    /// Shared with the worker's `Server`, which updates it as the worker subscribes
+    subscriptions: Subscriptions,
+    /// Shared with the worker's `Server`
+    calls: PendingCalls<W>,
+    /// Shared with the worker's `Server`, which updates it as samples arrive
+    stats: LatestStats,
+    /// Shared with the worker's `Server`, which updates it as messages pass
+    methods: MethodTable,
+    /// Shared with the worker's reader task, which updates it as frames arrive
+    transit: Transit,
+    /// Shared with the worker's `Server`, which updates it as frames pass
+    history: EventLog,
+}
+
+/// Counters for one worker, for `Manager::write_prometheus`
+#[derive(Default)]
+pub(crate) struct Traffic {
+    /// User messages written to the worker
+    pub(crate) sent: AtomicU64,
+    /// User messages read from the worker
+    pub(crate) received: AtomicU64,
+    pub(crate) disconnected: AtomicBool,
+    pub(crate) errors: RecentErrors,
+}
+
+impl Traffic {
+    /// Keeps the error, if any, for `Manager::diagnostic_snapshot`
+    fn note<T>(&self, result: &Result<T, Error>) {
+        if let Err(error) = result {
+            self.errors.push(error);
+        }
+    }
+}
+
+/// What happened to each worker during a `Manager::broadcast`
+#[derive(Debug, Default)]
+pub struct BroadcastReport {
+    pub delivered: Vec<String>,
+    /// Workers whose filter rejected the message
+    pub filtered: Vec<String>,
+    pub failed: Vec<(String, Error)>,
+}
+
+enum Command<M, W> {
+    Send(M, oneshot::Sender<Result<(), Error>>),
+    /// The caller's span, so the call's span is its child
+    Call(M, Span, oneshot::Sender<Result<ResponseFuture<W>, Error>>),
+    Power(PowerEvent, oneshot::Sender<Result<(), Error>>),
+    Network(NetworkChange, oneshot::Sender<Result<(), Error>>),
+    PushConfig(
+        Config,
+        oneshot::Sender<Result<ResponseFuture<ConfigAck>, Error>>,
+    ),
+    ReportStats(Option<Duration>, oneshot::Sender<Result<(), Error>>),
+    Health(oneshot::Sender<Result<HealthCheck, Error>>),
+    Close(oneshot::Sender<anyhow::Result<()>>),
+}
+
+impl<M, W> Default for Manager<M, W> {
+    fn default() -> Self {
+        Self::with_event_queue_len(Config::default().event_queue_len)
+    }
+}
+
+impl<M, W> Manager<M, W> {
+    fn with_event_queue_len(len: usize) -> Self {
+        // `mpsc::channel` panics on 0
+        let (events_tx, events_rx) = mpsc::channel(len.max(1));
+        Self {
+            workers: Default::default(),
+            router: None,
+            events_tx,
+            events_rx,
+            removed: VecDeque::new(),
+            shutdown: ShutdownToken::new(),
+        }
+    }
+
+    /// A token that trips when `close` starts or the manager is dropped
+    ///
+    /// Pass it to the manager's own tasks, so they stop along with the workers.
+    pub fn shutdown_token(&self) -> ShutdownToken {
+        self.shutdown.clone()
+    }
+
+    /// One sample per worker, sorted by role, for `write_prometheus`
+    #[cfg(feature = "prometheus")]
+    pub(crate) fn samples(&self) -> Vec<crate::prometheus::WorkerSample> {
+        let mut samples: Vec<_> = self
+            .workers
+            .iter()
+            .map(|(role, handle)| crate::prometheus::WorkerSample {
+                role: role.clone(),
+                sent: handle.traffic.sent.load(Ordering::Relaxed),
+                received: handle.traffic.received.load(Ordering::Relaxed),
+                connected: !handle.traffic.disconnected.load(Ordering::Relaxed),
+                queued: handle.queued.load(Ordering::Relaxed),
+                pending_calls: handle.calls.list().len(),
+                stats: handle.stats.get(),
+                methods: handle.methods.snapshot(),
+                transit: handle.transit.histogram(),
+            })
+            .collect();
+        samples.sort_by(|a, b| a.role.cmp(&b.role));
+        samples
+    }
+}
+
+impl<M, W> Manager<M, W>
+where
+    M: Serialize + Send + 'static,
+    W: DeserializeOwned + Send + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a `Manager` with the queue bounds from `config`
+    pub fn with_config(config: &Config) -> Self {
+        Self::with_event_queue_len(config.event_queue_len)
+    }
+
+    /// Creates a `Manager` whose `route` picks the worker from the message itself
+    pub fn with_router(router: impl Fn(&M) -> &str + Send + Sync + 'static) -> Self {
+        let mut this = Self::default();
+        this.router = Some(Box::new(router));
+        this
+    }
+
+    /// Takes ownership of a worker subprocess and its connection
+    pub fn add(&mut self, role: impl Into<String>, subprocess: Subprocess<M, W>) -> Result<()> {
+        let Subprocess { server, worker } = subprocess;
+        self.insert(role.into(), server, Some(worker))
+    }
+
+    /// Takes ownership of a connection to a process we didn't spawn
+    pub fn add_server(&mut self, role: impl Into<String>, server: Server<M, W>) -> Result<()> {
+        self.insert(role.into(), server, None)
+    }
+
+    fn insert(
+        &mut self,
+        role: String,
+        server: Server<M, W>,
+        worker: Option<SubcommandChild>,
+    ) -> Result<()> {
+        if self.workers.contains_key(&role) {
+            bail!("there's already a worker with role {role:?}");
+        }
+        let (commands, commands_rx) = mpsc::channel(1);
+        let client_pid = server.client_pid();
+        let info = server.connection_info().clone();
+        let subscriptions = server.subscriptions_handle();
+        let calls = server.calls_handle();
+        let stats = server.stats_handle();
+        let methods = server.methods_handle();
+        let transit = server.transit_handle();
+        let history = server.history_handle();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let traffic = Arc::new(Traffic::default());
+        let task = tokio::spawn(worker_task(
+            role.clone(),
+            server,
+            commands_rx,
+            Arc::clone(&queued),
+            Arc::clone(&traffic),
+            self.events_tx.clone(),
+        ));
+        self.workers.insert(
+            role,
+            WorkerHandle {
+                commands,
+                queued,
+                traffic,
+                task,
+                client_pid,
+                info,
+                worker,
+                filter: None,
+                subscriptions,
+                calls,
+                stats,
+                methods,
+                transit,
+                history,
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns the roles of all workers, including ones that have disconnected
+    pub fn roles(&self) -> impl Iterator<Item = &str> {
+        self.workers.keys().map(String::as_str)
+    }
+
+    /// Returns the process ID of the worker with this role
+    pub fn client_pid(&self, role: &str) -> Option<u32> {
+        self.workers.get(role).map(|handle| handle.client_pid)
+    }
+
+    /// Sends a message to the worker with this role
+    pub async fn send(&self, role: &str, msg: M) -> Result<(), Error> {
+        let handle = self
+            .workers
+            .get(role)
+            .ok_or_else(|| Error::UnknownRole(role.to_string()))?;
+        let (tx, rx) = oneshot::channel();
+        handle.enqueue(Command::Send(msg, tx)).await?;
+        rx.await.map_err(|_| Error::WorkerTaskStopped)?
+    }
+
+    /// Sends a request to the worker with this role and waits for its response
+    ///
+    /// See `Server::call`
+    pub async fn call(&self, role: &str, msg: M) -> Result<W, Error> {
+        let handle = self
+            .workers
+            .get(role)
+            .ok_or_else(|| Error::UnknownRole(role.to_string()))?;
+        let (tx, rx) = oneshot::channel();
+        // `Server::call`'s span is a sibling of this one, under the caller's span
+        let parent = Span::current();
+        let enqueue = tracing::info_span!("ipc.enqueue", role);
+        let response = async {
+            handle.enqueue(Command::Call(msg, parent, tx)).await?;
+            rx.await.map_err(|_| Error::WorkerTaskStopped)?
+        }
+        .instrument(enqueue)
+        .await?;
+        response.await
+    }
+
+    /// Retunes the worker with this role and waits for its ack
+    ///
+    /// See `Server::push_config`
+    pub async fn push_config(&self, role: &str, config: Config) -> Result<ConfigAck, Error> {
+        let handle = self
+            .workers
+            .get(role)
+            .ok_or_else(|| Error::UnknownRole(role.to_string()))?;
+        let (tx, rx) = oneshot::channel();
+        handle.enqueue(Command::PushConfig(config, tx)).await?;
+        let ack = rx.await.map_err(|_| Error::WorkerTaskStopped)??;
+        ack.await
+    }
+
+    /// Probes whether the worker with this role is live and ready
+    ///
+    /// See `Server::health`. Wrap it in a timeout, since a hung worker never answers.
+    pub async fn health(&self, role: &str) -> Result<Health, Error> {
+        let handle = self
+            .workers
+            .get(role)
+            .ok_or_else(|| Error::UnknownRole(role.to_string()))?;
+        let (tx, rx) = oneshot::channel();
+        handle.enqueue(Command::Health(tx)).await?;
+        let check = rx.await.map_err(|_| Error::WorkerTaskStopped)??;
+        check.await
+    }
+
+    /// Asks every worker to report its resource usage every `interval`
+    ///
+    /// `None` stops the reports. See `Server::report_stats`.
+    pub async fn report_stats(&self, interval: Option<Duration>) -> BroadcastReport {
+        self.fan_out(|_| true, |reply| Command::ReportStats(interval, reply))
+            .await
+    }
+
+    /// The latest resource usage sample from the worker with this role
+    pub fn worker_stats(&self, role: &str) -> Option<WorkerStats> {
+        self.workers.get(role)?.stats.get()
+    }
+
+    /// Per-method message sizes and call latency for the worker with this role
+    ///
+    /// See `Server::method_stats`
+    pub fn method_stats(&self, role: &str) -> Option<Vec<MethodStats>> {
+        Some(self.workers.get(role)?.methods.snapshot())
+    }
+
+    /// How long frames from the worker with this role took to arrive
+    ///
+    /// See `Server::transit_latency`
+    pub fn transit_latency(&self, role: &str) -> Option<Histogram> {
+        self.workers.get(role)?.transit.histogram()
+    }
+
+    /// Dumps every worker's state for a support bundle, see `DiagnosticSnapshot`
+    ///
+    /// Serialize it with `serde_json`.
+    pub fn diagnostic_snapshot(&self) -> DiagnosticSnapshot {
+        let mut workers: Vec<_> = self
+            .workers
+            .iter()
+            .map(|(role, handle)| WorkerSnapshot {
+                role: role.clone(),
+                pid: handle.client_pid,
+                spawned: handle.worker.is_some(),
+                connection: handle.info.clone(),
+                connected: !handle.traffic.disconnected.load(Ordering::Relaxed),
+                sent: handle.traffic.sent.load(Ordering::Relaxed),
+                received: handle.traffic.received.load(Ordering::Relaxed),
+                send_queue_depth: handle.queued.load(Ordering::Relaxed),
+                pending_calls: handle.calls.list(),
+                recent_errors: handle.traffic.errors.list(),
+                stats: handle.stats.get(),
+                methods: handle.methods.snapshot(),
+                transit: handle.transit.histogram(),
+                subscriptions: lock_subscriptions(&handle.subscriptions).clone(),
+                recent_events: handle.history.list(),
+            })
+            .collect();
+        workers.sort_by(|a, b| a.role.cmp(&b.role));
+        DiagnosticSnapshot {
+            taken_at: SystemTime::now(),
+            manager_pid: std::process::id(),
+            fingerprint: Fingerprint::current(),
+            workers,
+            removed: self.removed.iter().cloned().collect(),
+        }
+    }
+
+    /// Returns the calls the worker with this role hasn't answered yet, oldest first
+    pub fn pending_calls(&self, role: &str) -> Option<Vec<PendingCall>> {
+        Some(self.workers.get(role)?.calls.list())
+    }
+
+    /// Returns how many messages are waiting to be written to this worker's pipe
+    ///
+    /// If this keeps growing, the worker has stopped reading.
+    pub fn send_queue_depth(&self, role: &str) -> Option<usize> {
+        Some(self.workers.get(role)?.queued.load(Ordering::Relaxed))
+    }
+
+    /// Sends a message to whichever worker the router picks for it
+    ///
+    /// Returns `Error::UnknownRole` if there's no router, or it picks a role
+    /// that doesn't exist.
+    pub async fn route(&self, msg: M) -> Result<(), Error> {
+        let Some(router) = &self.router else {
+            return Err(Error::UnknownRole(String::new()));
+        };
+        let role = router(&msg).to_string();
+        self.send(&role, msg).await
+    }
+
+    /// Limits which broadcast messages a worker receives
+    ///
+    /// Messages sent with `send` or `route` aren't filtered.
+    pub fn set_broadcast_filter(
+        &mut self,
+        role: &str,
+        filter: impl Fn(&M) -> bool + Send + Sync + 'static,
+    ) -> Result<(), Error> {
+        let handle = self
+            .workers
+            .get_mut(role)
+            .ok_or_else(|| Error::UnknownRole(role.to_string()))?;
+        handle.filter = Some(Box::new(filter));
+        Ok(())
+    }
+
+    /// Sends a copy of `msg` to every worker whose filter accepts it
+    ///
+    /// Doesn't stop at the first failure. The writes happen concurrently,
+    /// so one stuck worker doesn't delay the rest.
+    pub async fn broadcast(&self, msg: M) -> BroadcastReport
+    where
+        M: Clone,
+    {
+        self.fan_out(
+            |handle| handle.filter.as_ref().is_none_or(|filter| filter(&msg)),
+            |reply| Command::Send(msg.clone(), reply),
+        )
+        .await
+    }
+
+    /// Sends a copy of `msg` to every worker subscribed to `topic`
+    ///
+    /// Workers subscribe with `Client::subscribe`. Broadcast filters don't apply,
+    /// and unsubscribed workers are listed in `BroadcastReport::filtered`.
+    pub async fn publish(&self, topic: &str, msg: M) -> BroadcastReport
+    where
+        M: Clone,
+    {
+        self.fan_out(
+            |handle| lock_subscriptions(&handle.subscriptions).contains(topic),
+            |reply| Command::Send(msg.clone(), reply),
+        )
+        .await
+    }
+
+    /// Returns the topics the worker with this role is subscribed to
+    pub fn subscriptions(&self, role: &str) -> Option<BTreeSet<String>> {
+        let handle = self.workers.get(role)?;
+        Some(lock_subscriptions(&handle.subscriptions).clone())
+    }
+
+    /// Tells every worker about a system suspend or resume
+    ///
+    /// Usually fed from a `PowerWatcher`. Broadcast filters don't apply.
+    pub async fn broadcast_power_event(&self, event: PowerEvent) -> BroadcastReport {
+        self.fan_out(|_| true, |reply| Command::Power(event, reply))
+            .await
+    }
+
+    /// Tells every worker that a network interface changed
+    ///
+    /// Usually fed from a `NetworkWatcher`. Broadcast filters don't apply.
+    pub async fn broadcast_network_change(&self, change: NetworkChange) -> BroadcastReport {
+        self.fan_out(|_| true, |reply| Command::Network(change, reply))
+            .await
+    }
+
+    /// Sends a command to every worker that `wants` it, without waiting on one before the next
+    async fn fan_out(
+        &self,
+        wants: impl Fn(&WorkerHandle<M, W>) -> bool,
+        command: impl Fn(oneshot::Sender<Result<(), Error>>) -> Command<M, W>,
+    ) -> BroadcastReport {
+        let mut report = BroadcastReport::default();
+        let mut replies = vec![];
+        for (role, handle) in &self.workers {
+            if !wants(handle) {
+                report.filtered.push(role.clone());
+                continue;
+            }
+            let (tx, rx) = oneshot::channel();
+            handle.queued.fetch_add(1, Ordering::Relaxed);
+            match handle.commands.try_send(command(tx)) {
+                Ok(()) => replies.push((role.clone(), rx)),
+                Err(mpsc::error::TrySendError::Full(command)) => {
+                    // Another send is in progress, queue behind it without blocking the loop
+                    let commands = handle.commands.clone();
+                    tokio::spawn(async move { commands.send(command).await });
+                    replies.push((role.clone(), rx));
+                }
+                Err(_) => {
+                    handle.queued.fetch_sub(1, Ordering::Relaxed);
+                    report.failed.push((role.clone(), Error::WorkerTaskStopped));
+                }
+            }
+        }
+        for (role, rx) in replies {
+            match rx.await {
+                Ok(Ok(())) => report.delivered.push(role),
+                Ok(Err(error)) => report.failed.push((role, error)),
+                Err(_) => report.failed.push((role, Error::WorkerTaskStopped)),
+            }
+        }
+        report
+    }
+
+    /// Receives the next event from any worker
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel-safe, internally it calls `tokio::sync::mpsc::Receiver::recv`
+    pub async fn next(&mut self) -> ManagerEvent<W> {
+        self.events_rx
+            .recv()
+            .await
+            .expect("the Manager keeps a sender, so the channel can't close")
+    }
+
+    /// Closes one worker's connection, then waits `dur` for it to exit before killing it
+    ///
+    /// Messages already queued for the worker are written before the close
+    /// handshake. If that takes longer than `dur`, e.g. because the worker stopped
+    /// reading, the rest are discarded and counted in a warning.
+    ///
+    /// Returns `None` for workers added with `add_server`.
+    pub async fn remove(&mut self, role: &str, dur: Duration) -> Result<Option<SubcommandExit>> {
+        let Some(handle) = self.workers.remove(role) else {
+            bail!("there's no worker with role {role:?}");
+        };
+        let WorkerHandle {
+            commands,
+            queued,
+            traffic,
+            task,
+            client_pid,
+            info,
+            worker,
+            history,
+            ..
+        } = handle;
+        let (tx, rx) = oneshot::channel();
+        let close = async {
+            // The worker task handles commands in order, so everything queued goes first
+            commands.send(Command::Close(tx)).await.ok()?;
+            rx.await.ok()
+        };
+        match tokio::time::timeout(dur, close).await {
+            Ok(Some(Ok(()))) => {}
+            Ok(Some(Err(error))) => tracing::warn!(?error, ?role, "Worker didn't close cleanly"),
+            Ok(None) => tracing::warn!(?role, "Worker task stopped while closing"),
+            Err(_) => tracing::warn!(
+                ?role,
+                discarded = queued.load(Ordering::Relaxed),
+                "Worker didn't take its queued messages before the deadline"
+            ),
+        }
+        task.abort();
+        let exit = match worker {
+            Some(mut worker) => Some(worker.wait_then_kill(dur).await?),
+            None => None,
+        };
+        if self.removed.len() == REMOVED_WORKERS_LEN {
+            self.removed.pop_front();
+        }
+        self.removed.push_back(RemovedWorker {
+            role: role.to_string(),
+            pid: client_pid,
+            connected_at: info.connected_at,
+            removed_at: SystemTime::now(),
+            exit: exit.as_ref().map(|exit| format!("{exit:?}")),
+            recent_errors: traffic.errors.list(),
+            recent_events: history.list(),
+        });
+        Ok(exit)
+    }
+
+    /// Removes every worker, see `remove`
+    pub async fn close(mut self, dur: Duration) -> Result<()> {
+        self.shutdown.trigger();
+        let roles: Vec<_> = self.workers.keys().cloned().collect();
+        for role in roles {
+            let exit = self.remove(&role, dur).await?;
+            tracing::debug!(?role, ?exit, "Removed worker");
+        }
+        Ok(())
+    }
+}
+
+impl<M, W> WorkerHandle<M, W> {
+    async fn enqueue(&self, command: Command<M, W>) -> Result<(), Error> {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        if self.commands.send(command).await.is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(Error::WorkerTaskStopped);
+        }
+        Ok(())
+    }
+}
+
+impl<M, W> Drop for Manager<M, W> {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+        for handle in self.workers.values() {
+            handle.task.abort();
+        }
+    }
+}
+
+/// Owns one `Server`, forwarding its messages and carrying out commands
+///
+/// A panic ends the worker's connection with an `Error::Internal` event, and
+/// fails its pending calls, instead of leaving them waiting forever.
+async fn worker_task<M: Serialize, W: DeserializeOwned>(
+    role: String,
+    server: Server<M, W>,
+    commands: mpsc::Receiver<Command<M, W>>,
+    queued: Arc<AtomicUsize>,
+    traffic: Arc<Traffic>,
+    events: mpsc::Sender<ManagerEvent<W>>,
+) {
+    let calls = server.calls_handle();
+    let run = run_worker(
+        role.clone(),
+        server,
+        commands,
+        queued,
+        Arc::clone(&traffic),
+        events.clone(),
+    );
+    let Err(message) = panic_guard::catch_unwind(run).await else {
+        return;
+    };
+    tracing::error!(?role, ?message, "Worker task panicked");
+    calls.fail_all(&DisconnectReason::Internal(message.clone()));
+    let error = Error::Internal(message);
+    traffic.errors.push(&error);
+    traffic.disconnected.store(true, Ordering::Relaxed);
+    let event = ManagerEvent {
+        role,
+        msg: Err(error),
+    };
+    events.send(event).await.ok();
+}
+
+async fn run_worker<M: Serialize, W: DeserializeOwned>(
+    role: String,
+    mut server: Server<M, W>,
+    mut commands: mpsc::Receiver<Command<M, W>>,
+    queued: Arc<AtomicUsize>,
+    traffic: Arc<Traffic>,
+    events: mpsc::Sender<ManagerEvent<W>>,
+) {
+    let mut disconnected = false;
+    // Read but not yet in the event queue. Waiting for room is a branch of its
+    // own, so commands keep running while `Manager::next` falls behind.
+    let mut unqueued: Option<ManagerEvent<W>> = None;
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(Command::Send(msg, reply)) => {
+                    let result = server.send(msg).await;
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    traffic.note(&result);
+                    if result.is_ok() {
+                        traffic.sent.fetch_add(1, Ordering::Relaxed);
+                    }
+                    reply.send(result).ok();
+                }
+                Some(Command::Call(msg, parent, reply)) => {
+                    // Wait for the response outside this task, so `next` keeps routing
+                    let result = server.call(msg).instrument(parent).await;
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    traffic.note(&result);
+                    reply.send(result).ok();
+                }
+                Some(Command::Power(event, reply)) => {
+                    let result = server.send_power_event(event).await;
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    traffic.note(&result);
+                    reply.send(result).ok();
+                }
+                Some(Command::Network(change, reply)) => {
+                    let result = server.send_network_change(change).await;
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    traffic.note(&result);
+                    reply.send(result).ok();
+                }
+                Some(Command::PushConfig(config, reply)) => {
+                    let result = server.push_config(&config).await;
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    traffic.note(&result);
+                    reply.send(result).ok();
+                }
+                Some(Command::ReportStats(interval, reply)) => {
+                    let result = server.report_stats(interval).await;
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    traffic.note(&result);
+                    reply.send(result).ok();
+                }
+                Some(Command::Health(reply)) => {
+                    let result = server.health().await;
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    traffic.note(&result);
+                    reply.send(result).ok();
+                }
+                Some(Command::Close(reply)) => {
+                    if let Some(event) = unqueued.take() {
+                        events.try_send(event).ok();
+                    }
+                    reply.send(server.close().await).ok();
+                    return;
+                }
+                // The Manager dropped
+                None => return,
+            },
+            msg = server.next(), if !disconnected && unqueued.is_none() => {
+                disconnected = matches!(msg, Err(Error::Disconnected(_)));
+                traffic.note(&msg);
+                if msg.is_ok() {
+                    traffic.received.fetch_add(1, Ordering::Relaxed);
+                }
+                traffic.disconnected.store(disconnected, Ordering::Relaxed);
+                unqueued = Some(ManagerEvent { role: role.clone(), msg });
+            }
+            permit = events.reserve(), if unqueued.is_some() => {
+                // The Manager dropped
+                let Ok(permit) = permit else {
+                    return;
+                };
+                if let Some(event) = unqueued.take() {
+                    permit.send(event);
+                }
+            }
+        }
+    }
+}
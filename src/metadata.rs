@@ -0,0 +1,95 @@
+//! Key-value headers that ride along with a message
+//!
+//! For things every message may need, like a session ID or a tenant, without
+//! adding them to each variant of the app's message enums. A frame with
+//! metadata wraps the usual frame in `Meta`, so frames without it are
+//! unchanged on the wire.
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{Error, ManagerMsgInternal, WorkerMsgInternal};
+
+/// Headers for one message, see `Server::send_with` and `Client::send_with`
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Metadata(BTreeMap<String, String>);
+
+impl Metadata {
+    /// Correlates log lines across both processes
+    pub const REQUEST_ID: &'static str = "request-id";
+    /// The user session the message acts for
+    pub const SESSION: &'static str = "session";
+    pub const TENANT: &'static str = "tenant";
+    /// W3C trace context, like `ManagerMsgInternal::Request` carries for calls
+    pub const TRACEPARENT: &'static str = "traceparent";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.insert(key, value);
+        self
+    }
+
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.0.insert(key.into(), value.into());
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<T> ManagerMsgInternal<T> {
+    /// Wraps the frame in `Meta`, unless there's no metadata
+    pub(crate) fn with_metadata(self, metadata: &Metadata) -> Self {
+        if metadata.is_empty() {
+            return self;
+        }
+        Self::Meta(metadata.clone(), Box::new(self))
+    }
+
+    /// Splits a `Meta` frame into its headers and the frame inside
+    pub(crate) fn split_metadata(self) -> Result<(Option<Metadata>, Self), Error> {
+        let Self::Meta(metadata, msg) = self else {
+            return Ok((None, self));
+        };
+        if matches!(*msg, Self::Meta(..)) {
+            return Err(Error::Protocol);
+        }
+        Ok((Some(metadata), *msg))
+    }
+}
+
+impl<T> WorkerMsgInternal<T> {
+    /// Wraps the frame in `Meta`, unless there's no metadata
+    pub(crate) fn with_metadata(self, metadata: &Metadata) -> Self {
+        if metadata.is_empty() {
+            return self;
+        }
+        Self::Meta(metadata.clone(), Box::new(self))
+    }
+
+    /// Splits a `Meta` frame into its headers and the frame inside
+    pub(crate) fn split_metadata(self) -> Result<(Option<Metadata>, Self), Error> {
+        let Self::Meta(metadata, msg) = self else {
+            return Ok((None, self));
+        };
+        if matches!(*msg, Self::Meta(..)) {
+            return Err(Error::Protocol);
+        }
+        Ok((Some(metadata), *msg))
+    }
+}
@@ -0,0 +1,477 @@
+//! Per-method message counts, payload sizes, and call latency
+//!
+//! Every `Server` keeps a table keyed by the enum variant name of each message,
+//! the same name `PendingCall::method` uses. Sizes are frame body lengths, so
+//! they include the envelope. Read it with `Server::method_stats` or
+//! `Manager::method_stats`, or export it with `Manager::write_prometheus`.
+
+use serde::{
+    de::{self, DeserializeSeed, IgnoredAny, MapAccess, SeqAccess, Visitor},
+    ser::{self, Impossible},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+/// Upper bounds for payload sizes, in bytes
+const SIZE_BOUNDS: &[f64] = &[
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0,
+];
+
+/// Upper bounds for call latency, in seconds
+const LATENCY_BOUNDS: &[f64] = &[0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Observations sorted into fixed buckets, like a Prometheus histogram
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Histogram {
+    /// Each bucket's upper bound and how many observations were at or below it.
+    /// Counts are cumulative, and observations above the last bound only show up in `count`.
+    pub buckets: Vec<(f64, u64)>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Histogram {
+    pub(crate) fn with_bounds(bounds: &[f64]) -> Self {
+        Self {
+            buckets: bounds.iter().map(|bound| (*bound, 0)).collect(),
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    pub(crate) fn observe(&mut self, value: f64) {
+        for (bound, count) in &mut self.buckets {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// What one connection saw for one method
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MethodStats {
+    /// The name of the message's enum variant, e.g. `"OnUpdateResources"`
+    pub method: String,
+    /// Sizes of the messages and requests written to the worker, in bytes
+    pub sent_bytes: Histogram,
+    /// Sizes of the messages read from the worker, in bytes
+    pub received_bytes: Histogram,
+    /// Time from writing a request to its response or failure, in seconds.
+    /// This covers the worker's handler plus the round trip.
+    pub call_seconds: Histogram,
+}
+
+impl MethodStats {
+    fn new(method: String) -> Self {
+        Self {
+            method,
+            sent_bytes: Histogram::with_bounds(SIZE_BOUNDS),
+            received_bytes: Histogram::with_bounds(SIZE_BOUNDS),
+            call_seconds: Histogram::with_bounds(LATENCY_BOUNDS),
+        }
+    }
+}
+
+/// The per-method table for one connection
+///
+/// Cloning shares it, so a `Manager` can read it while a task owns the `Server`.
+#[derive(Clone, Default)]
+pub(crate) struct MethodTable(Arc<Mutex<BTreeMap<String, MethodStats>>>);
+
+impl MethodTable {
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, MethodStats>> {
+        // Every update is a few additions to one entry, so a poisoned lock is still usable
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn update(&self, method: &str, f: impl FnOnce(&mut MethodStats)) {
+        let mut table = self.lock();
+        if let Some(stats) = table.get_mut(method) {
+            f(stats);
+            return;
+        }
+        let mut stats = MethodStats::new(method.to_string());
+        f(&mut stats);
+        table.insert(method.to_string(), stats);
+    }
+
+    pub(crate) fn record_sent(&self, method: &str, len: usize) {
+        self.update(method, |stats| stats.sent_bytes.observe(len as f64));
+    }
+
+    pub(crate) fn record_received(&self, method: &str, len: usize) {
+        self.update(method, |stats| stats.received_bytes.observe(len as f64));
+    }
+
+    pub(crate) fn record_call(&self, method: &str, elapsed: Duration) {
+        self.update(method, |stats| {
+            stats.call_seconds.observe(elapsed.as_secs_f64())
+        });
+    }
+
+    /// Every method seen so far, sorted by name
+    pub(crate) fn snapshot(&self) -> Vec<MethodStats> {
+        self.lock().values().cloned().collect()
+    }
+}
+
+/// Returns the method of a `User` frame from the worker, see `frame_method`
+pub(crate) fn received_method(buf: &str) -> String {
+    frame_method(buf).unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Returns the method of a `User` or `Request` frame from the manager, see `frame_method`
+pub(crate) fn received_manager_method(buf: &str) -> Option<String> {
+    frame_method(buf)
+}
+
+/// Reads the variant name of the message in a `User` or `Request` frame
+///
+/// The frame was already decoded, so this stops at the name instead of parsing
+/// the payload a second time. `W` only has to be `DeserializeOwned`, so the name
+/// can't come from the decoded message.
+fn frame_method(buf: &str) -> Option<String> {
+    let mut method = None;
+    // Every path ends in an error, since the rest of the frame is never read
+    let _ = Frame(&mut method).deserialize(&mut serde_json::Deserializer::from_str(buf));
+    method
+}
+
+/// Stops reading, once the method is known or known to be missing
+fn stop<E: de::Error>() -> E {
+    E::custom("stopped at the method")
+}
+
+/// `{"User": msg}`, `{"Request": {"msg": msg, ..}}`, or `{"Meta": [headers, frame]}`
+struct Frame<'a>(&'a mut Option<String>);
+
+impl<'de> DeserializeSeed<'de> for Frame<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Frame<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a frame")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        match map.next_key::<&str>()? {
+            Some("User") => map.next_value_seed(VariantName(self.0)),
+            Some("Request") => map.next_value_seed(RequestMsg(self.0)),
+            Some("Meta") => map.next_value_seed(Meta(self.0)),
+            _ => Err(stop()),
+        }
+    }
+}
+
+/// The frame after a `Meta` frame's headers
+struct Meta<'a>(&'a mut Option<String>);
+
+impl<'de> DeserializeSeed<'de> for Meta<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for Meta<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("headers and a frame")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        seq.next_element::<IgnoredAny>()?;
+        seq.next_element_seed(Frame(self.0))?;
+        Err(stop())
+    }
+}
+
+/// The `msg` field of a `Request`
+struct RequestMsg<'a>(&'a mut Option<String>);
+
+impl<'de> DeserializeSeed<'de> for RequestMsg<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for RequestMsg<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a request")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        while let Some(key) = map.next_key::<&str>()? {
+            if key == "msg" {
+                return map.next_value_seed(VariantName(self.0));
+            }
+            map.next_value::<IgnoredAny>()?;
+        }
+        Err(stop())
+    }
+}
+
+/// The variant name of an externally tagged enum, without reading its fields
+struct VariantName<'a>(&'a mut Option<String>);
+
+impl<'de> DeserializeSeed<'de> for VariantName<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> Visitor<'de> for VariantName<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an enum variant")
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<(), E> {
+        *self.0 = Some(name.to_string());
+        Err(stop())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        *self.0 = Some(
+            map.next_key::<String>()?
+                .unwrap_or_else(|| "unknown".to_string()),
+        );
+        Err(stop())
+    }
+}
+
+/// Returns the variant name of a message about to be sent, without serializing its fields
+///
+/// Matches what `frame_method` reads back from the frame.
+pub(crate) fn sent_method(msg: &impl Serialize) -> String {
+    msg.serialize(MethodSerializer)
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Serializes an enum to its variant name, and anything else but a string to an error
+struct MethodSerializer;
+
+/// A tuple or struct variant's name, with its fields skipped
+struct SkipFields(&'static str);
+
+impl ser::SerializeTupleVariant for SkipFields {
+    type Ok = String;
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, _: &T) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.to_string())
+    }
+}
+
+impl ser::SerializeStructVariant for SkipFields {
+    type Ok = String;
+    type Error = serde_json::Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        _: &T,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn end(self) -> Result<String, Self::Error> {
+        Ok(self.0.to_string())
+    }
+}
+
+/// What `MethodSerializer` returns for anything that has no method name
+fn no_method() -> serde_json::Error {
+    ser::Error::custom("not an enum")
+}
+
+impl Serializer for MethodSerializer {
+    type Ok = String;
+    type Error = serde_json::Error;
+    type SerializeSeq = Impossible<String, Self::Error>;
+    type SerializeTuple = Impossible<String, Self::Error>;
+    type SerializeTupleStruct = Impossible<String, Self::Error>;
+    type SerializeTupleVariant = SkipFields;
+    type SerializeMap = Impossible<String, Self::Error>;
+    type SerializeStruct = Impossible<String, Self::Error>;
+    type SerializeStructVariant = SkipFields;
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<String, Self::Error> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: &T,
+    ) -> Result<String, Self::Error> {
+        Ok(variant.to_string())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<SkipFields, Self::Error> {
+        Ok(SkipFields(variant))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        _: usize,
+    ) -> Result<SkipFields, Self::Error> {
+        Ok(SkipFields(variant))
+    }
+
+    /// A string message reads back as its own method, like a unit variant
+    fn serialize_str(self, v: &str) -> Result<String, Self::Error> {
+        Ok(v.to_string())
+    }
+
+    /// Wrappers like `Box` and `Arc` serialize as what they hold
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<String, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<String, Self::Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_i8(self, _: i8) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_i16(self, _: i16) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_i32(self, _: i32) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_i64(self, _: i64) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_u8(self, _: u8) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_u16(self, _: u16) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_u32(self, _: u32) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_u64(self, _: u64) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_f32(self, _: f32) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_f64(self, _: f64) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_char(self, _: char) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_none(self) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_unit(self) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<String, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_tuple(self, _: usize) -> Result<Self::SerializeTuple, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_map(self, _: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+        Err(no_method())
+    }
+
+    fn serialize_struct(
+        self,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self::SerializeStruct, Self::Error> {
+        Err(no_method())
+    }
+}
@@ -1,16 +1,34 @@
 //! Integration and unit tests for IPC security, leak guard, etc.
+//!
+//! Without the `harness` feature, only the unit tests' messages and helpers are used.
+#![cfg_attr(not(feature = "harness"), allow(dead_code))]
 
 // TODO: Try making these into no-harness integration tests, if the IPC module
 // ends up living long enough. See <https://doc.rust-lang.org/cargo/commands/cargo-test.html>
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
-use std::time::{Duration, Instant};
+use std::{
+    collections::HashSet,
+    io::{Read as _, Seek as _, Write as _},
+    sync::atomic::Ordering,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::time::timeout;
+use windows::Win32::System::Threading::CREATE_BREAKAWAY_FROM_JOB;
 
 use crate::{
-    server::UnconnectedServer, Client, LeakGuard, ManagerMsgInternal, Server, SubcommandChild,
-    SubcommandExit, Subprocess,
+    disconnect::PeerProcess,
+    orphans,
+    os::{
+        self,
+        handles::{self, RemoteHandle},
+    },
+    sys,
+    testing::{self, CrashHarness, LatencyBudget},
+    Client, Config, DisconnectReason, Error, Fingerprint, LeakGuard, LeakGuardOptions, Manager,
+    ManagerMsgInternal, PipeId, Redact, Scoped, SecretDelivery, Server, SubcommandChild,
+    SubcommandExit, Subprocess, SubprocessBuilder,
 };
 
 #[derive(clap::Subcommand)]
@@ -18,15 +36,97 @@ pub(crate) enum Subcommand {
     LeakManager {
         #[arg(long, action = clap::ArgAction::Set)]
         enable_protection: bool,
-        pipe_id: String,
+        #[arg(value_parser = PipeId::from_arg)]
+        pipe_id: PipeId,
     },
     LeakWorker {
-        pipe_id: String,
+        #[arg(value_parser = PipeId::from_arg)]
+        pipe_id: PipeId,
     },
 
     ApiWorker {
-        pipe_id: String,
+        #[arg(value_parser = PipeId::from_arg)]
+        pipe_id: PipeId,
+    },
+    /// Gets its pipe ID from `SecretDelivery::Stdin` or `SecretDelivery::Env`
+    InheritedWorker,
+    /// Spawns a child of its own and reports its PID
+    ParentWorker {
+        /// Start the child with `CREATE_BREAKAWAY_FROM_JOB`
+        #[arg(long, action = clap::ArgAction::Set)]
+        breakaway: bool,
+        #[arg(value_parser = PipeId::from_arg)]
+        pipe_id: PipeId,
     },
+    /// Reads files through handles the manager duplicates into it, see `test_mixed_arch`
+    ArchWorker {
+        #[arg(value_parser = PipeId::from_arg)]
+        pipe_id: PipeId,
+    },
+    /// Never finishes the handshake, optionally without even opening the pipe
+    IdleWorker {
+        #[arg(long, action = clap::ArgAction::Set)]
+        connect: bool,
+        #[arg(value_parser = PipeId::from_arg)]
+        pipe_id: PipeId,
+    },
+
+    /// Soak test, not part of the default run
+    Stress(StressArgs),
+    StressWorker {
+        #[arg(value_parser = PipeId::from_arg)]
+        pipe_id: PipeId,
+    },
+    /// Benchmark for `Config::pipe_buffer_size` and `read_buffer_size`, not part of the default run
+    Throughput(ThroughputArgs),
+    ThroughputWorker {
+        #[arg(long)]
+        read_buffer_size: usize,
+        #[arg(value_parser = PipeId::from_arg)]
+        pipe_id: PipeId,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct StressArgs {
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+    /// Calls per second, across all workers
+    #[arg(long, default_value_t = 200)]
+    rate: u32,
+    #[arg(long, default_value_t = 60)]
+    duration_secs: u64,
+    /// Largest random payload in bytes
+    #[arg(long, default_value_t = 64 * 1024)]
+    max_payload: u64,
+    /// Average seconds between killing a random worker. 0 disables kills.
+    #[arg(long, default_value_t = 10)]
+    kill_every_secs: u64,
+    /// Fail if any call takes longer than this
+    #[arg(long, default_value_t = 500)]
+    max_latency_ms: u64,
+    /// Seed for payload sizes and kills. Random if not set, and logged either way.
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+#[derive(clap::Args, Debug)]
+pub(crate) struct ThroughputArgs {
+    /// Payload sizes to measure, in bytes
+    #[arg(
+        long,
+        value_delimiter = ',',
+        default_value = "64,1024,16384,262144,1048576"
+    )]
+    frame_sizes: Vec<usize>,
+    /// How much to send at each size
+    #[arg(long, default_value_t = 64)]
+    megabytes: usize,
+    #[arg(long, default_value_t = Config::default().pipe_buffer_size)]
+    pipe_buffer_size: u32,
+    /// Used by both sides
+    #[arg(long, default_value_t = Config::default().read_buffer_size)]
+    read_buffer_size: usize,
 }
 
 pub(crate) fn run(cmd: Option<Subcommand>) -> Result<()> {
@@ -37,9 +137,28 @@ pub(crate) fn run(cmd: Option<Subcommand>) -> Result<()> {
             None => {
                 test_api().await.context("test_api failed")?;
                 tracing::info!("test_api passed");
+                test_secret_delivery(SecretDelivery::Stdin).await?;
+                test_secret_delivery(SecretDelivery::Env).await?;
+                tracing::info!("test_secret_delivery passed");
+                test_wine_fallback()
+                    .await
+                    .context("test_wine_fallback failed")?;
+                tracing::info!("test_wine_fallback passed");
+                test_handshake_errors()
+                    .await
+                    .context("test_handshake_errors failed")?;
+                tracing::info!("test_handshake_errors passed");
+                test_grandchild(false)
+                    .await
+                    .context("test_grandchild(false) failed")?;
+                test_grandchild(true)
+                    .await
+                    .context("test_grandchild(true) failed")?;
+                tracing::info!("test_grandchild passed");
                 test_leak(false).await.context("test_leak(false) failed")?;
                 test_leak(true).await.context("test_leak(true) failed")?;
                 tracing::info!("test_leak passed");
+                test_mixed_arch().await.context("test_mixed_arch failed")?;
                 tracing::info!("all tests passed");
                 Ok(())
             }
@@ -49,6 +168,21 @@ pub(crate) fn run(cmd: Option<Subcommand>) -> Result<()> {
             }) => leak_manager(pipe_id, enable_protection),
             Some(Subcommand::LeakWorker { pipe_id }) => leak_worker(pipe_id).await,
             Some(Subcommand::ApiWorker { pipe_id }) => test_api_worker(pipe_id).await,
+            Some(Subcommand::InheritedWorker) => inherited_worker().await,
+            Some(Subcommand::ParentWorker { breakaway, pipe_id }) => {
+                parent_worker(breakaway, pipe_id).await
+            }
+            Some(Subcommand::ArchWorker { pipe_id }) => arch_worker(pipe_id).await,
+            Some(Subcommand::IdleWorker { connect, pipe_id }) => {
+                idle_worker(connect, pipe_id).await
+            }
+            Some(Subcommand::Stress(args)) => stress(args).await,
+            Some(Subcommand::StressWorker { pipe_id }) => stress_worker(pipe_id).await,
+            Some(Subcommand::Throughput(args)) => throughput(args).await,
+            Some(Subcommand::ThroughputWorker {
+                read_buffer_size,
+                pipe_id,
+            }) => throughput_worker(read_buffer_size, pipe_id).await,
         }
     })?;
     Ok(())
@@ -58,12 +192,20 @@ pub(crate) fn run(cmd: Option<Subcommand>) -> Result<()> {
 #[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub(crate) enum ManagerMsg {
     Connect,
+    /// Padding for the stress test, the worker sends it back
+    Echo(String),
+    /// A file for `arch-worker` to read and send back in `Echo`
+    ReadFile(RemoteHandle),
 }
 
 /// A message from the worker process
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 pub(crate) enum WorkerMsg {
     Callback(Callback),
+    /// A process the worker spawned, see `test_grandchild`
+    // The alias lets `scopes` check that another wire name can't skip the scope
+    #[serde(alias = "Pid")]
+    ChildPid(u32),
     Response(ManagerMsg), // For debugging, just say what manager request we're responding to
 }
 
@@ -78,6 +220,19 @@ pub(crate) enum Callback {
     TunnelReady,
 }
 
+impl Redact for ManagerMsg {}
+
+impl Redact for WorkerMsg {}
+
+impl Scoped for WorkerMsg {
+    fn required_scope(&self) -> Option<&'static str> {
+        match self {
+            Self::ChildPid(_) => Some("admin"),
+            Self::Callback(_) | Self::Response(_) => None,
+        }
+    }
+}
+
 #[tracing::instrument(skip_all)]
 async fn test_api() -> Result<()> {
     let start_time = Instant::now();
@@ -89,7 +244,9 @@ async fn test_api() -> Result<()> {
         mut worker,
     } = timeout(
         Duration::from_secs(10),
-        Subprocess::new(&mut leak_guard, &args),
+        SubprocessBuilder::new(&args)
+            .resume_state(b"resume".to_vec())
+            .spawn(&mut leak_guard),
     )
     .await??;
     tracing::debug!("Manager got connection from worker");
@@ -99,6 +256,10 @@ async fn test_api() -> Result<()> {
         .await
         .context("should have gotten a TunnelReady callback")?;
     assert_eq!(msg, WorkerMsg::Callback(Callback::TunnelReady));
+    anyhow::ensure!(
+        server.connection_info().peer_fingerprint == Some(Fingerprint::current()),
+        "worker should have sent its fingerprint before any message"
+    );
 
     let msg = server
         .next()
@@ -116,19 +277,11 @@ async fn test_api() -> Result<()> {
         .context("should have gotten a response to Connect")?;
     anyhow::ensure!(msg == WorkerMsg::Response(ManagerMsg::Connect));
 
-    let elapsed = start_time.elapsed();
-    anyhow::ensure!(
-        elapsed < Duration::from_millis(100),
-        "IPC took too long: {elapsed:?}"
-    );
+    LatencyBudget::new("IPC", Duration::from_millis(100)).check_since(start_time)?;
 
-    let timer = Instant::now();
-    server.close().await?;
-    let elapsed = timer.elapsed();
-    anyhow::ensure!(
-        elapsed < Duration::from_millis(20),
-        "Server took too long to close: {elapsed:?}"
-    );
+    LatencyBudget::new("Server::close", Duration::from_millis(20))
+        .time(server.close())
+        .await??;
 
     assert_eq!(
         worker.wait_then_kill(Duration::from_secs(5)).await?,
@@ -139,8 +292,10 @@ async fn test_api() -> Result<()> {
 }
 
 #[tracing::instrument(skip_all)]
-async fn test_api_worker(pipe_id: String) -> Result<()> {
+async fn test_api_worker(pipe_id: PipeId) -> Result<()> {
     let mut client = Client::new(&pipe_id).await?;
+    anyhow::ensure!(client.resume_state() == Some(b"resume".as_slice()));
+    anyhow::ensure!(client.connection_info().peer_fingerprint == Some(Fingerprint::current()));
 
     client
         .send(WorkerMsg::Callback(Callback::TunnelReady))
@@ -160,16 +315,309 @@ async fn test_api_worker(pipe_id: String) -> Result<()> {
         client.send(WorkerMsg::Response(req)).await?;
     }
 
-    let timer = Instant::now();
+    // The manager is reading, so waiting for it to take the last frames is quick
+    let unsent = LatencyBudget::new("Client::close_within", Duration::from_millis(5))
+        .time(client.close_within(Duration::from_secs(5)))
+        .await??;
+    anyhow::ensure!(unsent == 0);
+    Ok(())
+}
+
+/// Spawns a worker without putting the pipe ID on its command line
+#[tracing::instrument]
+async fn test_secret_delivery(secret_delivery: SecretDelivery) -> Result<()> {
+    let mut leak_guard = LeakGuard::new()?;
+    let Subprocess {
+        mut server,
+        mut worker,
+    } = timeout(
+        Duration::from_secs(10),
+        SubprocessBuilder::new(&["inherited-worker"])
+            .secret_delivery(secret_delivery)
+            .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard),
+    )
+    .await
+    .context("worker didn't connect in time")??;
+
+    server.send(ManagerMsg::Connect).await?;
+    let msg = server
+        .next()
+        .await
+        .context("should have gotten a response to Connect")?;
+    anyhow::ensure!(msg == WorkerMsg::Response(ManagerMsg::Connect));
+    server.close().await?;
+    assert_eq!(
+        worker.wait_then_kill(Duration::from_secs(5)).await?,
+        SubcommandExit::Success
+    );
+    Ok(())
+}
+
+/// Spawns a worker while acting like a Wine that can't read the pipe client's
+/// PID, so the manager has only the cookie to go on
+#[tracing::instrument]
+async fn test_wine_fallback() -> Result<()> {
+    let mut leak_guard = LeakGuard::new()?;
+    os::wine::SIMULATE.store(true, Ordering::Relaxed);
+    let spawned = timeout(
+        Duration::from_secs(10),
+        SubprocessBuilder::new(&["inherited-worker"])
+            .secret_delivery(SecretDelivery::Env)
+            .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard),
+    )
+    .await;
+    os::wine::SIMULATE.store(false, Ordering::Relaxed);
+    let Subprocess {
+        mut server,
+        mut worker,
+    } = spawned.context("worker didn't connect in time")??;
+    anyhow::ensure!(
+        Some(server.connection_info().peer_pid) == worker.process.id(),
+        "should have fallen back to the child's PID"
+    );
+
+    server.send(ManagerMsg::Connect).await?;
+    let msg = server
+        .next()
+        .await
+        .context("should have gotten a response to Connect")?;
+    anyhow::ensure!(msg == WorkerMsg::Response(ManagerMsg::Connect));
+    server.close().await?;
+    assert_eq!(
+        worker.wait_then_kill(Duration::from_secs(5)).await?,
+        SubcommandExit::Success
+    );
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn inherited_worker() -> Result<()> {
+    let mut client = Client::new_inherited(&Config::default()).await?;
+    anyhow::ensure!(
+        std::env::var_os("SUBZONE_PIPE_ID").is_none(),
+        "pipe ID should be scrubbed from the environment"
+    );
+    while let ManagerMsgInternal::User(req) = client.next().await? {
+        client.send(WorkerMsg::Response(req)).await?;
+    }
     client.close().await?;
-    let elapsed = timer.elapsed();
+    Ok(())
+}
+
+/// Checks that a worker that never connects and one that never finishes the
+/// handshake fail with different errors
+#[tracing::instrument]
+async fn test_handshake_errors() -> Result<()> {
+    let mut leak_guard = LeakGuard::new()?;
+    let config = Config {
+        accept_timeout: Duration::from_millis(500),
+        handshake_timeout: Duration::from_millis(500),
+        ..Default::default()
+    };
+
+    let error = SubprocessBuilder::new(&["idle-worker", "--connect", "false"])
+        .config(config.clone())
+        .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard)
+        .await
+        .err()
+        .context("spawn should fail if the worker never connects")?;
+    anyhow::ensure!(
+        matches!(error.downcast_ref(), Some(Error::NoConnectionWithin(_))),
+        "{error:#}"
+    );
+
+    let error = SubprocessBuilder::new(&["idle-worker", "--connect", "true"])
+        .config(config)
+        .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard)
+        .await
+        .err()
+        .context("spawn should fail if the worker never sends the cookie")?;
     anyhow::ensure!(
-        elapsed < Duration::from_millis(5),
-        "Client took too long to close: {elapsed:?}"
+        matches!(error.downcast_ref(), Some(Error::HandshakeTimeout { .. })),
+        "{error:#}"
     );
     Ok(())
 }
 
+#[tracing::instrument(skip(pipe_id))]
+async fn idle_worker(connect: bool, pipe_id: PipeId) -> Result<()> {
+    let _client = if connect {
+        Some(Client::<ManagerMsg, WorkerMsg>::new_unsecured(&pipe_id)?)
+    } else {
+        None
+    };
+    // The manager should kill us
+    std::future::pending().await
+}
+
+/// Where `test_mixed_arch` finds a worker built for another arch, e.g.
+/// `target\i686-pc-windows-msvc\debug\subzone.exe`
+const MIXED_ARCH_WORKER_ENV: &str = "SUBZONE_TEST_WORKER_EXE";
+
+/// Runs a worker of another bitness, e.g. a 32-bit one under WOW64 from a 64-bit manager
+///
+/// Checks the pipe's security handshake, the leak guard's job object, and a
+/// handle duplicated across the bitness boundary, then checks that
+/// `Config::require_same_arch` rejects the same worker. Skipped unless
+/// `SUBZONE_TEST_WORKER_EXE` names a build of this exe for another arch.
+#[tracing::instrument]
+async fn test_mixed_arch() -> Result<()> {
+    let Some(exe) = std::env::var_os(MIXED_ARCH_WORKER_ENV) else {
+        tracing::info!("{MIXED_ARCH_WORKER_ENV} isn't set, skipping");
+        return Ok(());
+    };
+    let exe = std::fs::canonicalize(exe)?;
+    let mut leak_guard = LeakGuard::new()?;
+    let Subprocess {
+        mut server,
+        mut worker,
+    } = timeout(
+        Duration::from_secs(10),
+        SubprocessBuilder::new(&["arch-worker"])
+            .exe(&exe)
+            .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard),
+    )
+    .await??;
+    anyhow::ensure!(
+        leak_guard.contains(server.client_pid())?,
+        "worker should be in the job"
+    );
+
+    let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    let mut file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    file.write_all(b"subzone")?;
+    file.rewind()?;
+    let remote = handles::duplicate_into(&file, server.client_pid())?;
+    server.send(ManagerMsg::ReadFile(remote)).await?;
+    let response = server.next().await?;
+    drop(file);
+    std::fs::remove_file(path)?;
+    anyhow::ensure!(
+        response == WorkerMsg::Response(ManagerMsg::Echo("subzone".into())),
+        "{response:?}"
+    );
+
+    // The worker sends its fingerprint before anything else, so `next` has read it by now
+    let ours = Fingerprint::current();
+    let theirs = server
+        .connection_info()
+        .peer_fingerprint
+        .clone()
+        .context("worker should have sent its fingerprint")?;
+    anyhow::ensure!(
+        theirs.arch != ours.arch,
+        "{MIXED_ARCH_WORKER_ENV} should be built for another arch than {}",
+        ours.arch
+    );
+    tracing::info!(
+        theirs = theirs.arch,
+        theirs_native = ?theirs.native_arch,
+        ours = ours.arch,
+        "Worker arch"
+    );
+    server.close().await?;
+    assert_eq!(
+        worker.wait_then_kill(Duration::from_secs(5)).await?,
+        SubcommandExit::Success
+    );
+
+    let error = SubprocessBuilder::new(&["arch-worker"])
+        .exe(&exe)
+        .config(Config {
+            require_same_arch: true,
+            ..Default::default()
+        })
+        .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard)
+        .await
+        .err()
+        .context("spawn should fail if the worker's arch doesn't match")?;
+    let Some(Error::HandshakeRejected { reason, .. }) = error.downcast_ref() else {
+        anyhow::bail!("expected HandshakeRejected, got {error:#}");
+    };
+    anyhow::ensure!(reason.contains(&theirs.arch), "{reason}");
+    Ok(())
+}
+
+#[tracing::instrument(skip(pipe_id))]
+async fn arch_worker(pipe_id: PipeId) -> Result<()> {
+    let mut client = Client::<ManagerMsg, WorkerMsg>::new(&pipe_id).await?;
+    while let ManagerMsgInternal::User(req) = client.next().await? {
+        let ManagerMsg::ReadFile(remote) = req else {
+            client.send(WorkerMsg::Response(req)).await?;
+            continue;
+        };
+        // SAFETY: The manager duplicated it into this process and sends each handle once
+        let mut file = std::fs::File::from(unsafe { remote.into_owned() });
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        client
+            .send(WorkerMsg::Response(ManagerMsg::Echo(contents)))
+            .await?;
+    }
+    client.close().await?;
+    Ok(())
+}
+
+/// Checks that a process the worker spawns lands in the leak guard's job,
+/// unless the worker asks for it to break away and the leak guard allows that
+#[tracing::instrument]
+async fn test_grandchild(breakaway: bool) -> Result<()> {
+    let mut leak_guard = LeakGuard::with_options(LeakGuardOptions::new().breakaway(breakaway))?;
+    let Subprocess {
+        mut server,
+        mut worker,
+    } = timeout(
+        Duration::from_secs(10),
+        SubprocessBuilder::new(&["parent-worker", "--breakaway", &breakaway.to_string()])
+            .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard),
+    )
+    .await??;
+    let WorkerMsg::ChildPid(pid) = server.next().await? else {
+        anyhow::bail!("expected the grandchild's PID");
+    };
+    anyhow::ensure!(
+        leak_guard.contains(pid)? != breakaway,
+        "grandchild should only be in the job if it didn't break away"
+    );
+    let accounting = leak_guard.accounting()?;
+    let expected = if breakaway { 1 } else { 2 };
+    anyhow::ensure!(accounting.total_processes == expected, "{accounting:?}");
+    server.close().await?;
+    assert_eq!(
+        worker.wait_then_kill(Duration::from_secs(5)).await?,
+        SubcommandExit::Success
+    );
+    Ok(())
+}
+
+#[tracing::instrument(skip(pipe_id))]
+async fn parent_worker(breakaway: bool, pipe_id: PipeId) -> Result<()> {
+    let mut client = Client::<ManagerMsg, WorkerMsg>::new(&pipe_id).await?;
+    let mut child = tokio::process::Command::new(std::env::current_exe()?);
+    child
+        .args([
+            "idle-worker",
+            "--connect",
+            "false",
+            &PipeId::random().to_arg(),
+        ])
+        .kill_on_drop(true);
+    if breakaway {
+        child.creation_flags(CREATE_BREAKAWAY_FROM_JOB.0);
+    }
+    // Dropping it kills it
+    let child = child.spawn()?;
+    let pid = child.id().context("child should have a PID")?;
+    client.send(WorkerMsg::ChildPid(pid)).await?;
+    while let ManagerMsgInternal::User(_) = client.next().await? {}
+    client.close().await?;
+    Ok(())
+}
+
 /// Top-level function to test whether the process leak protection works.
 ///
 /// 1. Open a named pipe server
@@ -187,16 +635,12 @@ async fn test_api_worker(pipe_id: String) -> Result<()> {
 /// - [windows-rs docs](https://microsoft.github.io/windows-docs-rs/doc/windows/Win32/System/JobObjects/fn.AssignProcessToJobObject.html)
 #[tracing::instrument]
 async fn test_leak(enable_protection: bool) -> Result<()> {
-    let (server, pipe_id) = UnconnectedServer::new()?;
-    let args = [
+    let mut harness = CrashHarness::spawn_manager(&[
         "leak-manager",
         "--enable-protection",
         &enable_protection.to_string(),
-        &pipe_id,
-    ];
-    let mut manager = SubcommandChild::new(&args)?;
-    let mut server: Server<ManagerMsg, WorkerMsg> =
-        timeout(Duration::from_secs(5), server.accept()).await??;
+    ])?;
+    let mut server: Server<ManagerMsg, WorkerMsg> = harness.accept(Duration::from_secs(5)).await?;
 
     tracing::debug!("Actual pipe client PID = {}", server.client_pid());
     tracing::debug!("Harness accepted connection from Worker");
@@ -210,38 +654,31 @@ async fn test_leak(enable_protection: bool) -> Result<()> {
             .expect("should have gotten a response to Connect");
     }
 
-    timeout(Duration::from_secs(5), manager.process.kill()).await??;
-    tracing::debug!("Harness killed manager");
+    harness.kill_manager().await?;
 
     // I can't think of a good way to synchronize with the worker process stopping,
-    // so just give it 10 seconds for Windows to stop it.
-    for _ in 0..5 {
-        if server.send(ManagerMsg::Connect).await.is_err() {
-            tracing::info!("confirmed worker stopped responding");
-            break;
-        }
-        if server.next().await.is_err() {
-            tracing::info!("confirmed worker stopped responding");
-            break;
-        }
-        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
-    }
+    // so just give it 5 seconds for Windows to stop it.
+    let stopped = testing::worker_stopped(&mut server, Duration::from_secs(5)).await;
 
     if enable_protection {
         assert!(
-            server.send(ManagerMsg::Connect).await.is_err(),
+            stopped,
             "worker shouldn't be able to respond here, it should have stopped when the manager stopped"
         );
         assert!(
-            server.next().await.is_err(),
+            matches!(
+                server.next().await,
+                Err(Error::Disconnected(DisconnectReason::PeerExited { .. }))
+            ),
             "worker shouldn't be able to respond here, it should have stopped when the manager stopped"
         );
         tracing::info!("enabling leak protection worked");
     } else {
         assert!(
-            server.send(ManagerMsg::Connect).await.is_ok(),
+            !stopped,
             "worker should still respond here, this failure means the test is invalid"
         );
+        server.send(ManagerMsg::Connect).await?;
         assert!(
             server.next().await.is_ok(),
             "worker should still respond here, this failure means the test is invalid"
@@ -252,10 +689,10 @@ async fn test_leak(enable_protection: bool) -> Result<()> {
 }
 
 #[tracing::instrument]
-fn leak_manager(pipe_id: String, enable_protection: bool) -> Result<()> {
+fn leak_manager(pipe_id: PipeId, enable_protection: bool) -> Result<()> {
     let mut leak_guard = LeakGuard::new()?;
 
-    let worker = SubcommandChild::new(&["leak-worker", &pipe_id])?;
+    let worker = SubcommandChild::new(&["leak-worker", &pipe_id.to_arg()])?;
     tracing::debug!("Expected worker PID = {}", worker.process.id().unwrap());
 
     if enable_protection {
@@ -269,8 +706,8 @@ fn leak_manager(pipe_id: String, enable_protection: bool) -> Result<()> {
 }
 
 #[tracing::instrument(skip_all)]
-async fn leak_worker(pipe_id: String) -> Result<()> {
-    let mut client = Client::new_unsecured(&pipe_id)?;
+async fn leak_worker(pipe_id: PipeId) -> Result<()> {
+    let mut client = testing::connect_to_harness(&pipe_id)?;
     tracing::debug!("Worker connected to named pipe");
     loop {
         let ManagerMsgInternal::User(req) = client.next().await? else {
@@ -283,6 +720,239 @@ async fn leak_worker(pipe_id: String) -> Result<()> {
     Ok(())
 }
 
+/// Runs random-sized calls against several workers while killing some of them,
+/// then checks that nothing leaked
+#[tracing::instrument]
+async fn stress(args: StressArgs) -> Result<()> {
+    let seed = args.seed.unwrap_or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_nanos() as u64)
+            .unwrap_or(1)
+    });
+    tracing::info!(?seed, "Starting stress test, pass `--seed` to reproduce it");
+    let mut rng = XorShift::new(seed);
+    let handles = sys::HandleSnapshot::new()?;
+    let our_exe = std::env::current_exe()?;
+
+    let mut leak_guard = LeakGuard::new()?;
+    let mut manager = Manager::<ManagerMsg, WorkerMsg>::new();
+    let mut roles = vec![];
+    let mut pids = vec![];
+    for i in 0..args.workers {
+        let role = format!("worker-{i}");
+        pids.push(spawn_stress_worker(&mut manager, &mut leak_guard, &role).await?);
+        roles.push(role);
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_secs(1) / args.rate.max(1));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let deadline = Instant::now() + Duration::from_secs(args.duration_secs);
+    let mut killed = HashSet::new();
+    let mut latencies = vec![];
+    let mut generation = 0;
+    while Instant::now() < deadline {
+        tokio::select! {
+            _ = interval.tick() => {}
+            event = manager.next() => {
+                match event.msg {
+                    Err(Error::Disconnected(_)) if killed.contains(&event.role) => {}
+                    msg => anyhow::bail!("unexpected event from {}: {msg:?}", event.role),
+                }
+                continue;
+            }
+        }
+
+        let i = usize::try_from(rng.below(roles.len() as u64))?;
+        if args.kill_every_secs > 0 && rng.below(args.kill_every_secs * u64::from(args.rate)) == 0 {
+            let role = roles[i].clone();
+            let pid = manager.client_pid(&role).context("role should exist")?;
+            orphans::kill_if_running(pid, &our_exe, None)?;
+            killed.insert(role.clone());
+            manager.remove(&role, Duration::from_secs(1)).await?;
+            generation += 1;
+            roles[i] = format!("worker-{i}-{generation}");
+            pids.push(spawn_stress_worker(&mut manager, &mut leak_guard, &roles[i]).await?);
+            tracing::info!(killed = role, replacement = roles[i], "Killed a worker");
+            continue;
+        }
+
+        let payload = "x".repeat(usize::try_from(rng.below(args.max_payload))?);
+        let start = Instant::now();
+        let response = timeout(
+            Duration::from_secs(5),
+            manager.call(&roles[i], ManagerMsg::Echo(payload.clone())),
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "call to {} deadlocked, pending calls: {:?}",
+                roles[i],
+                manager.pending_calls(&roles[i])
+            )
+        })??;
+        latencies.push(start.elapsed());
+        anyhow::ensure!(response == WorkerMsg::Response(ManagerMsg::Echo(payload)));
+    }
+
+    latencies.sort();
+    let max_latency = latencies.last().copied().unwrap_or_default();
+    let p99 = latencies
+        .get(latencies.len() * 99 / 100)
+        .copied()
+        .unwrap_or_default();
+    tracing::info!(
+        calls = latencies.len(),
+        kills = killed.len(),
+        ?p99,
+        ?max_latency,
+        "Stress test finished"
+    );
+    anyhow::ensure!(
+        max_latency <= Duration::from_millis(args.max_latency_ms),
+        "slowest call took {max_latency:?}"
+    );
+
+    manager.close(Duration::from_secs(5)).await?;
+    for pid in pids {
+        anyhow::ensure!(
+            !PeerProcess::open(pid).is_some_and(|process| process.is_running()),
+            "worker {pid} is still running"
+        );
+    }
+    // Tokio's thread pool may have grown a little, but not by one handle per call
+    handles.check(100)?;
+    Ok(())
+}
+
+async fn spawn_stress_worker(
+    manager: &mut Manager<ManagerMsg, WorkerMsg>,
+    leak_guard: &mut LeakGuard,
+    role: &str,
+) -> Result<u32> {
+    let subprocess = timeout(
+        Duration::from_secs(10),
+        SubprocessBuilder::new(&["stress-worker"]).spawn(leak_guard),
+    )
+    .await??;
+    let pid = subprocess.server.client_pid();
+    manager.add(role, subprocess)?;
+    Ok(pid)
+}
+
+#[tracing::instrument(skip_all)]
+async fn stress_worker(pipe_id: PipeId) -> Result<()> {
+    let mut client = Client::<ManagerMsg, WorkerMsg>::new(&pipe_id).await?;
+    loop {
+        match client.next().await? {
+            ManagerMsgInternal::Request { id, msg, .. } => {
+                client.respond(id, WorkerMsg::Response(msg)).await?
+            }
+            ManagerMsgInternal::Shutdown => break,
+            _ => {}
+        }
+    }
+    client.close().await?;
+    Ok(())
+}
+
+/// Sends `megabytes` at each frame size to a worker and logs the rate
+///
+/// A call after the last frame of each size marks when the worker has read them all.
+#[tracing::instrument]
+async fn throughput(args: ThroughputArgs) -> Result<()> {
+    let config = Config {
+        pipe_buffer_size: args.pipe_buffer_size,
+        read_buffer_size: args.read_buffer_size,
+        ..Default::default()
+    };
+    let read_buffer_size = args.read_buffer_size.to_string();
+    let mut leak_guard = LeakGuard::new()?;
+    let Subprocess {
+        mut server,
+        mut worker,
+    } = SubprocessBuilder::new(&["throughput-worker", "--read-buffer-size", &read_buffer_size])
+        .config(config)
+        .spawn::<ManagerMsg, WorkerMsg>(&mut leak_guard)
+        .await?;
+    let info = server.connection_info();
+    tracing::info!(
+        in_buffer_size = info.in_buffer_size,
+        out_buffer_size = info.out_buffer_size,
+        "Connected"
+    );
+
+    for size in args.frame_sizes {
+        let payload = "x".repeat(size);
+        let frames = (args.megabytes * 1024 * 1024 / size.max(1)).max(1);
+        let start = Instant::now();
+        for _ in 0..frames {
+            server.send(ManagerMsg::Echo(payload.clone())).await?;
+        }
+        let mut done = server.call(ManagerMsg::Connect).await?;
+        tokio::select! {
+            result = &mut done => { result?; }
+            msg = server.next() => anyhow::bail!("unexpected message {msg:?}"),
+        }
+        let elapsed = start.elapsed();
+        let megabytes_per_sec = (frames * size) as f64 / 1_000_000.0 / elapsed.as_secs_f64();
+        let frames_per_sec = frames as f64 / elapsed.as_secs_f64();
+        tracing::info!(
+            size,
+            frames,
+            ?elapsed,
+            megabytes_per_sec = format!("{megabytes_per_sec:.1}"),
+            frames_per_sec = format!("{frames_per_sec:.0}"),
+            "Measured throughput"
+        );
+    }
+
+    server.close().await?;
+    worker.wait_then_kill(Duration::from_secs(5)).await?;
+    Ok(())
+}
+
+#[tracing::instrument(skip_all)]
+async fn throughput_worker(read_buffer_size: usize, pipe_id: PipeId) -> Result<()> {
+    let config = Config {
+        read_buffer_size,
+        ..Default::default()
+    };
+    let mut client = Client::<ManagerMsg, WorkerMsg>::new_with_config(&pipe_id, &config).await?;
+    loop {
+        match client.next().await? {
+            ManagerMsgInternal::Request { id, msg, .. } => {
+                client.respond(id, WorkerMsg::Response(msg)).await?
+            }
+            ManagerMsgInternal::Shutdown => break,
+            _ => {}
+        }
+    }
+    client.close().await?;
+    Ok(())
+}
+
+/// xorshift64*, so runs are reproducible without pulling in `rand`
+///
+/// Also used by `ChaosLayer`.
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    pub(crate) fn new(seed: u64) -> Self {
+        // Zero is a fixed point
+        Self(seed.max(1))
+    }
+
+    /// Returns a number in `0..n`, or 0 if `n` is 0
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let x = self.0.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        x.checked_rem(n).unwrap_or(0)
+    }
+}
+
 // Duplicated because I want this to be private in both test modules
 fn sample_resources() -> Vec<String> {
     vec![
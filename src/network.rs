@@ -0,0 +1,118 @@
+//! Telling workers about network interface changes
+//!
+//! The tunnel worker needs to re-bind when the network changes, so the manager
+//! watches for changes with `NetworkWatcher` and forwards them with
+//! `Manager::broadcast_network_change`. The app protocol doesn't need its own message for it.
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::c_void;
+use tokio::sync::mpsc;
+use windows::Win32::{
+    Foundation::{BOOLEAN, HANDLE},
+    NetworkManagement::IpHelper::{
+        CancelMibChangeNotify2, MibAddInstance, MibDeleteInstance, MibParameterNotification,
+        NotifyIpInterfaceChange, MIB_IPINTERFACE_ROW, MIB_NOTIFICATION_TYPE,
+    },
+    Networking::WinSock::AF_UNSPEC,
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct NetworkChange {
+    /// The interface's index, as used by the IP Helper API
+    pub interface_index: u32,
+    pub kind: NetworkChangeKind,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum NetworkChangeKind {
+    Added,
+    Removed,
+    /// e.g. the interface's metric or MTU changed
+    Changed,
+}
+
+/// Receives IPv4 and IPv6 interface changes from Windows
+pub struct NetworkWatcher {
+    rx: mpsc::UnboundedReceiver<NetworkChange>,
+    registration: HANDLE,
+    /// Windows holds a pointer to this until we unregister
+    _tx: Box<mpsc::UnboundedSender<NetworkChange>>,
+}
+
+impl NetworkWatcher {
+    pub fn new() -> Result<Self> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let tx = Box::new(tx);
+        let context = &*tx as *const mpsc::UnboundedSender<NetworkChange> as *const c_void;
+        let mut registration = HANDLE::default();
+        // SAFETY: The context pointer stays valid until `Drop` unregisters,
+        // since the `Box` never moves its contents.
+        unsafe {
+            NotifyIpInterfaceChange(
+                AF_UNSPEC,
+                Some(interface_callback),
+                Some(context),
+                BOOLEAN(0),
+                &mut registration,
+            )
+        }
+        .context("NotifyIpInterfaceChange")?;
+        Ok(Self {
+            rx,
+            registration,
+            _tx: tx,
+        })
+    }
+
+    /// Waits for the next interface change
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel-safe, internally it calls `tokio::sync::mpsc::UnboundedReceiver::recv`
+    pub async fn next(&mut self) -> NetworkChange {
+        self.rx
+            .recv()
+            .await
+            .expect("the NetworkWatcher keeps a sender, so the channel can't close")
+    }
+}
+
+impl Drop for NetworkWatcher {
+    fn drop(&mut self) {
+        // SAFETY: The handle came from `NotifyIpInterfaceChange` and is only
+        // cancelled here. This waits for running callbacks, so none run after it.
+        if let Err(error) = unsafe { CancelMibChangeNotify2(self.registration) } {
+            tracing::error!(?error, "Couldn't unregister network change notifications");
+        }
+    }
+}
+
+/// Called by Windows on its own thread
+unsafe extern "system" fn interface_callback(
+    context: *const c_void,
+    row: *const MIB_IPINTERFACE_ROW,
+    notification_type: MIB_NOTIFICATION_TYPE,
+) {
+    let kind = if notification_type == MibAddInstance {
+        NetworkChangeKind::Added
+    } else if notification_type == MibDeleteInstance {
+        NetworkChangeKind::Removed
+    } else if notification_type == MibParameterNotification {
+        NetworkChangeKind::Changed
+    } else {
+        // `MibInitialNotification` isn't requested
+        return;
+    };
+    // SAFETY: Windows passes a valid row for every notification except the initial one
+    let Some(row) = (unsafe { row.as_ref() }) else {
+        return;
+    };
+    let change = NetworkChange {
+        interface_index: row.InterfaceIndex,
+        kind,
+    };
+    // SAFETY: `context` is the sender boxed in `NetworkWatcher`, which outlives the registration
+    let tx = unsafe { &*(context as *const mpsc::UnboundedSender<NetworkChange>) };
+    tx.send(change).ok();
+}
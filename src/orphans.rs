@@ -0,0 +1,120 @@
+//! Cleaning up workers left behind by a manager that crashed
+//!
+//! The job object in `LeakGuard` normally kills workers with the manager, but
+//! a worker that was spawned without one, or that outlived the job, keeps
+//! holding resources like the TUN device. Workers can't reconnect to a new
+//! manager, so they're terminated instead of adopted.
+
+use anyhow::{Context as _, Result};
+use std::{
+    fs,
+    io::Write as _,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    etw,
+    sys::{self, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_TERMINATE},
+};
+
+/// Exit code for workers killed by `kill_orphans`
+const ORPHAN_EXIT_CODE: u32 = 1;
+
+/// A file listing the workers we spawned, one per line
+///
+/// Each line has a worker's PID and its creation time, so a PID that was
+/// recycled after the worker exited isn't mistaken for it.
+///
+/// Give it to `LeakGuard::set_pid_file` so every worker is recorded as it's spawned.
+pub struct WorkerPidFile {
+    path: PathBuf,
+}
+
+impl WorkerPidFile {
+    /// The parent directory should only be writable by the manager's user,
+    /// since every PID in the file is a candidate for `kill_orphans`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Kills workers recorded by a previous manager, then empties the file
+    ///
+    /// Call this at startup before spawning any workers. A PID is only killed if
+    /// it's still running our own exe and started when it was recorded, so
+    /// recycled PIDs are skipped, even when they went to another instance of our
+    /// exe. Lines without a creation time are skipped too. Never kills the
+    /// current process. Returns the PIDs that were killed.
+    pub fn kill_orphans(&self) -> Result<Vec<u32>> {
+        let contents = match fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error).context("couldn't read worker PID file"),
+        };
+        let our_exe = std::env::current_exe().context("couldn't get current exe name")?;
+        let mut killed = vec![];
+        for line in contents.lines() {
+            let Some((pid, created)) = parse_line(line) else {
+                tracing::warn!(?line, "Skipping invalid line in worker PID file");
+                continue;
+            };
+            // Our PID may have belonged to a worker of the last manager
+            if pid == std::process::id() {
+                continue;
+            }
+            match kill_if_running(pid, &our_exe, Some(created)) {
+                Ok(true) => killed.push(pid),
+                Ok(false) => {}
+                Err(error) => tracing::warn!(?error, ?pid, "Couldn't kill orphaned worker"),
+            }
+        }
+        fs::write(&self.path, "").context("couldn't clear worker PID file")?;
+        if !killed.is_empty() {
+            tracing::warn!(?killed, "Killed workers left over from a previous manager");
+        }
+        Ok(killed)
+    }
+
+    pub(crate) fn record(&self, pid: u32) -> Result<()> {
+        let created = sys::Process::open(pid, PROCESS_QUERY_LIMITED_INFORMATION)?
+            .creation_time()
+            .context("couldn't get the worker's creation time")?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .context("couldn't open worker PID file")?;
+        writeln!(file, "{pid} {created}").context("couldn't write worker PID file")?;
+        Ok(())
+    }
+}
+
+/// A PID and creation time, see `WorkerPidFile::record`
+fn parse_line(line: &str) -> Option<(u32, u64)> {
+    let (pid, created) = line.trim().split_once(' ')?;
+    Some((pid.parse().ok()?, created.parse().ok()?))
+}
+
+/// Returns `Ok(false)` if the process is gone, is running some other exe, or
+/// didn't start at `created`
+pub(crate) fn kill_if_running(pid: u32, our_exe: &Path, created: Option<u64>) -> Result<bool> {
+    let Ok(process) =
+        sys::Process::open(pid, PROCESS_QUERY_LIMITED_INFORMATION | PROCESS_TERMINATE)
+    else {
+        // Most likely it already exited
+        return Ok(false);
+    };
+    let exe = process.image_name()?;
+    if !exe.as_os_str().eq_ignore_ascii_case(our_exe.as_os_str()) {
+        return Ok(false);
+    }
+    if created.is_some_and(|created| process.creation_time().ok() != Some(created)) {
+        return Ok(false);
+    }
+    process.terminate(ORPHAN_EXIT_CODE)?;
+    etw::killed(pid, "orphaned by a previous manager");
+    Ok(true)
+}
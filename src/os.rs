@@ -0,0 +1,4 @@
+//! Lower-level OS utilities that subzone uses itself, for an app's own resources
+
+pub mod handles;
+pub mod wine;
@@ -0,0 +1,63 @@
+//! Passing an app's own handles to workers, e.g. a TUN device or a log file
+//!
+//! Either mark the handle inheritable just before spawning the worker, or
+//! duplicate it into the running worker. Either way, send the worker the
+//! `RemoteHandle` in a message so it can take ownership.
+//!
+//! Windows only, like the rest of subzone. The Unix equivalents are clearing
+//! `FD_CLOEXEC` and sending the fd with `SCM_RIGHTS`.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::os::windows::io::{
+    AsHandle, AsRawHandle as _, FromRawHandle as _, OwnedHandle, RawHandle,
+};
+
+use crate::sys;
+
+/// A handle's value inside another process
+///
+/// Only meaningful in the process it was made for, which should take ownership
+/// with `into_owned` exactly once. Windows keeps handle values within 32 bits,
+/// so a 64-bit manager can pass them to a 32-bit worker and back.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct RemoteHandle(i64);
+
+impl RemoteHandle {
+    /// The value `handle` has in a child that inherits it, see `set_inheritable`
+    pub fn inherited(handle: &impl AsHandle) -> Self {
+        Self(handle.as_handle().as_raw_handle() as i64)
+    }
+
+    /// Takes ownership of the handle in the process it was made for
+    ///
+    /// # Safety
+    ///
+    /// Must be called in the process that got the handle from `duplicate_into`
+    /// or inherited it, and at most once per handle.
+    pub unsafe fn into_owned(self) -> OwnedHandle {
+        // SAFETY: The caller promises the value is an open handle in this
+        // process, which nothing else owns
+        unsafe { OwnedHandle::from_raw_handle(self.0 as RawHandle) }
+    }
+}
+
+/// Sets whether processes we spawn inherit `handle`
+///
+/// Every process spawned while it's set gets a copy, not just the worker,
+/// so clear it again once the worker is spawned.
+pub fn set_inheritable(handle: &impl AsHandle, inherit: bool) -> Result<()> {
+    sys::set_inheritable(handle, inherit)
+}
+
+pub fn is_inheritable(handle: &impl AsHandle) -> Result<bool> {
+    sys::is_inheritable(handle)
+}
+
+/// Copies `handle` into the running process `pid`, with the same access rights
+///
+/// The copy leaks until that process calls `RemoteHandle::into_owned` or exits.
+pub fn duplicate_into(handle: &impl AsHandle, pid: u32) -> Result<RemoteHandle> {
+    let process = sys::Process::open(pid, sys::PROCESS_DUP_HANDLE)?;
+    Ok(RemoteHandle(process.duplicate_into(handle)? as i64))
+}
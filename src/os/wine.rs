@@ -0,0 +1,64 @@
+//! Running under Wine or Proton
+//!
+//! Wine implements most of what subzone needs, but how much of named pipes and
+//! job objects works varies between versions. When `version` finds Wine, the
+//! manager falls back to the cookie alone if it can't read the pipe client's
+//! PID, and keeps a worker it can't put in the `LeakGuard` job. That worker
+//! watches the manager instead and exits when it does, which is what the job
+//! would have done.
+
+#[cfg(feature = "harness")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{sync::OnceLock, time::Duration};
+
+use crate::{sys, WorkerExit};
+
+/// Wine's version, e.g. `9.0`, or `None` on Windows
+pub fn version() -> Option<&'static str> {
+    static VERSION: OnceLock<Option<String>> = OnceLock::new();
+    VERSION.get_or_init(sys::wine_version).as_deref()
+}
+
+pub fn detected() -> bool {
+    version().is_some() || simulated()
+}
+
+/// Set by `test_wine_fallback` to act like a Wine that can't read pipe client PIDs
+#[cfg(feature = "harness")]
+pub(crate) static SIMULATE: AtomicBool = AtomicBool::new(false);
+
+/// Whether `SIMULATE` is set
+#[cfg(feature = "harness")]
+pub(crate) fn simulated() -> bool {
+    SIMULATE.load(Ordering::Relaxed)
+}
+
+/// Only the multi-process tests simulate Wine
+#[cfg(not(feature = "harness"))]
+pub(crate) fn simulated() -> bool {
+    false
+}
+
+/// Exits this process with `WorkerExit::ManagerGone` once the manager `pid` exits
+///
+/// Stands in for the job object, so it runs on its own thread and doesn't
+/// depend on the app's runtime still being polled.
+pub(crate) fn watch_manager(pid: u32) {
+    let process = match sys::Process::open(pid, sys::PROCESS_SYNCHRONIZE) {
+        Ok(process) => process,
+        Err(error) => {
+            tracing::warn!(?error, pid, "Couldn't watch the manager, we may outlive it");
+            return;
+        }
+    };
+    let spawned = std::thread::Builder::new()
+        .name("subzone-manager-watch".into())
+        .spawn(move || {
+            while !process.wait(Duration::from_secs(60)) {}
+            tracing::warn!(pid, "Manager exited, exiting too");
+            std::process::exit(WorkerExit::ManagerGone.code().into());
+        });
+    if let Err(error) = spawned {
+        tracing::warn!(?error, "Couldn't spawn the manager watch thread");
+    }
+}
@@ -0,0 +1,130 @@
+//! Turning OS-initiated shutdowns into the worker's graceful shutdown
+//!
+//! On Ctrl+C, closing the console, logoff, or system shutdown, Windows sends a
+//! console ctrl event, and kills the process with `STATUS_CONTROL_C_EXIT` if it
+//! doesn't exit in time. The supervisor can't tell that from a crash. Windows
+//! has no `SIGTERM`, these events are the closest.
+//!
+//! `Client::handle_os_shutdown` makes `next` return `ManagerMsgInternal::Shutdown`
+//! instead, so the worker takes the same path as when its manager stops it:
+//! finish the current handler, `close`, and return 0 from `main`. Meanwhile the
+//! handler blocks, so Windows doesn't kill the process first.
+//!
+//! A process that loads user32 doesn't get the logoff and shutdown ctrl events,
+//! so we also listen for `WM_ENDSESSION` on a hidden window.
+
+use anyhow::Result;
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::watch;
+use windows::Win32::{
+    Foundation::TRUE,
+    System::Console::{
+        CTRL_BREAK_EVENT, CTRL_CLOSE_EVENT, CTRL_C_EVENT, CTRL_LOGOFF_EVENT, CTRL_SHUTDOWN_EVENT,
+    },
+    UI::WindowsAndMessaging::{WM_ENDSESSION, WM_QUERYENDSESSION},
+};
+
+use crate::sys;
+
+/// Why the OS asked the worker to stop
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OsShutdown {
+    CtrlC,
+    CtrlBreak,
+    /// The console window was closed
+    Close,
+    Logoff,
+    Shutdown,
+    /// `WM_ENDSESSION`, for logoff or shutdown
+    EndSession,
+}
+
+static SHUTDOWN: OnceLock<watch::Sender<Option<OsShutdown>>> = OnceLock::new();
+/// Whether the ctrl handler and window are installed
+static INSTALLED: Mutex<bool> = Mutex::new(false);
+
+fn sender() -> &'static watch::Sender<Option<OsShutdown>> {
+    SHUTDOWN.get_or_init(|| watch::channel(None).0)
+}
+
+/// Installs the handlers on first use, and subscribes to them
+pub(crate) fn subscribe() -> Result<watch::Receiver<Option<OsShutdown>>> {
+    let mut installed = INSTALLED
+        .lock()
+        .map_err(|_| anyhow::anyhow!("OS shutdown lock is poisoned"))?;
+    if !*installed {
+        sys::set_console_ctrl_handler(ctrl_handler)?;
+        // A hidden top-level window, since message-only windows don't get `WM_ENDSESSION`
+        std::thread::Builder::new()
+            .name("subzone-end-session".to_string())
+            .spawn(|| {
+                if let Err(error) = sys::run_hidden_window(window_handler) {
+                    tracing::warn!(?error, "Can't listen for WM_ENDSESSION");
+                }
+            })?;
+        *installed = true;
+    }
+    Ok(sender().subscribe())
+}
+
+/// Waits until the OS asks us to stop. Never resolves if `rx` is `None`.
+///
+/// # Cancel safety
+///
+/// This method is cancel-safe, internally it calls `tokio::sync::watch::Receiver::changed`
+pub(crate) async fn wait(rx: &mut Option<watch::Receiver<Option<OsShutdown>>>) -> OsShutdown {
+    if let Some(rx) = rx {
+        while rx.changed().await.is_ok() {
+            if let Some(event) = *rx.borrow_and_update() {
+                return event;
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+pub(crate) fn notify(event: OsShutdown) {
+    tracing::info!(?event, "OS asked us to shut down");
+    sender().send_replace(Some(event));
+}
+
+/// Blocks the calling OS thread until `main` returns and the process exits
+///
+/// The process is killed once a ctrl handler or `WM_ENDSESSION` returns, and
+/// Windows gives up on it anyway after its own timeout.
+fn wait_for_exit() -> ! {
+    loop {
+        std::thread::park();
+    }
+}
+
+/// Returns whether the event was handled, see `sys::set_console_ctrl_handler`
+fn ctrl_handler(ctrl_type: u32) -> bool {
+    let event = match ctrl_type {
+        CTRL_C_EVENT => OsShutdown::CtrlC,
+        CTRL_BREAK_EVENT => OsShutdown::CtrlBreak,
+        CTRL_CLOSE_EVENT => OsShutdown::Close,
+        CTRL_LOGOFF_EVENT => OsShutdown::Logoff,
+        CTRL_SHUTDOWN_EVENT => OsShutdown::Shutdown,
+        _ => return false,
+    };
+    notify(event);
+    match event {
+        // Handled, and the process keeps running
+        OsShutdown::CtrlC | OsShutdown::CtrlBreak => true,
+        _ => wait_for_exit(),
+    }
+}
+
+/// Answers the hidden window's messages, see `sys::run_hidden_window`
+fn window_handler(msg: u32, wparam: usize) -> Option<isize> {
+    match msg {
+        // Don't hold up the session ending, we'll stop when it does
+        WM_QUERYENDSESSION => Some(TRUE.0 as isize),
+        WM_ENDSESSION if wparam != 0 => {
+            notify(OsShutdown::EndSession);
+            wait_for_exit()
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,81 @@
+//! Mapping the call lifecycle to OpenTelemetry spans
+//!
+//! The spans themselves are plain `tracing` spans with the OpenTelemetry RPC
+//! attributes, so any subscriber sees them:
+//!
+//! - `ipc.enqueue`: `Manager::call` waiting for the worker's task to take the request
+//! - `ipc.call`: from `Server::call` writing the request until the response is routed
+//! - `ipc.handle`: from `Client::next` reading the request until `Client::respond` writes the answer
+//!
+//! With the `otel` feature, `ipc.call` sends its W3C `traceparent` in the
+//! `Request` frame, and `ipc.handle` uses it as its parent, so a
+//! `tracing-opentelemetry` layer in each process exports one connected trace.
+
+use tracing::Span;
+
+/// The `rpc.system` attribute on every span
+pub(crate) const RPC_SYSTEM: &str = "subzone";
+
+/// The `traceparent` header for `span`, if it belongs to a valid OpenTelemetry trace
+#[cfg(feature = "otel")]
+pub(crate) fn traceparent(span: &Span) -> Option<String> {
+    use opentelemetry::trace::TraceContextExt as _;
+    use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+    let context = span.context();
+    let span_context = context.span().span_context().clone();
+    if !span_context.is_valid() {
+        return None;
+    }
+    Some(format!(
+        "00-{}-{}-{:02x}",
+        span_context.trace_id(),
+        span_context.span_id(),
+        span_context.trace_flags().to_u8()
+    ))
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn traceparent(_span: &Span) -> Option<String> {
+    None
+}
+
+/// Makes `span` a child of the remote span in `traceparent`
+///
+/// Ignores headers it can't parse, so a bad peer only costs the link.
+#[cfg(feature = "otel")]
+pub(crate) fn set_parent(span: &Span, traceparent: &str) {
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt as _, TraceFlags, TraceId, TraceState,
+    };
+    use tracing_opentelemetry::OpenTelemetrySpanExt as _;
+
+    let parse = || {
+        let mut parts = traceparent.split('-');
+        let (Some("00"), Some(trace_id), Some(span_id), Some(flags), None) = (
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+            parts.next(),
+        ) else {
+            return None;
+        };
+        let span_context = SpanContext::new(
+            TraceId::from_hex(trace_id).ok()?,
+            SpanId::from_hex(span_id).ok()?,
+            TraceFlags::new(u8::from_str_radix(flags, 16).ok()?),
+            true,
+            TraceState::default(),
+        );
+        span_context.is_valid().then_some(span_context)
+    };
+    let Some(span_context) = parse() else {
+        tracing::debug!(?traceparent, "Ignoring invalid traceparent");
+        return;
+    };
+    span.set_parent(opentelemetry::Context::new().with_remote_span_context(span_context));
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) fn set_parent(_span: &Span, _traceparent: &str) {}
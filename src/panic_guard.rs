@@ -0,0 +1,33 @@
+//! Catching panics in the tasks that keep a connection going
+//!
+//! A panic in a spawned task only reaches its `JoinHandle`, which nobody awaits
+//! for the reader tasks, so the connection would just look closed. Instead the
+//! task catches it and reports `Error::Internal` to whoever reads the connection.
+
+use std::{any::Any, future::Future, panic::AssertUnwindSafe, task::Poll};
+
+/// Runs `future` to completion, or returns the panic message if it panics
+///
+/// The future is dropped after a panic, so anything it owned is cleaned up and
+/// shared state it was updating must tolerate being left half-done.
+pub(crate) async fn catch_unwind<F: Future>(future: F) -> Result<F::Output, String> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(poll) => poll.map(Ok),
+            Err(payload) => Poll::Ready(Err(message(payload.as_ref()))),
+        }
+    })
+    .await
+}
+
+/// The message passed to `panic!`, if it was a string
+pub(crate) fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        return message.to_string();
+    }
+    if let Some(message) = payload.downcast_ref::<String>() {
+        return message.clone();
+    }
+    "panic with a non-string payload".to_string()
+}
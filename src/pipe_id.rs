@@ -0,0 +1,77 @@
+//! The name of a named pipe, as passed from the manager to its workers
+//!
+//! Anyone who knows a pipe ID can try to connect before the real worker does,
+//! so it's kept out of logs. `Debug` shows only that there is one.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::Error;
+
+const PREFIX: &str = r"\\.\pipe\";
+
+/// Windows limits the whole path, including the prefix
+const MAX_LEN: usize = 256;
+
+/// A validated named pipe path, e.g. `\\.\pipe\subzone\9508e87c-1c92-4630-bb20-839325d169bd`
+#[derive(Clone, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PipeId(String);
+
+impl PipeId {
+    /// A new pipe ID based on a UUIDv4
+    pub(crate) fn random() -> Self {
+        Self::named(&uuid::Uuid::new_v4().to_string())
+    }
+
+    /// The pipe ID for a well-known `name`, e.g. for single-instance checks
+    pub(crate) fn named(name: &str) -> Self {
+        Self(format!(r"{PREFIX}subzone\{name}"))
+    }
+
+    /// Parses a pipe ID from a worker's command line, see `to_arg`
+    pub fn from_arg(arg: &str) -> Result<Self, Error> {
+        let Some(name) = arg.strip_prefix(PREFIX) else {
+            return Err(Error::InvalidPipeId(r"doesn't start with \\.\pipe\"));
+        };
+        if name.is_empty() {
+            return Err(Error::InvalidPipeId("pipe name is empty"));
+        }
+        if arg.len() > MAX_LEN {
+            return Err(Error::InvalidPipeId("longer than 256 characters"));
+        }
+        if arg.chars().any(char::is_control) {
+            return Err(Error::InvalidPipeId("contains control characters"));
+        }
+        Ok(Self(arg.to_string()))
+    }
+
+    /// The pipe ID as a command-line argument for a worker, see `from_arg`
+    pub fn to_arg(&self) -> String {
+        self.0.clone()
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PipeId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PipeId(<redacted>)")
+    }
+}
+
+impl TryFrom<String> for PipeId {
+    type Error = Error;
+
+    fn try_from(value: String) -> Result<Self, Error> {
+        Self::from_arg(&value)
+    }
+}
+
+impl From<PipeId> for String {
+    fn from(value: PipeId) -> Self {
+        value.0
+    }
+}
@@ -0,0 +1,103 @@
+//! Telling workers about system suspend and resume
+//!
+//! `PowerWatcher` listens for the OS notifications, and `Manager::broadcast_power_event`
+//! forwards them as control frames. Workers get them as `ManagerMsgInternal::Power`
+//! from `Client::next`, so they don't each need their own power handling.
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::c_void;
+use tokio::sync::mpsc;
+use windows::Win32::{
+    Foundation::HANDLE,
+    System::Power::{
+        PowerRegisterSuspendResumeNotification, PowerUnregisterSuspendResumeNotification,
+        DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS, HPOWERNOTIFY,
+    },
+    UI::WindowsAndMessaging::{DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC, PBT_APMSUSPEND},
+};
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum PowerEvent {
+    /// The system is about to sleep or hibernate
+    Suspend,
+    /// The system woke up. Sent even if no user is present.
+    Resume,
+}
+
+/// Receives suspend and resume notifications from Windows
+///
+/// Doesn't need a window or a service control handler.
+pub struct PowerWatcher {
+    rx: mpsc::UnboundedReceiver<PowerEvent>,
+    registration: HPOWERNOTIFY,
+    /// Windows holds a pointer to this until we unregister
+    _tx: Box<mpsc::UnboundedSender<PowerEvent>>,
+}
+
+impl PowerWatcher {
+    pub fn new() -> Result<Self> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let tx = Box::new(tx);
+        let mut params = DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+            Callback: Some(power_callback),
+            Context: &*tx as *const mpsc::UnboundedSender<PowerEvent> as *mut c_void,
+        };
+        let mut registration = std::ptr::null_mut();
+        // SAFETY: `params` only needs to live for the call. The context pointer
+        // stays valid until `Drop` unregisters, since the `Box` never moves its contents.
+        unsafe {
+            PowerRegisterSuspendResumeNotification(
+                DEVICE_NOTIFY_CALLBACK,
+                HANDLE(&mut params as *mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as isize),
+                &mut registration,
+            )
+        }
+        .context("PowerRegisterSuspendResumeNotification")?;
+        Ok(Self {
+            rx,
+            registration: HPOWERNOTIFY(registration as isize),
+            _tx: tx,
+        })
+    }
+
+    /// Waits for the next suspend or resume
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel-safe, internally it calls `tokio::sync::mpsc::UnboundedReceiver::recv`
+    pub async fn next(&mut self) -> PowerEvent {
+        self.rx
+            .recv()
+            .await
+            .expect("the PowerWatcher keeps a sender, so the channel can't close")
+    }
+}
+
+impl Drop for PowerWatcher {
+    fn drop(&mut self) {
+        // SAFETY: The handle came from `PowerRegisterSuspendResumeNotification`
+        // and is only unregistered here. After this, the callback won't run again.
+        if let Err(error) = unsafe { PowerUnregisterSuspendResumeNotification(self.registration) } {
+            tracing::error!(?error, "Couldn't unregister power notifications");
+        }
+    }
+}
+
+/// Called by Windows on its own thread
+unsafe extern "system" fn power_callback(
+    context: *const c_void,
+    event_type: u32,
+    _setting: *const c_void,
+) -> u32 {
+    let event = match event_type {
+        PBT_APMSUSPEND => PowerEvent::Suspend,
+        PBT_APMRESUMEAUTOMATIC => PowerEvent::Resume,
+        _ => return 0,
+    };
+    // SAFETY: `context` is the sender boxed in `PowerWatcher`, which outlives the registration
+    let tx = unsafe { &*(context as *const mpsc::UnboundedSender<PowerEvent>) };
+    tx.send(event).ok();
+    // ERROR_SUCCESS
+    0
+}
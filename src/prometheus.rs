@@ -0,0 +1,221 @@
+//! Exporting manager-side IPC metrics in the Prometheus text format
+//!
+//! There's no HTTP server here. Products either serve `write_prometheus` from
+//! their own endpoint, or write a file for node_exporter's textfile collector
+//! with `write_prometheus_file`. Message rates come from the counters with `rate()`,
+//! and each method's share of the bandwidth from the `_sum` of its size histogram.
+
+use anyhow::{Context as _, Result};
+use std::{fmt::Write as _, path::Path};
+
+use crate::{buffer_pool_stats, Histogram, Manager, MethodStats, WorkerStats};
+
+/// One worker's counters at the time of the export
+pub(crate) struct WorkerSample {
+    pub(crate) role: String,
+    pub(crate) sent: u64,
+    pub(crate) received: u64,
+    pub(crate) connected: bool,
+    pub(crate) queued: usize,
+    pub(crate) pending_calls: usize,
+    pub(crate) stats: Option<WorkerStats>,
+    pub(crate) methods: Vec<MethodStats>,
+    pub(crate) transit: Option<Histogram>,
+}
+
+/// One metric family, with one line per worker that has a value for it
+struct Metric {
+    name: &'static str,
+    /// `counter` or `gauge`
+    kind: &'static str,
+    help: &'static str,
+    value: fn(&WorkerSample) -> Option<f64>,
+}
+
+const METRICS: &[Metric] = &[
+    Metric {
+        name: "subzone_messages_sent_total",
+        kind: "counter",
+        help: "Messages written to the worker",
+        value: |s| Some(s.sent as f64),
+    },
+    Metric {
+        name: "subzone_messages_received_total",
+        kind: "counter",
+        help: "Messages read from the worker",
+        value: |s| Some(s.received as f64),
+    },
+    Metric {
+        name: "subzone_worker_connected",
+        kind: "gauge",
+        help: "1 until the worker's connection ends",
+        value: |s| Some(if s.connected { 1.0 } else { 0.0 }),
+    },
+    Metric {
+        name: "subzone_send_queue_depth",
+        kind: "gauge",
+        help: "Messages waiting to be written to the worker",
+        value: |s| Some(s.queued as f64),
+    },
+    Metric {
+        name: "subzone_pending_calls",
+        kind: "gauge",
+        help: "Calls the worker hasn't answered",
+        value: |s| Some(s.pending_calls as f64),
+    },
+    Metric {
+        name: "subzone_worker_rss_bytes",
+        kind: "gauge",
+        help: "Working set the worker last reported, see Manager::report_stats",
+        value: |s| Some(s.stats.as_ref()?.rss_bytes as f64),
+    },
+    Metric {
+        name: "subzone_worker_cpu_percent",
+        kind: "gauge",
+        help: "CPU the worker last reported, where 100 is one core",
+        value: |s| Some(s.stats.as_ref()?.cpu_percent),
+    },
+];
+
+/// One histogram family, with one series per worker and method
+struct MethodMetric {
+    name: &'static str,
+    help: &'static str,
+    value: fn(&MethodStats) -> &Histogram,
+}
+
+const METHOD_METRICS: &[MethodMetric] = &[
+    MethodMetric {
+        name: "subzone_method_sent_bytes",
+        help: "Sizes of the messages and requests written to the worker",
+        value: |m| &m.sent_bytes,
+    },
+    MethodMetric {
+        name: "subzone_method_received_bytes",
+        help: "Sizes of the messages and responses read from the worker",
+        value: |m| &m.received_bytes,
+    },
+    MethodMetric {
+        name: "subzone_call_duration_seconds",
+        help: "Time from writing a request to its response, including the worker's handler",
+        value: |m| &m.call_seconds,
+    },
+];
+
+impl<M, W> Manager<M, W> {
+    /// Appends every worker's metrics to `out` in the Prometheus text format
+    pub fn write_prometheus(&self, out: &mut String) {
+        let samples = self.samples();
+        for Metric {
+            name,
+            kind,
+            help,
+            value,
+        } in METRICS
+        {
+            // Writing to a `String` can't fail
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} {kind}").ok();
+            for sample in &samples {
+                if let Some(value) = value(sample) {
+                    writeln!(out, "{name}{{role=\"{}\"}} {value}", escape(&sample.role)).ok();
+                }
+            }
+        }
+        for MethodMetric { name, help, value } in METHOD_METRICS {
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} histogram").ok();
+            for sample in &samples {
+                for method in &sample.methods {
+                    let histogram = value(method);
+                    if histogram.count == 0 {
+                        continue;
+                    }
+                    let labels = format!(
+                        "role=\"{}\",method=\"{}\"",
+                        escape(&sample.role),
+                        escape(&method.method)
+                    );
+                    write_histogram(out, name, &labels, histogram);
+                }
+            }
+        }
+        let name = "subzone_transit_seconds";
+        writeln!(
+            out,
+            "# HELP {name} Time from the worker stamping a frame to the manager reading it"
+        )
+        .ok();
+        writeln!(out, "# TYPE {name} histogram").ok();
+        for sample in &samples {
+            if let Some(histogram) = &sample.transit {
+                let labels = format!("role=\"{}\"", escape(&sample.role));
+                write_histogram(out, name, &labels, histogram);
+            }
+        }
+        // Process-wide, so no labels
+        let pool = buffer_pool_stats();
+        for (name, kind, help, value) in [
+            (
+                "subzone_buffer_pool_hits_total",
+                "counter",
+                "Frame reads that reused a pooled buffer",
+                pool.hits as f64,
+            ),
+            (
+                "subzone_buffer_pool_misses_total",
+                "counter",
+                "Frame reads that allocated a buffer",
+                pool.misses as f64,
+            ),
+            (
+                "subzone_buffer_pool_discarded_total",
+                "counter",
+                "Buffers freed instead of pooled",
+                pool.discarded as f64,
+            ),
+            (
+                "subzone_buffer_pool_buffers",
+                "gauge",
+                "Buffers waiting in the pool",
+                pool.pooled as f64,
+            ),
+        ] {
+            writeln!(out, "# HELP {name} {help}").ok();
+            writeln!(out, "# TYPE {name} {kind}").ok();
+            writeln!(out, "{name} {value}").ok();
+        }
+    }
+
+    /// Writes the metrics to `path` for a textfile collector
+    ///
+    /// Writes a temporary file next to it and renames it over `path`, so the
+    /// collector never reads a half-written file.
+    pub fn write_prometheus_file(&self, path: &Path) -> Result<()> {
+        let mut out = String::new();
+        self.write_prometheus(&mut out);
+        let tmp = path.with_extension("prom.tmp");
+        std::fs::write(&tmp, out).with_context(|| format!("couldn't write {tmp:?}"))?;
+        std::fs::rename(&tmp, path).with_context(|| format!("couldn't rename to {path:?}"))?;
+        Ok(())
+    }
+}
+
+/// Writes the `_bucket`, `_sum`, and `_count` lines of one series
+fn write_histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+    for (bound, count) in &histogram.buckets {
+        writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {count}").ok();
+    }
+    let Histogram { count, sum, .. } = histogram;
+    writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}").ok();
+    writeln!(out, "{name}_sum{{{labels}}} {sum}").ok();
+    writeln!(out, "{name}_count{{{labels}}} {count}").ok();
+}
+
+/// Escapes a label value, see the text format's spec
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
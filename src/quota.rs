@@ -0,0 +1,227 @@
+//! Limits on how many connections one user or exe may hold on a `Listener`
+//!
+//! Every connection holds a pipe instance until it's dropped, so a runaway
+//! per-session agent that keeps reconnecting without closing its old
+//! connections could take them all. With `ServerOptions::quota`, the listener
+//! reads each client's `PeerIdentity` and counts its connections by user or exe.
+//! A client over the limit is either turned away, or makes room by evicting the
+//! oldest connection with the same key. Eviction disconnects that connection's
+//! pipe right away, so its client sees the disconnect and the pipe instance is
+//! freed even if nobody is polling its `Server`, whose `next` then returns
+//! `DisconnectReason::Evicted`.
+//!
+//! Clients whose identity can't be read are turned away, since they'd all share
+//! one key and could evict each other's connections.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    os::windows::io::AsHandle,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+};
+
+use crate::{sys, PeerIdentity};
+
+/// What connections count towards the same limit
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QuotaKey {
+    /// `PeerIdentity::user`
+    User,
+    /// `PeerIdentity::exe`
+    Executable,
+}
+
+/// What happens to a client that would go over its limit
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Eviction {
+    /// Close the new connection, leaving the old ones alone
+    #[default]
+    RejectNew,
+    /// Accept the new connection and disconnect the oldest one with the same key
+    ///
+    /// For clients that reconnect after a crash or hang, so the live one wins.
+    EvictOldest,
+}
+
+/// A limit on concurrent connections, see `ServerOptions::quota`
+#[derive(Clone, Debug)]
+pub struct ConnectionQuota {
+    key: QuotaKey,
+    max: usize,
+    eviction: Eviction,
+}
+
+impl ConnectionQuota {
+    /// At most `max` connections per user account. Values below 1 are treated as 1.
+    pub fn per_user(max: usize) -> Self {
+        Self::new(QuotaKey::User, max)
+    }
+
+    /// At most `max` connections per exe path. Values below 1 are treated as 1.
+    pub fn per_executable(max: usize) -> Self {
+        Self::new(QuotaKey::Executable, max)
+    }
+
+    fn new(key: QuotaKey, max: usize) -> Self {
+        Self {
+            key,
+            max: max.max(1),
+            eviction: Eviction::default(),
+        }
+    }
+
+    /// See `Eviction`
+    pub fn eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = eviction;
+        self
+    }
+
+    fn key_of(&self, identity: &PeerIdentity) -> String {
+        match self.key {
+            QuotaKey::User => identity.user.to_lowercase(),
+            // Windows paths are case-insensitive
+            QuotaKey::Executable => identity.exe.to_string_lossy().to_lowercase(),
+        }
+    }
+}
+
+/// Lets a `Listener` cut off a connection it handed out
+#[derive(Clone)]
+pub(crate) struct EvictHandle {
+    /// A second handle to the server end, so the `Server` doesn't have to be polled
+    pipe: Arc<sys::PipeHandle>,
+    evicted: Arc<AtomicBool>,
+}
+
+impl EvictHandle {
+    pub(crate) fn new(pipe: &impl AsHandle) -> anyhow::Result<Self> {
+        Ok(Self {
+            pipe: Arc::new(sys::PipeHandle::duplicate(pipe)?),
+            evicted: Default::default(),
+        })
+    }
+
+    fn evict(&self) {
+        // Set first, so the `Server` sees it once its reads start failing
+        self.evicted.store(true, Ordering::Release);
+        if let Err(error) = self.pipe.disconnect() {
+            tracing::warn!(?error, "Couldn't disconnect an evicted client");
+        }
+    }
+}
+
+/// A connection counted by the table
+struct Holder {
+    id: u64,
+    evict: EvictHandle,
+}
+
+#[derive(Default)]
+struct Held {
+    next_id: u64,
+    /// By index into the listener's quotas and key, oldest first
+    by_key: HashMap<(usize, String), VecDeque<Holder>>,
+}
+
+/// Connections a `Listener` has handed out that haven't been dropped yet
+///
+/// Cloning shares it, so each `QuotaSlot` can give its place back on drop.
+#[derive(Clone, Default)]
+pub(crate) struct QuotaTable(Arc<Mutex<Held>>);
+
+impl QuotaTable {
+    fn lock(&self) -> MutexGuard<'_, Held> {
+        // Every update leaves the map consistent before it can panic
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Counts a new connection against every quota, or returns the one that turns it away
+    ///
+    /// Nothing is evicted unless the connection is admitted.
+    pub(crate) fn admit(
+        &self,
+        quotas: &[ConnectionQuota],
+        identity: &PeerIdentity,
+        evict: EvictHandle,
+    ) -> Result<QuotaSlot, QuotaKey> {
+        let keys: Vec<_> = quotas
+            .iter()
+            .enumerate()
+            .map(|(index, quota)| (index, quota.key_of(identity)))
+            .collect();
+        let mut held = self.lock();
+        let full = |held: &Held, key: &(usize, String)| {
+            held.by_key.get(key).map_or(0, VecDeque::len) >= quotas[key.0].max
+        };
+        for (quota, key) in quotas.iter().zip(&keys) {
+            if quota.eviction == Eviction::RejectNew && full(&held, key) {
+                tracing::warn!(quota = ?quota.key, "Refused a connection over its quota");
+                return Err(quota.key);
+            }
+        }
+        let mut evicted = Vec::new();
+        for key in &keys {
+            while full(&held, key) {
+                let Some(oldest) = held.by_key.get_mut(key).and_then(VecDeque::pop_front) else {
+                    break;
+                };
+                evicted.push(oldest);
+            }
+        }
+        for holder in &evicted {
+            // It's gone, so it stops counting against its other quotas too
+            for holders in held.by_key.values_mut() {
+                holders.retain(|other| other.id != holder.id);
+            }
+        }
+        let id = held.next_id;
+        held.next_id += 1;
+        for key in &keys {
+            held.by_key
+                .entry(key.clone())
+                .or_default()
+                .push_back(Holder {
+                    id,
+                    evict: evict.clone(),
+                });
+        }
+        drop(held);
+        for holder in evicted {
+            tracing::warn!("Evicting the oldest connection to stay within its quota");
+            holder.evict.evict();
+        }
+        Ok(QuotaSlot {
+            table: self.clone(),
+            id,
+            evicted: evict.evicted,
+        })
+    }
+}
+
+/// A connection's place in a `QuotaTable`, given back on drop
+pub(crate) struct QuotaSlot {
+    table: QuotaTable,
+    id: u64,
+    evicted: Arc<AtomicBool>,
+}
+
+impl QuotaSlot {
+    /// Whether the listener disconnected this connection to make room for another
+    pub(crate) fn evicted(&self) -> bool {
+        self.evicted.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for QuotaSlot {
+    fn drop(&mut self) {
+        let mut held = self.table.lock();
+        held.by_key.retain(|_, holders| {
+            holders.retain(|holder| holder.id != self.id);
+            !holders.is_empty()
+        });
+    }
+}
@@ -0,0 +1,135 @@
+//! Masks secrets in user messages before they're logged
+//!
+//! `Server::log_messages` and `Client::log_messages` trace every user message
+//! sent and received. Payloads often carry tokens or keys, so any object field
+//! whose name contains one of `DEFAULT_SECRET_FIELDS`, or is listed in the
+//! message type's `Redact::SECRET_FIELDS`, is replaced with `REDACTED`, at any
+//! depth. Names are matched case-insensitively, and the defaults also ignore
+//! `_` and `-`.
+
+use serde::Serialize;
+use serde_json::Value;
+
+/// Replaces the value of every secret field
+pub const REDACTED: &str = "<redacted>";
+
+/// Field name fragments that are always masked
+pub const DEFAULT_SECRET_FIELDS: &[&str] = &[
+    "password",
+    "secret",
+    "token",
+    "api_key",
+    "private_key",
+    "authorization",
+];
+
+/// A message type that may be logged by `log_messages`
+///
+/// The default masks only `DEFAULT_SECRET_FIELDS`, so most types just need
+/// `impl Redact for MyMsg {}`. List any other sensitive field names, as they're
+/// serialized, in `SECRET_FIELDS`:
+///
+/// ```
+/// #[derive(serde::Serialize)]
+/// struct Login {
+///     user: String,
+///     otp: String,
+/// }
+///
+/// impl subzone::Redact for Login {
+///     const SECRET_FIELDS: &'static [&'static str] = &["otp"];
+/// }
+/// ```
+pub trait Redact {
+    /// Names of fields to mask on top of `DEFAULT_SECRET_FIELDS`, exact but case-insensitive
+    const SECRET_FIELDS: &'static [&'static str] = &[];
+}
+
+impl Redact for Value {}
+
+impl Redact for String {}
+
+impl Redact for () {}
+
+/// Traces user messages with their secrets masked, see `Server::log_messages`
+#[derive(Clone, Copy)]
+pub(crate) struct MessageLog {
+    sent: &'static [&'static str],
+    received: &'static [&'static str],
+}
+
+impl MessageLog {
+    /// For a connection that sends `S` and receives `R`
+    pub(crate) fn new<S: Redact, R: Redact>() -> Self {
+        Self {
+            sent: S::SECRET_FIELDS,
+            received: R::SECRET_FIELDS,
+        }
+    }
+
+    pub(crate) fn sent(&self, msg: &impl Serialize) {
+        if let Ok(mut msg) = serde_json::to_value(msg) {
+            redact(&mut msg, self.sent);
+            tracing::trace!(%msg, "Sent message");
+        }
+    }
+
+    /// `frame` is the whole JSON frame, which is small next to the cost of logging it
+    pub(crate) fn received(&self, frame: &str) {
+        if let Ok(mut msg) = serde_json::from_str::<Value>(frame) {
+            redact(&mut msg, self.received);
+            tracing::trace!(%msg, "Received message");
+        }
+    }
+}
+
+/// Masks secret fields of `value` in place
+pub(crate) fn redact(value: &mut Value, extra: &[&str]) {
+    redact_with(value, extra, &|_| Value::String(REDACTED.to_string()));
+}
+
+/// Like `redact`, but each mask has the secret's JSON type, so the frame still decodes
+pub(crate) fn redact_keeping_types(value: &mut Value, extra: &[&str]) {
+    redact_with(value, extra, &|secret| match secret {
+        Value::Null => Value::Null,
+        Value::Bool(_) => Value::Bool(false),
+        Value::Number(_) => Value::from(0),
+        Value::String(_) => Value::String(REDACTED.to_string()),
+        Value::Array(_) => Value::Array(Vec::new()),
+        Value::Object(_) => Value::Object(Default::default()),
+    });
+}
+
+fn redact_with(value: &mut Value, extra: &[&str], mask: &dyn Fn(&Value) -> Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, field) in fields.iter_mut() {
+                if is_secret(name, extra) {
+                    *field = mask(field);
+                } else {
+                    redact_with(field, extra, mask);
+                }
+            }
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| redact_with(item, extra, mask)),
+        _ => {}
+    }
+}
+
+fn is_secret(name: &str, extra: &[&str]) -> bool {
+    // So `privateKey` and `private-key` match `private_key`
+    let squashed = squash(name);
+    DEFAULT_SECRET_FIELDS
+        .iter()
+        .any(|fragment| squashed.contains(&squash(fragment)))
+        || extra.iter().any(|field| field.eq_ignore_ascii_case(name))
+}
+
+fn squash(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '_' | '-'))
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
@@ -0,0 +1,111 @@
+//! Publishing where a running manager can be reached
+//!
+//! The manager writes an `Endpoint` file named after the app, and independently
+//! started clients read it to find the pipe. A file left behind by a manager
+//! that crashed is detected by checking if its PID is still running.
+
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use crate::{disconnect::PeerProcess, PipeId, PROTOCOL_VERSION};
+
+/// How to reach a running manager
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Endpoint {
+    pub pipe_id: PipeId,
+    pub pid: u32,
+    pub protocol_version: u32,
+    pub registered_at: SystemTime,
+}
+
+/// A directory of `Endpoint` files, one per app name
+///
+/// Anyone who can write to the directory can point clients at their own pipe,
+/// so it should only be writable by the manager's user, e.g. a directory under
+/// `%ProgramData%` with an ACL for SYSTEM and Administrators.
+pub struct Registry {
+    dir: PathBuf,
+}
+
+/// Removes the `Endpoint` file when dropped
+pub struct Registration {
+    path: PathBuf,
+}
+
+impl Registry {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Records that this process is reachable at `pipe_id` under `name`
+    ///
+    /// Replaces any file left behind by a previous manager.
+    pub fn register(&self, name: &str, pipe_id: &PipeId) -> Result<Registration> {
+        let endpoint = Endpoint {
+            pipe_id: pipe_id.clone(),
+            pid: std::process::id(),
+            protocol_version: PROTOCOL_VERSION,
+            registered_at: SystemTime::now(),
+        };
+        fs::create_dir_all(&self.dir).context("couldn't create registry dir")?;
+        let path = self.path(name)?;
+        // Write then rename, so a client never reads a half-written file
+        let tmp_path = path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_string(&endpoint)?)
+            .context("couldn't write endpoint file")?;
+        fs::rename(&tmp_path, &path).context("couldn't move endpoint file into place")?;
+        Ok(Registration { path })
+    }
+
+    /// Returns the endpoint registered under `name`, if its manager is still running
+    ///
+    /// Stale files are deleted. An endpoint with a different protocol version
+    /// is still returned, so the caller can report the mismatch.
+    pub fn lookup(&self, name: &str) -> Result<Option<Endpoint>> {
+        let path = self.path(name)?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(error) => return Err(error).context("couldn't read endpoint file"),
+        };
+        let endpoint: Endpoint =
+            serde_json::from_str(&contents).context("couldn't parse endpoint file")?;
+        let running = PeerProcess::open(endpoint.pid).is_some_and(|process| process.is_running());
+        if !running {
+            tracing::debug!(?endpoint, "Removing stale endpoint file");
+            fs::remove_file(&path).ok();
+            return Ok(None);
+        }
+        Ok(Some(endpoint))
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+        {
+            bail!("registry names may only contain ASCII letters, digits, '-', '_', and '.'");
+        }
+        Ok(self.dir.join(format!("{name}.json")))
+    }
+}
+
+impl Registration {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_file(&self.path) {
+            tracing::warn!(?error, path = ?self.path, "Couldn't remove endpoint file");
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Handler errors that cross the pipe intact
+//!
+//! A worker answers a call with `Client::respond_error` instead of folding the
+//! error into a string in its message enum. The call fails on the manager with
+//! `Error::Remote`, which keeps the code, the context chain, and optionally the
+//! typed error.
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// What a fallible call returns once the envelope is opened, see `Error::Remote`
+pub type Envelope<T> = Result<T, RemoteError>;
+
+/// An error from a worker's handler, as it arrived from the worker
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize, thiserror::Error)]
+#[error("{message}")]
+pub struct RemoteError {
+    /// App-defined, for matching on without parsing `message`
+    pub code: Option<String>,
+    pub message: String,
+    /// The error's sources, outermost first, like `{:#}` prints for an `anyhow::Error`
+    pub chain: Vec<String>,
+    /// The typed error, see `with_details`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    details: Option<serde_json::Value>,
+}
+
+impl RemoteError {
+    pub fn new(message: impl Into<String>) -> Self {
+        Self {
+            code: None,
+            message: message.into(),
+            chain: vec![],
+            details: None,
+        }
+    }
+
+    /// Keeps `error`'s message and its sources
+    pub fn from_error(error: &(dyn std::error::Error + 'static)) -> Self {
+        let mut chain = vec![];
+        let mut source = error.source();
+        while let Some(error) = source {
+            chain.push(error.to_string());
+            source = error.source();
+        }
+        Self {
+            chain,
+            ..Self::new(error.to_string())
+        }
+    }
+
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+
+    /// Attaches the typed error, so the manager can get it back with `details`
+    pub fn with_details<E: Serialize>(mut self, details: &E) -> Result<Self, serde_json::Error> {
+        self.details = Some(serde_json::to_value(details)?);
+        Ok(self)
+    }
+
+    /// The typed error from `with_details`, if there is one and it's an `E`
+    pub fn details<E: DeserializeOwned>(&self) -> Option<E> {
+        serde_json::from_value(self.details.clone()?).ok()
+    }
+}
+
+impl From<&anyhow::Error> for RemoteError {
+    fn from(error: &anyhow::Error) -> Self {
+        let mut chain = error.chain().map(ToString::to_string);
+        let message = chain.next().unwrap_or_default();
+        Self {
+            chain: chain.collect(),
+            ..Self::new(message)
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! Reproduction bundles for frames a connection couldn't accept
+//!
+//! With `Server::capture_repros` or `Client::capture_repros`, a frame that fails
+//! to decode, or that `acl` refuses, is written to a `.subzone-repro` file along
+//! with the connection's `ConnectionInfo` and recent events. Secret fields are
+//! the same as for `log_messages`, but each is masked with a value of its own
+//! JSON type, so the frame still decodes. Frames that aren't JSON at all can't
+//! be redacted, so only their length and hash are kept.
+//!
+//! A peer that keeps sending bad frames mustn't fill the disk, so a connection
+//! writes at most `MAX_REPROS_PER_CONNECTION` bundles, no more than one per
+//! `REPRO_INTERVAL`, and frames are cut to `MAX_REPRO_FRAME` bytes. Bundles
+//! are written on a blocking thread, so `next` doesn't wait for the disk.
+//!
+//! `testing::replay_repro` decodes the frame again through the same functions
+//! `next` uses, so a bundle attached to a bug report becomes a regression test.
+
+use anyhow::{Context as _, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    acl, redact, sys, ConnectionEvent, ConnectionInfo, DisconnectReason, Error, ManagerMsgInternal,
+    Metadata, WorkerMsgInternal,
+};
+
+/// Bumped when `ReproBundle` changes incompatibly
+pub const REPRO_FORMAT: u32 = 2;
+
+/// File extension of bundles written by `capture_repros`
+pub const REPRO_EXTENSION: &str = "subzone-repro";
+
+/// Frames longer than this are cut short in a bundle, and won't replay to the same error
+pub const MAX_REPRO_FRAME: usize = 64 * 1024;
+
+/// How many bundles one connection writes before it stops capturing
+pub const MAX_REPROS_PER_CONNECTION: usize = 16;
+
+/// Bad frames that arrive sooner than this after a bundle was written are skipped
+pub const REPRO_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Everything needed to decode an offending frame again, see `testing::replay_repro`
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ReproBundle {
+    pub format: u32,
+    pub captured_at: SystemTime,
+    /// Which end read the frame
+    pub reader: ReproSide,
+    /// The reader's view of the connection when the frame arrived
+    pub connection: ConnectionInfo,
+    /// What `next` returned
+    pub error: String,
+    /// The frame body, redacted and cut to `MAX_REPRO_FRAME`, in hex.
+    /// Empty if the frame wasn't JSON.
+    #[serde(with = "hex_bytes")]
+    pub frame: Vec<u8>,
+    /// Length of the frame as it arrived
+    pub frame_len: usize,
+    /// SHA-256 of a frame that wasn't JSON, in hex, to tell captures of the same frame apart
+    pub frame_sha256: Option<String>,
+    /// See `Server::recent_events`
+    pub events: Vec<ConnectionEvent>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ReproSide {
+    /// A `Server` reading a worker's frame
+    Manager,
+    /// A `Client` reading the manager's frame
+    Worker,
+}
+
+impl ReproBundle {
+    pub fn load(path: &Path) -> Result<Self> {
+        let bytes = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let bundle: Self = serde_json::from_slice(&bytes)?;
+        anyhow::ensure!(
+            bundle.format == REPRO_FORMAT,
+            "unsupported repro format {}",
+            bundle.format
+        );
+        Ok(bundle)
+    }
+}
+
+/// Where a connection writes its bundles, see `Server::capture_repros`
+pub(crate) struct ReproCapture {
+    dir: PathBuf,
+    /// `Redact::SECRET_FIELDS` of the type being read
+    secrets: &'static [&'static str],
+    /// Bundles written so far
+    written: usize,
+    last_written: Option<Instant>,
+}
+
+impl ReproCapture {
+    pub(crate) fn new(dir: PathBuf, secrets: &'static [&'static str]) -> Self {
+        Self {
+            dir,
+            secrets,
+            written: 0,
+            last_written: None,
+        }
+    }
+
+    /// Writes a bundle in the background, logging instead of failing so the
+    /// original error still surfaces
+    ///
+    /// Skipped once the connection has used up its bundles, or within
+    /// `REPRO_INTERVAL` of the last one.
+    pub(crate) fn write(
+        &mut self,
+        reader: ReproSide,
+        connection: &ConnectionInfo,
+        error: &Error,
+        frame: &[u8],
+        events: Vec<ConnectionEvent>,
+    ) {
+        if self.written >= MAX_REPROS_PER_CONNECTION {
+            return;
+        }
+        let now = Instant::now();
+        if self
+            .last_written
+            .is_some_and(|last| now.duration_since(last) < REPRO_INTERVAL)
+        {
+            tracing::debug!("Skipped a repro bundle, the last one was too recent");
+            return;
+        }
+        self.written += 1;
+        self.last_written = Some(now);
+        if self.written == MAX_REPROS_PER_CONNECTION {
+            tracing::warn!("Wrote the last repro bundle this connection may write");
+        }
+        let bundle = ReproBundle {
+            format: REPRO_FORMAT,
+            captured_at: SystemTime::now(),
+            reader,
+            connection: connection.clone(),
+            error: error.to_string(),
+            frame: frame.to_vec(),
+            frame_len: frame.len(),
+            frame_sha256: None,
+            events,
+        };
+        let secrets = self.secrets;
+        let path = self.dir.join(format!(
+            "{}-{}-{}.{REPRO_EXTENSION}",
+            bundle
+                .captured_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis(),
+            connection.peer_pid,
+            uuid::Uuid::new_v4(),
+        ));
+        // Redacting a large frame and writing it would hold up `next`
+        tokio::task::spawn_blocking(move || {
+            let written = serde_json::to_vec_pretty(&bundle.redacted(secrets))
+                .map_err(anyhow::Error::from)
+                .and_then(|json| Ok(std::fs::write(&path, json)?));
+            match written {
+                Ok(()) => tracing::info!(path = %path.display(), "Wrote repro bundle"),
+                Err(error) => {
+                    tracing::warn!(?error, path = %path.display(), "Couldn't write repro bundle")
+                }
+            }
+        });
+    }
+}
+
+impl ReproBundle {
+    /// Masks the secrets in `frame` and cuts it to `MAX_REPRO_FRAME`, or drops
+    /// it for its hash if it isn't JSON
+    fn redacted(mut self, secrets: &[&str]) -> Self {
+        match serde_json::from_slice(&self.frame) {
+            Ok(mut value) => {
+                redact::redact_keeping_types(&mut value, secrets);
+                self.frame = serde_json::to_vec(&value).unwrap_or_default();
+                self.frame.truncate(MAX_REPRO_FRAME);
+            }
+            Err(_) => {
+                self.frame_sha256 = sys::sha256(&self.frame)
+                    .ok()
+                    .map(|hash| hash.iter().map(|byte| format!("{byte:02x}")).collect());
+                self.frame = Vec::new();
+            }
+        }
+        self
+    }
+}
+
+/// Stores `ReproBundle::frame` as a hex string, rather than a JSON array of numbers
+mod hex_bytes {
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = bytes.iter().map(|byte| format!("{byte:02x}")).collect();
+        serializer.serialize_str(&hex)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() % 2 != 0 {
+            return Err(D::Error::custom("odd number of hex digits"));
+        }
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| {
+                hex.get(i..i + 2)
+                    .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                    .ok_or_else(|| D::Error::custom("invalid hex digit"))
+            })
+            .collect()
+    }
+}
+
+/// Decodes a worker frame the way `Server::next` does
+///
+/// A frame `acl` refuses is `Error::Disconnected(DisconnectReason::ProtocolViolation)`.
+pub(crate) fn decode_worker_frame<'a, W: DeserializeOwned>(
+    buf: &'a [u8],
+    info: &ConnectionInfo,
+) -> Result<(&'a str, Option<Metadata>, WorkerMsgInternal<W>), Error> {
+    let buf = std::str::from_utf8(buf)?;
+    let (metadata, msg) = serde_json::from_str::<WorkerMsgInternal<W>>(buf)?.split_metadata()?;
+    if !acl::worker_frame_allowed(&msg, info) {
+        return Err(Error::Disconnected(DisconnectReason::ProtocolViolation));
+    }
+    Ok((buf, metadata, msg))
+}
+
+/// Decodes a manager frame the way `Client::next` does, see `decode_worker_frame`
+pub(crate) fn decode_manager_frame<M: DeserializeOwned>(
+    buf: &[u8],
+) -> Result<(&str, Option<Metadata>, ManagerMsgInternal<M>), Error> {
+    let buf = std::str::from_utf8(buf)?;
+    let (metadata, msg) = serde_json::from_str::<ManagerMsgInternal<M>>(buf)?.split_metadata()?;
+    if !acl::manager_frame_allowed(&msg) {
+        return Err(Error::Disconnected(DisconnectReason::ProtocolViolation));
+    }
+    Ok((buf, metadata, msg))
+}
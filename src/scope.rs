@@ -0,0 +1,150 @@
+//! Which methods each kind of client may call on a shared `Listener`
+//!
+//! A `Listener` accepts anyone who can open its pipe, e.g. a GUI, a CLI, and a
+//! per-session agent. When a client connects, the listener impersonates it to
+//! read its `PeerIdentity` from the token it opened the pipe with, and asks
+//! `ServerOptions::grant_scopes` which scopes it gets. The worker message type says which scope each message
+//! needs with `Scoped::required_scope`, and `Server::next` refuses a user message
+//! that needs a scope the client wasn't granted with `Error::ScopeDenied`.
+//!
+//! The check runs on the decoded message rather than its method name on the
+//! wire, so a `serde` rename or alias can't let a message slip past.
+
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeSet, fmt, path::PathBuf, sync::Arc};
+
+use crate::sys;
+
+/// A worker message type whose methods may need scopes, see `Listener::accept_scoped`
+///
+/// Match every variant, without a wildcard, so a new method doesn't compile
+/// until someone decides what scope it needs.
+///
+/// ```
+/// #[derive(serde::Deserialize)]
+/// enum AgentRequest {
+///     Status,
+///     SetTunnel { enabled: bool },
+/// }
+///
+/// impl subzone::Scoped for AgentRequest {
+///     fn required_scope(&self) -> Option<&'static str> {
+///         match self {
+///             Self::Status => None,
+///             Self::SetTunnel { .. } => Some("admin"),
+///         }
+///     }
+/// }
+/// ```
+pub trait Scoped {
+    /// The scope a client needs to send this message, or `None` if any client may
+    fn required_scope(&self) -> Option<&'static str>;
+}
+
+impl Scoped for serde_json::Value {
+    fn required_scope(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl Scoped for String {
+    fn required_scope(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+impl Scoped for () {
+    fn required_scope(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// Who is on the other end of a pipe, as Windows sees it
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PeerIdentity {
+    pub pid: u32,
+    /// Full path of the process' exe
+    pub exe: PathBuf,
+    /// The token's account, as `DOMAIN\user`
+    pub user: String,
+    /// Whether the token is elevated, e.g. an admin who accepted a UAC prompt, or SYSTEM
+    pub elevated: bool,
+    /// The logon session, e.g. to tell one desktop user's agent from another's
+    pub session_id: u32,
+}
+
+impl PeerIdentity {
+    /// Reads the token of process `pid`
+    pub fn of_process(pid: u32) -> anyhow::Result<Self> {
+        sys::peer_identity(pid)
+    }
+
+    /// Reads the token of the client on our end of `pipe`, by impersonating it
+    ///
+    /// What `Listener` uses, since a PID can be reused by another process.
+    pub(crate) fn of_pipe_client(
+        pipe: &impl std::os::windows::io::AsHandle,
+    ) -> anyhow::Result<Self> {
+        sys::pipe_client_identity(pipe)
+    }
+}
+
+/// Picks a client's scopes, see `ServerOptions::grant_scopes`
+type GrantFn = Arc<dyn Fn(&PeerIdentity) -> Vec<String> + Send + Sync>;
+
+/// Decides a client's scopes from its identity, see `ServerOptions::grant_scopes`
+#[derive(Clone)]
+pub(crate) struct ScopeGrant(GrantFn);
+
+impl ScopeGrant {
+    pub(crate) fn new(
+        grant: impl Fn(&PeerIdentity) -> Vec<String> + Send + Sync + 'static,
+    ) -> Self {
+        Self(Arc::new(grant))
+    }
+
+    /// No scopes if the identity couldn't be read, so a failure never grants more
+    pub(crate) fn scopes_for(&self, identity: Option<&PeerIdentity>) -> BTreeSet<String> {
+        let Some(identity) = identity else {
+            return BTreeSet::new();
+        };
+        let scopes = (self.0)(identity).into_iter().collect();
+        tracing::debug!(?identity, ?scopes, "Granted scopes");
+        scopes
+    }
+}
+
+impl fmt::Debug for ScopeGrant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ScopeGrant")
+    }
+}
+
+/// A connection's granted scopes, checked against each message's requirement
+///
+/// Holds `W::required_scope` as a function pointer, so `Server` only needs
+/// `W: Scoped` where scopes are turned on.
+pub(crate) struct ScopeCheck<W> {
+    granted: BTreeSet<String>,
+    required: fn(&W) -> Option<&'static str>,
+}
+
+impl<W: Scoped> ScopeCheck<W> {
+    pub(crate) fn new(granted: BTreeSet<String>) -> Self {
+        Self {
+            granted,
+            required: W::required_scope,
+        }
+    }
+}
+
+impl<W> ScopeCheck<W> {
+    pub(crate) fn granted(&self) -> &BTreeSet<String> {
+        &self.granted
+    }
+
+    /// The scope `msg` needs, if it wasn't granted
+    pub(crate) fn missing(&self, msg: &W) -> Option<&'static str> {
+        (self.required)(msg).filter(|scope| !self.granted.contains(*scope))
+    }
+}
@@ -0,0 +1,256 @@
+//! A worker-side driver that runs a handler for each message from the manager
+//!
+//! `serve` answers `Server::call` with `Client::respond`, and replies to
+//! `Server::send` with `Client::send`, until the manager sends `Shutdown`.
+//! Workers that need the other control frames, e.g. `Power`, should loop on
+//! `Client::next` themselves.
+//!
+//! A handler that panics fails its call with a `RemoteError` whose code is
+//! `HANDLER_PANIC_CODE`, and `serve` keeps going.
+//!
+//! Long handlers can watch `Client::shutdown_token`, cloned before the client
+//! is passed in, which trips as soon as `Shutdown` arrives.
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    task::JoinSet,
+};
+use tracing::{Instrument as _, Span};
+
+use crate::{method_stats, panic_guard, Client, ManagerMsgInternal, RemoteError};
+
+/// `RemoteError::code` for calls whose handler panicked
+pub const HANDLER_PANIC_CODE: &str = "panic";
+
+/// Picks the key a message is ordered by, see `ServeOptions::ordered_by`
+type KeyFn<M> = Box<dyn Fn(&M) -> String + Send + Sync>;
+
+/// How `serve` schedules handlers
+pub struct ServeOptions<M> {
+    key: Option<KeyFn<M>>,
+    /// Limits how many handlers run at once, see `concurrency`
+    permits: Option<Arc<Semaphore>>,
+    /// By method name, see `timeout`
+    timeouts: HashMap<String, Duration>,
+    /// Set by `timeout`, which is the only place that knows `M` is `Serialize`
+    method_of: Option<fn(&M) -> String>,
+}
+
+impl<M> Default for ServeOptions<M> {
+    fn default() -> Self {
+        Self {
+            key: None,
+            permits: None,
+            timeouts: HashMap::new(),
+            method_of: None,
+        }
+    }
+}
+
+impl<M> ServeOptions<M> {
+    /// Handles one message at a time, in the order they arrive
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handles messages with the same key in order, and different keys concurrently
+    ///
+    /// e.g. keyed by resource ID, so two updates to one resource can't be
+    /// applied out of order, but a slow resource doesn't hold up the rest.
+    pub fn ordered_by(mut self, key: impl Fn(&M) -> String + Send + Sync + 'static) -> Self {
+        self.key = Some(Box::new(key));
+        self
+    }
+
+    /// Runs up to `limit` handlers at once on spawned tasks
+    ///
+    /// The driver keeps reading while they run, so a slow handler doesn't hold
+    /// up health probes or the other control frames. With `ordered_by`, the
+    /// limit counts handlers across all keys.
+    pub fn concurrency(mut self, limit: usize) -> Self {
+        // A zero limit would never run anything
+        self.permits = Some(Arc::new(Semaphore::new(limit.max(1))));
+        self
+    }
+
+    /// Gives up on `method`'s handler after `timeout`
+    ///
+    /// `method` is the name of the message's enum variant, e.g. `"Connect"`.
+    /// The handler is dropped, and the stuck method is logged. If the message
+    /// came from `Server::call`, the call fails with `Error::HandlerTimeout`
+    /// instead of waiting on the manager's own deadline.
+    pub fn timeout(mut self, method: impl Into<String>, timeout: Duration) -> Self
+    where
+        M: Serialize,
+    {
+        self.timeouts.insert(method.into(), timeout);
+        self.method_of = Some(method_stats::sent_method);
+        self
+    }
+
+    /// The method name and timeout for `msg`, if it has one
+    fn timeout_for(&self, msg: &M) -> Option<(String, Duration)> {
+        let method = (self.method_of?)(msg);
+        let timeout = *self.timeouts.get(&method)?;
+        Some((method, timeout))
+    }
+
+    /// Whether handlers run on the driver's task, one at a time
+    fn is_serial(&self) -> bool {
+        self.key.is_none() && self.permits.is_none()
+    }
+}
+
+/// A handler's output, and where it goes
+struct Reply<W> {
+    /// `Some` for calls, which are answered with `respond`
+    id: Option<u64>,
+    outcome: Outcome<W>,
+}
+
+enum Outcome<W> {
+    Done(W),
+    TimedOut {
+        method: String,
+        timeout: Duration,
+    },
+    /// The handler panicked, with the panic message
+    Panicked(String),
+}
+
+/// Runs a handler's future, giving up after its timeout
+///
+/// A panicking handler fails its call instead of taking `serve` down with it.
+async fn run<W>(
+    handler: impl Future<Output = W>,
+    timeout: Option<(String, Duration)>,
+) -> Outcome<W> {
+    let handler = panic_guard::catch_unwind(handler);
+    let result = match timeout {
+        None => handler.await,
+        Some((method, timeout)) => match tokio::time::timeout(timeout, handler).await {
+            Ok(result) => result,
+            Err(_) => {
+                tracing::warn!(?method, ?timeout, "Handler timed out");
+                return Outcome::TimedOut { method, timeout };
+            }
+        },
+    };
+    match result {
+        Ok(msg) => Outcome::Done(msg),
+        Err(message) => {
+            tracing::error!(?message, "Handler panicked");
+            Outcome::Panicked(message)
+        }
+    }
+}
+
+/// Runs `handler` for every message and call from the manager, until it sends `Shutdown`
+///
+/// On `Shutdown`, waits for the handlers still running, sends their replies,
+/// and closes the connection. The client's `shutdown_token` has already tripped
+/// by then, so handlers watching it can finish early.
+pub async fn serve<M, W, H, Fut>(
+    mut client: Client<M, W>,
+    options: ServeOptions<M>,
+    handler: H,
+) -> Result<()>
+where
+    M: DeserializeOwned + Send + 'static,
+    W: Serialize + Send + 'static,
+    H: Fn(M) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = W> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+    // Every spawned handler. Dropping it aborts them, so returning early,
+    // or being dropped, doesn't leave them running without a connection.
+    let mut handlers = JoinSet::new();
+    // Closes when the last handler spawned for each key finishes, which the next one waits on
+    let mut tails: HashMap<String, oneshot::Receiver<()>> = HashMap::new();
+    loop {
+        tokio::select! {
+            msg = client.next() => {
+                let (id, msg) = match msg? {
+                    ManagerMsgInternal::Request { id, msg, .. } => (Some(id), msg),
+                    ManagerMsgInternal::User(msg) => (None, msg),
+                    ManagerMsgInternal::Shutdown => break,
+                    _ => continue,
+                };
+                let span = id
+                    .and_then(|id| client.request_span(id))
+                    .unwrap_or_else(Span::none);
+                let timeout = options.timeout_for(&msg);
+                if options.is_serial() {
+                    let outcome = run(handler(msg), timeout).instrument(span).await;
+                    deliver(&mut client, Reply { id, outcome }).await?;
+                    continue;
+                }
+                tails.retain(|_, tail| {
+                    matches!(tail.try_recv(), Err(oneshot::error::TryRecvError::Empty))
+                });
+                let key = options.key.as_ref().map(|key| key(&msg));
+                let previous = key.as_ref().and_then(|key| tails.remove(key));
+                let (done_tx, done_rx) = oneshot::channel::<()>();
+                let permits = options.permits.clone();
+                let handler = Arc::clone(&handler);
+                let reply_tx = reply_tx.clone();
+                handlers.spawn(
+                    async move {
+                        // Dropped when this handler finishes or is aborted
+                        let _done = done_tx;
+                        if let Some(previous) = previous {
+                            // Closed, not sent, so this waits however the previous one ended
+                            previous.await.ok();
+                        }
+                        // Taken after the previous handler for the key, so a waiting
+                        // handler doesn't hold a permit
+                        let _permit = match &permits {
+                            Some(permits) => permits.acquire().await.ok(),
+                            None => None,
+                        };
+                        let outcome = run(handler(msg), timeout).await;
+                        reply_tx.send(Reply { id, outcome }).ok();
+                    }
+                    .instrument(span),
+                );
+                if let Some(key) = key {
+                    tails.insert(key, done_rx);
+                }
+            }
+            Some(reply) = reply_rx.recv() => deliver(&mut client, reply).await?,
+            // Reaps finished handlers, so the set doesn't grow
+            Some(_) = handlers.join_next(), if !handlers.is_empty() => {}
+        }
+    }
+    drop(reply_tx);
+    while handlers.join_next().await.is_some() {}
+    while let Some(reply) = reply_rx.recv().await {
+        deliver(&mut client, reply).await?;
+    }
+    client.close().await
+}
+
+async fn deliver<M: DeserializeOwned, W: Serialize>(
+    client: &mut Client<M, W>,
+    reply: Reply<W>,
+) -> Result<()> {
+    match (reply.id, reply.outcome) {
+        (Some(id), Outcome::Done(msg)) => client.respond(id, msg).await?,
+        (None, Outcome::Done(msg)) => client.send(msg).await?,
+        (Some(id), Outcome::TimedOut { method, timeout }) => {
+            client.respond_timed_out(id, method, timeout).await?
+        }
+        (Some(id), Outcome::Panicked(message)) => {
+            let error = RemoteError::new(format!("handler panicked: {message}"))
+                .with_code(HANDLER_PANIC_CODE);
+            client.respond_error(id, error).await?
+        }
+        // Nobody is waiting on it, `run` already logged it
+        (None, Outcome::TimedOut { .. } | Outcome::Panicked(_)) => {}
+    }
+    Ok(())
+}
@@ -1,31 +1,56 @@
 use anyhow::{bail, Context, Result};
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
-    ffi::c_void,
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
     marker::PhantomData,
-    os::windows::io::{AsHandle, AsRawHandle},
+    mem::ManuallyDrop,
+    path::{Path, PathBuf},
     process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
     time::Duration,
 };
 use tokio::{
-    io::{AsyncWriteExt, WriteHalf},
+    io::{AsyncWriteExt, BufReader, WriteHalf},
     net::windows::named_pipe::{self, NamedPipeServer},
     process::{self, Child},
     sync::mpsc,
     time::timeout,
 };
-use windows::Win32::{
-    Foundation::HANDLE,
-    System::JobObjects::{
-        AssignProcessToJobObject, CreateJobObjectA, JobObjectExtendedLimitInformation,
-        SetInformationJobObject, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
-        JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE,
+use tracing::Instrument as _;
+
+use crate::{
+    call::PendingCalls,
+    clock::{self, Transit},
+    disconnect::PeerProcess,
+    etw,
+    event_log::EventLog,
+    extension::Extensions,
+    fingerprint,
+    frame::{
+        reader_task, recv_frame, try_recv_frame, FrameLimits, FrameReader, FrameWriter, ReadLimits,
     },
-    System::Pipes::GetNamedPipeClientProcessId,
+    handshake::{HandshakeState, Input, Output},
+    integrity, log_filter, log_forward,
+    method_stats::{self, MethodTable},
+    os, otel,
+    quota::QuotaSlot,
+    redact::MessageLog,
+    repro::{self, ReproCapture},
+    scope::ScopeCheck,
+    stats::LatestStats,
+    sys,
+    watchdog::{self, PollTracker},
+    BinaryCheck, Callbacks, ClockSample, Config, ConfigAck, ConnectionEvent, ConnectionEventKind,
+    ConnectionInfo, DisconnectReason, Error, HealthCheck, HealthStatus, Histogram, JobAccounting,
+    ManagerMsgInternal, Metadata, MethodStats, NetworkChange, PendingCall, PipeId, PipeMode,
+    PowerEvent, Redact, ReproSide, ResponseFuture, Scoped, WorkerExit, WorkerMsgInternal,
+    WorkerPidFile, WorkerStats,
 };
 
-use crate::{read_deserialize, write_serialize, Error, ManagerMsgInternal, WorkerMsgInternal};
-
 /// A named pipe server linked to a worker subprocess
 pub struct Subprocess<M, W> {
     pub server: Server<M, W>,
@@ -38,69 +63,319 @@ impl<M: Serialize, W: DeserializeOwned> Subprocess<M, W> {
     /// The process ID and cookie have already been checked for security
     /// when this function returns.
     pub async fn new(leak_guard: &mut LeakGuard, args: &[&str]) -> Result<Self> {
-        let (mut server, pipe_id) =
-            UnconnectedServer::new().context("couldn't create UnconnectedServer")?;
-        let mut process = process::Command::new(
-            std::env::current_exe().context("couldn't get current exe name")?,
-        );
+        SubprocessBuilder::new(args).spawn(leak_guard).await
+    }
+}
+
+/// Environment variable that carries the pipe ID for `SecretDelivery::Env`
+pub(crate) const PIPE_ID_ENV: &str = "SUBZONE_PIPE_ID";
+
+/// How `SubprocessBuilder::spawn` tells the worker which pipe to connect to
+///
+/// Command lines can be read by any process on the machine, so anything that
+/// knows the pipe ID from `Args` can race the worker to connect. The cookie is
+/// always written to the worker's stdin.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum SecretDelivery {
+    /// Appended to the worker's arguments, for workers that call `Client::new`
+    #[default]
+    Args,
+    /// Written to the worker's stdin ahead of the cookie, see `Client::new_inherited`
+    Stdin,
+    /// Set in the worker's environment, which `Client::new_inherited` scrubs after reading
+    ///
+    /// Other processes running as the same user can still read it before then.
+    Env,
+}
+
+/// Options for spawning a `Subprocess`
+pub struct SubprocessBuilder {
+    args: Vec<OsString>,
+    resume_state: Option<Vec<u8>>,
+    config: Config,
+    secret_delivery: SecretDelivery,
+    binary_check: Option<BinaryCheck>,
+    exe: Option<PathBuf>,
+}
+
+impl SubprocessBuilder {
+    /// * `args` - Arguments for the worker, the pipe ID is appended after them
+    ///   unless `secret_delivery` says otherwise
+    pub fn new(args: &[&str]) -> Self {
+        Self {
+            args: args.iter().map(OsString::from).collect(),
+            resume_state: None,
+            config: Config::default(),
+            secret_delivery: SecretDelivery::default(),
+            binary_check: None,
+            exe: None,
+        }
+    }
+
+    /// Runs `exe` instead of the current exe, e.g. when the worker ships as its own binary
+    ///
+    /// Must be an absolute path. The worker gets the same arguments either way.
+    pub fn exe(mut self, exe: impl Into<PathBuf>) -> Self {
+        self.exe = Some(exe.into());
+        self
+    }
+
+    /// How the worker learns the pipe ID, see `SecretDelivery`
+    pub fn secret_delivery(mut self, secret_delivery: SecretDelivery) -> Self {
+        self.secret_delivery = secret_delivery;
+        self
+    }
+
+    /// Appends an argument for the worker, before the pipe ID
+    ///
+    /// Arguments that look like a pipe ID fail `spawn` with `Error::ReservedArgument`,
+    /// so they can't be mistaken for the real one, see `WorkerArgs`.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Appends arguments for the worker, see `arg`
+    pub fn args(mut self, args: impl IntoIterator<Item = impl Into<OsString>>) -> Self {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    /// Timeouts and limits for the handshake and the connection, see `Config`
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Opaque state handed to the worker at the end of the handshake
+    ///
+    /// e.g. the last state a crashed worker acknowledged, so its replacement can
+    /// skip full re-initialization. The worker reads it from `Client::resume_state`.
+    pub fn resume_state(mut self, resume_state: Vec<u8>) -> Self {
+        self.resume_state = Some(resume_state);
+        self
+    }
+
+    /// Checks the worker exe before running it, failing with `Error::UntrustedBinary`
+    ///
+    /// Use this when the manager runs with more privileges than whoever can
+    /// write to the exe's directory.
+    pub fn binary_check(mut self, binary_check: BinaryCheck) -> Self {
+        self.binary_check = Some(binary_check);
+        self
+    }
+
+    /// Spawns the worker and waits for it to connect and pass the security checks
+    pub async fn spawn<M: Serialize, W: DeserializeOwned>(
+        self,
+        leak_guard: &mut LeakGuard,
+    ) -> Result<Subprocess<M, W>> {
+        let Self {
+            args,
+            resume_state,
+            config,
+            secret_delivery,
+            binary_check,
+            exe,
+        } = self;
+        if args.iter().any(|arg| {
+            arg.to_str()
+                .is_some_and(|arg| PipeId::from_arg(arg).is_ok())
+        }) {
+            return Err(Error::ReservedArgument.into());
+        }
+        let (server, pipe_id) =
+            UnconnectedServer::with_config(&config).context("couldn't create UnconnectedServer")?;
+        let exe = integrity::resolve_exe(exe.as_deref())?;
+        // Held until the process is created, so the exe can't be swapped after the check
+        let _exe_lock = binary_check
+            .map(|check| integrity::verify(&exe, &check))
+            .transpose()?;
+        let mut process = process::Command::new(&exe);
         // Make the child's stdin piped so we can send it a security cookie.
         process.stdin(Stdio::piped());
-        for arg in args {
-            process.arg(arg);
+        process.args(&args);
+        match secret_delivery {
+            SecretDelivery::Args => {
+                process.arg(pipe_id.to_arg());
+            }
+            SecretDelivery::Stdin => {}
+            SecretDelivery::Env => {
+                process.env(PIPE_ID_ENV, pipe_id.to_arg());
+            }
+        }
+        if secret_delivery != SecretDelivery::Env {
+            // In case we were spawned with one and it wasn't scrubbed
+            process.env_remove(PIPE_ID_ENV);
         }
-        process.arg(&pipe_id);
+        // Start suspended, so anything the worker spawns is in the job too
+        process.creation_flags(sys::CREATE_SUSPENDED.0);
         let mut process = process.spawn().context("couldn't spawn subprocess")?;
-        if let Err(error) = leak_guard.add_process(&process) {
-            tracing::error!("couldn't add subprocess to leak guard, attempting to kill subprocess");
+        let contained = leak_guard
+            .add_process(&process)
+            .context("couldn't add subprocess to leak guard")
+            .and_then(|()| {
+                let pid = process.id().context("child process should have an ID")?;
+                sys::resume_threads(pid).context("couldn't resume subprocess")
+            });
+        if let Err(error) = contained {
+            tracing::error!(
+                "couldn't start subprocess in leak guard, attempting to kill subprocess"
+            );
             process.kill().await.ok();
-            return Err(error.context("couldn't add subprocess to leak guard"));
+            return Err(error);
+        }
+        if secret_delivery == SecretDelivery::Stdin {
+            let line = format!("{}\n", pipe_id.to_arg());
+            process
+                .stdin
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("couldn't get stdin of subprocess"))?
+                .write_all(line.as_bytes())
+                .await
+                .context("couldn't write pipe ID to subprocess stdin")?;
         }
         let child_pid = process
             .id()
             .ok_or_else(|| anyhow::anyhow!("child process should have an ID"))?;
-        let mut worker = SubcommandChild { process };
+        let mut worker = SubcommandChild {
+            process,
+            kills: Some(Arc::clone(&leak_guard.kills)),
+        };
 
         // Accept the connection
-        server
-            .pipe
-            .connect()
+        timeout(config.accept_timeout, server.pipe.connect())
             .await
+            .map_err(|_| Error::NoConnectionWithin(config.accept_timeout))?
             .context("expected a client connection")?;
-        let client_pid = server.client_pid()?;
-
-        // Make sure our child process connected to our pipe, and not some 3rd-party process
-        if child_pid != client_pid {
-            bail!("PID of child process and pipe client should match");
-        }
-
-        // Make sure the process on the other end of the pipe knows the cookie we went
-        // to our child process' stdin
-        let mut child_stdin = worker
-            .process
-            .stdin
-            .take()
-            .ok_or_else(|| anyhow::anyhow!("couldn't get stdin of subprocess"))?;
-        let cookie = uuid::Uuid::new_v4().to_string();
-        let line = format!("{}\n", cookie);
-        tracing::trace!(?cookie, "Sending cookie");
-        child_stdin
-            .write_all(line.as_bytes())
+        let found = if os::wine::simulated() {
+            Err(anyhow::anyhow!(
+                "simulated Wine can't read the pipe client's PID"
+            ))
+        } else {
+            server.client_pid()
+        };
+        let client_pid = match found {
+            Ok(pid) => pid,
+            // The cookie alone proves it's our child, just with less defense in depth
+            Err(error) if os::wine::detected() => {
+                tracing::warn!(
+                    ?error,
+                    "Couldn't read the pipe client's PID under Wine, relying on the cookie"
+                );
+                child_pid
+            }
+            Err(error) => return Err(error),
+        };
+        let mut state = HandshakeState::new(
+            child_pid,
+            uuid::Uuid::new_v4().to_string(),
+            config
+                .require_same_arch
+                .then(|| std::env::consts::ARCH.to_string()),
+        );
+        let handshake = async {
+            // Make sure our child process connected to our pipe, and not some 3rd-party process
+            if let Output::Rejected(reason) = state.advance(Input::Connected { pid: client_pid }) {
+                return Err(Error::HandshakeRejected {
+                    peer: client_pid,
+                    reason,
+                }
+                .into());
+            }
+            let mut server = Server::<M, W>::new(server.pipe, &config, client_pid)?;
+            match timeout(
+                config.handshake_timeout,
+                handshake(&mut server, &mut worker, &mut state, resume_state),
+            )
             .await
-            .context("couldn't write cookie to subprocess stdin")?;
-
-        let buf = read_deserialize(&mut server.pipe).await?;
-        let buf = std::str::from_utf8(&buf)?;
-        let WorkerMsgInternal::<W>::Cookie(echoed_cookie) = serde_json::from_str(buf)? else {
-            bail!("didn't receive cookie from pipe client");
+            {
+                Ok(Ok(())) => Ok(server),
+                Ok(Err(error)) => Err(Error::HandshakeRejected {
+                    peer: client_pid,
+                    reason: format!("{error:#}"),
+                }
+                .into()),
+                Err(_) => Err(Error::HandshakeTimeout { peer: client_pid }.into()),
+            }
         };
-        tracing::trace!(?echoed_cookie, "Got cookie back");
-        if echoed_cookie != cookie {
-            bail!("cookie received from pipe client should match the cookie we sent to our child process");
-        }
+        let server = match handshake.await {
+            Ok(server) => server,
+            Err(error) => {
+                etw::handshake_failed(child_pid, &error);
+                // Only kill the client if it's ours, a 3rd-party process isn't ours to kill
+                if client_pid == child_pid && worker.process.kill().await.is_ok() {
+                    worker.record_kill(child_pid, "failed the handshake");
+                }
+                return Err(error);
+            }
+        };
+        Ok(Subprocess { server, worker })
+    }
+}
 
-        let server = Server::new(server.pipe)?;
+/// Writes the cookie to the worker's stdin and drives `state` until the worker passes
+async fn handshake<M: Serialize, W: DeserializeOwned>(
+    server: &mut Server<M, W>,
+    worker: &mut SubcommandChild,
+    state: &mut HandshakeState,
+    resume_state: Option<Vec<u8>>,
+) -> Result<()> {
+    // Make sure the process on the other end of the pipe knows the cookie we went
+    // to our child process' stdin
+    let mut child_stdin = worker
+        .process
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("couldn't get stdin of subprocess"))?;
+    tracing::trace!(cookie = state.cookie(), "Sending cookie");
+    let line = format!("{}\n", state.cookie());
+    child_stdin
+        .write_all(line.as_bytes())
+        .await
+        .context("couldn't write cookie to subprocess stdin")?;
 
-        Ok(Self { server, worker })
+    let mut resume_state = Some(resume_state);
+    loop {
+        let buf = recv_frame(&mut server.read_rx, &mut server.disconnect).await?;
+        let received_us = clock::now_us();
+        match state.advance(Input::Frame(&buf)) {
+            Output::Read => {}
+            Output::Prove(proof) => {
+                server
+                    .pipe_writer
+                    .write(&ManagerMsgInternal::<M>::Proof(proof))
+                    .await
+                    .context("couldn't send the server proof")?;
+            }
+            Output::Accept { finished } => {
+                server
+                    .pipe_writer
+                    .write(&ManagerMsgInternal::<M>::Accepted {
+                        resume_state: resume_state.take().flatten(),
+                        fingerprint: Some(server.info.fingerprint.clone()),
+                        clock: Some(ClockSample {
+                            received_us,
+                            sent_us: clock::now_us(),
+                        }),
+                    })
+                    .await
+                    .context("couldn't finish handshake")?;
+                if finished {
+                    return Ok(());
+                }
+            }
+            Output::Finished(theirs) => {
+                fingerprint::warn_on_mismatch(
+                    &server.info.fingerprint,
+                    &theirs,
+                    server.info.peer_pid,
+                );
+                server.info.peer_fingerprint = Some(theirs);
+                return Ok(());
+            }
+            Output::Rejected(reason) => bail!("{reason}"),
+        }
     }
 }
 
@@ -111,20 +386,28 @@ pub(crate) struct UnconnectedServer {
 
 impl UnconnectedServer {
     /// Requires a Tokio context
-    pub(crate) fn new() -> Result<(Self, String)> {
-        let id = super::random_pipe_id();
-        let this = Self::new_with_id(&id)?;
+    pub(crate) fn new() -> Result<(Self, PipeId)> {
+        Self::with_config(&Config::default())
+    }
+
+    /// Creates the pipe with `Config::pipe_buffer_size`
+    fn with_config(config: &Config) -> Result<(Self, PipeId)> {
+        let id = PipeId::random();
+        let this = Self::new_with_id(&id, config)?;
         Ok((this, id))
     }
 
     fn client_pid(&self) -> Result<u32> {
-        get_client_pid(&self.pipe)
+        sys::named_pipe_client_pid(&self.pipe)
     }
 
-    fn new_with_id(id: &str) -> Result<Self> {
+    fn new_with_id(id: &PipeId, config: &Config) -> Result<Self> {
         let pipe = named_pipe::ServerOptions::new()
             .first_pipe_instance(true)
-            .create(id)?;
+            .pipe_mode(PipeMode::Byte.into())
+            .in_buffer_size(config.pipe_buffer_size)
+            .out_buffer_size(config.pipe_buffer_size)
+            .create(id.as_str())?;
 
         Ok(Self { pipe })
     }
@@ -135,7 +418,8 @@ impl UnconnectedServer {
     /// Try pairing it with `tokio::time:timeout`
     pub(crate) async fn accept<M: Serialize, W: DeserializeOwned>(self) -> Result<Server<M, W>> {
         self.pipe.connect().await?;
-        Server::new(self.pipe)
+        let client_pid = self.client_pid()?;
+        Server::new(self.pipe, &Config::default(), client_pid)
     }
 }
 
@@ -144,34 +428,113 @@ impl UnconnectedServer {
 /// Manual testing shows that if the corresponding Client's process crashes, Windows will
 /// be nice and return errors for anything trying to read from the Server
 pub struct Server<M, W> {
-    client_pid: u32,
-    pipe_writer: WriteHalf<NamedPipeServer>,
+    info: ConnectionInfo,
+    pipe_writer: FrameWriter<WriteHalf<NamedPipeServer>>,
     /// Needed to make `next` cancel-safe
-    read_rx: mpsc::Receiver<Vec<u8>>,
+    read_rx: mpsc::Receiver<Result<Vec<u8>, Error>>,
     /// Needed to make `next` cancel-safe
-    _reader_task: tokio::task::JoinHandle<Result<()>>,
+    reader_task: tokio::task::JoinHandle<()>,
+    /// Why the connection ended, once `next` has seen it
+    disconnect: Option<DisconnectReason>,
+    extensions: Extensions,
+    log_filter: Option<log_filter::LogFilterHandle>,
+    /// Topics the worker subscribed to, see `publish`
+    subscriptions: Subscriptions,
+    /// Calls waiting for a `Response` frame
+    calls: PendingCalls<W>,
+    /// `push_config`s waiting for a `ConfigAck` frame
+    config_acks: PendingCalls<ConfigAck>,
+    /// The frame limits both sides have agreed on
+    limits: FrameLimits,
+    /// Limits pushed with `push_config` that the worker hasn't acked yet, by ack ID
+    pushed_limits: BTreeMap<u64, FrameLimits>,
+    /// Shared with the reader task, see `retune`
+    read_limits: ReadLimits,
+    /// See `report_stats`
+    stats: LatestStats,
+    /// `health` probes waiting for a `Health` frame
+    probes: PendingCalls<HealthStatus>,
+    /// See `method_stats`
+    methods: MethodTable,
+    /// Shared with the reader task, see `transit_latency`
+    transit: Transit,
+    poll_tracker: PollTracker,
+    /// Warns if `next` stops being polled, see `set_watchdog`
+    watchdog_task: Option<tokio::task::JoinHandle<()>>,
+    /// Headers of the last message from `next`
+    metadata: Option<Metadata>,
+    /// See `log_messages`
+    message_log: Option<MessageLog>,
+    /// See `recent_events`
+    history: EventLog,
+    /// See `capture_repros`
+    repro: Option<ReproCapture>,
+    /// See `enforce_scopes`
+    scopes: Option<ScopeCheck<W>>,
+    /// This connection's place under the `Listener`'s quotas, given back on drop
+    quota: Option<QuotaSlot>,
     _manager_msg: PhantomData<M>,
     _worker_msg: PhantomData<W>,
 }
 
 impl<M: Serialize, W: DeserializeOwned> Server<M, W> {
+    /// * `client_pid` - The connected client's PID, which the caller looks up
+    ///   so `SubprocessBuilder::spawn` can fall back to its child's under Wine
     #[tracing::instrument(skip_all)]
-    fn new(pipe: named_pipe::NamedPipeServer) -> Result<Self> {
-        let client_pid = get_client_pid(&pipe)?;
-        let (mut pipe_reader, pipe_writer) = tokio::io::split(pipe);
+    pub(crate) fn new(
+        pipe: named_pipe::NamedPipeServer,
+        config: &Config,
+        client_pid: u32,
+    ) -> Result<Self> {
+        let pipe_info = pipe.info()?;
+        let (pipe_reader, pipe_writer) = tokio::io::split(pipe);
         let (read_tx, read_rx) = mpsc::channel(1);
-        let _reader_task = tokio::spawn(async move {
-            loop {
-                let msg = read_deserialize(&mut pipe_reader).await?;
-                read_tx.send(msg).await?;
-            }
-        });
+        let transit = Transit::default();
+        let pipe_reader = FrameReader::with_max_len(
+            BufReader::with_capacity(config.read_buffer_size, pipe_reader),
+            config.max_frame_len,
+        )
+        .max_message_len(config.max_message_len)
+        .transit(transit.clone());
+        let read_limits = pipe_reader.limits();
+        let reader_task = tokio::spawn(reader_task(
+            pipe_reader,
+            read_tx,
+            PeerProcess::open(client_pid),
+        ));
 
+        etw::connected(etw::Side::Manager, client_pid);
+        let history = EventLog::default();
+        history.push(ConnectionEventKind::Connected {
+            peer_pid: client_pid,
+        });
         Ok(Self {
-            client_pid,
-            pipe_writer,
+            info: ConnectionInfo::new(client_pid, pipe_info),
+            pipe_writer: FrameWriter::with_max_len(pipe_writer, config.max_frame_len)
+                .max_message_len(config.max_message_len),
             read_rx,
-            _reader_task,
+            reader_task,
+            disconnect: None,
+            extensions: Default::default(),
+            log_filter: None,
+            subscriptions: Default::default(),
+            calls: Default::default(),
+            config_acks: Default::default(),
+            limits: FrameLimits::of(config),
+            pushed_limits: Default::default(),
+            read_limits,
+            stats: Default::default(),
+            probes: Default::default(),
+            methods: Default::default(),
+            transit,
+            poll_tracker: Default::default(),
+            watchdog_task: None,
+            metadata: None,
+            message_log: None,
+            history,
+            repro: None,
+            scopes: None,
+            quota: None,
             _manager_msg: Default::default(),
             _worker_msg: Default::default(),
         })
@@ -179,14 +542,21 @@ impl<M: Serialize, W: DeserializeOwned> Server<M, W> {
 
     /// Tells the pipe client to shutdown.
     ///
+    /// `send` writes its frame before returning, so nothing is queued here. The
+    /// `Shutdown` frame follows everything already sent, and this waits until the
+    /// worker disconnects, which it does after reading `Shutdown`, so nothing sent
+    /// before `close` is lost.
+    ///
     /// Should be wrapped in a Tokio timeout in case the pipe client isn't responding.
     pub async fn close(mut self) -> Result<()> {
-        write_serialize(&mut self.pipe_writer, &ManagerMsgInternal::<M>::Shutdown).await?;
+        self.pipe_writer
+            .write(&ManagerMsgInternal::<M>::Shutdown)
+            .await?;
         loop {
             // Pump out the read half until it errors
             match self.next().await {
                 Ok(_) => {}
-                Err(Error::Eof) => break,
+                Err(Error::Disconnected(_)) => break,
                 Err(error) => {
                     tracing::error!(?error, "Error while shutting down the named pipe");
                     break;
@@ -197,8 +567,48 @@ impl<M: Serialize, W: DeserializeOwned> Server<M, W> {
         Ok(())
     }
 
+    /// Waits up to `deadline` for pending calls to be answered, then closes
+    ///
+    /// Since this takes `self`, nothing new can be sent while draining, so a
+    /// half-applied change gets to finish. Messages that arrive in the meantime
+    /// are returned instead of dropped. Calls still pending at the deadline fail
+    /// with `Error::Disconnected`.
+    pub async fn drain(mut self, deadline: Duration) -> Result<Vec<W>> {
+        let mut received = vec![];
+        let mut disconnected = false;
+        let wait = async {
+            while !self.calls.is_empty() {
+                match self.next().await {
+                    Ok(msg) => received.push(msg),
+                    Err(Error::Disconnected(_)) => {
+                        disconnected = true;
+                        break;
+                    }
+                    Err(error) => tracing::warn!(?error, "Error while draining"),
+                }
+            }
+        };
+        if timeout(deadline, wait).await.is_err() {
+            tracing::warn!(
+                pending_calls = ?self.pending_calls(),
+                "Calls still pending after drain deadline"
+            );
+        }
+        if disconnected {
+            // Nobody left to do the close handshake with
+            return Ok(received);
+        }
+        self.close().await?;
+        Ok(received)
+    }
+
     pub fn client_pid(&self) -> u32 {
-        self.client_pid
+        self.info.peer_pid
+    }
+
+    /// Returns diagnostic details about this connection
+    pub fn connection_info(&self) -> &ConnectionInfo {
+        &self.info
     }
 
     /// Receives a message from the client
@@ -206,30 +616,628 @@ impl<M: Serialize, W: DeserializeOwned> Server<M, W> {
     /// # Cancel safety
     ///
     /// This method is cancel-safe, internally it calls `tokio::sync::mpsc::Receiver::recv`
+    ///
+    /// Extension frames are routed to their registered channels, and responses
+    /// to their `ResponseFuture`s, while this is being polled. Neither is returned here.
     pub async fn next(&mut self) -> Result<W, Error> {
-        let buf = self.read_rx.recv().await.ok_or_else(|| Error::Eof)?;
-        let buf = std::str::from_utf8(&buf)?;
-        let msg = serde_json::from_str(buf)?;
-        let WorkerMsgInternal::User(msg) = msg else {
-            return Err(Error::Protocol);
+        // A clone, so the guard doesn't borrow `self` while a violation drops the worker
+        let poll_tracker = self.poll_tracker.clone();
+        let _poll = poll_tracker.enter();
+        loop {
+            let was_connected = self.disconnect.is_none();
+            let buf = match recv_frame(&mut self.read_rx, &mut self.disconnect).await {
+                Ok(buf) => buf,
+                Err(error) => return Err(self.recv_failed(was_connected, error)),
+            };
+            if let Some(msg) = self.handle_frame(&buf) {
+                return msg;
+            }
+        }
+    }
+
+    /// Handles one frame from the reader task, returning what `next` should
+    ///
+    /// `None` for frames handled internally, like responses and extension frames.
+    fn handle_frame(&mut self, buf: &[u8]) -> Option<Result<W, Error>> {
+        let (buf, metadata, msg) = match repro::decode_worker_frame::<W>(buf, &self.info) {
+            Ok(decoded) => decoded,
+            Err(error) => {
+                if let Some(repro) = &mut self.repro {
+                    let events = self.history.list();
+                    repro.write(ReproSide::Manager, &self.info, &error, buf, events);
+                }
+                if matches!(
+                    error,
+                    Error::Disconnected(DisconnectReason::ProtocolViolation)
+                ) {
+                    return Some(Err(self.protocol_violation()));
+                }
+                return Some(Err(error));
+            }
+        };
+        self.metadata = metadata;
+        // User frames and responses are recorded with their method below
+        if !matches!(
+            msg,
+            WorkerMsgInternal::User(_)
+                | WorkerMsgInternal::Response { .. }
+                | WorkerMsgInternal::Log(_)
+        ) {
+            self.history.received(msg.kind(), None);
+        }
+        match msg {
+            WorkerMsgInternal::User(msg) => {
+                if let Some(log) = &self.message_log {
+                    log.received(buf);
+                }
+                let method = method_stats::received_method(buf);
+                self.history.received("User", Some(method.clone()));
+                self.methods.record_received(&method, buf.len());
+                if let Some(scope) = self.scopes.as_ref().and_then(|s| s.missing(&msg)) {
+                    tracing::warn!(
+                        method,
+                        scope,
+                        "Refused a message the client has no scope for"
+                    );
+                    return Some(Err(Error::ScopeDenied {
+                        method,
+                        scope: scope.to_string(),
+                    }));
+                }
+                return Some(Ok(msg));
+            }
+            WorkerMsgInternal::Response { id, msg } => {
+                if let Some(log) = &self.message_log {
+                    log.received(buf);
+                }
+                let completed = self.calls.complete(id, msg);
+                self.history.received(
+                    "Response",
+                    completed.as_ref().map(|(method, _)| method.clone()),
+                );
+                if let Some((method, elapsed)) = completed {
+                    self.methods.record_received(&method, buf.len());
+                    self.methods.record_call(&method, elapsed);
+                }
+            }
+            WorkerMsgInternal::Extension(id, payload) => self.extensions.dispatch(id, payload),
+            WorkerMsgInternal::SetLogFilter(directives) => {
+                log_filter::apply_from_peer(self.log_filter.as_ref(), &directives)
+            }
+            WorkerMsgInternal::Log(record) => log_forward::emit(self.info.peer_pid, record),
+            WorkerMsgInternal::Subscribe(topic) => {
+                tracing::debug!(?topic, "Worker subscribed");
+                lock_subscriptions(&self.subscriptions).insert(topic);
+            }
+            WorkerMsgInternal::Unsubscribe(topic) => {
+                tracing::debug!(?topic, "Worker unsubscribed");
+                lock_subscriptions(&self.subscriptions).remove(&topic);
+            }
+            WorkerMsgInternal::ConfigAck { id, ack } => {
+                if let Some(pushed) = self.pushed_limits.remove(&id) {
+                    if ack == ConfigAck::Applied {
+                        self.limits = pushed;
+                    }
+                    self.retune();
+                }
+                self.config_acks.complete(id, ack);
+            }
+            WorkerMsgInternal::Stats(stats) => self.stats.set(stats),
+            WorkerMsgInternal::Health { id, status } => {
+                self.probes.complete(id, status);
+            }
+            WorkerMsgInternal::Fingerprint(fingerprint) => {
+                fingerprint::warn_on_mismatch(
+                    &self.info.fingerprint,
+                    &fingerprint,
+                    self.info.peer_pid,
+                );
+                self.info.peer_fingerprint = Some(fingerprint);
+            }
+            WorkerMsgInternal::ClockOffset(offset) => {
+                tracing::debug!(?offset, "Worker estimated the clock offset");
+                if let Some(offset) = offset.plausible() {
+                    self.info.clock_offset = Some(offset);
+                    self.transit.set_offset(offset);
+                    self.pipe_writer.set_timestamps(true);
+                }
+            }
+            WorkerMsgInternal::HandlerTimeout {
+                id,
+                method,
+                timeout_ms,
+            } => {
+                let error = Error::HandlerTimeout {
+                    method,
+                    timeout: Duration::from_millis(timeout_ms),
+                };
+                if let Some((method, elapsed)) = self.calls.fail(id, error) {
+                    self.methods.record_call(&method, elapsed);
+                }
+            }
+            WorkerMsgInternal::Failed { id, error } => {
+                if let Some((method, elapsed)) = self.calls.fail(id, Error::Remote(error)) {
+                    self.methods.record_received(&method, buf.len());
+                    self.methods.record_call(&method, elapsed);
+                }
+            }
+            // Refused by `acl`
+            WorkerMsgInternal::Cookie(_)
+            | WorkerMsgInternal::Hello { .. }
+            | WorkerMsgInternal::Meta(..) => return Some(Err(self.protocol_violation())),
+        }
+        None
+    }
+
+    /// Handles an error from `recv_frame`, which sets `disconnect` for
+    /// `Error::Disconnected` and `Error::Internal`
+    fn recv_failed(&mut self, was_connected: bool, error: Error) -> Error {
+        // The pipe was cut from our side, so the reader saw a plain disconnect
+        let error = match error {
+            Error::Disconnected(_) if self.quota.as_ref().is_some_and(QuotaSlot::evicted) => {
+                self.disconnect = Some(DisconnectReason::Evicted);
+                Error::Disconnected(DisconnectReason::Evicted)
+            }
+            error => error,
         };
-        Ok(msg)
+        self.fail_pending(was_connected);
+        error
+    }
+
+    /// Fails everything waiting on the connection, once `disconnect` is set
+    fn fail_pending(&mut self, was_connected: bool) {
+        let Some(reason) = &self.disconnect else {
+            return;
+        };
+        if was_connected {
+            etw::disconnected(etw::Side::Manager, self.info.peer_pid, reason);
+            self.history.disconnected(self.info.peer_pid, reason);
+        }
+        self.calls.fail_all(reason);
+        self.config_acks.fail_all(reason);
+        self.probes.fail_all(reason);
+        self.pipe_writer.mark_closed();
     }
 
+    /// Drops a worker that sent a frame `acl` refuses
+    fn protocol_violation(&mut self) -> Error {
+        tracing::warn!(
+            pid = self.info.peer_pid,
+            "Worker broke the protocol, dropping it"
+        );
+        let was_connected = self.disconnect.is_none();
+        self.disconnect = Some(DisconnectReason::ProtocolViolation);
+        self.reader_task.abort();
+        self.fail_pending(was_connected);
+        Error::Disconnected(DisconnectReason::ProtocolViolation)
+    }
+
+    /// Receives messages the worker sent on its own, as a `Stream`
+    ///
+    /// Same as calling `next` in a loop. Responses to `call` go to their
+    /// `ResponseFuture` and never show up here, so poll this alongside them,
+    /// e.g. in a `select!`, until the response arrives.
+    pub fn callbacks(&mut self) -> Callbacks<'_, M, W>
+    where
+        M: Send,
+        W: Send,
+    {
+        Callbacks::new(self)
+    }
+
+    /// Sends `msg` without expecting a response
+    ///
+    /// If the worker does reply, the reply comes back from `next` along with its
+    /// callbacks, and can't be told apart from them. Use `call` for requests.
     pub async fn send(&mut self, msg: M) -> Result<(), Error> {
-        write_serialize(&mut self.pipe_writer, &ManagerMsgInternal::User(msg)).await
+        self.send_with(msg, &Metadata::new()).await
+    }
+
+    /// Like `send`, with headers the worker reads with `Client::metadata`
+    pub async fn send_with(&mut self, msg: M, metadata: &Metadata) -> Result<(), Error> {
+        self.log_sent(&msg);
+        let method = method_stats::sent_method(&msg);
+        let len = self
+            .pipe_writer
+            .write_counted(&ManagerMsgInternal::User(msg).with_metadata(metadata))
+            .await?;
+        self.history.sent("User", Some(method.clone()));
+        self.methods.record_sent(&method, len);
+        Ok(())
+    }
+
+    /// Sends several messages with one write
+    ///
+    /// For bulk synchronization, e.g. a full resource list, so the worker's reader
+    /// wakes up once instead of once per message. If any message can't be
+    /// serialized, none are sent.
+    pub async fn send_all(&mut self, msgs: impl IntoIterator<Item = M>) -> Result<(), Error> {
+        let mut methods = Vec::new();
+        let mut frames = Vec::new();
+        for msg in msgs {
+            self.log_sent(&msg);
+            methods.push(method_stats::sent_method(&msg));
+            frames.push(ManagerMsgInternal::User(msg));
+        }
+        if frames.is_empty() {
+            return Ok(());
+        }
+        let lens = self.pipe_writer.write_batch(&frames).await?;
+        for (method, len) in methods.iter().zip(lens) {
+            self.history.sent("User", Some(method.clone()));
+            self.methods.record_sent(method, len);
+        }
+        Ok(())
+    }
+
+    /// Waits for a message, then moves it and up to `max - 1` more that have
+    /// already arrived into `buf`
+    ///
+    /// Returns how many were added, which is 0 only if `max` is 0. Modeled on
+    /// `tokio::sync::mpsc::Receiver::recv_many`. Like `next`, this is cancel-safe,
+    /// and on error the messages already added stay in `buf`.
+    pub async fn recv_many(&mut self, buf: &mut Vec<W>, max: usize) -> Result<usize, Error> {
+        if max == 0 {
+            return Ok(0);
+        }
+        buf.push(self.next().await?);
+        let mut count = 1;
+        // Only frames that are already buffered, so nothing is dropped half-handled
+        while count < max {
+            let was_connected = self.disconnect.is_none();
+            let Some(frame) = try_recv_frame(&mut self.read_rx, &mut self.disconnect) else {
+                break;
+            };
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(error) => return Err(self.recv_failed(was_connected, error)),
+            };
+            if let Some(msg) = self.handle_frame(&frame) {
+                buf.push(msg?);
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Headers of the message `next` returned last, if the worker sent any
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Sends `msg` as a request that the worker answers with `Client::respond`
+    ///
+    /// Returns once the request is written. The returned future resolves when the
+    /// response arrives, which only happens while `next` is being polled.
+    pub async fn call(&mut self, msg: M) -> Result<ResponseFuture<W>, Error> {
+        self.call_with(msg, &Metadata::new()).await
+    }
+
+    /// Like `call`, with headers the worker reads with `Client::metadata`
+    pub async fn call_with(
+        &mut self,
+        msg: M,
+        metadata: &Metadata,
+    ) -> Result<ResponseFuture<W>, Error> {
+        self.log_sent(&msg);
+        let method = method_stats::sent_method(&msg);
+        let (id, response) = self.calls.register(method.clone());
+        let span = tracing::info_span!(
+            "ipc.call",
+            otel.kind = "client",
+            rpc.system = otel::RPC_SYSTEM,
+            rpc.method = %method,
+            rpc.id = id,
+        );
+        let traceparent = otel::traceparent(&span);
+        let len = self
+            .pipe_writer
+            .write_counted(
+                &ManagerMsgInternal::Request {
+                    id,
+                    msg,
+                    traceparent,
+                }
+                .with_metadata(metadata),
+            )
+            .instrument(span.clone())
+            .await?;
+        self.history.sent("Request", Some(method.clone()));
+        self.methods.record_sent(&method, len);
+        Ok(response.with_span(span))
+    }
+
+    /// Traces every user message sent and received, with secrets masked
+    ///
+    /// Off by default. Events are at `trace` level, see `Redact` for what's masked.
+    pub fn log_messages(&mut self)
+    where
+        M: Redact,
+        W: Redact,
+    {
+        self.message_log = Some(MessageLog::new::<M, W>());
+    }
+
+    fn log_sent(&self, msg: &M) {
+        if let Some(log) = &self.message_log {
+            log.sent(msg);
+        }
+    }
+
+    /// The last `EVENT_LOG_LEN` things that happened on this connection, oldest first
+    ///
+    /// Only frame kinds and method names, never payloads, so it's safe to attach to
+    /// an error report.
+    pub fn recent_events(&self) -> Vec<ConnectionEvent> {
+        self.history.list()
+    }
+
+    /// Writes a `ReproBundle` into `dir` for each frame that fails to decode or breaks the protocol
+    ///
+    /// Off by default. Secrets in the frame are masked like in `log_messages`.
+    /// At most `MAX_REPROS_PER_CONNECTION` are written, one per `REPRO_INTERVAL`.
+    pub fn capture_repros(&mut self, dir: impl Into<PathBuf>)
+    where
+        W: Redact,
+    {
+        self.repro = Some(ReproCapture::new(dir.into(), W::SECRET_FIELDS));
+    }
+
+    /// Makes `next` refuse user messages that need a scope outside `granted`
+    ///
+    /// `Listener::accept_scoped` calls this with the scopes from
+    /// `ServerOptions::grant_scopes`. See `Scoped`.
+    pub fn enforce_scopes(&mut self, granted: impl IntoIterator<Item = impl Into<String>>)
+    where
+        W: Scoped,
+    {
+        let granted = granted.into_iter().map(Into::into).collect();
+        self.scopes = Some(ScopeCheck::new(granted));
+    }
+
+    /// The scopes passed to `enforce_scopes`, or `None` if every method is allowed
+    pub fn granted_scopes(&self) -> Option<&BTreeSet<String>> {
+        self.scopes.as_ref().map(ScopeCheck::granted)
+    }
+
+    pub(crate) fn set_quota(&mut self, slot: QuotaSlot) {
+        self.quota = Some(slot);
+    }
+
+    pub(crate) fn history_handle(&self) -> EventLog {
+        self.history.clone()
+    }
+
+    /// Returns the calls the worker hasn't answered yet, oldest first
+    ///
+    /// A call that stays here for a long time usually means the worker's handler is stuck.
+    pub fn pending_calls(&self) -> Vec<PendingCall> {
+        self.calls.list()
+    }
+
+    /// Warns if `next` isn't polled for `threshold` while calls are pending
+    ///
+    /// Responses are only routed inside `next`, so a manager that stops polling
+    /// looks like a hung worker. The warning lists the stuck call IDs. `None` turns it off.
+    pub fn set_watchdog(&mut self, threshold: Option<Duration>)
+    where
+        W: Send + 'static,
+    {
+        if let Some(task) = self.watchdog_task.take() {
+            task.abort();
+        }
+        let Some(threshold) = threshold else {
+            return;
+        };
+        let calls = self.calls.clone();
+        self.watchdog_task = Some(watchdog::spawn(
+            "manager",
+            threshold,
+            self.poll_tracker.clone(),
+            move || calls.list().into_iter().map(|call| call.id).collect(),
+        ));
+    }
+
+    pub(crate) fn calls_handle(&self) -> PendingCalls<W> {
+        self.calls.clone()
+    }
+
+    /// Message counts, payload sizes, and call latency for each method so far
+    ///
+    /// Sorted by method name. Only user messages, requests, and their responses count.
+    pub fn method_stats(&self) -> Vec<MethodStats> {
+        self.methods.snapshot()
+    }
+
+    pub(crate) fn methods_handle(&self) -> MethodTable {
+        self.methods.clone()
+    }
+
+    /// How long frames from the worker took to arrive, in seconds
+    ///
+    /// Measured from the worker's send timestamp, corrected by the handshake's
+    /// clock offset, to when the reader task read the frame. Compare it with
+    /// `MethodStats::call_seconds` to tell a slow pipe from a slow handler.
+    /// `None` until the worker has sent its clock offset, and always for
+    /// connections that didn't handshake.
+    pub fn transit_latency(&self) -> Option<Histogram> {
+        self.transit.histogram()
+    }
+
+    pub(crate) fn transit_handle(&self) -> Transit {
+        self.transit.clone()
+    }
+
+    /// Sends `msg` only if the worker is subscribed to `topic`
+    ///
+    /// Returns whether it was sent. Subscriptions are only updated while `next`
+    /// is being polled.
+    pub async fn publish(&mut self, topic: &str, msg: M) -> Result<bool, Error> {
+        if !self.is_subscribed(topic) {
+            return Ok(false);
+        }
+        self.send(msg).await?;
+        Ok(true)
+    }
+
+    pub fn is_subscribed(&self, topic: &str) -> bool {
+        lock_subscriptions(&self.subscriptions).contains(topic)
+    }
+
+    /// Returns the topics the worker is subscribed to
+    pub fn subscriptions(&self) -> BTreeSet<String> {
+        lock_subscriptions(&self.subscriptions).clone()
+    }
+
+    pub(crate) fn subscriptions_handle(&self) -> Subscriptions {
+        Arc::clone(&self.subscriptions)
+    }
+
+    /// Returns a channel that receives the worker's extension frames with this ID
+    ///
+    /// Frames only arrive while `next` is being polled.
+    pub fn register_extension(&mut self, id: u16) -> Result<mpsc::Receiver<Vec<u8>>, Error> {
+        self.extensions.register(id)
+    }
+
+    /// Lets the worker change our log filter, see `Client::set_manager_log_filter`
+    pub fn set_log_filter_handle(&mut self, handle: log_filter::LogFilterHandle) {
+        self.log_filter = Some(handle);
+    }
+
+    /// Asks the worker to replace its `tracing` filter without restarting it
+    ///
+    /// `directives` uses `RUST_LOG` syntax. The worker ignores this unless it
+    /// called `Client::set_log_filter_handle`.
+    pub async fn set_worker_log_filter(&mut self, directives: &str) -> Result<(), Error> {
+        self.pipe_writer
+            .write(&ManagerMsgInternal::<M>::SetLogFilter(
+                directives.to_string(),
+            ))
+            .await
+    }
+
+    /// Retunes the worker's side of the connection without restarting it
+    ///
+    /// The worker applies `log_filter`, `send_timeout`, `close_timeout`, and
+    /// `max_frame_len` and `max_message_len` both ways. The rest only matter when
+    /// connecting or to the manager. `Client::next` also returns the frame, so the
+    /// app can apply settings of its own. Like `call`, the returned future only
+    /// resolves while `next` is being polled.
+    ///
+    /// Until the worker acks, this side reads frames within the old or the new
+    /// limits, and writes only messages within both, so nothing in flight is
+    /// refused. Once it acks, this side switches to the new limits too.
+    pub async fn push_config(
+        &mut self,
+        config: &Config,
+    ) -> Result<ResponseFuture<ConfigAck>, Error> {
+        let (id, ack) = self.config_acks.register("SetConfig".to_string());
+        // The worker refuses a zero `max_frame_len`, see `Client::apply_config`
+        if config.max_frame_len != 0 {
+            self.pushed_limits.insert(id, FrameLimits::of(config));
+            self.retune();
+        }
+        self.pipe_writer
+            .write(&ManagerMsgInternal::<M>::SetConfig {
+                id,
+                config: config.clone(),
+            })
+            .await?;
+        Ok(ack)
+    }
+
+    /// Sets the limits this side reads and writes with, from the agreed limits
+    /// and those still waiting for an ack
+    fn retune(&mut self) {
+        let pushed = self.pushed_limits.values().copied();
+        let read = pushed.clone().fold(self.limits, FrameLimits::union);
+        let write = pushed.fold(self.limits, FrameLimits::intersection);
+        self.read_limits.set(read);
+        self.pipe_writer.set_limits(write);
+    }
+
+    /// Probes whether the worker is live and ready
+    ///
+    /// Returns once the `Ping` is written. The returned future resolves when the
+    /// worker answers, which only happens while `next` is being polled.
+    pub async fn health(&mut self) -> Result<HealthCheck, Error> {
+        let (id, status) = self.probes.register("Ping".to_string());
+        let sent = std::time::Instant::now();
+        self.pipe_writer
+            .write(&ManagerMsgInternal::<M>::Ping { id })
+            .await?;
+        Ok(HealthCheck { sent, status })
+    }
+
+    /// Asks the worker to sample its resource usage every `interval`
+    ///
+    /// `None` stops the reports. Samples are only received while `next` is
+    /// being polled, read the latest with `worker_stats`.
+    pub async fn report_stats(&mut self, interval: Option<Duration>) -> Result<(), Error> {
+        let interval_ms =
+            interval.map(|interval| u64::try_from(interval.as_millis()).unwrap_or(u64::MAX));
+        self.pipe_writer
+            .write(&ManagerMsgInternal::<M>::ReportStats { interval_ms })
+            .await
+    }
+
+    /// The latest sample the worker sent, see `report_stats`
+    pub fn worker_stats(&self) -> Option<WorkerStats> {
+        self.stats.get()
+    }
+
+    pub(crate) fn stats_handle(&self) -> LatestStats {
+        self.stats.clone()
+    }
+
+    /// Tells the worker the system is suspending or has resumed
+    ///
+    /// The worker gets `ManagerMsgInternal::Power` from `Client::next`.
+    pub async fn send_power_event(&mut self, event: PowerEvent) -> Result<(), Error> {
+        self.pipe_writer
+            .write(&ManagerMsgInternal::<M>::Power(event))
+            .await
+    }
+
+    /// Tells the worker a network interface changed, e.g. so it can re-bind its sockets
+    ///
+    /// The worker gets `ManagerMsgInternal::NetworkChanged` from `Client::next`.
+    pub async fn send_network_change(&mut self, change: NetworkChange) -> Result<(), Error> {
+        self.pipe_writer
+            .write(&ManagerMsgInternal::<M>::NetworkChanged(change))
+            .await
+    }
+
+    /// Sends an app-defined control frame to the worker
+    pub async fn send_extension(&mut self, id: u16, payload: Vec<u8>) -> Result<(), Error> {
+        self.pipe_writer
+            .write(&ManagerMsgInternal::<M>::Extension(id, payload))
+            .await
+    }
+}
+
+impl<M, W> Drop for Server<M, W> {
+    fn drop(&mut self) {
+        // The reader task holds half of the pipe, so it won't close until the task stops.
+        // This matters when a handshake is abandoned while the worker is still running.
+        self.reader_task.abort();
+        if let Some(task) = self.watchdog_task.take() {
+            task.abort();
+        }
     }
 }
 
-pub(crate) fn get_client_pid(pipe: &named_pipe::NamedPipeServer) -> Result<u32> {
-    let handle = pipe.as_handle();
-    // SAFETY: TODO
-    let handle = HANDLE(unsafe { handle.as_raw_handle().offset_from(std::ptr::null()) });
-    let mut pid = 0;
-    // SAFETY: Not sure if this can be called from two threads at once?
-    // But the pointer is valid at least.
-    unsafe { GetNamedPipeClientProcessId(handle, &mut pid) }?;
-    Ok(pid)
+/// A worker's topic subscriptions, shared so the `Manager` can read them
+pub(crate) type Subscriptions = Arc<Mutex<BTreeSet<String>>>;
+
+pub(crate) fn lock_subscriptions(
+    subscriptions: &Subscriptions,
+) -> MutexGuard<'_, BTreeSet<String>> {
+    // The set can't be left half-updated, so a poisoned lock is still usable
+    subscriptions
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
 }
 
 /// `std::process::Child` but for a subcommand running from the same exe as
@@ -239,19 +1247,35 @@ pub(crate) fn get_client_pid(pipe: &named_pipe::NamedPipeServer) -> Result<u32>
 /// if it can't.
 pub struct SubcommandChild {
     pub(crate) process: Child,
+    /// Counts kills for `LeakGuard::accounting`, if it came from `SubprocessBuilder::spawn`
+    kills: Option<Arc<AtomicU64>>,
 }
 
-///
+/// How a `SubcommandChild` ended
 #[derive(Debug, PartialEq)]
 pub enum SubcommandExit {
     /// The process exited gracefully
     Success,
     /// The process didn't crash, but it returned a non-success exit code
     Failure,
+    /// The process returned one of the non-zero codes from `WorkerExit`
+    Worker(WorkerExit),
     /// The process had to be killed
     Killed,
 }
 
+impl SubcommandExit {
+    fn from_status(status: std::process::ExitStatus) -> Self {
+        if status.success() {
+            return Self::Success;
+        }
+        status
+            .code()
+            .and_then(|code| WorkerExit::from_code(code as u32))
+            .map_or(Self::Failure, Self::Worker)
+    }
+}
+
 impl SubcommandChild {
     /// Launches the current exe as a subprocess with new arguments
     ///
@@ -259,8 +1283,16 @@ impl SubcommandChild {
     ///
     /// * `args` - e.g. `["debug", "test", "ipc-worker"]`
     pub fn new(args: &[&str]) -> Result<Self> {
+        Self::with_exe(None, args)
+    }
+
+    /// Launches `exe`, or the current exe if it's `None`, as a subprocess
+    ///
+    /// `exe` must be an absolute path, see `SubprocessBuilder::exe`.
+    pub fn with_exe(exe: Option<&Path>, args: &[&str]) -> Result<Self> {
+        let exe = integrity::resolve_exe(exe)?;
         // Need this binding to avoid a "temporary freed while still in use" error
-        let mut process = process::Command::new(std::env::current_exe()?);
+        let mut process = process::Command::new(exe);
         process
             // Make stdin a pipe so we can send the child a security cookie
             .stdin(Stdio::piped())
@@ -272,20 +1304,22 @@ impl SubcommandChild {
             process.arg(arg);
         }
         let process = process.spawn()?;
-        Ok(SubcommandChild { process })
+        Ok(SubcommandChild {
+            process,
+            kills: None,
+        })
     }
 
     /// Joins the subprocess without blocking, returning an error if the process doesn't stop
     #[tracing::instrument(skip(self))]
     pub(crate) fn wait_or_kill(&mut self) -> Result<SubcommandExit> {
         if let Ok(Some(status)) = self.process.try_wait() {
-            if status.success() {
-                Ok(SubcommandExit::Success)
-            } else {
-                Ok(SubcommandExit::Failure)
-            }
+            Ok(SubcommandExit::from_status(status))
         } else {
             self.process.start_kill()?;
+            if let Some(pid) = self.process.id() {
+                self.record_kill(pid, "dropped while running");
+            }
             Ok(SubcommandExit::Killed)
         }
     }
@@ -293,16 +1327,23 @@ impl SubcommandChild {
     /// Waits `dur` for process to exit gracefully, and then `dur` to kill process if needed
     pub async fn wait_then_kill(&mut self, dur: Duration) -> Result<SubcommandExit> {
         if let Ok(status) = timeout(dur, self.process.wait()).await {
-            return if status?.success() {
-                Ok(SubcommandExit::Success)
-            } else {
-                Ok(SubcommandExit::Failure)
-            };
+            return Ok(SubcommandExit::from_status(status?));
         }
 
+        let pid = self.process.id();
         timeout(dur, self.process.kill()).await??;
+        if let Some(pid) = pid {
+            self.record_kill(pid, "didn't exit within the grace period");
+        }
         Ok(SubcommandExit::Killed)
     }
+
+    fn record_kill(&self, pid: u32, reason: &str) {
+        etw::killed(pid, reason);
+        if let Some(kills) = &self.kills {
+            kills.fetch_add(1, Ordering::Relaxed);
+        }
+    }
 }
 
 impl Drop for SubcommandChild {
@@ -321,43 +1362,194 @@ impl Drop for SubcommandChild {
 /// This contains a Windows handle that always leaks. Try to create one LeakGuard
 /// and use it throughout your whole main process.
 pub struct LeakGuard {
-    // Technically this job object handle does leak
-    job_object: HANDLE,
+    /// Never closed, since closing the job kills every worker in it
+    job_object: ManuallyDrop<sys::JobObject>,
+    options: LeakGuardOptions,
+    /// Records every worker, see `set_pid_file`
+    pid_file: Option<WorkerPidFile>,
+    /// Workers from `SubprocessBuilder::spawn` that had to be killed
+    kills: Arc<AtomicU64>,
+}
+
+/// How a `LeakGuard` sets up its job object
+#[derive(Clone, Debug)]
+pub struct LeakGuardOptions {
+    name: Option<String>,
+    kill_on_close: bool,
+    breakaway: bool,
+    silent_breakaway: bool,
+}
+
+impl Default for LeakGuardOptions {
+    fn default() -> Self {
+        Self {
+            name: None,
+            kill_on_close: true,
+            breakaway: false,
+            silent_breakaway: false,
+        }
+    }
+}
+
+impl LeakGuardOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names the job object, so tools like Process Explorer can find it
+    ///
+    /// Use the `Local\` or `Global\` prefix to pick the namespace. Creating the
+    /// `LeakGuard` fails if a job with this name already exists, since someone
+    /// else may control it.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Whether Windows kills every process in the job when the manager exits. On by default.
+    ///
+    /// Without it, workers outlive a crashed manager, and only
+    /// `WorkerPidFile::kill_orphans` cleans them up.
+    pub fn kill_on_close(mut self, kill_on_close: bool) -> Self {
+        self.kill_on_close = kill_on_close;
+        self
+    }
+
+    /// Lets a worker start a process outside the job by passing
+    /// `CREATE_BREAKAWAY_FROM_JOB` to `CreateProcess`
+    ///
+    /// Use this for helper tools that must survive the worker. Everything else
+    /// the worker spawns stays in the job. Off by default.
+    pub fn breakaway(mut self, breakaway: bool) -> Self {
+        self.breakaway = breakaway;
+        self
+    }
+
+    /// Starts every process a worker spawns outside the job, without any flags
+    ///
+    /// Only the workers themselves are contained then, so prefer `breakaway`.
+    /// Off by default.
+    pub fn silent_breakaway(mut self, silent_breakaway: bool) -> Self {
+        self.silent_breakaway = silent_breakaway;
+        self
+    }
+
+    fn flags(&self) -> sys::JobFlags {
+        sys::JobFlags {
+            kill_on_close: self.kill_on_close,
+            breakaway_ok: self.breakaway,
+            silent_breakaway_ok: self.silent_breakaway,
+        }
+    }
 }
 
 impl LeakGuard {
     pub fn new() -> Result<Self> {
-        // SAFETY: TODO
-        let job_object = unsafe { CreateJobObjectA(None, None) }?;
-
-        let mut jeli = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
-        jeli.BasicLimitInformation.LimitFlags = JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
-        // SAFETY: Windows shouldn't hang on to `jeli`. I'm not sure why this is unsafe.
-        unsafe {
-            SetInformationJobObject(
-                job_object,
-                JobObjectExtendedLimitInformation,
-                &jeli as *const JOBOBJECT_EXTENDED_LIMIT_INFORMATION as *const c_void,
-                u32::try_from(std::mem::size_of_val(&jeli))?,
-            )
-        }?;
+        Self::with_options(LeakGuardOptions::new())
+    }
+
+    /// Creates a named job object, see `LeakGuardOptions::name`
+    pub fn with_name(name: &str) -> Result<Self> {
+        Self::with_options(LeakGuardOptions::new().name(name))
+    }
+
+    pub fn with_options(options: LeakGuardOptions) -> Result<Self> {
+        Ok(Self {
+            job_object: ManuallyDrop::new(sys::JobObject::new(
+                options.name.as_deref(),
+                &options.flags(),
+            )?),
+            options,
+            pid_file: None,
+            kills: Default::default(),
+        })
+    }
+
+    /// Checks that job objects work on this system, e.g. at startup in debug builds
+    ///
+    /// Puts a suspended copy of the current exe in a new job with the same
+    /// options, closes that job, and checks that the copy dies, or survives if
+    /// `LeakGuardOptions::kill_on_close` is off. The copy never runs any code,
+    /// and our own job isn't touched.
+    pub async fn verify(&self) -> Result<()> {
+        const CANARY_TIMEOUT: Duration = Duration::from_secs(2);
+
+        let job = sys::JobObject::new(None, &self.options.flags())?;
+        let mut canary = process::Command::new(std::env::current_exe()?);
+        canary
+            .creation_flags(sys::CREATE_SUSPENDED.0)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        let mut canary = canary.spawn().context("couldn't spawn canary")?;
+        job.assign(&canary)?;
+        let pid = canary.id().context("canary should have an ID")?;
+        let process = sys::Process::open(pid, sys::PROCESS_QUERY_LIMITED_INFORMATION)?;
+        anyhow::ensure!(job.contains(&process)?, "canary isn't in the job");
+
+        drop(job);
+        let died = timeout(CANARY_TIMEOUT, canary.wait()).await.is_ok();
+        if !died {
+            canary.kill().await?;
+        }
+        anyhow::ensure!(
+            died == self.options.kill_on_close,
+            "closing the job should have {} the canary",
+            if self.options.kill_on_close {
+                "killed"
+            } else {
+                "kept"
+            }
+        );
+        Ok(())
+    }
 
-        Ok(Self { job_object })
+    /// What the job has contained so far, e.g. for a diagnostics bundle
+    pub fn accounting(&self) -> Result<JobAccounting> {
+        let counters = self.job_object.counters()?;
+        Ok(JobAccounting {
+            name: self.options.name.clone(),
+            total_processes: counters.total_processes,
+            active_processes: counters.active_processes,
+            terminated_by_limits: counters.terminated_processes,
+            kills: self.kills.load(Ordering::Relaxed),
+            cpu_time_ms: u64::try_from(counters.cpu_time.as_millis()).unwrap_or(u64::MAX),
+            peak_process_memory: counters.peak_process_memory,
+            peak_job_memory: counters.peak_job_memory,
+        })
+    }
+
+    /// Records the PID of every process added from now on, so the next manager
+    /// can kill them with `WorkerPidFile::kill_orphans` if we crash
+    pub fn set_pid_file(&mut self, pid_file: WorkerPidFile) {
+        self.pid_file = Some(pid_file);
     }
 
     /// Registers a child process with the LeakGuard so that Windows will kill the child if the manager exits or crashes
+    ///
+    /// Processes the child spawns from then on are in the job too, unless
+    /// `LeakGuardOptions` lets them break away. `SubprocessBuilder::spawn` starts
+    /// workers suspended until they're added, so none of their children escape.
     pub fn add_process(&mut self, process: &Child) -> Result<()> {
-        // Process IDs are not the same as handles, so get our handle to the process.
-        let process_handle = process
-            .raw_handle()
-            .ok_or_else(|| anyhow::anyhow!("Child should have a handle"))?;
-        // SAFETY: The docs say this is UB since the null pointer doesn't belong to the same allocated object as the handle.
-        // I couldn't get `OpenProcess` to work, and I don't have any other way to convert the process ID to a handle safely.
-        // Since the handles aren't pointers per se, maybe it'll work?
-        let process_handle = HANDLE(unsafe { process_handle.offset_from(std::ptr::null()) });
-        // SAFETY: TODO
-        unsafe { AssignProcessToJobObject(self.job_object, process_handle) }
-            .context("AssignProcessToJobObject")?;
+        match self.job_object.assign(process) {
+            Ok(()) => {}
+            // Workers watch the manager themselves under Wine, see `os::wine`
+            Err(error) if os::wine::detected() => {
+                tracing::warn!(?error, "Couldn't add the worker to the job under Wine");
+            }
+            Err(error) => return Err(error),
+        }
+        if let (Some(pid_file), Some(pid)) = (&self.pid_file, process.id()) {
+            // The job object is the main protection, so this isn't fatal
+            if let Err(error) = pid_file.record(pid) {
+                tracing::error!(?error, ?pid, "Couldn't record worker PID");
+            }
+        }
         Ok(())
     }
+
+    /// Whether the process is in the job, e.g. a worker's own child
+    pub fn contains(&self, pid: u32) -> Result<bool> {
+        let process = sys::Process::open(pid, sys::PROCESS_QUERY_LIMITED_INFORMATION)?;
+        self.job_object.contains(&process)
+    }
 }
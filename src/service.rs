@@ -0,0 +1,211 @@
+//! `Server::call` as a `tower::Service`, behind the `tower` feature
+//!
+//! `call` needs `&mut Server`, and responses are only routed while `next` is
+//! polled, so `CallService` can't borrow the `Server` the way `Callbacks` does.
+//! Instead a task owns it, and each clone of the service sends requests to that
+//! task. Its futures are `'static`, so `Buffer`, `Retry`, and `Timeout` layers work.
+
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::SendError, OwnedPermit},
+        oneshot,
+    },
+    task::JoinSet,
+};
+
+use crate::{DisconnectReason, Error, Server};
+
+/// A request on its way to the task driving the `Server`
+struct Request<M, W> {
+    msg: M,
+    reply: oneshot::Sender<Result<W, Error>>,
+}
+
+type Reserve<M, W> =
+    Pin<Box<dyn Future<Output = Result<OwnedPermit<Request<M, W>>, SendError<()>>> + Send>>;
+
+/// Sends each request with `Server::call` and resolves to the worker's response
+///
+/// `poll_ready` waits for room in the queue to the `Server`'s task, so load
+/// shedding sees backpressure. After the connection ends it fails with
+/// `Error::Closed`, and calls already queued fail with `Error::Disconnected`.
+///
+/// When every clone is dropped and their calls are answered, the task closes
+/// the `Server`.
+pub struct CallService<M, W> {
+    tx: mpsc::Sender<Request<M, W>>,
+    /// Waiting for room in the queue, started by `poll_ready`
+    reserve: Option<Reserve<M, W>>,
+    /// Room for the next `call`, taken by `poll_ready`
+    permit: Option<OwnedPermit<Request<M, W>>>,
+}
+
+impl<M, W> CallService<M, W>
+where
+    M: Serialize + Send + 'static,
+    W: DeserializeOwned + Send + 'static,
+{
+    /// Moves `server` to a task, which queues up to `buffer` requests
+    ///
+    /// The worker's callbacks come out of the returned receiver, which ends after
+    /// the `Error::Disconnected` that ends the connection. Drop it if they aren't
+    /// needed. If it's kept but not read, the task stops routing responses once
+    /// `buffer` callbacks are waiting. Must be called inside a Tokio runtime.
+    pub fn new(server: Server<M, W>, buffer: usize) -> (Self, mpsc::Receiver<Result<W, Error>>) {
+        let (tx, rx) = mpsc::channel(buffer);
+        let (callbacks_tx, callbacks_rx) = mpsc::channel(buffer);
+        tokio::spawn(drive(server, rx, callbacks_tx));
+        let service = Self {
+            tx,
+            reserve: None,
+            permit: None,
+        };
+        (service, callbacks_rx)
+    }
+}
+
+impl<M, W> Clone for CallService<M, W> {
+    /// A clone has to reserve its own room in the queue
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            reserve: None,
+            permit: None,
+        }
+    }
+}
+
+impl<M, W> tower_service::Service<M> for CallService<M, W>
+where
+    M: Send + 'static,
+    W: Send + 'static,
+{
+    type Response = W;
+    type Error = Error;
+    type Future = CallFuture<W>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        if self.permit.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        let tx = &self.tx;
+        let reserve = self
+            .reserve
+            .get_or_insert_with(|| Box::pin(tx.clone().reserve_owned()));
+        let result = std::task::ready!(reserve.as_mut().poll(cx));
+        self.reserve = None;
+        match result {
+            Ok(permit) => {
+                self.permit = Some(permit);
+                Poll::Ready(Ok(()))
+            }
+            // The task is gone, so is the connection
+            Err(_) => Poll::Ready(Err(Error::Closed)),
+        }
+    }
+
+    fn call(&mut self, msg: M) -> CallFuture<W> {
+        let permit = self
+            .permit
+            .take()
+            .expect("`poll_ready` must return `Ready(Ok(()))` before `call`");
+        let (reply, rx) = oneshot::channel();
+        permit.send(Request { msg, reply });
+        CallFuture { rx }
+    }
+}
+
+/// Resolves to the worker's response to a `CallService` request
+///
+/// Dropping this cancels the call, like dropping a `ResponseFuture`.
+pub struct CallFuture<W> {
+    rx: oneshot::Receiver<Result<W, Error>>,
+}
+
+impl<W> Future for CallFuture<W> {
+    type Output = Result<W, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx).map(|result| {
+            // The sender is only dropped without sending if the task dropped
+            result.unwrap_or(Err(Error::Disconnected(DisconnectReason::GracefulClose)))
+        })
+    }
+}
+
+/// Sends queued requests, and polls `next` so their responses are routed
+async fn drive<M, W>(
+    mut server: Server<M, W>,
+    mut rx: mpsc::Receiver<Request<M, W>>,
+    callbacks_tx: mpsc::Sender<Result<W, Error>>,
+) where
+    M: Serialize + Send + 'static,
+    W: DeserializeOwned + Send + 'static,
+{
+    // Each call waiting on its response. Dropping the set cancels them.
+    let mut calls = JoinSet::new();
+    let mut open = true;
+    loop {
+        tokio::select! {
+            request = rx.recv(), if open => {
+                let Some(Request { msg, mut reply }) = request else {
+                    // Every clone is gone, finish the calls they made
+                    open = false;
+                    if calls.is_empty() {
+                        break;
+                    }
+                    continue;
+                };
+                match server.call(msg).await {
+                    Ok(response) => {
+                        calls.spawn(async move {
+                            tokio::select! {
+                                result = response => {
+                                    reply.send(result).ok();
+                                }
+                                // The caller gave up, so cancel the call
+                                () = reply.closed() => {}
+                            }
+                        });
+                    }
+                    Err(error) => {
+                        reply.send(Err(error)).ok();
+                    }
+                }
+            }
+            msg = server.next() => {
+                let reason = match &msg {
+                    Err(Error::Disconnected(reason)) => Some(reason.clone()),
+                    _ => None,
+                };
+                callbacks_tx.send(msg).await.ok();
+                if let Some(reason) = reason {
+                    // `next` already failed the calls in flight
+                    rx.close();
+                    while let Some(request) = rx.recv().await {
+                        request
+                            .reply
+                            .send(Err(Error::Disconnected(reason.clone())))
+                            .ok();
+                    }
+                    while calls.join_next().await.is_some() {}
+                    return;
+                }
+            }
+            Some(_) = calls.join_next(), if !calls.is_empty() => {
+                if !open && calls.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+    if let Err(error) = server.close().await {
+        tracing::debug!(?error, "Couldn't close the `Server` behind a `CallService`");
+    }
+}
@@ -0,0 +1,49 @@
+//! One cancellation signal for app code in both processes
+//!
+//! `Manager::shutdown_token` trips when the manager starts closing its
+//! workers, and `Client::shutdown_token` trips when the worker reads `Shutdown`
+//! or loses the manager. Handlers run by `serve` can clone the client's token
+//! before `serve` takes the client, and stop early instead of holding up the
+//! shutdown.
+
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Trips once when shutdown begins. Clones share the same state.
+#[derive(Clone, Debug)]
+pub struct ShutdownToken(Arc<watch::Sender<bool>>);
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self(Arc::new(tx))
+    }
+}
+
+impl ShutdownToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trips the token for every clone. Later calls do nothing.
+    pub fn trigger(&self) {
+        // `send` would fail with no receivers, and nobody may be waiting yet
+        self.0
+            .send_if_modified(|tripped| !std::mem::replace(tripped, true));
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Resolves once the token trips, right away if it already has
+    ///
+    /// # Cancel safety
+    ///
+    /// This method is cancel-safe, so it can be one branch of a `select!`.
+    pub async fn triggered(&self) {
+        let mut rx = self.0.subscribe();
+        // The sender lives in `self`, so this can't fail
+        rx.wait_for(|tripped| *tripped).await.ok();
+    }
+}
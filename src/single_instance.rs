@@ -0,0 +1,94 @@
+//! Making sure only one manager runs at a time
+//!
+//! The first manager creates a named pipe with a well-known name. Creating it
+//! with `first_pipe_instance` fails if another process already owns it, so the
+//! pipe is both the lock and the way a second launch hands its command line to
+//! the first. Windows deletes the pipe when its owner exits, even by crashing,
+//! so a stale lock can't outlive the manager.
+
+use anyhow::{Context as _, Result};
+use std::time::Duration;
+use tokio::net::windows::named_pipe::{self, NamedPipeServer};
+
+use crate::{
+    frame::{FrameReader, FrameWriter},
+    sys, ImpersonationLevel, PipeId,
+};
+
+/// How long a later launch has to send its args once it connects
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The largest command line a later launch can forward, as JSON
+pub(crate) const MAX_FORWARDED_LEN: usize = 64 * 1024;
+
+/// The result of `single_instance`
+pub enum SingleInstance {
+    /// We're the only manager. Keep this alive for as long as we run.
+    Primary(InstanceGuard),
+    /// Another manager holds the lock, see `forward_to_running_instance`
+    AlreadyRunning,
+}
+
+/// Holds the single-instance lock until dropped
+pub struct InstanceGuard {
+    pipe_id: PipeId,
+    /// The instance that the next launch will connect to
+    pipe: NamedPipeServer,
+}
+
+/// Takes the single-instance lock for `name`, if nobody else has it
+///
+/// Requires a Tokio context.
+pub fn single_instance(name: &str) -> Result<SingleInstance> {
+    let pipe_id = instance_pipe_id(name);
+    match named_pipe::ServerOptions::new()
+        .first_pipe_instance(true)
+        .create(pipe_id.as_str())
+    {
+        Ok(pipe) => Ok(SingleInstance::Primary(InstanceGuard { pipe_id, pipe })),
+        Err(error) if error.kind() == std::io::ErrorKind::PermissionDenied => {
+            Ok(SingleInstance::AlreadyRunning)
+        }
+        Err(error) => Err(error).context("couldn't create single-instance pipe"),
+    }
+}
+
+impl InstanceGuard {
+    /// Waits for a later launch to forward its command line
+    ///
+    /// Any local process that can open the pipe can send args, so treat them
+    /// like user input. A launch that doesn't send them within a few seconds,
+    /// or sends more than `MAX_FORWARDED_LEN`, is dropped with an error, and the
+    /// next call waits for the launch after it.
+    pub async fn next_forwarded(&mut self) -> Result<Vec<String>> {
+        self.pipe.connect().await?;
+        // Create the next instance before giving this one up, so the lock is never released
+        let next = named_pipe::ServerOptions::new()
+            .create(self.pipe_id.as_str())
+            .context("couldn't create next single-instance pipe")?;
+        let connected = std::mem::replace(&mut self.pipe, next);
+        let mut reader = FrameReader::with_max_len(connected, MAX_FORWARDED_LEN);
+        let buf = tokio::time::timeout(FORWARD_TIMEOUT, reader.read())
+            .await
+            .context("later launch didn't send its args in time")??;
+        Ok(serde_json::from_slice(&buf)?)
+    }
+}
+
+/// Sends `args` to the manager holding the lock for `name`
+///
+/// Call this after `single_instance` returns `AlreadyRunning`, then exit.
+pub async fn forward_to_running_instance(name: &str, args: Vec<String>) -> Result<()> {
+    let pipe = named_pipe::ClientOptions::new()
+        .security_qos_flags(sys::security_qos_flags(ImpersonationLevel::default()))
+        .open(instance_pipe_id(name).as_str())
+        .context("couldn't connect to the running instance")?;
+    let mut writer = FrameWriter::with_max_len(pipe, MAX_FORWARDED_LEN);
+    writer.write(&args).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+fn instance_pipe_id(name: &str) -> PipeId {
+    PipeId::named(&format!("instance-{name}"))
+}
@@ -0,0 +1,126 @@
+//! Workers reporting their own resource usage
+//!
+//! The worker samples itself, since it can do that without extra rights on
+//! every platform, and writes a `Stats` frame on a timer. The manager asks for
+//! reports with `Server::report_stats` and reads the latest with `Server::worker_stats`.
+
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{io::AsyncWrite, sync::Mutex as AsyncMutex};
+
+use crate::{
+    client::{lock_unanswered, Unanswered},
+    frame::FrameWriter,
+    sys, WorkerMsgInternal,
+};
+
+/// One sample of a worker's resource usage
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct WorkerStats {
+    /// Working set, in bytes
+    pub rss_bytes: u64,
+    /// CPU used since the previous sample, where 100 is one full core
+    pub cpu_percent: f64,
+    /// Open handles, or file descriptors on other platforms
+    pub handles: u32,
+    /// Requests returned by `Client::next` that haven't been answered yet
+    pub unanswered_requests: usize,
+    /// When the worker took the sample, in ms since the Unix epoch
+    pub timestamp_ms: u64,
+}
+
+/// The latest `WorkerStats` a `Server` received
+///
+/// Cloning shares it, so a `Manager` can read it while a task owns the `Server`.
+#[derive(Clone, Default)]
+pub(crate) struct LatestStats(Arc<Mutex<Option<WorkerStats>>>);
+
+impl LatestStats {
+    pub(crate) fn get(&self) -> Option<WorkerStats> {
+        // Overwritten in one step, so a poisoned lock is still consistent
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub(crate) fn set(&self, stats: WorkerStats) {
+        *self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(stats);
+    }
+}
+
+/// Keeps the previous CPU time, so each sample covers the time since the last one
+struct Sampler {
+    cpu_time: Duration,
+    at: Instant,
+}
+
+impl Sampler {
+    fn new() -> Self {
+        Self {
+            cpu_time: sys::process_cpu_time().unwrap_or_default(),
+            at: Instant::now(),
+        }
+    }
+
+    fn sample(&mut self, unanswered_requests: usize) -> anyhow::Result<WorkerStats> {
+        let cpu_time = sys::process_cpu_time()?;
+        let now = Instant::now();
+        let wall = now.duration_since(self.at).as_secs_f64();
+        let cpu_percent = if wall > 0.0 {
+            cpu_time.saturating_sub(self.cpu_time).as_secs_f64() / wall * 100.0
+        } else {
+            0.0
+        };
+        self.cpu_time = cpu_time;
+        self.at = now;
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        Ok(WorkerStats {
+            rss_bytes: sys::process_rss()?,
+            cpu_percent,
+            handles: sys::process_handle_count()?,
+            unanswered_requests,
+            timestamp_ms: u64::try_from(timestamp_ms).unwrap_or(u64::MAX),
+        })
+    }
+}
+
+/// Writes a `Stats` frame every `interval` until a write fails
+///
+/// The task started by `Client` when the manager asks for reports.
+pub(crate) async fn report<W: AsyncWrite + Unpin>(
+    interval: Duration,
+    pipe_writer: Arc<AsyncMutex<FrameWriter<W>>>,
+    unanswered: Unanswered,
+) {
+    let mut sampler = Sampler::new();
+    let mut ticks = tokio::time::interval(interval);
+    ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // The first tick is immediate, and a CPU sample over no time means nothing
+    ticks.tick().await;
+    loop {
+        ticks.tick().await;
+        let unanswered_requests = lock_unanswered(&unanswered).len();
+        let stats = match sampler.sample(unanswered_requests) {
+            Ok(stats) => stats,
+            Err(error) => {
+                tracing::warn!(?error, "Couldn't sample resource usage");
+                continue;
+            }
+        };
+        let msg = WorkerMsgInternal::<()>::Stats(stats);
+        if let Err(error) = pipe_writer.lock().await.write(&msg).await {
+            tracing::debug!(?error, "Stopped reporting stats");
+            return;
+        }
+    }
+}
@@ -0,0 +1,14 @@
+//! `std::sync`, or `loom::sync` when building the model-checked tests
+//!
+//! Run those with
+//! ```bash
+//! RUSTFLAGS="--cfg subzone_loom" cargo test --release loom
+//! ```
+//!
+//! Tokio checks `cfg(loom)` itself and can't build with it, hence the longer name.
+//! The other tests panic outside of `loom::model` in that build, so filter to `loom`.
+
+#[cfg(all(test, subzone_loom))]
+pub(crate) use loom::sync::{Arc, Mutex, MutexGuard};
+#[cfg(not(all(test, subzone_loom)))]
+pub(crate) use std::sync::{Arc, Mutex, MutexGuard};
@@ -0,0 +1,1136 @@
+//! Safe wrappers around the Windows calls for processes, pipes, job objects,
+//! console ctrl events, and window messages
+//!
+//! Every `unsafe` block for those lives here, next to the invariant that makes
+//! it sound, so the rest of the crate only sees owned handles. The power and
+//! network notification callbacks stay in `power` and `network`, because their
+//! safety depends on how long the registration lives.
+
+use anyhow::{Context as _, Result};
+use std::{
+    ffi::{c_char, c_void, CStr},
+    fs::File,
+    os::windows::io::{AsHandle, AsRawHandle, OwnedHandle as StdOwnedHandle},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+use windows::{
+    core::{s, w, HRESULT, HSTRING, PCWSTR, PWSTR},
+    Win32::{
+        Foundation::{
+            CloseHandle, DuplicateHandle, GetHandleInformation, LocalFree, SetHandleInformation,
+            BOOL, DUPLICATE_SAME_ACCESS, ERROR_ALREADY_EXISTS, FALSE, FILETIME, HANDLE,
+            HANDLE_FLAGS, HANDLE_FLAG_INHERIT, HLOCAL, HWND, LPARAM, LRESULT, PSID, TRUE,
+            WAIT_OBJECT_0, WPARAM,
+        },
+        Security::{
+            Authorization::{GetNamedSecurityInfoW, SE_KERNEL_OBJECT},
+            Cryptography::{
+                BCryptHash, CertGetCertificateContextProperty, CertGetNameStringW,
+                BCRYPT_HMAC_SHA256_ALG_HANDLE, BCRYPT_SHA256_ALG_HANDLE,
+                CERT_NAME_SIMPLE_DISPLAY_TYPE, CERT_SHA1_HASH_PROP_ID,
+            },
+            GetTokenInformation, LookupAccountSidW, TokenElevation, TokenSessionId, TokenUser,
+            WinTrust::{
+                WTHelperGetProvCertFromChain, WTHelperGetProvSignerFromChain,
+                WTHelperProvDataFromStateData, WinVerifyTrust, WINTRUST_ACTION_GENERIC_VERIFY_V2,
+                WINTRUST_DATA, WINTRUST_DATA_0, WINTRUST_FILE_INFO, WTD_CHOICE_FILE,
+                WTD_REVOKE_NONE, WTD_STATEACTION_CLOSE, WTD_STATEACTION_VERIFY, WTD_UI_NONE,
+            },
+            OWNER_SECURITY_INFORMATION, PSECURITY_DESCRIPTOR, SID_NAME_USE, TOKEN_ELEVATION,
+            TOKEN_INFORMATION_CLASS, TOKEN_QUERY, TOKEN_USER,
+        },
+        Storage::FileSystem::{
+            FlushFileBuffers, SECURITY_ANONYMOUS, SECURITY_DELEGATION, SECURITY_IDENTIFICATION,
+            SECURITY_IMPERSONATION, SECURITY_SQOS_PRESENT,
+        },
+        System::{
+            Console::SetConsoleCtrlHandler,
+            Diagnostics::ToolHelp::{
+                CreateToolhelp32Snapshot, Thread32First, Thread32Next, TH32CS_SNAPTHREAD,
+                THREADENTRY32,
+            },
+            JobObjects::{
+                AssignProcessToJobObject, CreateJobObjectW, IsProcessInJob,
+                JobObjectBasicAccountingInformation, JobObjectExtendedLimitInformation,
+                QueryInformationJobObject, SetInformationJobObject,
+                JOBOBJECT_BASIC_ACCOUNTING_INFORMATION, JOBOBJECT_EXTENDED_LIMIT_INFORMATION,
+                JOB_OBJECT_LIMIT, JOB_OBJECT_LIMIT_BREAKAWAY_OK,
+                JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE, JOB_OBJECT_LIMIT_SILENT_BREAKAWAY_OK,
+            },
+            LibraryLoader::{GetModuleHandleW, GetProcAddress},
+            Performance::{QueryPerformanceCounter, QueryPerformanceFrequency},
+            Pipes::{
+                DisconnectNamedPipe, GetNamedPipeClientProcessId, GetNamedPipeServerProcessId,
+            },
+            ProcessStatus::{GetProcessMemoryInfo, PROCESS_MEMORY_COUNTERS},
+            Registry::{RegGetValueW, HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD, RRF_RT_REG_SZ},
+            SystemInformation::{
+                IMAGE_FILE_MACHINE, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64,
+                IMAGE_FILE_MACHINE_ARMNT, IMAGE_FILE_MACHINE_I386,
+            },
+            Threading::{
+                GetCurrentProcess, GetCurrentThreadId, GetExitCodeProcess, GetProcessHandleCount,
+                GetProcessTimes, IsWow64Process2, OpenProcess, OpenProcessToken, OpenThread,
+                QueryFullProcessImageNameW, ResumeThread, TerminateProcess, WaitForSingleObject,
+                PROCESS_ACCESS_RIGHTS, PROCESS_NAME_WIN32, THREAD_SUSPEND_RESUME, THREAD_TERMINATE,
+            },
+            IO::CancelSynchronousIo,
+        },
+        UI::WindowsAndMessaging::{
+            CreateWindowExW, DefWindowProcW, DispatchMessageW, GetMessageW, RegisterClassW, MSG,
+            WINDOW_EX_STYLE, WINDOW_STYLE, WNDCLASSW,
+        },
+    },
+};
+
+use crate::{ImpersonationLevel, PeerIdentity};
+
+pub(crate) use windows::Win32::System::Threading::{
+    CREATE_SUSPENDED, PROCESS_DUP_HANDLE, PROCESS_QUERY_LIMITED_INFORMATION, PROCESS_SYNCHRONIZE,
+    PROCESS_TERMINATE,
+};
+
+/// A kernel object handle that we own and close on drop
+///
+/// Invariant: the handle is valid and nothing else closes it.
+struct OwnedHandle(HANDLE);
+
+// SAFETY: Kernel object handles can be used and closed from any thread
+unsafe impl Send for OwnedHandle {}
+// SAFETY: Every wrapper below only passes the handle by value to thread-safe Win32 calls
+unsafe impl Sync for OwnedHandle {}
+
+impl Drop for OwnedHandle {
+    fn drop(&mut self) {
+        // SAFETY: We own the handle and nothing else closes it
+        if let Err(error) = unsafe { CloseHandle(self.0) } {
+            tracing::error!(?error, "Couldn't close handle");
+        }
+    }
+}
+
+/// Converts a borrowed std handle for a windows-rs call
+///
+/// The result must not outlive the borrow, which holds as long as it's only
+/// passed straight into a call.
+fn raw(handle: &impl AsHandle) -> HANDLE {
+    HANDLE(handle.as_handle().as_raw_handle() as isize)
+}
+
+/// The process ID of the client connected to our pipe server
+pub(crate) fn named_pipe_client_pid(pipe: &impl AsHandle) -> Result<u32> {
+    let mut pid = 0;
+    // SAFETY: The handle is borrowed for the duration of the call, and so is the pointer
+    unsafe { GetNamedPipeClientProcessId(raw(pipe), &mut pid) }
+        .context("GetNamedPipeClientProcessId")?;
+    Ok(pid)
+}
+
+/// The process ID of the server our pipe client connected to
+pub(crate) fn named_pipe_server_pid(pipe: &impl AsHandle) -> Result<u32> {
+    let mut pid = 0;
+    // SAFETY: The handle is borrowed for the duration of the call, and so is the pointer
+    unsafe { GetNamedPipeServerProcessId(raw(pipe), &mut pid) }
+        .context("GetNamedPipeServerProcessId")?;
+    Ok(pid)
+}
+
+/// The `CreateFile` flags that cap how far a pipe server may impersonate us
+pub(crate) fn security_qos_flags(level: ImpersonationLevel) -> u32 {
+    let level = match level {
+        ImpersonationLevel::Anonymous => SECURITY_ANONYMOUS,
+        ImpersonationLevel::Identification => SECURITY_IDENTIFICATION,
+        ImpersonationLevel::Impersonation => SECURITY_IMPERSONATION,
+        ImpersonationLevel::Delegation => SECURITY_DELEGATION,
+    };
+    (level | SECURITY_SQOS_PRESENT).0
+}
+
+/// Impersonates our pipe client, like a hostile server would, and returns the
+/// level Windows granted
+#[cfg(test)]
+pub(crate) fn client_impersonation_level(pipe: &impl AsHandle) -> Result<ImpersonationLevel> {
+    use windows::Win32::Security::{
+        SecurityAnonymous, SecurityDelegation, SecurityIdentification, SecurityImpersonation,
+        TokenImpersonationLevel, SECURITY_IMPERSONATION_LEVEL,
+    };
+
+    let token = pipe_client_token(pipe)?;
+    let level: SECURITY_IMPERSONATION_LEVEL = token_info(&token, TokenImpersonationLevel)?;
+    [
+        (SecurityAnonymous, ImpersonationLevel::Anonymous),
+        (SecurityIdentification, ImpersonationLevel::Identification),
+        (SecurityImpersonation, ImpersonationLevel::Impersonation),
+        (SecurityDelegation, ImpersonationLevel::Delegation),
+    ]
+    .into_iter()
+    .find_map(|(raw, ours)| (raw == level).then_some(ours))
+    .with_context(|| format!("unknown impersonation level {}", level.0))
+}
+
+/// The token of the client connected to our pipe server, from impersonating it
+///
+/// Impersonation is per thread, so it happens on a thread of its own. No task
+/// ever runs as the client, even if `RevertToSelf` were to fail.
+fn pipe_client_token(pipe: &impl AsHandle) -> Result<OwnedHandle> {
+    use windows::Win32::{
+        Foundation::TRUE,
+        Security::RevertToSelf,
+        System::{
+            Pipes::ImpersonateNamedPipeClient,
+            Threading::{GetCurrentThread, OpenThreadToken},
+        },
+    };
+
+    let pipe = raw(pipe);
+    let impersonate = move || {
+        // SAFETY: The caller borrows the pipe until the scoped thread is joined
+        unsafe { ImpersonateNamedPipeClient(pipe) }.context("ImpersonateNamedPipeClient")?;
+        let mut token = HANDLE::default();
+        // SAFETY: The pseudo-handle doesn't need to be closed, and we own the
+        // token handle once the call succeeds. `OpenAsSelf` checks access as us,
+        // since an identification token can't open anything.
+        let opened = unsafe { OpenThreadToken(GetCurrentThread(), TOKEN_QUERY, TRUE, &mut token) }
+            .context("OpenThreadToken")
+            .map(|()| OwnedHandle(token));
+        // SAFETY: This thread is impersonating, and goes back to our own token
+        unsafe { RevertToSelf() }.context("RevertToSelf")?;
+        opened
+    };
+    std::thread::scope(|scope| scope.spawn(impersonate).join())
+        .map_err(|_| anyhow::anyhow!("impersonation thread panicked"))?
+}
+
+/// Who is on the other end of our pipe server, read from the pipe's own token
+///
+/// Unlike `peer_identity`, the user, elevation, and session can't belong to a
+/// process that reused the client's PID. `exe` still comes from the PID,
+/// since a token doesn't name one. Works at any impersonation level except
+/// `Anonymous`.
+pub(crate) fn pipe_client_identity(pipe: &impl AsHandle) -> Result<PeerIdentity> {
+    let pid = named_pipe_client_pid(pipe)?;
+    let token = pipe_client_token(pipe)?;
+    let exe = Process::open(pid, PROCESS_QUERY_LIMITED_INFORMATION)?.image_name()?;
+    token_identity(&token, pid, exe)
+}
+
+/// Sets whether processes we spawn inherit `handle`
+pub(crate) fn set_inheritable(handle: &impl AsHandle, inherit: bool) -> Result<()> {
+    let flags = if inherit {
+        HANDLE_FLAG_INHERIT
+    } else {
+        HANDLE_FLAGS(0)
+    };
+    // SAFETY: The handle is borrowed for the duration of the call
+    unsafe { SetHandleInformation(raw(handle), HANDLE_FLAG_INHERIT.0, flags) }
+        .context("SetHandleInformation")?;
+    Ok(())
+}
+
+pub(crate) fn is_inheritable(handle: &impl AsHandle) -> Result<bool> {
+    let mut flags = 0;
+    // SAFETY: The handle is borrowed for the duration of the call, and so is the pointer
+    unsafe { GetHandleInformation(raw(handle), &mut flags) }.context("GetHandleInformation")?;
+    Ok(flags & HANDLE_FLAG_INHERIT.0 != 0)
+}
+
+/// A second handle to one end of a named pipe, so it can be flushed after the pipe is split
+pub(crate) struct PipeHandle(StdOwnedHandle);
+
+impl PipeHandle {
+    pub(crate) fn duplicate(pipe: &impl AsHandle) -> Result<Self> {
+        Ok(Self(
+            pipe.as_handle()
+                .try_clone_to_owned()
+                .context("couldn't duplicate pipe handle")?,
+        ))
+    }
+
+    /// Blocks until the other end has read everything written to the pipe so far
+    ///
+    /// Fails if the other end disconnects first, or `thread.cancel` is called.
+    pub(crate) fn flush(&self, thread: &FlushThread) -> Result<()> {
+        {
+            let mut state = thread.lock();
+            if state.cancelled {
+                anyhow::bail!("flush was cancelled before it started");
+            }
+            // SAFETY: No pointers are passed, and `OwnedHandle` takes ownership of the result
+            let handle = unsafe { OpenThread(THREAD_TERMINATE, FALSE, GetCurrentThreadId()) }
+                .context("OpenThread")?;
+            state.thread = Some(OwnedHandle(handle));
+        }
+        // SAFETY: The handle is valid until `self` drops
+        let flushed = unsafe { FlushFileBuffers(raw(&self.0)) }.context("FlushFileBuffers");
+        thread.lock().thread = None;
+        flushed
+    }
+}
+
+impl PipeHandle {
+    /// Cuts off the client of our pipe server, failing its reads and writes and ours
+    ///
+    /// The instance stays unusable until every handle to it is closed.
+    pub(crate) fn disconnect(&self) -> Result<()> {
+        // SAFETY: The handle is valid until `self` drops
+        unsafe { DisconnectNamedPipe(raw(&self.0)) }.context("DisconnectNamedPipe")
+    }
+}
+
+/// The thread blocked in `PipeHandle::flush`, so another thread can cancel it
+///
+/// A pending flush holds the pipe open, so it must be cancelled before the
+/// pipe can close.
+#[derive(Default)]
+pub(crate) struct FlushThread(std::sync::Mutex<FlushState>);
+
+#[derive(Default)]
+struct FlushState {
+    /// Set while the flush runs
+    thread: Option<OwnedHandle>,
+    cancelled: bool,
+}
+
+impl FlushThread {
+    fn lock(&self) -> std::sync::MutexGuard<'_, FlushState> {
+        // Each update is one assignment, so a poisoned lock is still consistent
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Cancels the flush, or makes it fail as soon as it starts
+    ///
+    /// The thread may be between registering and blocking, where there's nothing
+    /// to cancel yet, so call this until the flush returns.
+    pub(crate) fn cancel(&self) {
+        let mut state = self.lock();
+        state.cancelled = true;
+        if let Some(thread) = &state.thread {
+            // SAFETY: The handle is valid while it's in `state`, which we hold locked.
+            // Fails with `ERROR_NOT_FOUND` if the thread isn't blocked yet.
+            unsafe { CancelSynchronousIo(thread.0) }.ok();
+        }
+    }
+}
+
+/// How many handles the current process has open, for leak tests
+pub(crate) fn process_handle_count() -> Result<u32> {
+    let mut count = 0;
+    // SAFETY: The pseudo-handle from `GetCurrentProcess` doesn't need to be closed,
+    // and the pointer is valid for the duration of the call
+    unsafe { GetProcessHandleCount(GetCurrentProcess(), &mut count) }
+        .context("GetProcessHandleCount")?;
+    Ok(count)
+}
+
+/// The current process' working set, in bytes
+pub(crate) fn process_rss() -> Result<u64> {
+    let mut counters = PROCESS_MEMORY_COUNTERS::default();
+    // SAFETY: The pseudo-handle doesn't need to be closed, and the pointer and
+    // size describe `counters`, which outlives the call
+    unsafe {
+        GetProcessMemoryInfo(
+            GetCurrentProcess(),
+            &mut counters,
+            u32::try_from(std::mem::size_of_val(&counters))?,
+        )
+    }
+    .context("GetProcessMemoryInfo")?;
+    Ok(u64::try_from(counters.WorkingSetSize)?)
+}
+
+/// The certificate that signed an exe, see `verify_authenticode`
+pub(crate) struct SigningCert {
+    /// SHA-1 hash of the certificate
+    pub(crate) thumbprint: [u8; 20],
+    /// The subject's simple display name
+    pub(crate) subject: String,
+}
+
+/// Checks that `file`, opened from `path`, has a valid Authenticode signature,
+/// and returns the certificate that made it
+///
+/// Revocation isn't checked, since that needs the network, and a manager may
+/// start before the network is up.
+pub(crate) fn verify_authenticode(path: &Path, file: &File) -> Result<SigningCert> {
+    let path = HSTRING::from(path);
+    let mut file_info = WINTRUST_FILE_INFO {
+        cbStruct: u32::try_from(std::mem::size_of::<WINTRUST_FILE_INFO>())?,
+        pcwszFilePath: PCWSTR(path.as_ptr()),
+        hFile: raw(file),
+        ..Default::default()
+    };
+    let mut data = WINTRUST_DATA {
+        cbStruct: u32::try_from(std::mem::size_of::<WINTRUST_DATA>())?,
+        dwUIChoice: WTD_UI_NONE,
+        fdwRevocationChecks: WTD_REVOKE_NONE,
+        dwUnionChoice: WTD_CHOICE_FILE,
+        Anonymous: WINTRUST_DATA_0 {
+            pFile: &mut file_info,
+        },
+        dwStateAction: WTD_STATEACTION_VERIFY,
+        ..Default::default()
+    };
+    let mut action = WINTRUST_ACTION_GENERIC_VERIFY_V2;
+    // SAFETY: `data` points to `file_info`, which points to `path` and the
+    // borrowed file handle, and all of them outlive both calls
+    let status = unsafe {
+        WinVerifyTrust(
+            HWND::default(),
+            &mut action,
+            std::ptr::from_mut(&mut data).cast(),
+        )
+    };
+    // The signer lives in the state data, so read it before that's released
+    let signer = HRESULT(status)
+        .ok()
+        .context("WinVerifyTrust")
+        .and_then(|()| signing_cert(data.hWVTStateData));
+    // The state data has to be released whether or not the signature checked out
+    data.dwStateAction = WTD_STATEACTION_CLOSE;
+    // SAFETY: Same as above, and `hWVTStateData` is what the first call returned
+    unsafe {
+        WinVerifyTrust(
+            HWND::default(),
+            &mut action,
+            std::ptr::from_mut(&mut data).cast(),
+        )
+    };
+    signer
+}
+
+/// Reads the leaf certificate of the first signer from `WinVerifyTrust`'s state data
+fn signing_cert(state: HANDLE) -> Result<SigningCert> {
+    // SAFETY: `state` came from a successful verify and isn't closed yet
+    let provider = unsafe { WTHelperProvDataFromStateData(state) };
+    anyhow::ensure!(!provider.is_null(), "WinVerifyTrust kept no provider data");
+    // SAFETY: `provider` is valid until the state is closed, and so is everything it points to
+    let signer = unsafe { WTHelperGetProvSignerFromChain(provider, 0, FALSE, 0) };
+    anyhow::ensure!(!signer.is_null(), "the signature has no signer");
+    // SAFETY: Same as above. Certificate 0 is the signer's own, the rest are its chain.
+    let cert = unsafe { WTHelperGetProvCertFromChain(signer, 0) };
+    anyhow::ensure!(!cert.is_null(), "the signer has no certificate");
+    // SAFETY: `cert` isn't null, and points into the provider data
+    let context = unsafe { (*cert).pCert };
+    anyhow::ensure!(!context.is_null(), "the signer has no certificate");
+
+    let mut thumbprint = [0; 20];
+    let mut len = u32::try_from(thumbprint.len())?;
+    // SAFETY: `thumbprint` and `len` describe a buffer that outlives the call
+    unsafe {
+        CertGetCertificateContextProperty(
+            context,
+            CERT_SHA1_HASH_PROP_ID,
+            Some(thumbprint.as_mut_ptr().cast()),
+            &mut len,
+        )
+    }
+    .context("CertGetCertificateContextProperty")?;
+    anyhow::ensure!(
+        usize::try_from(len)? == thumbprint.len(),
+        "SHA-1 thumbprint is {len} bytes"
+    );
+
+    let mut subject = [0; 256];
+    // SAFETY: The slice gives the buffer and its length, and outlives the call
+    let len = unsafe {
+        CertGetNameStringW(
+            context,
+            CERT_NAME_SIMPLE_DISPLAY_TYPE,
+            0,
+            None,
+            Some(&mut subject),
+        )
+    };
+    // The length counts the null terminator
+    let len = usize::try_from(len)?.saturating_sub(1);
+    let subject = String::from_utf16(subject.get(..len).context("subject overflowed")?)?;
+    Ok(SigningCert {
+        thumbprint,
+        subject,
+    })
+}
+
+/// Whether the current process runs with an elevated token, e.g. as an admin or SYSTEM
+pub(crate) fn is_elevated() -> Result<bool> {
+    let mut token = HANDLE::default();
+    // SAFETY: The pseudo-handle doesn't need to be closed, and we own the token
+    // handle once the call succeeds
+    unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) }
+        .context("OpenProcessToken")?;
+    let token = OwnedHandle(token);
+    let elevation: TOKEN_ELEVATION = token_info(&token, TokenElevation)?;
+    Ok(elevation.TokenIsElevated != 0)
+}
+
+/// Who runs process `pid`, read from its token
+///
+/// Needs `PROCESS_QUERY_LIMITED_INFORMATION` on the process, which SYSTEM and
+/// the process' own user have.
+pub(crate) fn peer_identity(pid: u32) -> Result<PeerIdentity> {
+    let process = Process::open(pid, PROCESS_QUERY_LIMITED_INFORMATION)?;
+    let mut token = HANDLE::default();
+    // SAFETY: The process handle is valid until `process` drops, and we own the
+    // token handle once the call succeeds
+    unsafe { OpenProcessToken(process.0 .0, TOKEN_QUERY, &mut token) }
+        .context("OpenProcessToken")?;
+    let token = OwnedHandle(token);
+    token_identity(&token, pid, process.image_name()?)
+}
+
+fn token_identity(token: &OwnedHandle, pid: u32, exe: PathBuf) -> Result<PeerIdentity> {
+    let elevation: TOKEN_ELEVATION = token_info(token, TokenElevation)?;
+    let session_id: u32 = token_info(token, TokenSessionId)?;
+    Ok(PeerIdentity {
+        pid,
+        exe,
+        user: token_user(token)?,
+        elevated: elevation.TokenIsElevated != 0,
+        session_id,
+    })
+}
+
+/// Reads a fixed-size token information class, e.g. `TokenElevation` into `TOKEN_ELEVATION`
+fn token_info<T: Default>(token: &OwnedHandle, class: TOKEN_INFORMATION_CLASS) -> Result<T> {
+    let mut info = T::default();
+    let mut len = 0;
+    // SAFETY: The token is valid until `token` drops, and the pointer and size
+    // describe `info`
+    unsafe {
+        GetTokenInformation(
+            token.0,
+            class,
+            Some(std::ptr::from_mut(&mut info).cast()),
+            u32::try_from(std::mem::size_of::<T>())?,
+            &mut len,
+        )
+    }
+    .context("GetTokenInformation")?;
+    Ok(info)
+}
+
+/// The token's account as `DOMAIN\user`
+fn token_user(token: &OwnedHandle) -> Result<String> {
+    // `TOKEN_USER` is followed by its SID, so ask for the size first
+    let mut len = 0;
+    // SAFETY: With no buffer, the call only writes `len`. It fails with
+    // `ERROR_INSUFFICIENT_BUFFER`, which is expected.
+    unsafe { GetTokenInformation(token.0, TokenUser, None, 0, &mut len) }.ok();
+    // `u64`s, so the buffer is aligned for `TOKEN_USER`
+    let mut buf = vec![0u64; usize::try_from(len)?.saturating_add(7) / 8];
+    // SAFETY: The token is valid until `token` drops, and the pointer and size
+    // describe `buf`
+    unsafe {
+        GetTokenInformation(
+            token.0,
+            TokenUser,
+            Some(buf.as_mut_ptr().cast()),
+            u32::try_from(std::mem::size_of_val(buf.as_slice()))?,
+            &mut len,
+        )
+    }
+    .context("GetTokenInformation")?;
+    // SAFETY: The call succeeded, so `buf` starts with an initialized `TOKEN_USER`
+    // whose SID points into `buf`, which outlives this
+    let user = unsafe { &*buf.as_ptr().cast::<TOKEN_USER>() };
+    account_name(user.User.Sid)
+}
+
+/// The HMAC-SHA256 of `data` keyed with `key`
+pub(crate) fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<[u8; 32]> {
+    let mut mac = [0; 32];
+    // SAFETY: The pseudo-handle doesn't need to be opened or closed, and the
+    // slices are valid for the duration of the call
+    unsafe { BCryptHash(BCRYPT_HMAC_SHA256_ALG_HANDLE, Some(key), data, &mut mac) }
+        .ok()
+        .context("BCryptHash")?;
+    Ok(mac)
+}
+
+/// The account that owns the named pipe at `path`, as `DOMAIN\user`
+///
+/// Only needs `READ_CONTROL`, which a pipe can grant to clients it won't let connect.
+pub(crate) fn pipe_owner(path: &str) -> Result<String> {
+    let path = HSTRING::from(path);
+    let mut owner = PSID::default();
+    let mut descriptor = PSECURITY_DESCRIPTOR::default();
+    // SAFETY: The path is nul-terminated and outlives the call. On success,
+    // `owner` points into `descriptor`, which is freed below.
+    unsafe {
+        GetNamedSecurityInfoW(
+            PCWSTR(path.as_ptr()),
+            SE_KERNEL_OBJECT,
+            OWNER_SECURITY_INFORMATION,
+            Some(&mut owner),
+            None,
+            None,
+            None,
+            &mut descriptor,
+        )
+    }
+    .context("GetNamedSecurityInfoW")?;
+    let name = account_name(owner);
+    // SAFETY: `GetNamedSecurityInfoW` allocated the descriptor with `LocalAlloc`,
+    // and `owner` isn't used after this
+    unsafe { LocalFree(HLOCAL(descriptor.0)) }.ok();
+    name
+}
+
+/// Looks up `sid` as `DOMAIN\user`, or just `user` for accounts without a domain
+fn account_name(sid: PSID) -> Result<String> {
+    // Longer than any account or domain name Windows allows
+    let mut name = [0u16; 256];
+    let mut domain = [0u16; 256];
+    let mut name_len = u32::try_from(name.len())?;
+    let mut domain_len = u32::try_from(domain.len())?;
+    let mut kind = SID_NAME_USE::default();
+    // SAFETY: `sid` is valid for the call, and the lengths are the buffers' sizes in `u16`s
+    unsafe {
+        LookupAccountSidW(
+            PCWSTR::null(),
+            sid,
+            PWSTR(name.as_mut_ptr()),
+            &mut name_len,
+            PWSTR(domain.as_mut_ptr()),
+            &mut domain_len,
+            &mut kind,
+        )
+    }
+    .context("LookupAccountSidW")?;
+    // On success, the lengths don't count the nul
+    let name = String::from_utf16_lossy(name.get(..usize::try_from(name_len)?).unwrap_or_default());
+    let domain = String::from_utf16_lossy(
+        domain
+            .get(..usize::try_from(domain_len)?)
+            .unwrap_or_default(),
+    );
+    Ok(if domain.is_empty() {
+        name
+    } else {
+        format!("{domain}\\{name}")
+    })
+}
+
+/// Microseconds on the performance counter, which every process on the machine shares
+///
+/// `None` only if the counter can't be read, which Windows XP and later guarantee it can.
+pub(crate) fn performance_counter_us() -> Option<u64> {
+    let mut count = 0;
+    let mut frequency = 0;
+    // SAFETY: Both point to writable `i64`s that outlive the calls
+    unsafe { QueryPerformanceCounter(&mut count).and(QueryPerformanceFrequency(&mut frequency)) }
+        .ok()?;
+    let count = u128::try_from(count).ok()?;
+    let frequency = u128::try_from(frequency).ok()?;
+    let us = count.checked_mul(1_000_000)?.checked_div(frequency)?;
+    u64::try_from(us).ok()
+}
+
+/// The SHA-256 hash of `data`
+pub(crate) fn sha256(data: &[u8]) -> Result<[u8; 32]> {
+    let mut hash = [0; 32];
+    // SAFETY: The pseudo-handle doesn't need to be opened or closed, and the
+    // slices are valid for the duration of the call
+    unsafe { BCryptHash(BCRYPT_SHA256_ALG_HANDLE, None, data, &mut hash) }
+        .ok()
+        .context("BCryptHash")?;
+    Ok(hash)
+}
+
+/// The Windows build and update revision, e.g. `22631.2861`
+///
+/// Read from the registry, since `GetVersionEx` reports an older version to
+/// exes without a compatibility manifest.
+pub(crate) fn os_build() -> Result<String> {
+    const KEY: PCWSTR = w!(r"SOFTWARE\Microsoft\Windows NT\CurrentVersion");
+    let mut build = [0u16; 32];
+    let mut len = u32::try_from(std::mem::size_of_val(&build))?;
+    // SAFETY: `build` is writable for `len` bytes, and `RRF_RT_REG_SZ` makes
+    // Windows null-terminate the string or fail if it doesn't fit
+    unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            KEY,
+            w!("CurrentBuildNumber"),
+            RRF_RT_REG_SZ,
+            None,
+            Some(build.as_mut_ptr().cast()),
+            Some(&mut len),
+        )
+    }
+    .context("RegGetValueW CurrentBuildNumber")?;
+    let end = build.iter().position(|c| *c == 0).unwrap_or(build.len());
+    let build = String::from_utf16(&build[..end])?;
+
+    let mut revision = 0u32;
+    let mut len = u32::try_from(std::mem::size_of_val(&revision))?;
+    // SAFETY: `revision` is writable for `len` bytes
+    let revision = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            KEY,
+            w!("UBR"),
+            RRF_RT_REG_DWORD,
+            None,
+            Some(std::ptr::from_mut(&mut revision).cast()),
+            Some(&mut len),
+        )
+    }
+    .map(|()| revision);
+    // Older builds don't have an update revision
+    Ok(match revision {
+        Ok(revision) => format!("{build}.{revision}"),
+        Err(_) => build,
+    })
+}
+
+/// The architecture Windows itself runs as, in `std::env::consts::ARCH` terms
+///
+/// Differs from ours for a 32-bit process under WOW64, or an x64 process
+/// emulated on ARM64.
+pub(crate) fn native_arch() -> Result<&'static str> {
+    let mut process = IMAGE_FILE_MACHINE::default();
+    let mut native = IMAGE_FILE_MACHINE::default();
+    // SAFETY: The pseudo-handle doesn't need to be closed, and the pointers are
+    // valid for the duration of the call
+    unsafe { IsWow64Process2(GetCurrentProcess(), &mut process, Some(&mut native)) }
+        .context("IsWow64Process2")?;
+    Ok(match native {
+        IMAGE_FILE_MACHINE_AMD64 => "x86_64",
+        IMAGE_FILE_MACHINE_ARM64 => "aarch64",
+        IMAGE_FILE_MACHINE_I386 => "x86",
+        IMAGE_FILE_MACHINE_ARMNT => "arm",
+        other => anyhow::bail!("unknown native machine {:#x}", other.0),
+    })
+}
+
+/// Wine's version, if ntdll exports `wine_get_version` like Wine's does
+pub(crate) fn wine_version() -> Option<String> {
+    // SAFETY: ntdll is loaded in every process and never unloaded
+    let ntdll = unsafe { GetModuleHandleW(w!("ntdll.dll")) }.ok()?;
+    // SAFETY: The module handle is valid and the name is a static C string
+    let proc = unsafe { GetProcAddress(ntdll, s!("wine_get_version")) }?;
+    // SAFETY: Wine declares it as `const char *wine_get_version(void)`
+    let wine_get_version: unsafe extern "C" fn() -> *const c_char =
+        unsafe { std::mem::transmute(proc) };
+    // SAFETY: It returns a static, nul-terminated string
+    let version = unsafe { CStr::from_ptr(wine_get_version()) };
+    Some(version.to_string_lossy().into_owned())
+}
+
+/// Kernel plus user CPU time the current process has used on all cores
+pub(crate) fn process_cpu_time() -> Result<Duration> {
+    let mut creation = FILETIME::default();
+    let mut exit = FILETIME::default();
+    let mut kernel = FILETIME::default();
+    let mut user = FILETIME::default();
+    // SAFETY: The pseudo-handle doesn't need to be closed, and the pointers are
+    // valid for the duration of the call
+    unsafe {
+        GetProcessTimes(
+            GetCurrentProcess(),
+            &mut creation,
+            &mut exit,
+            &mut kernel,
+            &mut user,
+        )
+    }
+    .context("GetProcessTimes")?;
+    Ok(Duration::from_nanos(
+        ticks(kernel)
+            .saturating_add(ticks(user))
+            .saturating_mul(100),
+    ))
+}
+
+/// `FILETIME` counts 100 ns intervals
+fn ticks(time: FILETIME) -> u64 {
+    (u64::from(time.dwHighDateTime) << 32) | u64::from(time.dwLowDateTime)
+}
+
+/// Fails if the process' handle count grew since it was taken, for leak tests
+#[cfg(any(test, feature = "harness"))]
+pub(crate) struct HandleSnapshot {
+    before: u32,
+}
+
+#[cfg(any(test, feature = "harness"))]
+impl HandleSnapshot {
+    pub(crate) fn new() -> Result<Self> {
+        Ok(Self {
+            before: process_handle_count()?,
+        })
+    }
+
+    /// `slack` allows for handles that grow without bound, like the runtime's
+    /// worker threads, but should be much less than one handle per iteration
+    pub(crate) fn check(&self, slack: u32) -> Result<()> {
+        let after = process_handle_count()?;
+        tracing::debug!(before = self.before, ?after, "Handle count");
+        anyhow::ensure!(
+            after <= self.before + slack,
+            "handle count grew from {} to {after}",
+            self.before
+        );
+        Ok(())
+    }
+}
+
+/// An open handle to another process
+///
+/// Holding it keeps the process ID from being recycled.
+pub(crate) struct Process(OwnedHandle);
+
+impl Process {
+    /// Fails if the process is gone or `access` isn't allowed
+    pub(crate) fn open(pid: u32, access: PROCESS_ACCESS_RIGHTS) -> Result<Self> {
+        // SAFETY: No pointers are passed, and `OwnedHandle` takes ownership of the result
+        let handle = unsafe { OpenProcess(access, FALSE, pid) }.context("OpenProcess")?;
+        Ok(Self(OwnedHandle(handle)))
+    }
+
+    /// Returns true if the process exits within `timeout`. Needs `PROCESS_SYNCHRONIZE`.
+    ///
+    /// Blocks the current thread unless `timeout` is zero.
+    pub(crate) fn wait(&self, timeout: Duration) -> bool {
+        let millis = u32::try_from(timeout.as_millis()).unwrap_or(u32::MAX);
+        // SAFETY: The handle is valid until `self` drops
+        let result = unsafe { WaitForSingleObject(self.0 .0, millis) };
+        result == WAIT_OBJECT_0
+    }
+
+    /// The exit code, or 259 (`STILL_ACTIVE`) if it's still running
+    pub(crate) fn exit_code(&self) -> Result<u32> {
+        let mut exit = 0;
+        // SAFETY: The handle is valid and the pointer is valid for the duration of the call
+        unsafe { GetExitCodeProcess(self.0 .0, &mut exit) }.context("GetExitCodeProcess")?;
+        Ok(exit)
+    }
+
+    /// Copies `handle` into this process with the same access. Needs `PROCESS_DUP_HANDLE`.
+    ///
+    /// Returns the copy's value, which is only meaningful inside this process.
+    /// Only this process can close it, so it leaks until this process takes
+    /// ownership or exits.
+    pub(crate) fn duplicate_into(&self, handle: &impl AsHandle) -> Result<isize> {
+        let mut target = HANDLE::default();
+        // SAFETY: Both handles are valid for the call, and `target` outlives it.
+        // The copy belongs to the other process, so it isn't wrapped in `OwnedHandle`.
+        unsafe {
+            DuplicateHandle(
+                GetCurrentProcess(),
+                raw(handle),
+                self.0 .0,
+                &mut target,
+                0,
+                FALSE,
+                DUPLICATE_SAME_ACCESS,
+            )
+        }
+        .context("DuplicateHandle")?;
+        Ok(target.0)
+    }
+
+    /// The full path of the process' exe. Needs `PROCESS_QUERY_LIMITED_INFORMATION`.
+    pub(crate) fn image_name(&self) -> Result<PathBuf> {
+        let mut buf = vec![0u16; 32_768];
+        let mut len = u32::try_from(buf.len())?;
+        // SAFETY: `len` is the size of `buf` in u16s, and both outlive the call
+        unsafe {
+            QueryFullProcessImageNameW(
+                self.0 .0,
+                PROCESS_NAME_WIN32,
+                PWSTR(buf.as_mut_ptr()),
+                &mut len,
+            )
+        }
+        .context("QueryFullProcessImageNameW")?;
+        let name = buf
+            .get(..usize::try_from(len)?)
+            .context("QueryFullProcessImageNameW returned a bad length")?;
+        Ok(PathBuf::from(String::from_utf16_lossy(name)))
+    }
+
+    /// When the process started, as a `FILETIME`. Needs `PROCESS_QUERY_LIMITED_INFORMATION`.
+    ///
+    /// With the PID, this tells a process apart from a later one that got its PID.
+    pub(crate) fn creation_time(&self) -> Result<u64> {
+        let mut creation = FILETIME::default();
+        let mut exit = FILETIME::default();
+        let mut kernel = FILETIME::default();
+        let mut user = FILETIME::default();
+        // SAFETY: The handle is valid until `self` drops, and the pointers are
+        // valid for the duration of the call
+        unsafe { GetProcessTimes(self.0 .0, &mut creation, &mut exit, &mut kernel, &mut user) }
+            .context("GetProcessTimes")?;
+        Ok(ticks(creation))
+    }
+
+    /// Needs `PROCESS_TERMINATE`
+    pub(crate) fn terminate(&self, exit_code: u32) -> Result<()> {
+        // SAFETY: The handle is valid until `self` drops
+        unsafe { TerminateProcess(self.0 .0, exit_code) }.context("TerminateProcess")?;
+        Ok(())
+    }
+}
+
+/// See `LeakGuardOptions`
+pub(crate) struct JobFlags {
+    pub(crate) kill_on_close: bool,
+    pub(crate) breakaway_ok: bool,
+    pub(crate) silent_breakaway_ok: bool,
+}
+
+/// Accounting from `JobObject::counters`, see `JobAccounting`
+pub(crate) struct JobCounters {
+    pub(crate) total_processes: u32,
+    pub(crate) active_processes: u32,
+    pub(crate) terminated_processes: u32,
+    pub(crate) cpu_time: Duration,
+    pub(crate) peak_process_memory: u64,
+    pub(crate) peak_job_memory: u64,
+}
+
+/// A job object that kills every process in it when it's closed
+pub(crate) struct JobObject(OwnedHandle);
+
+impl JobObject {
+    /// Fails if `name` is set and a job with that name already exists, since
+    /// someone else may control it
+    pub(crate) fn new(name: Option<&str>, flags: &JobFlags) -> Result<Self> {
+        let name = name.map(HSTRING::from);
+        let name_ptr = name
+            .as_ref()
+            .map_or(PCWSTR::null(), |name| PCWSTR(name.as_ptr()));
+        // SAFETY: The name outlives the call, and no other pointers are passed.
+        // `OwnedHandle` takes ownership of the result.
+        let job =
+            OwnedHandle(unsafe { CreateJobObjectW(None, name_ptr) }.context("CreateJobObjectW")?);
+        if name.is_some()
+            && windows::core::Error::from_win32().code() == ERROR_ALREADY_EXISTS.to_hresult()
+        {
+            anyhow::bail!("a job object with this name already exists");
+        }
+
+        let mut limits = JOB_OBJECT_LIMIT::default();
+        if flags.kill_on_close {
+            limits |= JOB_OBJECT_LIMIT_KILL_ON_JOB_CLOSE;
+        }
+        if flags.breakaway_ok {
+            limits |= JOB_OBJECT_LIMIT_BREAKAWAY_OK;
+        }
+        if flags.silent_breakaway_ok {
+            limits |= JOB_OBJECT_LIMIT_SILENT_BREAKAWAY_OK;
+        }
+        let mut jeli = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+        jeli.BasicLimitInformation.LimitFlags = limits;
+        // SAFETY: The pointer and size describe `jeli`, which outlives the call.
+        // Windows copies the limits and doesn't keep the pointer.
+        unsafe {
+            SetInformationJobObject(
+                job.0,
+                JobObjectExtendedLimitInformation,
+                &jeli as *const JOBOBJECT_EXTENDED_LIMIT_INFORMATION as *const c_void,
+                u32::try_from(std::mem::size_of_val(&jeli))?,
+            )
+        }
+        .context("SetInformationJobObject")?;
+        Ok(Self(job))
+    }
+
+    pub(crate) fn assign(&self, process: &tokio::process::Child) -> Result<()> {
+        let process = process
+            .raw_handle()
+            .context("Child should have a handle until it's reaped")?;
+        // SAFETY: Both handles are valid for the duration of the call. `Child` owns
+        // the process handle and we only borrow it here.
+        unsafe { AssignProcessToJobObject(self.0 .0, HANDLE(process as isize)) }
+            .context("AssignProcessToJobObject")?;
+        Ok(())
+    }
+
+    pub(crate) fn counters(&self) -> Result<JobCounters> {
+        let mut basic = JOBOBJECT_BASIC_ACCOUNTING_INFORMATION::default();
+        // SAFETY: The pointer and size describe `basic`, which outlives the call
+        unsafe {
+            QueryInformationJobObject(
+                self.0 .0,
+                JobObjectBasicAccountingInformation,
+                std::ptr::from_mut(&mut basic).cast(),
+                u32::try_from(std::mem::size_of_val(&basic))?,
+                None,
+            )
+        }
+        .context("QueryInformationJobObject JobObjectBasicAccountingInformation")?;
+        let mut extended = JOBOBJECT_EXTENDED_LIMIT_INFORMATION::default();
+        // SAFETY: The pointer and size describe `extended`, which outlives the call
+        unsafe {
+            QueryInformationJobObject(
+                self.0 .0,
+                JobObjectExtendedLimitInformation,
+                std::ptr::from_mut(&mut extended).cast(),
+                u32::try_from(std::mem::size_of_val(&extended))?,
+                None,
+            )
+        }
+        .context("QueryInformationJobObject JobObjectExtendedLimitInformation")?;
+        // Job times count 100 ns intervals
+        let ticks = |time: i64| u64::try_from(time).unwrap_or_default();
+        Ok(JobCounters {
+            total_processes: basic.TotalProcesses,
+            active_processes: basic.ActiveProcesses,
+            terminated_processes: basic.TotalTerminatedProcesses,
+            cpu_time: Duration::from_nanos(
+                ticks(basic.TotalUserTime)
+                    .saturating_add(ticks(basic.TotalKernelTime))
+                    .saturating_mul(100),
+            ),
+            peak_process_memory: u64::try_from(extended.PeakProcessMemoryUsed)?,
+            peak_job_memory: u64::try_from(extended.PeakJobMemoryUsed)?,
+        })
+    }
+
+    /// Needs `PROCESS_QUERY_LIMITED_INFORMATION` on `process`
+    pub(crate) fn contains(&self, process: &Process) -> Result<bool> {
+        let mut result = FALSE;
+        // SAFETY: Both handles are valid until their owners drop, and the pointer
+        // is valid for the duration of the call
+        unsafe { IsProcessInJob(process.0 .0, self.0 .0, &mut result) }
+            .context("IsProcessInJob")?;
+        Ok(result.as_bool())
+    }
+}
+
+/// Resumes every thread of a process created with `CREATE_SUSPENDED`
+///
+/// A new process only has its main thread, but `Child` doesn't expose that
+/// thread's handle, so this finds it by process ID.
+pub(crate) fn resume_threads(pid: u32) -> Result<()> {
+    // SAFETY: No pointers are passed, and `OwnedHandle` takes ownership of the result
+    let snapshot = OwnedHandle(
+        unsafe { CreateToolhelp32Snapshot(TH32CS_SNAPTHREAD, 0) }
+            .context("CreateToolhelp32Snapshot")?,
+    );
+    let mut entry = THREADENTRY32 {
+        dwSize: u32::try_from(std::mem::size_of::<THREADENTRY32>())?,
+        ..Default::default()
+    };
+    let mut resumed = 0;
+    // SAFETY: The snapshot is valid until it drops, and `dwSize` describes `entry`
+    let mut next = unsafe { Thread32First(snapshot.0, &mut entry) };
+    while next.is_ok() {
+        if entry.th32OwnerProcessID == pid {
+            // SAFETY: No pointers are passed, and `OwnedHandle` takes ownership of the result
+            let thread = OwnedHandle(
+                unsafe { OpenThread(THREAD_SUSPEND_RESUME, FALSE, entry.th32ThreadID) }
+                    .context("OpenThread")?,
+            );
+            // SAFETY: The thread handle is valid until it drops
+            if unsafe { ResumeThread(thread.0) } == u32::MAX {
+                return Err(windows::core::Error::from_win32()).context("ResumeThread");
+            }
+            resumed += 1;
+        }
+        // SAFETY: Same as `Thread32First`
+        next = unsafe { Thread32Next(snapshot.0, &mut entry) };
+    }
+    anyhow::ensure!(resumed > 0, "process {pid} has no threads to resume");
+    Ok(())
+}
+
+/// The function `ctrl_handler` calls, see `set_console_ctrl_handler`
+static CTRL_HANDLER: OnceLock<fn(u32) -> bool> = OnceLock::new();
+
+/// Calls `handler` with each console ctrl event, e.g. `CTRL_C_EVENT`
+///
+/// `handler` returns whether it handled the event. It stays installed until the
+/// process exits, so this can only be called once.
+pub(crate) fn set_console_ctrl_handler(handler: fn(u32) -> bool) -> Result<()> {
+    CTRL_HANDLER
+        .set(handler)
+        .map_err(|_| anyhow::anyhow!("a console ctrl handler is already set"))?;
+    // SAFETY: `ctrl_handler` is a plain function, so it lives forever
+    unsafe { SetConsoleCtrlHandler(Some(ctrl_handler), TRUE) }.context("SetConsoleCtrlHandler")
+}
+
+unsafe extern "system" fn ctrl_handler(ctrl_type: u32) -> BOOL {
+    CTRL_HANDLER
+        .get()
+        .is_some_and(|handler| handler(ctrl_type))
+        .into()
+}
+
+/// The function `window_proc` calls, see `run_hidden_window`
+static WINDOW_HANDLER: OnceLock<fn(u32, usize) -> Option<isize>> = OnceLock::new();
+
+/// Creates a hidden top-level window and pumps its messages on this thread
+///
+/// Message-only windows don't get broadcasts like `WM_ENDSESSION`, so this one is
+/// top-level but never shown. `handler` gets each message and its `WPARAM`, and
+/// returns the reply, or `None` for the default handling. Returns on `WM_QUIT`.
+/// Only one per process.
+pub(crate) fn run_hidden_window(handler: fn(u32, usize) -> Option<isize>) -> Result<()> {
+    WINDOW_HANDLER
+        .set(handler)
+        .map_err(|_| anyhow::anyhow!("a hidden window is already running"))?;
+    let class_name = w!("subzone_hidden_window");
+    // SAFETY: `None` gets the handle of our own exe, which doesn't need to be freed
+    let instance = unsafe { GetModuleHandleW(None) }.context("GetModuleHandleW")?;
+    let class = WNDCLASSW {
+        lpfnWndProc: Some(window_proc),
+        hInstance: instance.into(),
+        lpszClassName: class_name,
+        ..Default::default()
+    };
+    // SAFETY: `class` only needs to live for the call, and its strings are static
+    if unsafe { RegisterClassW(&class) } == 0 {
+        return Err(windows::core::Error::from_win32()).context("RegisterClassW");
+    }
+    // SAFETY: The class was just registered. The window is never shown, and
+    // lives until the process exits.
+    let hwnd = unsafe {
+        CreateWindowExW(
+            WINDOW_EX_STYLE::default(),
+            class_name,
+            w!(""),
+            WINDOW_STYLE::default(),
+            0,
+            0,
+            0,
+            0,
+            None,
+            None,
+            instance,
+            None,
+        )
+    };
+    if hwnd.0 == 0 {
+        return Err(windows::core::Error::from_win32()).context("CreateWindowExW");
+    }
+    let mut msg = MSG::default();
+    // SAFETY: `msg` outlives both calls. `GetMessageW` returns -1 on errors and 0 on `WM_QUIT`.
+    while unsafe { GetMessageW(&mut msg, None, 0, 0) }.0 > 0 {
+        // SAFETY: `msg` was just filled in by `GetMessageW`
+        unsafe { DispatchMessageW(&msg) };
+    }
+    Ok(())
+}
+
+unsafe extern "system" fn window_proc(
+    hwnd: HWND,
+    msg: u32,
+    wparam: WPARAM,
+    lparam: LPARAM,
+) -> LRESULT {
+    match WINDOW_HANDLER
+        .get()
+        .and_then(|handler| handler(msg, wparam.0))
+    {
+        Some(result) => LRESULT(result),
+        // SAFETY: The arguments are the ones Windows passed us
+        None => unsafe { DefWindowProcW(hwnd, msg, wparam, lparam) },
+    }
+}
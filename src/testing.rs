@@ -0,0 +1,165 @@
+//! Helpers for downstream tests
+//!
+//! `LatencyBudget` replaces hard-coded `elapsed < 100ms` checks with ones that
+//! allow for slow CI machines and explain themselves when they fail.
+//!
+//! `CrashHarness` simulates a manager crash. The multi-process tests use it to check that a `LeakGuard` takes the worker
+//! down with a crashed manager. An app can check its own workers the same way:
+//!
+//! - The test calls `CrashHarness::spawn_manager` with the app's manager subcommand.
+//! - The manager takes the harness pipe ID off the end of its command line,
+//!   spawns its worker as usual, and passes the ID along.
+//! - The worker calls `connect_to_harness` and keeps polling `Client::next`.
+//! - The test kills the manager with `CrashHarness::kill_manager`, and checks
+//!   the worker with `worker_stopped`.
+//!
+//! `replay_repro` decodes a frame from a `ReproBundle` again, see `Server::capture_repros`.
+
+use anyhow::{Context as _, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+use tokio::time::timeout;
+
+use crate::{
+    repro, server::UnconnectedServer, Client, Error, PipeId, ReproBundle, ReproSide, Server,
+    SubcommandChild,
+};
+
+mod latency;
+
+pub use latency::{margin_from_env, LatencyBudget, CI_MARGIN, MARGIN_ENV_VAR};
+
+/// How long `worker_stopped` waits between health probes
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// A manager process under test, and a pipe its worker can report back on
+pub struct CrashHarness {
+    manager: SubcommandChild,
+    /// Taken by the first `accept`
+    server: Option<UnconnectedServer>,
+}
+
+impl CrashHarness {
+    /// Launches the current exe as the manager, with the harness pipe ID appended to `args`
+    ///
+    /// Requires a Tokio context
+    pub fn spawn_manager(args: &[&str]) -> Result<Self> {
+        Self::with_exe(None, args)
+    }
+
+    /// Launches `exe`, or the current exe if it's `None`, as the manager
+    ///
+    /// `exe` must be an absolute path, see `SubprocessBuilder::exe`.
+    pub fn with_exe(exe: Option<&Path>, args: &[&str]) -> Result<Self> {
+        let (server, pipe_id) = UnconnectedServer::new()?;
+        let pipe_id = pipe_id.to_arg();
+        let mut args = args.to_vec();
+        args.push(&pipe_id);
+        let manager = SubcommandChild::with_exe(exe, &args)?;
+        Ok(Self {
+            manager,
+            server: Some(server),
+        })
+    }
+
+    /// Waits up to `within` for the worker to call `connect_to_harness`
+    ///
+    /// Only one worker can connect, so this fails if it's called again.
+    pub async fn accept<M: Serialize, W: DeserializeOwned>(
+        &mut self,
+        within: Duration,
+    ) -> Result<Server<M, W>> {
+        let server = self
+            .server
+            .take()
+            .context("the harness already accepted its worker")?;
+        timeout(within, server.accept())
+            .await
+            .context("worker didn't connect to the harness in time")?
+    }
+
+    /// The manager's PID, or `None` if it's been killed
+    pub fn manager_pid(&self) -> Option<u32> {
+        self.manager.process.id()
+    }
+
+    /// Kills the manager with `TerminateProcess`, the closest thing Windows has to `SIGKILL`
+    ///
+    /// Nothing in the manager gets to clean up, same as if it crashed.
+    pub async fn kill_manager(&mut self) -> Result<()> {
+        timeout(Duration::from_secs(5), self.manager.process.kill())
+            .await
+            .context("manager didn't die in time")??;
+        tracing::debug!("Harness killed manager");
+        Ok(())
+    }
+}
+
+/// Connects a worker to the pipe from `CrashHarness`, without the security cookie
+///
+/// Only for tests, the harness can't check who's connecting. Requires a Tokio context.
+pub fn connect_to_harness<M: DeserializeOwned, W: Serialize>(
+    pipe_id: &PipeId,
+) -> Result<Client<M, W>> {
+    Client::new_unsecured(pipe_id)
+}
+
+/// Decodes the frame from a `ReproBundle` again, the way the side that captured it did
+///
+/// Returns the error it reproduces, or `None` once the frame is accepted, e.g.
+/// after a fix. Load the bundle with `ReproBundle::load`.
+pub fn replay_repro<M: DeserializeOwned, W: DeserializeOwned>(
+    bundle: &ReproBundle,
+) -> Option<Error> {
+    let decoded = match bundle.reader {
+        ReproSide::Manager => {
+            repro::decode_worker_frame::<W>(&bundle.frame, &bundle.connection).map(drop)
+        }
+        ReproSide::Worker => repro::decode_manager_frame::<M>(&bundle.frame).map(drop),
+    };
+    decoded.err()
+}
+
+/// Probes the worker until it stops answering, or `within` passes
+///
+/// Returns `true` if the worker stopped. The worker only answers while it's
+/// polling `Client::next`, so a hung worker counts as running until `within`
+/// passes. Messages the worker sends meanwhile are dropped.
+pub async fn worker_stopped<M: Serialize, W: DeserializeOwned>(
+    server: &mut Server<M, W>,
+    within: Duration,
+) -> bool {
+    let deadline = Instant::now() + within;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return false;
+        }
+        match timeout(remaining, probe(server)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(error)) => {
+                tracing::info!(?error, "Worker stopped responding");
+                return true;
+            }
+            Err(_) => return false,
+        }
+        tokio::time::sleep(PROBE_INTERVAL.min(remaining)).await;
+    }
+}
+
+/// Sends one health probe and waits for the answer
+async fn probe<M: Serialize, W: DeserializeOwned>(server: &mut Server<M, W>) -> Result<(), Error> {
+    let check = server.health().await?;
+    tokio::pin!(check);
+    loop {
+        tokio::select! {
+            health = &mut check => return health.map(|_| ()),
+            msg = server.next() => {
+                msg?;
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Timing assertions that tolerate slow CI machines
+
+use anyhow::{bail, Result};
+use std::{
+    future::Future,
+    time::{Duration, Instant},
+};
+
+/// Overrides the margin for every `LatencyBudget`, e.g. `SUBZONE_LATENCY_MARGIN=5`
+pub const MARGIN_ENV_VAR: &str = "SUBZONE_LATENCY_MARGIN";
+
+/// The margin when `CI` is set and `MARGIN_ENV_VAR` isn't
+///
+/// Shared runners are often several times slower than a dev machine.
+pub const CI_MARGIN: f64 = 3.0;
+
+/// How long something may take, scaled for the machine the test runs on
+///
+/// The budget is what it should take on a dev machine. The limit the checks
+/// enforce is the budget times the margin, which comes from `MARGIN_ENV_VAR`,
+/// else `CI_MARGIN` under CI, else 1.
+#[derive(Clone, Debug)]
+pub struct LatencyBudget {
+    /// Names the operation in failure messages, e.g. `"Server::close"`
+    what: String,
+    budget: Duration,
+    margin: f64,
+}
+
+impl LatencyBudget {
+    pub fn new(what: impl Into<String>, budget: Duration) -> Self {
+        Self {
+            what: what.into(),
+            budget,
+            margin: margin_from_env(),
+        }
+    }
+
+    /// Uses this margin instead of the one from the environment
+    pub fn margin(mut self, margin: f64) -> Self {
+        self.margin = margin;
+        self
+    }
+
+    /// The longest time the checks accept
+    pub fn limit(&self) -> Duration {
+        self.budget.mul_f64(self.margin)
+    }
+
+    /// Fails if `elapsed` is over the limit
+    pub fn check(&self, elapsed: Duration) -> Result<()> {
+        if elapsed <= self.limit() {
+            return Ok(());
+        }
+        bail!(
+            "{} took {elapsed:?}, over its budget of {}",
+            self.what,
+            self.describe()
+        )
+    }
+
+    /// Fails if more than the limit has passed since `start`
+    pub fn check_since(&self, start: Instant) -> Result<()> {
+        self.check(start.elapsed())
+    }
+
+    /// Awaits `future`, then fails if it took longer than the limit
+    pub async fn time<F: Future>(&self, future: F) -> Result<F::Output> {
+        let start = Instant::now();
+        let output = future.await;
+        self.check_since(start)?;
+        Ok(output)
+    }
+
+    /// Fails if the `percentile` of `samples`, e.g. 99 for p99, is over the limit
+    ///
+    /// Uses the nearest-rank method. Single outliers don't fail a p99 check, so
+    /// this is less flaky than checking every sample.
+    pub fn check_percentile(&self, samples: &[Duration], percentile: f64) -> Result<()> {
+        if samples.is_empty() {
+            bail!("{} has no samples to check", self.what);
+        }
+        let mut sorted = samples.to_vec();
+        sorted.sort();
+        let value = nearest_rank(&sorted, percentile);
+        if value <= self.limit() {
+            return Ok(());
+        }
+        let over = sorted.iter().filter(|s| **s > self.limit()).count();
+        bail!(
+            "p{percentile} of {} samples of {} was {value:?}, over its budget of {} \
+             (p50 {:?}, max {:?}, {over} samples over the limit)",
+            sorted.len(),
+            self.what,
+            self.describe(),
+            nearest_rank(&sorted, 50.0),
+            sorted[sorted.len() - 1],
+        )
+    }
+
+    fn describe(&self) -> String {
+        if self.margin == 1.0 {
+            return format!("{:?}", self.budget);
+        }
+        format!(
+            "{:?} (x{} margin, limit {:?})",
+            self.budget,
+            self.margin,
+            self.limit()
+        )
+    }
+}
+
+/// Reads the margin from `MARGIN_ENV_VAR` or `CI`
+///
+/// An unparsable or non-positive `MARGIN_ENV_VAR` is ignored with a warning.
+pub fn margin_from_env() -> f64 {
+    if let Ok(value) = std::env::var(MARGIN_ENV_VAR) {
+        match value.parse::<f64>() {
+            Ok(margin) if margin > 0.0 => return margin,
+            _ => tracing::warn!(?value, "Ignoring invalid {MARGIN_ENV_VAR}"),
+        }
+    }
+    if std::env::var_os("CI").is_some() {
+        CI_MARGIN
+    } else {
+        1.0
+    }
+}
+
+/// `sorted` must not be empty
+fn nearest_rank(sorted: &[Duration], percentile: f64) -> Duration {
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
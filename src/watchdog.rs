@@ -0,0 +1,97 @@
+//! Noticing when the app stops polling `next` while calls are outstanding
+//!
+//! A hung handler looks like a quiet connection from the outside, so a
+//! background task warns when `next` hasn't been polled for a while and some
+//! calls are still unanswered.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+/// Tracks when `next` was last polled
+#[derive(Clone)]
+pub(crate) struct PollTracker {
+    in_next: Arc<AtomicBool>,
+    last_poll: Arc<Mutex<Instant>>,
+}
+
+impl Default for PollTracker {
+    fn default() -> Self {
+        Self {
+            in_next: Default::default(),
+            last_poll: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+/// Marks `next` as being polled until it's dropped, even if `next` is cancelled
+pub(crate) struct PollGuard<'a>(&'a PollTracker);
+
+impl PollTracker {
+    pub(crate) fn enter(&self) -> PollGuard<'_> {
+        self.in_next.store(true, Ordering::Relaxed);
+        PollGuard(self)
+    }
+
+    /// How long it's been since anyone waited in `next`
+    fn idle(&self) -> Duration {
+        if self.in_next.load(Ordering::Relaxed) {
+            return Duration::ZERO;
+        }
+        self.last_poll
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .elapsed()
+    }
+}
+
+impl Drop for PollGuard<'_> {
+    fn drop(&mut self) {
+        *self
+            .0
+            .last_poll
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+        self.0.in_next.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Starts a task that warns when `next` is idle for `threshold` while `outstanding` returns IDs
+///
+/// `side` is `"manager"` or `"worker"`, for the log. Warns once per stall.
+pub(crate) fn spawn(
+    side: &'static str,
+    threshold: Duration,
+    tracker: PollTracker,
+    outstanding: impl Fn() -> Vec<u64> + Send + 'static,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        // `interval` panics on a zero period
+        let mut interval = tokio::time::interval((threshold / 2).max(Duration::from_millis(1)));
+        let mut warned = false;
+        loop {
+            interval.tick().await;
+            let idle = tracker.idle();
+            if idle < threshold {
+                warned = false;
+                continue;
+            }
+            let stuck_ids = outstanding();
+            if stuck_ids.is_empty() || warned {
+                continue;
+            }
+            warned = true;
+            tracing::warn!(
+                side,
+                ?idle,
+                ?stuck_ids,
+                "`next` hasn't been polled while calls are outstanding, the handler may be hung"
+            );
+        }
+    })
+}
@@ -0,0 +1,86 @@
+//! Finding the pipe ID in a worker's command line, stdin, or environment
+//!
+//! A worker that's the same exe as its manager can declare the pipe ID as a
+//! clap argument. A separate worker binary, see `SubprocessBuilder::exe`, has its
+//! own CLI, so it takes ours out with `WorkerArgs::from_env` and parses the rest,
+//! i.e. whatever the manager passed to `SubprocessBuilder::arg`.
+
+use anyhow::Result;
+use std::ffi::OsString;
+
+use crate::{server::PIPE_ID_ENV, Error, PipeId};
+
+/// The pipe ID the manager handed a worker, and the worker's own arguments
+#[derive(Debug)]
+pub struct WorkerArgs {
+    pipe_id: PipeId,
+    app_args: Vec<OsString>,
+}
+
+impl WorkerArgs {
+    /// Finds the pipe ID however the manager delivered it, see `SecretDelivery`
+    ///
+    /// Checks the environment first, then the last argument. If neither has it,
+    /// reads the first line of stdin, so call this before anything else reads stdin.
+    pub fn from_env() -> Result<Self> {
+        let args: Vec<_> = std::env::args_os().collect();
+        if let Some(pipe_id) = take_env_pipe_id()? {
+            return Ok(Self {
+                pipe_id,
+                app_args: args,
+            });
+        }
+        if let Some(this) = Self::from_args(args.clone()) {
+            return Ok(this);
+        }
+        Ok(Self {
+            pipe_id: read_stdin_pipe_id()?,
+            app_args: args,
+        })
+    }
+
+    /// Splits off the pipe ID that `SecretDelivery::Args` appends, if it's there
+    pub(crate) fn from_args(mut args: Vec<OsString>) -> Option<Self> {
+        let pipe_id = PipeId::from_arg(args.last()?.to_str()?).ok()?;
+        args.pop();
+        Some(Self {
+            pipe_id,
+            app_args: args,
+        })
+    }
+
+    pub fn pipe_id(&self) -> &PipeId {
+        &self.pipe_id
+    }
+
+    /// The command line without the pipe ID, starting with the exe name
+    pub fn app_args(&self) -> &[OsString] {
+        &self.app_args
+    }
+
+    /// Parses `app_args` with the worker's own clap CLI
+    #[cfg(feature = "clap")]
+    pub fn parse<C: clap::Parser>(&self) -> Result<C, clap::Error> {
+        C::try_parse_from(&self.app_args)
+    }
+}
+
+/// Takes the pipe ID that `SecretDelivery::Env` set, if it's there
+pub(crate) fn take_env_pipe_id() -> Result<Option<PipeId>> {
+    let Some(pipe_id) = std::env::var_os(PIPE_ID_ENV) else {
+        return Ok(None);
+    };
+    // Scrub it so our own subprocesses don't inherit it
+    std::env::remove_var(PIPE_ID_ENV);
+    let pipe_id = pipe_id
+        .into_string()
+        .map_err(|_| Error::InvalidPipeId("not valid Unicode"))?;
+    Ok(Some(PipeId::from_arg(&pipe_id)?))
+}
+
+/// Reads the pipe ID that `SecretDelivery::Stdin` wrote ahead of the cookie
+pub(crate) fn read_stdin_pipe_id() -> Result<PipeId> {
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(PipeId::from_arg(line.trim_end())?)
+}
@@ -0,0 +1,98 @@
+//! Exit codes that tell the manager why a worker stopped
+//!
+//! A worker that returns `worker_main` from its `main` exits with one of these,
+//! and `SubcommandChild` decodes them into `SubcommandExit::Worker`, so the
+//! manager can restart after a crash but not after a bad config. The codes
+//! follow `sysexits.h` where one fits, like the ones in `cli`.
+
+use serde::{Deserialize, Serialize};
+use std::{panic::AssertUnwindSafe, process::ExitCode};
+
+use crate::Error;
+
+/// Why a worker exited, see `worker_main`
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum WorkerExit {
+    /// Stopped when the manager or the OS asked. Exit code 0
+    Clean,
+    /// The connection to the manager broke. Same as `EX_UNAVAILABLE`, 69
+    ManagerGone,
+    /// Couldn't connect to the manager or finish the handshake. Same as `EX_PROTOCOL`, 76
+    HandshakeFailed,
+    /// Restarting won't help, e.g. the config is bad. Same as `EX_CONFIG`, 78
+    FatalAppError,
+    /// `main` panicked. Same as Rust's own exit code for panics, 101
+    Panic,
+}
+
+/// An error that should stop the worker for good, see `WorkerExit::FatalAppError`
+#[derive(Debug, thiserror::Error)]
+#[error(transparent)]
+pub struct FatalError(#[from] pub anyhow::Error);
+
+impl WorkerExit {
+    pub const fn code(self) -> u8 {
+        match self {
+            Self::Clean => 0,
+            Self::ManagerGone => 69,
+            Self::HandshakeFailed => 76,
+            Self::FatalAppError => 78,
+            Self::Panic => 101,
+        }
+    }
+
+    /// The `WorkerExit` for a process exit code, if it's one of ours
+    pub fn from_code(code: u32) -> Option<Self> {
+        [
+            Self::Clean,
+            Self::ManagerGone,
+            Self::HandshakeFailed,
+            Self::FatalAppError,
+            Self::Panic,
+        ]
+        .into_iter()
+        .find(|exit| u32::from(exit.code()) == code)
+    }
+
+    /// Picks the exit for an error that ended the worker
+    ///
+    /// Returns `None` for errors outside the contract, which exit with 1.
+    pub fn from_error(error: &anyhow::Error) -> Option<Self> {
+        if error.downcast_ref::<FatalError>().is_some() {
+            return Some(Self::FatalAppError);
+        }
+        match error.downcast_ref::<Error>()? {
+            Error::Disconnected(_) | Error::Closed | Error::WriteStalled => Some(Self::ManagerGone),
+            Error::InvalidPipeId(_)
+            | Error::NoConnectionWithin(_)
+            | Error::HandshakeTimeout { .. }
+            | Error::HandshakeRejected { .. }
+            | Error::ServerUnverified
+            | Error::PermissionDenied { .. } => Some(Self::HandshakeFailed),
+            _ => None,
+        }
+    }
+}
+
+impl From<WorkerExit> for ExitCode {
+    fn from(exit: WorkerExit) -> Self {
+        ExitCode::from(exit.code())
+    }
+}
+
+/// Runs a worker's `main` and exits with the matching `WorkerExit`
+///
+/// Return `FatalError` from `f` for errors that restarting won't fix. Errors
+/// are logged, and panics are caught so they exit with `WorkerExit::Panic`
+/// even if the panic hook changed. Meant to be returned straight from `main`.
+pub fn worker_main(f: impl FnOnce() -> anyhow::Result<()>) -> ExitCode {
+    match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => WorkerExit::Clean.into(),
+        Ok(Err(error)) => {
+            let exit = WorkerExit::from_error(&error);
+            tracing::error!(?error, ?exit, "Worker failed");
+            exit.map_or(ExitCode::FAILURE, ExitCode::from)
+        }
+        Err(_) => WorkerExit::Panic.into(),
+    }
+}